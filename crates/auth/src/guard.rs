@@ -0,0 +1,295 @@
+//! Protection against password guessing. Failed attempts are counted per
+//! connection, per client address and per username: each failure is
+//! followed by a growing delay, connections are closed after a few
+//! failures, and clients and usernames with many recent failures are
+//! locked out for a while.
+//!
+//! Every attempt is logged with the `audit` target.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::{Credentials, Identity, ValidationError, Validator};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The delay after the first failure from a client, doubled after
+    /// each further failure.
+    pub delay: Duration,
+    pub max_delay: Duration,
+    /// How many failures a connection may have before it is closed.
+    pub max_failures_per_connection: u32,
+    /// How many failures a client or username may have within `window`
+    /// before it is locked out.
+    pub max_failures: u32,
+    pub window: Duration,
+    /// How long a lockout lasts.
+    pub lockout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(16),
+            max_failures_per_connection: 3,
+            max_failures: 10,
+            window: Duration::from_secs(15 * 60),
+            lockout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+impl Config {
+    /// The delay after a client's `failures`th failure.
+    fn delay(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Client(IpAddr),
+    Username(String),
+}
+
+#[derive(Debug)]
+struct Record {
+    /// Failures since `since`.
+    failures: u32,
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failed attempts on one connection.
+#[derive(Debug, Default)]
+pub struct Failures(AtomicU32);
+
+impl Failures {
+    #[must_use]
+    pub fn count(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Records shared by all connections.
+#[derive(Debug, Default)]
+pub struct Guard {
+    config: Config,
+    records: Mutex<HashMap<Key, Record>>,
+}
+
+/// Records aren't pruned before there are this many.
+const PRUNE_THRESHOLD: usize = 1024;
+
+impl Guard {
+    #[must_use]
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            records: Mutex::default(),
+        }
+    }
+
+    /// Wrap `validator` for a connection from `client` with `failures` so
+    /// far.
+    #[must_use]
+    pub fn validator<'a, V: Validator>(
+        &'a self,
+        validator: &'a V,
+        client: IpAddr,
+        failures: &'a Failures,
+    ) -> Guarded<'a, V> {
+        Guarded {
+            inner: validator,
+            guard: self,
+            client,
+            failures,
+        }
+    }
+
+    /// Whether a connection has failed too often and should be closed.
+    #[must_use]
+    pub fn exhausted(&self, failures: &Failures) -> bool {
+        failures.count() >= self.config.max_failures_per_connection
+    }
+
+    fn locked(&self, keys: &[Key], now: Instant) -> bool {
+        let records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        keys.iter().any(|key| {
+            records
+                .get(key)
+                .and_then(|record| record.locked_until)
+                .is_some_and(|until| until > now)
+        })
+    }
+
+    /// Count a failure for each of `keys`, locking out those with too many.
+    /// Returns the number of recent failures of the first key.
+    fn fail(&self, keys: &[Key], now: Instant) -> u32 {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        if records.len() >= PRUNE_THRESHOLD {
+            records.retain(|_, record| {
+                now.saturating_duration_since(record.since) < self.config.window
+                    || record.locked_until.is_some_and(|until| until > now)
+            });
+        }
+
+        let mut first = 0;
+        for (i, key) in keys.iter().enumerate() {
+            let record = records.entry(key.clone()).or_insert(Record {
+                failures: 0,
+                since: now,
+                locked_until: None,
+            });
+            if now.saturating_duration_since(record.since) >= self.config.window {
+                record.failures = 0;
+                record.since = now;
+            }
+            record.failures += 1;
+            if record.failures >= self.config.max_failures {
+                warn!(target: "audit", ?key, failures = record.failures, "locked out");
+                record.locked_until = Some(now + self.config.lockout);
+                record.failures = 0;
+                record.since = now;
+            }
+            if i == 0 {
+                first = record.failures;
+            }
+        }
+        first
+    }
+
+    /// Forget the failures of a username after a successful attempt. The
+    /// client's are kept, so that guessing can't be hidden between logins
+    /// to an account the client owns.
+    fn succeed(&self, username: Key) {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records.remove(&username);
+    }
+}
+
+/// A [`Validator`] that counts failures and refuses locked out clients and
+/// usernames with [`ValidationError::LockedOut`].
+#[derive(Debug)]
+pub struct Guarded<'a, V> {
+    inner: &'a V,
+    guard: &'a Guard,
+    client: IpAddr,
+    failures: &'a Failures,
+}
+
+#[async_trait::async_trait]
+impl<V: Validator> Validator for Guarded<'_, V> {
+    async fn validate(&self, credentials: &Credentials) -> Result<Identity, ValidationError> {
+        let client = self.client;
+        let username = credentials.username.to_lowercase();
+        let keys = [Key::Client(client), Key::Username(username.clone())];
+
+        if self.guard.locked(&keys, Instant::now()) {
+            warn!(target: "audit", client = %client, username, "authentication refused, locked out");
+            return Err(ValidationError::LockedOut);
+        }
+
+        match self.inner.validate(credentials).await {
+            Ok(identity) => {
+                info!(target: "audit", client = %client, username, "authentication succeeded");
+                self.guard.succeed(Key::Username(username));
+                Ok(identity)
+            }
+            Err(e) => {
+                let failures = self.guard.fail(&keys, Instant::now());
+                self.failures.0.fetch_add(1, Ordering::Relaxed);
+                warn!(target: "audit", client = %client, username, %e, failures, "authentication failed");
+                tokio::time::sleep(self.guard.config.delay(failures)).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use secrecy::SecretString;
+
+    use super::{Config, Failures, Guard};
+    use crate::{Credentials, Identity, ValidationError, Validator};
+
+    /// Accepts any user with the password `hunter2`.
+    struct Hunter2;
+
+    #[async_trait::async_trait]
+    impl Validator for Hunter2 {
+        async fn validate(&self, credentials: &Credentials) -> Result<Identity, ValidationError> {
+            use secrecy::ExposeSecret;
+
+            if credentials.password.expose_secret() == "hunter2" {
+                Ok(Identity(credentials.username.clone()))
+            } else {
+                Err(ValidationError::InvalidCredentials)
+            }
+        }
+    }
+
+    fn credentials(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_owned(),
+            password: SecretString::new(password.to_owned()),
+        }
+    }
+
+    #[test]
+    fn delay() {
+        let config = Config::default();
+        let delays: Vec<_> = (1..7).map(|n| config.delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 16]);
+    }
+
+    #[tokio::test]
+    async fn lockout() {
+        let guard = Guard::new(Config {
+            delay: Duration::ZERO,
+            max_failures_per_connection: 2,
+            max_failures: 3,
+            ..Config::default()
+        });
+        let mallory: IpAddr = [192, 0, 2, 1].into();
+        let alice: IpAddr = [198, 51, 100, 1].into();
+
+        let failures = Failures::default();
+        let validator = guard.validator(&Hunter2, mallory, &failures);
+        for _ in 0..2 {
+            let result = validator.validate(&credentials("alice", "123456")).await;
+            assert!(matches!(result, Err(ValidationError::InvalidCredentials)));
+        }
+        assert!(guard.exhausted(&failures));
+
+        // the third failure from mallory locks out both mallory and alice
+        let failures = Failures::default();
+        let validator = guard.validator(&Hunter2, mallory, &failures);
+        let result = validator.validate(&credentials("Alice", "qwerty")).await;
+        assert!(matches!(result, Err(ValidationError::InvalidCredentials)));
+        let result = validator.validate(&credentials("bob", "hunter2")).await;
+        assert!(matches!(result, Err(ValidationError::LockedOut)));
+
+        let failures = Failures::default();
+        let validator = guard.validator(&Hunter2, alice, &failures);
+        let result = validator.validate(&credentials("alice", "hunter2")).await;
+        assert!(matches!(result, Err(ValidationError::LockedOut)));
+        let result = validator.validate(&credentials("carol", "hunter2")).await;
+        assert_eq!(result.unwrap(), Identity("carol".to_owned()));
+        assert_eq!(failures.count(), 0);
+    }
+}
@@ -1,5 +1,6 @@
 use secrecy::SecretString;
 
+pub mod guard;
 pub mod sasl;
 
 pub struct Credentials {
@@ -14,6 +15,9 @@ pub struct Identity(pub String);
 pub enum ValidationError {
     #[error("invalid credentials")]
     InvalidCredentials,
+    /// Too many recent failures from the client or for the username.
+    #[error("temporarily locked out")]
+    LockedOut,
     #[error("unknown error")]
     Unknown,
 }
@@ -22,3 +26,11 @@ pub enum ValidationError {
 pub trait Validator: Send + Sync {
     async fn validate(&self, credentials: &Credentials) -> Result<Identity, ValidationError>;
 }
+
+/// For a backend chosen at runtime.
+#[async_trait::async_trait]
+impl<V: Validator + ?Sized> Validator for Box<V> {
+    async fn validate(&self, credentials: &Credentials) -> Result<Identity, ValidationError> {
+        (**self).validate(credentials).await
+    }
+}
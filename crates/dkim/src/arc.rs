@@ -0,0 +1,437 @@
+//! Authenticated Received Chain ([RFC 8617]), which lets intermediaries
+//! that modify or forward a message vouch for the authentication results
+//! they saw.
+//!
+//! Each intermediary adds an ARC set of three headers with the same
+//! instance number: `ARC-Authentication-Results:`, an
+//! `ARC-Message-Signature:` that is a DKIM signature under another name,
+//! and an `ARC-Seal:` that signs the sets so far.
+//!
+//! [RFC 8617]: https://datatracker.ietf.org/doc/html/rfc8617
+
+use std::{fmt, time::SystemTime};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dns::Resolver;
+use tracing::debug;
+
+use crate::{
+    message::{self, Header},
+    sign::{fold_signature, timestamp},
+    tags::{compact, Tags},
+    verify::{key, strip_b},
+    Algorithm, Canonicalization, Error, Signature, Signer,
+};
+
+/// The highest instance number a chain may reach.
+const MAX_INSTANCE: u32 = 50;
+
+const RESULTS: &str = "arc-authentication-results";
+const MESSAGE_SIGNATURE: &str = "arc-message-signature";
+const SEAL: &str = "arc-seal";
+
+/// The state of a chain (`cv=` tag), also used for the `arc=` result in
+/// `Authentication-Results:`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainStatus {
+    /// The message has no ARC sets.
+    None,
+    Pass,
+    Fail,
+}
+
+impl fmt::Display for ChainStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+        })
+    }
+}
+
+/// The outcome of validating the chain of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validation {
+    pub status: ChainStatus,
+    /// The highest instance number in the message, or 0 if there are no
+    /// ARC headers.
+    pub instance: u32,
+    /// Why the chain failed.
+    pub reason: Option<String>,
+}
+
+/// The headers one intermediary added.
+struct Set<'h, 'a> {
+    results: &'h Header<'a>,
+    signature: &'h Header<'a>,
+    seal: &'h Header<'a>,
+}
+
+impl Set<'_, '_> {
+    fn raw(&self) -> [&[u8]; 3] {
+        [self.results.raw, self.signature.raw, self.seal.raw]
+    }
+}
+
+/// The instance number of an ARC header, from the `i=` tag that comes
+/// first in all three of them.
+fn instance(header: &Header<'_>) -> Option<u32> {
+    let value = String::from_utf8_lossy(header.value());
+    let (first, _) = value.split_once(';')?;
+    let (name, instance) = first.split_once('=')?;
+    if name.trim() != "i" {
+        return None;
+    }
+    instance
+        .trim()
+        .parse()
+        .ok()
+        .filter(|i| (1..=MAX_INSTANCE).contains(i))
+}
+
+/// The ARC headers of a message and their instance numbers.
+fn arc_headers<'h, 'a>(headers: &'h [Header<'a>]) -> Vec<(&'h Header<'a>, Option<u32>)> {
+    headers
+        .iter()
+        .filter(|header| {
+            [RESULTS, MESSAGE_SIGNATURE, SEAL]
+                .iter()
+                .any(|name| header.name.eq_ignore_ascii_case(name))
+        })
+        .map(|header| (header, instance(header)))
+        .collect()
+}
+
+/// Group the ARC headers into sets, ordered by instance. Every instance up
+/// to the highest one needs exactly one header of each kind ([Section 5.2]
+/// of RFC 8617).
+///
+/// [Section 5.2]: https://datatracker.ietf.org/doc/html/rfc8617#section-5.2
+fn sets<'h, 'a>(headers: &'h [Header<'a>]) -> Result<Vec<Set<'h, 'a>>, String> {
+    let arc = arc_headers(headers);
+    let mut numbered = Vec::with_capacity(arc.len());
+    for (header, instance) in arc {
+        let instance = instance.ok_or(format!("invalid instance in {}", header.name))?;
+        numbered.push((header, instance));
+    }
+
+    let highest = numbered.iter().map(|&(_, i)| i).max().unwrap_or(0);
+    let find = |name: &str, i: u32| {
+        let mut matching = numbered
+            .iter()
+            .filter(|(header, instance)| *instance == i && header.name.eq_ignore_ascii_case(name));
+        match (matching.next(), matching.next()) {
+            (Some(&(header, _)), None) => Ok(header),
+            _ => Err(format!("ARC set {i} does not have exactly one {name}")),
+        }
+    };
+    (1..=highest)
+        .map(|i| {
+            Ok(Set {
+                results: find(RESULTS, i)?,
+                signature: find(MESSAGE_SIGNATURE, i)?,
+                seal: find(SEAL, i)?,
+            })
+        })
+        .collect()
+}
+
+/// The data an `ARC-Seal:` signs: all the sets up to its own, in the order
+/// results, message signature, seal ([Section 5.1.1] of RFC 8617). The last
+/// seal must already have its `b=` value removed.
+///
+/// [Section 5.1.1]: https://datatracker.ietf.org/doc/html/rfc8617#section-5.1.1
+fn seal_data<'r>(sets: impl IntoIterator<Item = [&'r [u8]; 3]>) -> Vec<u8> {
+    let mut data = Vec::new();
+    for raw in sets.into_iter().flatten() {
+        Canonicalization::Relaxed.header(raw, &mut data);
+    }
+    // without the final CRLF
+    data.truncate(data.len().saturating_sub(2));
+    data
+}
+
+/// Check the message signature of the latest set.
+async fn check_message_signature<R: Resolver + ?Sized>(
+    resolver: &R,
+    headers: &[Header<'_>],
+    body: &[u8],
+    header: &Header<'_>,
+) -> Result<(), String> {
+    let value = String::from_utf8_lossy(header.value());
+    let tags = Tags::parse(&value).ok_or("malformed ARC-Message-Signature")?;
+    let signature = Signature::from_tags(&tags)?;
+    let record = key(
+        resolver,
+        &signature.selector,
+        &signature.domain,
+        signature.algorithm,
+    )
+    .await
+    .map_err(|(_, reason)| reason)?;
+
+    if !signature.body_matches(body) {
+        return Err("ARC-Message-Signature body hash did not verify".to_owned());
+    }
+    if !record.key.verify(
+        &signature.signed_data(headers, header),
+        &signature.signature,
+    ) {
+        return Err("ARC-Message-Signature did not verify".to_owned());
+    }
+    Ok(())
+}
+
+/// Check the seal of the last of `sets`.
+async fn check_seal<R: Resolver + ?Sized>(
+    resolver: &R,
+    sets: &[Set<'_, '_>],
+) -> Result<(), String> {
+    let Some((last, earlier)) = sets.split_last() else {
+        return Ok(());
+    };
+    let i = sets.len();
+    let value = String::from_utf8_lossy(last.seal.value());
+    let tags = Tags::parse(&value).ok_or(format!("malformed ARC-Seal {i}"))?;
+    if tags.get("h").is_some() {
+        return Err(format!("ARC-Seal {i} has an h= tag"));
+    }
+    let tag = |name: &str| {
+        tags.get(name)
+            .ok_or(format!("ARC-Seal {i} has no {name}= tag"))
+    };
+    let algorithm: Algorithm = tag("a")?.parse().map_err(|e: Error| e.to_string())?;
+    let signature = STANDARD
+        .decode(compact(tag("b")?))
+        .map_err(|_| format!("invalid base64 in ARC-Seal {i}"))?;
+    let record = key(resolver, tag("s")?, tag("d")?, algorithm)
+        .await
+        .map_err(|(_, reason)| reason)?;
+
+    let stripped = strip_b(last.seal.raw);
+    let data = seal_data(earlier.iter().map(Set::raw).chain([[
+        last.results.raw,
+        last.signature.raw,
+        &stripped,
+    ]]));
+    if !record.key.verify(&data, &signature) {
+        return Err(format!("ARC-Seal {i} did not verify"));
+    }
+    Ok(())
+}
+
+async fn check<R: Resolver + ?Sized>(
+    resolver: &R,
+    headers: &[Header<'_>],
+    body: &[u8],
+    sets: &[Set<'_, '_>],
+) -> Result<(), String> {
+    for (i, set) in sets.iter().enumerate() {
+        let value = String::from_utf8_lossy(set.seal.value());
+        let cv = Tags::parse(&value).and_then(|tags| tags.get("cv").map(ToOwned::to_owned));
+        let expected = if i == 0 { "none" } else { "pass" };
+        if cv.as_deref() != Some(expected) {
+            return Err(format!("ARC-Seal {} does not have cv={expected}", i + 1));
+        }
+    }
+
+    if let Some(latest) = sets.last() {
+        check_message_signature(resolver, headers, body, latest.signature).await?;
+    }
+    for end in (1..=sets.len()).rev() {
+        check_seal(resolver, &sets[..end]).await?;
+    }
+    Ok(())
+}
+
+/// Validate the ARC chain of `message` ([Section 5.2] of RFC 8617).
+///
+/// [Section 5.2]: https://datatracker.ietf.org/doc/html/rfc8617#section-5.2
+pub async fn validate<R: Resolver + ?Sized>(resolver: &R, message: &[u8]) -> Validation {
+    let (headers, body) = message::split(message);
+    let instance = arc_headers(&headers)
+        .iter()
+        .filter_map(|&(_, instance)| instance)
+        .max()
+        .unwrap_or(0);
+
+    let result = match sets(&headers) {
+        Ok(sets) if sets.is_empty() => Ok(ChainStatus::None),
+        Ok(sets) => check(resolver, &headers, body, &sets)
+            .await
+            .map(|()| ChainStatus::Pass),
+        Err(reason) => Err(reason),
+    };
+    let validation = match result {
+        Ok(status) => Validation {
+            status,
+            instance,
+            reason: None,
+        },
+        Err(reason) => Validation {
+            status: ChainStatus::Fail,
+            instance,
+            reason: Some(reason),
+        },
+    };
+    debug!(?validation, "validated ARC chain");
+    validation
+}
+
+impl Signer {
+    /// Add an ARC set to `message`, whose chain validated as `chain`.
+    /// `results` is the value of the `Authentication-Results:` header the
+    /// message was checked with, starting with the authserv-id. Returns the
+    /// headers to prepend, or `None` if the chain is already as long as it
+    /// can get.
+    ///
+    /// A failed chain is sealed with `cv=fail`, and the seal only covers
+    /// the new set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key fails to sign.
+    pub fn seal(
+        &self,
+        message: &[u8],
+        chain: &Validation,
+        results: &str,
+        time: SystemTime,
+    ) -> Result<Option<String>, Error> {
+        if chain.instance >= MAX_INSTANCE {
+            return Ok(None);
+        }
+        let i = chain.instance + 1;
+
+        let results = format!("ARC-Authentication-Results: i={i}; {}\r\n", results.trim());
+        let signature = self.sign_as(&format!("ARC-Message-Signature: i={i};"), message, time)?;
+        let mut seal = format!(
+            "ARC-Seal: i={i}; a={}; t={}; cv={};\r\n\td={}; s={};\r\n\tb=",
+            self.key.algorithm(),
+            timestamp(time),
+            chain.status,
+            self.domain,
+            self.selector,
+        );
+
+        let (headers, _) = message::split(message);
+        let earlier = match chain.status {
+            ChainStatus::Pass => sets(&headers).unwrap_or_default(),
+            ChainStatus::None | ChainStatus::Fail => Vec::new(),
+        };
+        let unsigned = format!("{seal}\r\n");
+        let data = seal_data(earlier.iter().map(Set::raw).chain([[
+            results.as_bytes(),
+            signature.as_bytes(),
+            unsigned.as_bytes(),
+        ]]));
+        fold_signature(&mut seal, &STANDARD.encode(self.key.sign(&data)?));
+        debug!(domain = self.domain, instance = i, "sealed message");
+
+        Ok(Some(format!("{seal}{signature}{results}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use dns::StaticResolver;
+
+    use super::{validate, ChainStatus};
+    use crate::{PrivateKey, Signer};
+
+    const MESSAGE: &[u8] = b"From: Alice <alice@example.org>\r\n\
+        To: list@lists.example.com\r\n\
+        Subject: hi\r\n\
+        \r\n\
+        hello\r\n";
+
+    fn signer(domain: &str, seed: u8) -> (Signer, String) {
+        let key = PrivateKey::from_ed25519_seed(&[seed; 32]).unwrap();
+        let record = format!(
+            "v=DKIM1; k=ed25519; p={}",
+            STANDARD.encode(key.public_key())
+        );
+        (Signer::new(domain, "arc", key), record)
+    }
+
+    #[tokio::test]
+    async fn chain() {
+        let (first, first_key) = signer("lists.example.com", 1);
+        let (second, second_key) = signer("forwarder.example.net", 2);
+        let resolver = StaticResolver::default()
+            .with_txt("arc._domainkey.lists.example.com", first_key)
+            .with_txt("arc._domainkey.forwarder.example.net", second_key);
+
+        let none = validate(&resolver, MESSAGE).await;
+        assert_eq!((none.status, none.instance), (ChainStatus::None, 0));
+
+        let mut message = first
+            .seal(MESSAGE, &none, "mx.lists.example.com; spf=pass", UNIX_EPOCH)
+            .unwrap()
+            .unwrap()
+            .into_bytes();
+        assert!(message.starts_with(b"ARC-Seal: i=1; a=ed25519-sha256; t=0; cv=none;"));
+        message.extend_from_slice(MESSAGE);
+
+        let validation = validate(&resolver, &message).await;
+        assert_eq!(
+            (validation.status, validation.instance),
+            (ChainStatus::Pass, 1),
+            "{validation:?}"
+        );
+
+        let mut forwarded = second
+            .seal(
+                &message,
+                &validation,
+                "mx.example.net;\r\n\tarc=pass",
+                UNIX_EPOCH,
+            )
+            .unwrap()
+            .unwrap()
+            .into_bytes();
+        forwarded.extend_from_slice(&message);
+        let validation = validate(&resolver, &forwarded).await;
+        assert_eq!(
+            (validation.status, validation.instance),
+            (ChainStatus::Pass, 2),
+            "{validation:?}"
+        );
+
+        // the latest message signature covers the body
+        let mut modified = forwarded.clone();
+        modified.extend_from_slice(b"unsubscribe\r\n");
+        assert_eq!(
+            validate(&resolver, &modified).await.status,
+            ChainStatus::Fail
+        );
+
+        // the seals cover the earlier results
+        let modified = String::from_utf8(forwarded.clone())
+            .unwrap()
+            .replace("spf=pass", "spf=fail");
+        let validation = validate(&resolver, modified.as_bytes()).await;
+        assert_eq!(validation.status, ChainStatus::Fail);
+        assert_eq!(
+            validation.reason.as_deref(),
+            Some("ARC-Seal 2 did not verify")
+        );
+
+        // a set is missing
+        let (_, without_seal) = forwarded.split_at(
+            forwarded
+                .windows(11)
+                .position(|w| w == b"ARC-Message")
+                .unwrap(),
+        );
+        let validation = validate(&resolver, without_seal).await;
+        assert_eq!(
+            (validation.status, validation.instance),
+            (ChainStatus::Fail, 2)
+        );
+    }
+}
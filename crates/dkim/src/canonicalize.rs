@@ -0,0 +1,173 @@
+//! Canonicalization algorithms ([Section 3.4] of RFC 6376).
+//!
+//! [Section 3.4]: https://datatracker.ietf.org/doc/html/rfc6376#section-3.4
+
+use std::{fmt, str::FromStr};
+
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Canonicalization {
+    /// Tolerates almost no modification.
+    #[default]
+    Simple,
+    /// Tolerates changes to whitespace and header folding.
+    Relaxed,
+}
+
+impl fmt::Display for Canonicalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Simple => "simple",
+            Self::Relaxed => "relaxed",
+        })
+    }
+}
+
+impl FromStr for Canonicalization {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "simple" => Ok(Self::Simple),
+            "relaxed" => Ok(Self::Relaxed),
+            _ => Err(Error::UnsupportedAlgorithm(s.to_owned())),
+        }
+    }
+}
+
+fn is_wsp(c: u8) -> bool {
+    matches!(c, b' ' | b'\t')
+}
+
+/// Replace runs of whitespace with a single space and drop trailing
+/// whitespace.
+fn compress_wsp(line: &[u8], out: &mut Vec<u8>) {
+    let mut space = false;
+    for &c in line {
+        if is_wsp(c) {
+            space = true;
+        } else {
+            if space {
+                out.push(b' ');
+                space = false;
+            }
+            out.push(c);
+        }
+    }
+}
+
+impl Canonicalization {
+    /// Canonicalize a header field, which includes its final CRLF.
+    pub fn header(self, raw: &[u8], out: &mut Vec<u8>) {
+        match self {
+            Self::Simple => out.extend_from_slice(raw),
+            Self::Relaxed => {
+                let colon = raw.iter().position(|&c| c == b':').unwrap_or(raw.len());
+                let name = raw[..colon].trim_ascii();
+                out.extend(name.iter().map(u8::to_ascii_lowercase));
+                out.push(b':');
+
+                let value: Vec<u8> = raw
+                    .get(colon + 1..)
+                    .unwrap_or_default()
+                    .iter()
+                    .copied()
+                    .filter(|&c| c != b'\r' && c != b'\n')
+                    .collect();
+                compress_wsp(value.trim_ascii_start(), out);
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+
+    /// Canonicalize a message body.
+    #[must_use]
+    pub fn body(self, body: &[u8]) -> Vec<u8> {
+        let mut lines: Vec<&[u8]> = body
+            .split(|&c| c == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .collect();
+        // the split after the final CRLF
+        if body.ends_with(b"\n") {
+            lines.pop();
+        }
+
+        let mut out = Vec::with_capacity(body.len() + 2);
+        let mut empty = 0;
+        for line in lines {
+            let start = out.len();
+            match self {
+                Self::Simple => out.extend_from_slice(line),
+                Self::Relaxed => compress_wsp(line, &mut out),
+            }
+
+            // empty lines are only kept if something follows them
+            if out.len() == start {
+                empty += 1;
+            } else {
+                let text = out.split_off(start);
+                for _ in 0..empty {
+                    out.extend_from_slice(b"\r\n");
+                }
+                empty = 0;
+                out.extend_from_slice(&text);
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+
+        if out.is_empty() && self == Self::Simple {
+            out.extend_from_slice(b"\r\n");
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use sha2::{Digest, Sha256};
+
+    use super::Canonicalization;
+    use crate::message::split;
+
+    /// The examples in Section 3.4.5 of RFC 6376.
+    #[test]
+    fn examples() {
+        let message = b"A: X\r\nB : Y\t\r\n\tZ  \r\n\r\n C \r\nD \t E\r\n\r\n\r\n";
+        let (headers, body) = split(message);
+
+        let mut relaxed = Vec::new();
+        let mut simple = Vec::new();
+        for header in &headers {
+            Canonicalization::Relaxed.header(header.raw, &mut relaxed);
+            Canonicalization::Simple.header(header.raw, &mut simple);
+        }
+        assert_eq!(relaxed, b"a:X\r\nb:Y Z\r\n");
+        assert_eq!(simple, b"A: X\r\nB : Y\t\r\n\tZ  \r\n");
+
+        assert_eq!(Canonicalization::Relaxed.body(body), b" C\r\nD E\r\n");
+        assert_eq!(Canonicalization::Simple.body(body), b" C \r\nD \t E\r\n");
+    }
+
+    #[test]
+    fn empty_body() {
+        let hash = |body: Vec<u8>| STANDARD.encode(Sha256::digest(body));
+
+        // Section 3.4.3 and 3.4.4 of RFC 6376
+        assert_eq!(
+            hash(Canonicalization::Simple.body(b"")),
+            "frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY="
+        );
+        assert_eq!(
+            hash(Canonicalization::Relaxed.body(b"\r\n \r\n")),
+            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+        // a missing final CRLF is added
+        assert_eq!(
+            Canonicalization::Relaxed.body(b"a \r\n\r\nb"),
+            b"a\r\n\r\nb\r\n"
+        );
+        assert_eq!(Canonicalization::Simple.body(b"a\r\n\r\n\r\n"), b"a\r\n");
+    }
+}
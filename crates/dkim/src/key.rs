@@ -0,0 +1,237 @@
+use std::fmt;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePublicKey},
+    traits::PublicKeyParts,
+    Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    tags::{compact, Tags},
+    Algorithm, Error,
+};
+
+/// The smallest RSA key accepted for verification ([RFC 8301]).
+///
+/// [RFC 8301]: https://datatracker.ietf.org/doc/html/rfc8301
+const MIN_RSA_BITS: usize = 1024;
+
+/// A key to sign messages with.
+pub enum PrivateKey {
+    Rsa(Box<RsaPrivateKey>),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rsa(key) => write!(f, "PrivateKey::Rsa({} bits)", key.size() * 8),
+            Self::Ed25519(key) => f.debug_tuple("PrivateKey::Ed25519").field(key).finish(),
+        }
+    }
+}
+
+impl PrivateKey {
+    /// Parse a PEM encoded key: an RSA key in PKCS #1 or PKCS #8 format, or
+    /// an Ed25519 key in PKCS #8 format.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key can't be parsed.
+    pub fn from_pem(pem: &str) -> Result<Self, Error> {
+        let invalid = |e: &dyn fmt::Display| Error::InvalidKey(e.to_string());
+
+        let (label, der) =
+            rsa::pkcs8::der::pem::decode_vec(pem.as_bytes()).map_err(|e| invalid(&e))?;
+        match label {
+            "RSA PRIVATE KEY" => Ok(Self::Rsa(Box::new(
+                RsaPrivateKey::from_pkcs1_der(&der).map_err(|e| invalid(&e))?,
+            ))),
+            "PRIVATE KEY" => match RsaPrivateKey::from_pkcs8_der(&der) {
+                Ok(key) => Ok(Self::Rsa(Box::new(key))),
+                Err(_) => Ok(Self::Ed25519(
+                    Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der).map_err(|e| invalid(&e))?,
+                )),
+            },
+            label => Err(Error::InvalidKey(format!("unexpected PEM label {label}"))),
+        }
+    }
+
+    /// An Ed25519 key from its 32 byte seed, the form used in [RFC 8463].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the seed has the wrong length.
+    ///
+    /// [RFC 8463]: https://datatracker.ietf.org/doc/html/rfc8463
+    pub fn from_ed25519_seed(seed: &[u8]) -> Result<Self, Error> {
+        Ed25519KeyPair::from_seed_unchecked(seed)
+            .map(Self::Ed25519)
+            .map_err(|e| Error::InvalidKey(e.to_string()))
+    }
+
+    #[must_use]
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Self::Rsa(_) => Algorithm::RsaSha256,
+            Self::Ed25519(_) => Algorithm::Ed25519Sha256,
+        }
+    }
+
+    /// The public key to publish in the `p=` tag of the key record.
+    #[must_use]
+    pub fn public_key(&self) -> Vec<u8> {
+        match self {
+            Self::Rsa(key) => key
+                .to_public_key()
+                .to_public_key_der()
+                .map(rsa::pkcs8::Document::into_vec)
+                .unwrap_or_default(),
+            Self::Ed25519(key) => key.public_key().as_ref().to_vec(),
+        }
+    }
+
+    /// Sign the SHA-256 hash of `data`. Ed25519 signs the hash rather than
+    /// the data itself ([Section 3] of RFC 8463).
+    ///
+    /// [Section 3]: https://datatracker.ietf.org/doc/html/rfc8463#section-3
+    pub(crate) fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let hash = Sha256::digest(data);
+        match self {
+            Self::Rsa(key) => key
+                .sign(Pkcs1v15Sign::new::<Sha256>(), &hash)
+                .map_err(|_| Error::Signing),
+            Self::Ed25519(key) => Ok(key.sign(&hash).as_ref().to_vec()),
+        }
+    }
+}
+
+/// A key to verify signatures with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    Rsa(RsaPublicKey),
+    Ed25519(Vec<u8>),
+}
+
+impl PublicKey {
+    #[must_use]
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Self::Rsa(_) => Algorithm::RsaSha256,
+            Self::Ed25519(_) => Algorithm::Ed25519Sha256,
+        }
+    }
+
+    /// Check `signature` over the SHA-256 hash of `data`.
+    #[must_use]
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        let hash = Sha256::digest(data);
+        match self {
+            Self::Rsa(key) => key
+                .verify(Pkcs1v15Sign::new::<Sha256>(), &hash, signature)
+                .is_ok(),
+            Self::Ed25519(key) => UnparsedPublicKey::new(&ED25519, key)
+                .verify(&hash, signature)
+                .is_ok(),
+        }
+    }
+}
+
+/// A key record published in DNS ([Section 3.6.1] of RFC 6376).
+///
+/// [Section 3.6.1]: https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub key: PublicKey,
+    /// The domain is testing DKIM (`t=y`).
+    pub testing: bool,
+    /// The `i=` domain must be the signing domain (`t=s`).
+    pub strict: bool,
+}
+
+impl Record {
+    /// Parse a key record.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if the record is invalid,
+    /// revoked or not meant for email.
+    pub fn parse(record: &str) -> Result<Self, String> {
+        let tags = Tags::parse(record).ok_or("malformed key record")?;
+
+        // v= is optional, but has to come first
+        match (tags.first(), tags.get("v")) {
+            (_, None) | (Some(("v", "DKIM1")), _) => (),
+            _ => return Err("invalid key record version".to_owned()),
+        }
+        if let Some(hashes) = tags.get("h") {
+            if !hashes.split(':').any(|hash| hash.trim() == "sha256") {
+                return Err("key does not allow SHA-256".to_owned());
+            }
+        }
+        if let Some(services) = tags.get("s") {
+            if !services
+                .split(':')
+                .any(|s| matches!(s.trim(), "*" | "email"))
+            {
+                return Err("key is not for email".to_owned());
+            }
+        }
+        let flags: Vec<_> = tags
+            .get("t")
+            .map(|flags| flags.split(':').map(str::trim).collect())
+            .unwrap_or_default();
+
+        let data = compact(tags.get("p").ok_or("key record without p=")?);
+        if data.is_empty() {
+            return Err("key revoked".to_owned());
+        }
+        let data = STANDARD.decode(data).map_err(|_| "invalid base64 in p=")?;
+
+        let key = match tags.get("k").unwrap_or("rsa") {
+            "rsa" => {
+                let key = RsaPublicKey::from_public_key_der(&data)
+                    .or_else(|_| RsaPublicKey::from_pkcs1_der(&data))
+                    .map_err(|e| format!("invalid RSA key: {e}"))?;
+                if key.size() * 8 < MIN_RSA_BITS {
+                    return Err("RSA key too short".to_owned());
+                }
+                PublicKey::Rsa(key)
+            }
+            "ed25519" if data.len() == 32 => PublicKey::Ed25519(data),
+            "ed25519" => return Err("invalid Ed25519 key".to_owned()),
+            k => return Err(format!("unsupported key type {k}")),
+        };
+
+        Ok(Self {
+            key,
+            testing: flags.contains(&"y"),
+            strict: flags.contains(&"s"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PublicKey, Record};
+
+    #[test]
+    fn record() {
+        let record = Record::parse(
+            "v=DKIM1; k=ed25519; t=y; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
+        )
+        .unwrap();
+        assert!(matches!(record.key, PublicKey::Ed25519(_)));
+        assert!(record.testing);
+        assert!(!record.strict);
+
+        assert_eq!(Record::parse("v=DKIM1; p=").unwrap_err(), "key revoked");
+        assert!(Record::parse("k=rsa; v=DKIM1; p=abc").is_err());
+        assert!(Record::parse("v=DKIM1; h=sha1; p=abc").is_err());
+        assert!(Record::parse("v=DKIM1; k=ed25519; p=abcd").is_err());
+    }
+}
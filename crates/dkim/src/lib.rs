@@ -0,0 +1,66 @@
+//! DKIM ([RFC 6376]) with RSA-SHA256 and Ed25519-SHA256 ([RFC 8463])
+//! signatures.
+//!
+//! Key records are looked up with a [`dns::Resolver`]. The [`arc`] module
+//! builds ARC ([RFC 8617]) on top of the same signatures.
+//!
+//! [RFC 6376]: https://datatracker.ietf.org/doc/html/rfc6376
+//! [RFC 8463]: https://datatracker.ietf.org/doc/html/rfc8463
+//! [RFC 8617]: https://datatracker.ietf.org/doc/html/rfc8617
+
+#![warn(clippy::pedantic)]
+
+use std::{fmt, str::FromStr};
+
+pub mod arc;
+mod canonicalize;
+mod key;
+pub mod message;
+mod sign;
+pub mod tags;
+mod verify;
+
+pub use canonicalize::Canonicalization;
+pub use key::{PrivateKey, PublicKey, Record};
+pub use sign::{KeyConfig, Keys, Signer, DEFAULT_HEADERS};
+pub use verify::{strip_b, verify, Signature, Status, Verification};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    #[error("unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("signing failed")]
+    Signing,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// The signing algorithm (`a=` tag).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    RsaSha256,
+    Ed25519Sha256,
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::RsaSha256 => "rsa-sha256",
+            Self::Ed25519Sha256 => "ed25519-sha256",
+        })
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rsa-sha256" => Ok(Self::RsaSha256),
+            "ed25519-sha256" => Ok(Self::Ed25519Sha256),
+            _ => Err(Error::UnsupportedAlgorithm(s.to_owned())),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Splitting a message into the header fields and body that DKIM hashes.
+
+/// A header field as it appears in the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header<'a> {
+    pub name: &'a str,
+    /// The whole field including the name, any folding and the final CRLF.
+    pub raw: &'a [u8],
+}
+
+impl<'a> Header<'a> {
+    fn parse(raw: &'a [u8]) -> Self {
+        let name = raw
+            .iter()
+            .position(|&c| c == b':')
+            .and_then(|colon| std::str::from_utf8(&raw[..colon]).ok())
+            .unwrap_or_default()
+            .trim();
+        Self { name, raw }
+    }
+
+    /// The unparsed value after the colon, with folding.
+    #[must_use]
+    pub fn value(&self) -> &'a [u8] {
+        let colon = self.raw.iter().position(|&c| c == b':').unwrap_or(0);
+        &self.raw[colon + 1..]
+    }
+}
+
+/// Split `message` into its header fields and body.
+#[must_use]
+pub fn split(message: &[u8]) -> (Vec<Header<'_>>, &[u8]) {
+    let mut headers = Vec::new();
+    let mut start = None;
+    let mut pos = 0;
+
+    let flush = |headers: &mut Vec<_>, start: Option<usize>, end: usize| {
+        if let Some(start) = start {
+            headers.push(Header::parse(&message[start..end]));
+        }
+    };
+
+    while pos < message.len() {
+        let end = message[pos..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map_or(message.len(), |i| pos + i + 1);
+        let line = &message[pos..end];
+
+        if line == b"\r\n" || line == b"\n" {
+            flush(&mut headers, start, pos);
+            return (headers, &message[end..]);
+        }
+        // continuation lines start with whitespace
+        if !matches!(line[0], b' ' | b'\t') {
+            flush(&mut headers, start, pos);
+            start = Some(pos);
+        }
+        pos = end;
+    }
+
+    flush(&mut headers, start, pos);
+    (headers, &[])
+}
+
+/// Pick the headers named in `names` in the order they are hashed. Each
+/// occurrence of a name selects the next instance from the bottom, and
+/// names without an instance left select nothing ([Section 5.4.2] of
+/// RFC 6376).
+///
+/// [Section 5.4.2]: https://datatracker.ietf.org/doc/html/rfc6376#section-5.4.2
+pub fn select<'a, 'h>(
+    headers: &'h [Header<'a>],
+    names: impl IntoIterator<Item = &'h str>,
+) -> Vec<&'h Header<'a>> {
+    let mut used = vec![false; headers.len()];
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let i = (0..headers.len())
+                .rev()
+                .find(|&i| !used[i] && headers[i].name.eq_ignore_ascii_case(name))?;
+            used[i] = true;
+            Some(&headers[i])
+        })
+        .collect()
+}
+
+/// The domain of the address in the `From:` header, which identifies the
+/// author.
+#[must_use]
+pub fn from_domain(headers: &[Header<'_>]) -> Option<String> {
+    let from = headers
+        .iter()
+        .rev()
+        .find(|header| header.name.eq_ignore_ascii_case("from"))?;
+    let value = String::from_utf8_lossy(from.value());
+    let address = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value.trim(),
+    };
+    let (_, domain) = address.rsplit_once('@')?;
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    (!domain.is_empty()).then_some(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_domain, select, split, Header};
+
+    #[test]
+    fn headers() {
+        let message = b"From: Alice <alice@Example.ORG>\r\n\
+            Subject: a\r\n \tlong subject\r\n\
+            Received: one\r\n\
+            Received: two\r\n\
+            \r\n\
+            Subject: not a header\r\n";
+        let (headers, body) = split(message);
+        assert_eq!(body, b"Subject: not a header\r\n");
+        assert_eq!(
+            headers[1],
+            Header {
+                name: "Subject",
+                raw: b"Subject: a\r\n \tlong subject\r\n"
+            }
+        );
+        assert_eq!(headers[1].value(), b" a\r\n \tlong subject\r\n");
+        assert_eq!(from_domain(&headers).as_deref(), Some("example.org"));
+
+        let selected = select(&headers, ["received", "from", "received", "received"]);
+        let raw: Vec<_> = selected.iter().map(|header| header.raw).collect();
+        assert_eq!(
+            raw,
+            [
+                &b"Received: two\r\n"[..],
+                b"From: Alice <alice@Example.ORG>\r\n",
+                b"Received: one\r\n",
+            ]
+        );
+
+        let (headers, body) = split(b"Subject: no body\r\n");
+        assert_eq!(headers.len(), 1);
+        assert!(body.is_empty());
+    }
+}
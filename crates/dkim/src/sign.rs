@@ -0,0 +1,419 @@
+//! Signing ([Section 5] of RFC 6376).
+//!
+//! [Section 5]: https://datatracker.ietf.org/doc/html/rfc6376#section-5
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::{
+    message::{self, Header},
+    Algorithm, Canonicalization, Error, PrivateKey,
+};
+
+/// The headers signed by default, following [Section 5.4.1] of RFC 6376.
+///
+/// [Section 5.4.1]: https://datatracker.ietf.org/doc/html/rfc6376#section-5.4.1
+pub const DEFAULT_HEADERS: &[&str] = &[
+    "from",
+    "reply-to",
+    "subject",
+    "date",
+    "to",
+    "cc",
+    "message-id",
+    "in-reply-to",
+    "references",
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+];
+
+/// The length of the lines the signature is folded into.
+const LINE_LENGTH: usize = 72;
+
+/// Signs messages for one domain.
+#[derive(Debug)]
+pub struct Signer {
+    /// The signing domain (`d=` tag).
+    pub domain: String,
+    /// The selector of the key record (`s=` tag).
+    pub selector: String,
+    pub key: PrivateKey,
+    /// The names of the headers to sign. Only the ones present in a
+    /// message are signed, except for `From:` which is always signed.
+    pub headers: Vec<String>,
+    pub header_canonicalization: Canonicalization,
+    pub body_canonicalization: Canonicalization,
+}
+
+impl Signer {
+    /// A signer with the default headers and relaxed canonicalization.
+    #[must_use]
+    pub fn new(domain: impl Into<String>, selector: impl Into<String>, key: PrivateKey) -> Self {
+        Self {
+            domain: domain.into(),
+            selector: selector.into(),
+            key,
+            headers: DEFAULT_HEADERS
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect(),
+            header_canonicalization: Canonicalization::Relaxed,
+            body_canonicalization: Canonicalization::Relaxed,
+        }
+    }
+
+    #[must_use]
+    pub fn with_headers<S: Into<String>>(mut self, headers: impl IntoIterator<Item = S>) -> Self {
+        self.headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    #[must_use]
+    pub fn with_canonicalization(
+        mut self,
+        header: Canonicalization,
+        body: Canonicalization,
+    ) -> Self {
+        self.header_canonicalization = header;
+        self.body_canonicalization = body;
+        self
+    }
+
+    /// The names to put in the `h=` tag, one for each instance present in
+    /// the message.
+    fn signed_headers<'a>(&'a self, headers: &[Header<'_>]) -> Vec<&'a str> {
+        let mut names: Vec<&str> = Vec::new();
+        let configured = self.headers.iter().map(String::as_str);
+        for name in std::iter::once("from").chain(configured) {
+            if names.iter().any(|signed| signed.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            let count = headers
+                .iter()
+                .filter(|header| header.name.eq_ignore_ascii_case(name))
+                .count();
+            names.extend(std::iter::repeat_n(name, count));
+        }
+        names
+    }
+
+    /// Sign `message` and return the `DKIM-Signature:` header to prepend to
+    /// it, including the final CRLF.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key fails to sign.
+    pub fn sign(&self, message: &[u8], time: SystemTime) -> Result<String, Error> {
+        self.sign_as("DKIM-Signature: v=1;", message, time)
+    }
+
+    /// Sign `message` with a header that starts with `prefix`, the name and
+    /// any tags before the common ones. ARC message signatures are DKIM
+    /// signatures under another name ([Section 4.1.2] of RFC 8617).
+    ///
+    /// [Section 4.1.2]: https://datatracker.ietf.org/doc/html/rfc8617#section-4.1.2
+    pub(crate) fn sign_as(
+        &self,
+        prefix: &str,
+        message: &[u8],
+        time: SystemTime,
+    ) -> Result<String, Error> {
+        let (headers, body) = message::split(message);
+
+        let body_hash = Sha256::digest(self.body_canonicalization.body(body));
+        let names = self.signed_headers(&headers);
+
+        let mut header = format!(
+            "{prefix} a={}; c={}/{}; d={}; s={};\r\n\tt={}; h={};\r\n\tbh={};\r\n\tb=",
+            self.key.algorithm(),
+            self.header_canonicalization,
+            self.body_canonicalization,
+            self.domain,
+            self.selector,
+            timestamp(time),
+            names.join(":"),
+            STANDARD.encode(body_hash),
+        );
+
+        let mut data = Vec::new();
+        for signed in message::select(&headers, names.iter().copied()) {
+            self.header_canonicalization.header(signed.raw, &mut data);
+        }
+        // the signature header itself, with an empty b= tag and no CRLF
+        self.header_canonicalization
+            .header(format!("{header}\r\n").as_bytes(), &mut data);
+        data.truncate(data.len() - 2);
+
+        let signature = STANDARD.encode(self.key.sign(&data)?);
+        debug!(
+            domain = self.domain,
+            selector = self.selector,
+            "signed message"
+        );
+
+        fold_signature(&mut header, &signature);
+        Ok(header)
+    }
+}
+
+/// Seconds since the epoch for the `t=` tag.
+pub(crate) fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Append the base64 `signature` to `header`, folded into lines, and end
+/// the header.
+pub(crate) fn fold_signature(header: &mut String, signature: &str) {
+    for start in (0..signature.len()).step_by(LINE_LENGTH) {
+        if start > 0 {
+            header.push_str("\r\n\t ");
+        }
+        header.push_str(&signature[start..signature.len().min(start + LINE_LENGTH)]);
+    }
+    header.push_str("\r\n");
+}
+
+/// Where to find the key for a signing domain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyConfig {
+    pub domain: String,
+    pub selector: String,
+    /// The algorithm of the key, to catch a key that doesn't match the
+    /// published record.
+    pub algorithm: Algorithm,
+    /// A PEM file with the private key.
+    pub key: PathBuf,
+}
+
+/// The signers for each of our domains.
+#[derive(Debug, Default)]
+pub struct Keys {
+    signers: HashMap<String, Signer>,
+}
+
+impl Keys {
+    /// Load the keys with the default signing options.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a key file can't be read or parsed, or holds a
+    /// key for another algorithm.
+    pub fn load(config: &[KeyConfig]) -> Result<Self, Error> {
+        config.iter().try_fold(Self::default(), |keys, config| {
+            let pem = std::fs::read_to_string(&config.key)?;
+            let key = PrivateKey::from_pem(&pem)
+                .map_err(|e| Error::InvalidKey(format!("{}: {e}", config.key.display())))?;
+            if key.algorithm() != config.algorithm {
+                return Err(Error::InvalidKey(format!(
+                    "{}: {} key, expected {}",
+                    config.key.display(),
+                    key.algorithm(),
+                    config.algorithm
+                )));
+            }
+            Ok(keys.with_signer(Signer::new(&config.domain, &config.selector, key)))
+        })
+    }
+
+    #[must_use]
+    pub fn with_signer(mut self, signer: Signer) -> Self {
+        self.signers
+            .insert(signer.domain.to_ascii_lowercase(), signer);
+        self
+    }
+
+    /// The signer for `domain`, if it's one of ours.
+    #[must_use]
+    pub fn get(&self, domain: &str) -> Option<&Signer> {
+        self.signers.get(&domain.to_ascii_lowercase())
+    }
+
+    /// Sign `message` with the key of the domain in its `From:` header.
+    /// Returns `None` if we don't have a key for that domain.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key fails to sign.
+    pub fn sign(&self, message: &[u8], time: SystemTime) -> Result<Option<String>, Error> {
+        let (headers, _) = message::split(message);
+        let Some(signer) = message::from_domain(&headers).and_then(|domain| self.get(&domain))
+        else {
+            return Ok(None);
+        };
+        signer.sign(message, time).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use ring::signature::{UnparsedPublicKey, ED25519};
+    use rsa::{pkcs8::DecodePublicKey, Pkcs1v15Sign, RsaPublicKey};
+    use sha2::{Digest, Sha256};
+
+    use super::{KeyConfig, Keys, Signer};
+    use crate::{Algorithm, Canonicalization, PrivateKey};
+
+    /// The message of Appendix A.3 of RFC 8463.
+    const MESSAGE: &[u8] = b"From: Joe SixPack <joe@football.example.com>\r\n\
+        To: Suzie Q <suzie@shopping.example.net>\r\n\
+        Subject: Is dinner ready?\r\n\
+        Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n\
+        Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n\
+        \r\n\
+        Hi.\r\n\
+        \r\n\
+        We lost the game.  Are you hungry yet?\r\n\
+        \r\n\
+        Joe.\r\n";
+
+    /// The Ed25519 key of Appendix A.1 of RFC 8463.
+    const ED25519_SEED: &str = "nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A=";
+    const ED25519_PUBLIC: &str = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+    /// A 2048 bit key generated with `openssl genpkey -algorithm RSA`.
+    const RSA_KEY: &str = include_str!("../testdata/rsa.pem");
+
+    fn ed25519() -> PrivateKey {
+        PrivateKey::from_ed25519_seed(&STANDARD.decode(ED25519_SEED).unwrap()).unwrap()
+    }
+
+    /// The signature in the RFC, which is deterministic with Ed25519.
+    #[test]
+    fn rfc8463() {
+        let key = ed25519();
+        assert_eq!(STANDARD.encode(key.public_key()), ED25519_PUBLIC);
+
+        // the RFC signature signs some headers twice, which we don't do, so
+        // check the published header against the key and our hashing
+        let published = b"DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r\n \
+            d=football.example.com; i=@football.example.com;\r\n \
+            q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r\n \
+            subject : date : message-id : from : subject : date;\r\n \
+            bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n \
+            b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus\r\n \
+            Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==\r\n";
+        let (headers, body) = crate::message::split(MESSAGE);
+        assert_eq!(
+            STANDARD.encode(Sha256::digest(Canonicalization::Relaxed.body(body))),
+            "2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8="
+        );
+
+        let mut data = Vec::new();
+        let names = [
+            "from",
+            "to",
+            "subject",
+            "date",
+            "message-id",
+            "from",
+            "subject",
+            "date",
+        ];
+        for header in crate::message::select(&headers, names) {
+            Canonicalization::Relaxed.header(header.raw, &mut data);
+        }
+        let unsigned = String::from_utf8_lossy(published);
+        let unsigned = &unsigned[..unsigned.find("b=/").unwrap() + 2];
+        Canonicalization::Relaxed.header(format!("{unsigned}\r\n").as_bytes(), &mut data);
+        data.truncate(data.len() - 2);
+
+        let signature = STANDARD
+            .decode("/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11BusFa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==")
+            .unwrap();
+        assert_eq!(key.sign(&data).unwrap(), signature);
+
+        let public = STANDARD.decode(ED25519_PUBLIC).unwrap();
+        UnparsedPublicKey::new(&ED25519, public)
+            .verify(&Sha256::digest(&data), &signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn sign() {
+        let signer = Signer::new("football.example.com", "brisbane", ed25519());
+        let time = UNIX_EPOCH + Duration::from_secs(1_528_637_909);
+        let header = signer.sign(MESSAGE, time).unwrap();
+        assert!(header.starts_with(
+            "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; \
+            d=football.example.com; s=brisbane;\r\n\
+            \tt=1528637909; h=from:subject:date:to:message-id;\r\n\
+            \tbh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n\
+            \tb="
+        ));
+        assert!(header.ends_with("==\r\n"));
+
+        // signing is deterministic
+        assert_eq!(signer.sign(MESSAGE, time).unwrap(), header);
+    }
+
+    #[test]
+    fn rsa() {
+        let key = PrivateKey::from_pem(RSA_KEY).unwrap();
+        let public = RsaPublicKey::from_public_key_der(&key.public_key()).unwrap();
+        let signer = Signer::new("football.example.com", "test", key)
+            .with_headers(["From", "To", "Subject"])
+            .with_canonicalization(Canonicalization::Simple, Canonicalization::Simple);
+        let header = signer.sign(MESSAGE, UNIX_EPOCH).unwrap();
+
+        let (unsigned, signature) = header.split_once("b=").unwrap();
+        let signature: String = signature.split_whitespace().collect();
+        let mut data = b"From: Joe SixPack <joe@football.example.com>\r\n\
+            To: Suzie Q <suzie@shopping.example.net>\r\n\
+            Subject: Is dinner ready?\r\n"
+            .to_vec();
+        data.extend_from_slice(unsigned.as_bytes());
+        data.extend_from_slice(b"b=");
+
+        public
+            .verify(
+                Pkcs1v15Sign::new::<Sha256>(),
+                &Sha256::digest(&data),
+                &STANDARD.decode(signature).unwrap(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn keys() {
+        let keys =
+            Keys::default().with_signer(Signer::new("Football.example.com", "brisbane", ed25519()));
+        assert!(keys.sign(MESSAGE, UNIX_EPOCH).unwrap().is_some());
+        assert!(keys
+            .sign(b"From: someone@example.net\r\n\r\n", UNIX_EPOCH)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn load() {
+        let path = std::env::temp_dir().join(format!("dkim-load-{}.pem", std::process::id()));
+        std::fs::write(&path, RSA_KEY).unwrap();
+        let config = |algorithm| KeyConfig {
+            domain: "football.example.com".to_owned(),
+            selector: "test".to_owned(),
+            algorithm,
+            key: path.clone(),
+        };
+
+        let keys = Keys::load(&[config(Algorithm::RsaSha256)]).unwrap();
+        assert_eq!(keys.get("football.example.com").unwrap().selector, "test");
+        let e = Keys::load(&[config(Algorithm::Ed25519Sha256)]).unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("rsa-sha256 key, expected ed25519-sha256"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+//! Tag lists ([Section 3.2] of RFC 6376), used by signatures and key
+//! records.
+//!
+//! [Section 3.2]: https://datatracker.ietf.org/doc/html/rfc6376#section-3.2
+
+/// The tags of a tag list in the order they appear.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tags<'a>(Vec<(&'a str, &'a str)>);
+
+impl<'a> Tags<'a> {
+    /// Parse a tag list. Values are trimmed but may contain folding
+    /// whitespace. Returns `None` if a tag is malformed or duplicated.
+    #[must_use]
+    pub fn parse(list: &'a str) -> Option<Self> {
+        let mut tags: Vec<(&str, &str)> = Vec::new();
+        for tag in list.split(';') {
+            if tag.trim().is_empty() {
+                continue;
+            }
+            let (name, value) = tag.split_once('=')?;
+            let name = name.trim();
+            if name.is_empty() || tags.iter().any(|&(other, _)| other == name) {
+                return None;
+            }
+            tags.push((name, value.trim()));
+        }
+        Some(Self(tags))
+    }
+
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.0
+            .iter()
+            .find(|&&(other, _)| other == name)
+            .map(|&(_, value)| value)
+    }
+
+    /// The first tag in the list.
+    #[must_use]
+    pub fn first(&self) -> Option<(&'a str, &'a str)> {
+        self.0.first().copied()
+    }
+}
+
+/// A value with all whitespace removed, as needed for base64 values.
+#[must_use]
+pub fn compact(value: &str) -> String {
+    value.split_whitespace().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compact, Tags};
+
+    #[test]
+    fn parse() {
+        let tags = Tags::parse("v=1; a=rsa-sha256;\r\n\tb=abc\r\n\t def ;").unwrap();
+        assert_eq!(tags.first(), Some(("v", "1")));
+        assert_eq!(tags.get("a"), Some("rsa-sha256"));
+        assert_eq!(tags.get("b").map(compact).as_deref(), Some("abcdef"));
+        assert_eq!(tags.get("c"), None);
+
+        assert_eq!(Tags::parse("v=1; v=1"), None);
+        assert_eq!(Tags::parse("v=1; garbage"), None);
+    }
+}
@@ -0,0 +1,482 @@
+//! Verification ([Section 6] of RFC 6376).
+//!
+//! [Section 6]: https://datatracker.ietf.org/doc/html/rfc6376#section-6
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dns::Resolver;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::{
+    key::Record,
+    message::{self, Header},
+    tags::{compact, Tags},
+    Algorithm, Canonicalization,
+};
+
+/// How many signatures of a message are checked at most.
+const MAX_SIGNATURES: usize = 5;
+
+/// The result of checking a signature, as used in `Authentication-Results:`
+/// ([Section 2.7.1] of RFC 8601).
+///
+/// [Section 2.7.1]: https://datatracker.ietf.org/doc/html/rfc8601#section-2.7.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    /// The signature doesn't match the message.
+    Fail,
+    /// The signature is valid, but the domain is only testing DKIM.
+    Neutral,
+    /// The key couldn't be retrieved, but may be later.
+    TempError,
+    /// The signature or key is invalid.
+    PermError,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::Neutral => "neutral",
+            Self::TempError => "temperror",
+            Self::PermError => "permerror",
+        })
+    }
+}
+
+/// The outcome of checking one signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub status: Status,
+    /// The signing domain (`d=` tag), if the signature has one.
+    pub domain: Option<String>,
+    pub selector: Option<String>,
+    /// The start of the signature, to tell signatures apart ([RFC 6008]).
+    ///
+    /// [RFC 6008]: https://datatracker.ietf.org/doc/html/rfc6008
+    pub b: Option<String>,
+    /// Why the signature didn't pass.
+    pub reason: Option<String>,
+}
+
+/// A parsed `DKIM-Signature:` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub algorithm: Algorithm,
+    pub signature: Vec<u8>,
+    pub body_hash: Vec<u8>,
+    pub header_canonicalization: Canonicalization,
+    pub body_canonicalization: Canonicalization,
+    pub domain: String,
+    pub selector: String,
+    /// The names of the signed headers.
+    pub headers: Vec<String>,
+    /// The agent or user identifier (`i=` tag).
+    pub identity: String,
+    /// How many bytes of the canonicalized body are signed.
+    pub length: Option<usize>,
+    pub timestamp: Option<u64>,
+    pub expiration: Option<u64>,
+}
+
+/// Whether `domain` is `parent` or one of its subdomains.
+fn is_subdomain(domain: &str, parent: &str) -> bool {
+    let (domain, parent) = (domain.to_ascii_lowercase(), parent.to_ascii_lowercase());
+    domain == parent || domain.ends_with(&format!(".{parent}"))
+}
+
+impl Signature {
+    /// Parse the value of a `DKIM-Signature:` header and check that it's
+    /// usable ([Section 6.1.1] of RFC 6376).
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem.
+    ///
+    /// [Section 6.1.1]: https://datatracker.ietf.org/doc/html/rfc6376#section-6.1.1
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let tags = Tags::parse(value).ok_or("malformed signature")?;
+        if tags.get("v").ok_or("missing v= tag")? != "1" {
+            return Err("unsupported version".to_owned());
+        }
+        let mut signature = Self::from_tags(&tags)?;
+
+        if let Some(identity) = tags.get("i") {
+            let identity_domain = identity.rsplit_once('@').map_or("", |(_, domain)| domain);
+            if !is_subdomain(identity_domain, &signature.domain) {
+                return Err("i= is not in the signing domain".to_owned());
+            }
+            identity.clone_into(&mut signature.identity);
+        }
+        Ok(signature)
+    }
+
+    /// The tags shared by DKIM signatures and ARC message signatures, which
+    /// use `i=` for the instance instead of the identity.
+    pub(crate) fn from_tags(tags: &Tags<'_>) -> Result<Self, String> {
+        let tag = |name: &str| tags.get(name).ok_or(format!("missing {name}= tag"));
+        let base64 = |name: &str| {
+            STANDARD
+                .decode(compact(tag(name)?))
+                .map_err(|_| format!("invalid base64 in {name}="))
+        };
+        let number = |name: &str| {
+            tags.get(name)
+                .map(|value| value.parse().map_err(|_| format!("invalid {name}= tag")))
+                .transpose()
+        };
+
+        let algorithm = Algorithm::from_str(tag("a")?).map_err(|e| e.to_string())?;
+
+        let (header_canonicalization, body_canonicalization) = match tags.get("c") {
+            None => (Canonicalization::Simple, Canonicalization::Simple),
+            Some(c) => {
+                let (header, body) = c.split_once('/').unwrap_or((c, "simple"));
+                (
+                    header.parse().map_err(|_| "unsupported canonicalization")?,
+                    body.parse().map_err(|_| "unsupported canonicalization")?,
+                )
+            }
+        };
+
+        let domain = tag("d")?.to_owned();
+        let headers: Vec<String> = tag("h")?
+            .split(':')
+            .map(|name| name.trim().to_owned())
+            .collect();
+        if !headers.iter().any(|name| name.eq_ignore_ascii_case("from")) {
+            return Err("From: is not signed".to_owned());
+        }
+
+        let timestamp = number("t")?;
+        let expiration = number("x")?;
+        if let (Some(t), Some(x)) = (timestamp, expiration) {
+            if x < t {
+                return Err("x= is before t=".to_owned());
+            }
+        }
+
+        Ok(Self {
+            algorithm,
+            signature: base64("b")?,
+            body_hash: base64("bh")?,
+            header_canonicalization,
+            body_canonicalization,
+            identity: format!("@{domain}"),
+            domain,
+            selector: tag("s")?.to_owned(),
+            headers,
+            length: number("l")?.map(|l: u64| usize::try_from(l).unwrap_or(usize::MAX)),
+            timestamp,
+            expiration,
+        })
+    }
+
+    /// The data that is signed: the signed headers followed by `header`, the
+    /// signature header itself, with the value of its `b=` tag removed.
+    #[must_use]
+    pub fn signed_data(&self, headers: &[Header<'_>], header: &Header<'_>) -> Vec<u8> {
+        let mut data = Vec::new();
+        let names = self.headers.iter().map(String::as_str);
+        for signed in message::select(headers, names) {
+            self.header_canonicalization.header(signed.raw, &mut data);
+        }
+        self.header_canonicalization
+            .header(&strip_b(header.raw), &mut data);
+        // without the final CRLF
+        data.truncate(data.len().saturating_sub(2));
+        data
+    }
+
+    /// Whether the body hash matches `body`.
+    #[must_use]
+    pub fn body_matches(&self, body: &[u8]) -> bool {
+        let mut canonical = self.body_canonicalization.body(body);
+        if let Some(length) = self.length {
+            if length > canonical.len() {
+                return false;
+            }
+            canonical.truncate(length);
+        }
+        Sha256::digest(canonical).as_slice() == self.body_hash
+    }
+}
+
+/// Remove the value of the `b=` tag from a raw signature header.
+#[must_use]
+pub fn strip_b(raw: &[u8]) -> Vec<u8> {
+    let mut out = raw.to_vec();
+    let Some(colon) = raw.iter().position(|&c| c == b':') else {
+        return out;
+    };
+
+    let mut start = colon + 1;
+    while start < raw.len() {
+        let end = raw[start..]
+            .iter()
+            .position(|&c| c == b';')
+            .map_or(raw.len(), |i| start + i);
+        let tag = &raw[start..end];
+        if let Some(eq) = tag.iter().position(|&c| c == b'=') {
+            if tag[..eq].trim_ascii() == b"b" {
+                // keep the CRLF that ends the header
+                let value_end = if end == raw.len() {
+                    raw.strip_suffix(b"\r\n").map_or(end, <[u8]>::len)
+                } else {
+                    end
+                };
+                out.drain(start + eq + 1..value_end);
+                return out;
+            }
+        }
+        start = end + 1;
+    }
+    out
+}
+
+/// Check one signature.
+async fn verify_one<R: Resolver + ?Sized>(
+    resolver: &R,
+    headers: &[Header<'_>],
+    body: &[u8],
+    header: &Header<'_>,
+    now: u64,
+) -> Verification {
+    let value = String::from_utf8_lossy(header.value());
+    let tags = Tags::parse(&value);
+    let mut verification = Verification {
+        status: Status::PermError,
+        domain: tags
+            .as_ref()
+            .and_then(|tags| tags.get("d"))
+            .map(ToOwned::to_owned),
+        selector: tags
+            .as_ref()
+            .and_then(|tags| tags.get("s"))
+            .map(ToOwned::to_owned),
+        b: tags
+            .as_ref()
+            .and_then(|tags| tags.get("b"))
+            .map(|b| compact(b).chars().take(8).collect()),
+        reason: None,
+    };
+    let (status, reason) = match check(resolver, headers, body, header, &value, now).await {
+        Ok(status) => (status, None),
+        Err((status, reason)) => (status, Some(reason)),
+    };
+    verification.status = status;
+    verification.reason = reason;
+    verification
+}
+
+/// Look up the key `selector` of `domain` for `algorithm`.
+pub(crate) async fn key<R: Resolver + ?Sized>(
+    resolver: &R,
+    selector: &str,
+    domain: &str,
+    algorithm: Algorithm,
+) -> Result<Record, (Status, String)> {
+    let perm = |reason: String| (Status::PermError, reason);
+
+    let name = format!("{selector}._domainkey.{domain}");
+    let records = match resolver.txt(&name).await {
+        Ok(records) => records,
+        Err(e) if e.is_temporary() => {
+            return Err((Status::TempError, format!("key lookup failed: {e}")));
+        }
+        Err(_) => return Err(perm("no key for signature".to_owned())),
+    };
+    let record = records
+        .iter()
+        .map(|record| Record::parse(record))
+        .reduce(Result::or)
+        .unwrap_or_else(|| Err("no key for signature".to_owned()))
+        .map_err(perm)?;
+
+    if record.key.algorithm() != algorithm {
+        return Err(perm("key type does not match the algorithm".to_owned()));
+    }
+    Ok(record)
+}
+
+async fn check<R: Resolver + ?Sized>(
+    resolver: &R,
+    headers: &[Header<'_>],
+    body: &[u8],
+    header: &Header<'_>,
+    value: &str,
+    now: u64,
+) -> Result<Status, (Status, String)> {
+    let perm = |reason: String| (Status::PermError, reason);
+
+    let signature = Signature::parse(value).map_err(perm)?;
+    if signature.expiration.is_some_and(|x| x < now) {
+        return Err(perm("signature expired".to_owned()));
+    }
+
+    let record = key(
+        resolver,
+        &signature.selector,
+        &signature.domain,
+        signature.algorithm,
+    )
+    .await?;
+    let identity_domain = signature
+        .identity
+        .rsplit_once('@')
+        .map_or("", |(_, domain)| domain);
+    if record.strict && !identity_domain.eq_ignore_ascii_case(&signature.domain) {
+        return Err(perm("i= must be the signing domain".to_owned()));
+    }
+
+    if !signature.body_matches(body) {
+        return Err((Status::Fail, "body hash did not verify".to_owned()));
+    }
+    let data = signature.signed_data(headers, header);
+    if !record.key.verify(&data, &signature.signature) {
+        return Err((Status::Fail, "signature did not verify".to_owned()));
+    }
+
+    Ok(if record.testing {
+        Status::Neutral
+    } else {
+        Status::Pass
+    })
+}
+
+/// Check the DKIM signatures of `message`. Returns nothing if it isn't
+/// signed.
+pub async fn verify<R: Resolver + ?Sized>(resolver: &R, message: &[u8]) -> Vec<Verification> {
+    let (headers, body) = message::split(message);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    let mut verifications = Vec::new();
+    for header in headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("dkim-signature"))
+        .take(MAX_SIGNATURES)
+    {
+        let verification = verify_one(resolver, &headers, body, header, now).await;
+        debug!(?verification, "checked DKIM signature");
+        verifications.push(verification);
+    }
+    verifications
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use dns::StaticResolver;
+
+    use super::{strip_b, verify, Status};
+    use crate::{Canonicalization, PrivateKey, Signer};
+
+    /// The message of Appendix A.3 of RFC 8463, with the Ed25519 signature.
+    const SIGNED: &[u8] = b"DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r\n \
+        d=football.example.com; i=@football.example.com;\r\n \
+        q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r\n \
+        subject : date : message-id : from : subject : date;\r\n \
+        bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n \
+        b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus\r\n \
+        Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==\r\n\
+        From: Joe SixPack <joe@football.example.com>\r\n\
+        To: Suzie Q <suzie@shopping.example.net>\r\n\
+        Subject: Is dinner ready?\r\n\
+        Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n\
+        Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n\
+        \r\n\
+        Hi.\r\n\
+        \r\n\
+        We lost the game.  Are you hungry yet?\r\n\
+        \r\n\
+        Joe.\r\n";
+
+    fn resolver() -> StaticResolver {
+        StaticResolver::default().with_txt(
+            "brisbane._domainkey.football.example.com",
+            "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
+        )
+    }
+
+    #[test]
+    fn strip() {
+        assert_eq!(
+            strip_b(b"DKIM-Signature: a=1; b=abc\r\n def; bh=xyz\r\n"),
+            b"DKIM-Signature: a=1; b=; bh=xyz\r\n"
+        );
+        assert_eq!(
+            strip_b(b"DKIM-Signature: bh=xyz; b = abc \r\n"),
+            b"DKIM-Signature: bh=xyz; b =\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn rfc8463() {
+        let verifications = verify(&resolver(), SIGNED).await;
+        assert_eq!(verifications.len(), 1);
+        let verification = &verifications[0];
+        assert_eq!(verification.status, Status::Pass, "{verification:?}");
+        assert_eq!(verification.domain.as_deref(), Some("football.example.com"));
+        assert_eq!(verification.selector.as_deref(), Some("brisbane"));
+        assert_eq!(verification.b.as_deref(), Some("/gCrinpc"));
+
+        // changes to whitespace are fine with relaxed canonicalization
+        let relaxed = String::from_utf8_lossy(SIGNED).replace("Subject: Is", "Subject:   Is");
+        let verifications = verify(&resolver(), relaxed.as_bytes()).await;
+        assert_eq!(verifications[0].status, Status::Pass);
+
+        let modified = String::from_utf8_lossy(SIGNED).replace("hungry", "thirsty");
+        let verifications = verify(&resolver(), modified.as_bytes()).await;
+        assert_eq!(verifications[0].status, Status::Fail);
+        assert_eq!(
+            verifications[0].reason.as_deref(),
+            Some("body hash did not verify")
+        );
+
+        let modified = String::from_utf8_lossy(SIGNED).replace("dinner", "lunch");
+        let verifications = verify(&resolver(), modified.as_bytes()).await;
+        assert_eq!(verifications[0].status, Status::Fail);
+
+        let verifications = verify(&StaticResolver::default(), SIGNED).await;
+        assert_eq!(verifications[0].status, Status::PermError);
+
+        assert!(verify(&resolver(), b"From: a@example.com\r\n\r\nhi\r\n")
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let message = b"From: Alice <alice@example.org>\r\nSubject: hi\r\n\r\nhello  \r\n\r\n";
+        let public_key = PrivateKey::from_pem(include_str!("../testdata/rsa.pem"))
+            .unwrap()
+            .public_key();
+        let resolver = StaticResolver::default().with_txt(
+            "sel._domainkey.example.org",
+            format!("v=DKIM1; p={}", STANDARD.encode(public_key)),
+        );
+
+        for canonicalization in [Canonicalization::Simple, Canonicalization::Relaxed] {
+            let key = PrivateKey::from_pem(include_str!("../testdata/rsa.pem")).unwrap();
+            let signer = Signer::new("example.org", "sel", key)
+                .with_canonicalization(canonicalization, canonicalization);
+            let mut output = signer.sign(message, UNIX_EPOCH).unwrap().into_bytes();
+            output.extend_from_slice(message);
+
+            let verifications = verify(&resolver, &output).await;
+            assert_eq!(verifications[0].status, Status::Pass, "{verifications:?}");
+        }
+    }
+}
@@ -0,0 +1,460 @@
+//! DMARC ([RFC 7489]), which ties the results of DKIM and SPF to the domain
+//! in the `From:` header and lets that domain say what to do with mail that
+//! fails.
+//!
+//! Organizational domains are found with a short built-in list of
+//! multi-label public suffixes rather than the full Public Suffix List.
+//!
+//! [RFC 7489]: https://datatracker.ietf.org/doc/html/rfc7489
+
+#![warn(clippy::pedantic)]
+
+use std::{fmt, str::FromStr};
+
+use dkim::tags::Tags;
+use dns::Resolver;
+use tracing::debug;
+
+/// Public suffixes with more than one label, whose organizational domains
+/// have three labels.
+const MULTI_LABEL_SUFFIXES: &[&str] = &[
+    "ac.uk", "co.uk", "gov.uk", "org.uk", "com.au", "net.au", "org.au", "co.nz", "co.jp", "ne.jp",
+    "or.jp", "com.br", "com.cn", "co.in", "co.za", "com.mx", "com.tr",
+];
+
+/// What a domain asks receivers to do with mail that fails DMARC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Policy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl Policy {
+    /// The policy for messages left out by `pct=` ([Section 6.6.4] of RFC
+    /// 7489).
+    ///
+    /// [Section 6.6.4]: https://datatracker.ietf.org/doc/html/rfc7489#section-6.6.4
+    #[must_use]
+    fn weaker(self) -> Self {
+        match self {
+            Self::Reject => Self::Quarantine,
+            Self::Quarantine | Self::None => Self::None,
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Quarantine => "quarantine",
+            Self::Reject => "reject",
+        })
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "quarantine" => Ok(Self::Quarantine),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("invalid policy {s}")),
+        }
+    }
+}
+
+/// How closely an authenticated domain has to match the `From:` domain
+/// ([Section 3.1] of RFC 7489).
+///
+/// [Section 3.1]: https://datatracker.ietf.org/doc/html/rfc7489#section-3.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Alignment {
+    /// The organizational domains are the same.
+    #[default]
+    Relaxed,
+    /// The domains are the same.
+    Strict,
+}
+
+impl Alignment {
+    fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("r") => Ok(Self::Relaxed),
+            Some("s") => Ok(Self::Strict),
+            Some(value) => Err(format!("invalid alignment mode {value}")),
+        }
+    }
+
+    #[must_use]
+    pub fn aligned(self, domain: &str, from: &str) -> bool {
+        match self {
+            Self::Strict => domain.eq_ignore_ascii_case(from),
+            Self::Relaxed => {
+                organizational_domain(domain).eq_ignore_ascii_case(organizational_domain(from))
+            }
+        }
+    }
+}
+
+/// The domain registered under a public suffix, e.g. `example.co.uk` for
+/// `mail.example.co.uk`.
+#[must_use]
+pub fn organizational_domain(domain: &str) -> &str {
+    let domain = domain.trim_end_matches('.');
+    let labels = |n: usize| {
+        domain
+            .rmatch_indices('.')
+            .nth(n - 1)
+            .map_or(domain, |(i, _)| &domain[i + 1..])
+    };
+    let suffix = labels(2);
+    if MULTI_LABEL_SUFFIXES
+        .iter()
+        .any(|multi| suffix.eq_ignore_ascii_case(multi))
+    {
+        labels(3)
+    } else {
+        suffix
+    }
+}
+
+/// A DMARC record ([Section 6.3] of RFC 7489).
+///
+/// [Section 6.3]: https://datatracker.ietf.org/doc/html/rfc7489#section-6.3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub policy: Policy,
+    /// The policy for subdomains, if different.
+    pub subdomain_policy: Option<Policy>,
+    pub dkim: Alignment,
+    pub spf: Alignment,
+    /// The percentage of failing messages the policy applies to.
+    pub percent: u8,
+}
+
+impl Record {
+    /// Parse a DMARC record.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if the record is invalid.
+    pub fn parse(record: &str) -> Result<Self, String> {
+        let tags = Tags::parse(record).ok_or("malformed DMARC record")?;
+        if tags.first() != Some(("v", "DMARC1")) {
+            return Err("invalid DMARC record version".to_owned());
+        }
+
+        Ok(Self {
+            policy: tags.get("p").ok_or("DMARC record without p=")?.parse()?,
+            subdomain_policy: tags.get("sp").map(str::parse).transpose()?,
+            dkim: Alignment::parse(tags.get("adkim"))?,
+            spf: Alignment::parse(tags.get("aspf"))?,
+            percent: match tags.get("pct") {
+                Some(pct) => pct
+                    .parse()
+                    .ok()
+                    .filter(|&pct| pct <= 100)
+                    .ok_or(format!("invalid pct={pct}"))?,
+                None => 100,
+            },
+        })
+    }
+}
+
+/// The result of a DMARC check, as used in `Authentication-Results:`
+/// ([Section 11.2] of RFC 7489).
+///
+/// [Section 11.2]: https://datatracker.ietf.org/doc/html/rfc7489#section-11.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// An aligned DKIM signature or SPF check passed.
+    Pass,
+    Fail,
+    /// The domain has no DMARC record.
+    None,
+    TempError,
+    /// The DMARC record is invalid.
+    PermError,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::None => "none",
+            Self::TempError => "temperror",
+            Self::PermError => "permerror",
+        })
+    }
+}
+
+/// The outcome of a DMARC check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub status: Status,
+    /// The policy published for the `From:` domain.
+    pub policy: Option<Policy>,
+    /// What to do with the message: the published policy if it failed,
+    /// after `pct=` sampling, and [`Policy::None`] otherwise.
+    pub disposition: Policy,
+    pub reason: Option<String>,
+}
+
+impl Verification {
+    fn new(status: Status, reason: impl Into<String>) -> Self {
+        Self {
+            status,
+            policy: None,
+            disposition: Policy::None,
+            reason: Some(reason.into()),
+        }
+    }
+}
+
+/// Find the DMARC record for `domain`, which is the record of the domain
+/// itself or else of its organizational domain ([Section 6.6.3] of RFC
+/// 7489). The second value is whether it's the organizational domain's.
+///
+/// [Section 6.6.3]: https://datatracker.ietf.org/doc/html/rfc7489#section-6.6.3
+async fn discover<R: Resolver + ?Sized>(
+    resolver: &R,
+    domain: &str,
+) -> Result<Option<(Record, bool)>, Verification> {
+    let organizational = organizational_domain(domain);
+    let candidates = if organizational.eq_ignore_ascii_case(domain) {
+        vec![(domain, false)]
+    } else {
+        vec![(domain, false), (organizational, true)]
+    };
+
+    for (domain, inherited) in candidates {
+        let txt = match resolver.txt(&format!("_dmarc.{domain}")).await {
+            Ok(txt) => txt,
+            Err(dns::Error::NxDomain) => continue,
+            Err(e) => {
+                return Err(Verification::new(
+                    Status::TempError,
+                    format!("DNS lookup failed: {e}"),
+                ))
+            }
+        };
+        let mut records = txt.iter().filter(|txt| txt.starts_with("v=DMARC1"));
+        match (records.next(), records.next()) {
+            (None, _) => (),
+            (Some(record), None) => {
+                return Record::parse(record)
+                    .map(|record| Some((record, inherited)))
+                    .map_err(|e| Verification::new(Status::PermError, e))
+            }
+            (Some(_), Some(_)) => {
+                return Err(Verification::new(
+                    Status::PermError,
+                    format!("{domain} has several DMARC records"),
+                ))
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Check a message whose `From:` domain is `from` against the DKIM results
+/// and the SPF result for `spf_domain`, the domain of the reverse-path or
+/// the HELO name.
+pub async fn verify<R: Resolver + ?Sized>(
+    resolver: &R,
+    from: &str,
+    dkim: &[dkim::Verification],
+    spf: &spf::Verification,
+    spf_domain: &str,
+) -> Verification {
+    let verification = match discover(resolver, from).await {
+        Ok(Some((record, inherited))) => {
+            let dkim_aligned = dkim.iter().any(|dkim| {
+                dkim.status == dkim::Status::Pass
+                    && dkim
+                        .domain
+                        .as_deref()
+                        .is_some_and(|domain| record.dkim.aligned(domain, from))
+            });
+            let spf_aligned =
+                spf.status == spf::Status::Pass && record.spf.aligned(spf_domain, from);
+
+            let policy = match record.subdomain_policy {
+                Some(policy) if inherited => policy,
+                _ => record.policy,
+            };
+            let (status, disposition) = if dkim_aligned || spf_aligned {
+                (Status::Pass, Policy::None)
+            } else if fastrand::u8(0..100) < record.percent {
+                (Status::Fail, policy)
+            } else {
+                (Status::Fail, policy.weaker())
+            };
+            Verification {
+                status,
+                policy: Some(policy),
+                disposition,
+                reason: (status == Status::Fail)
+                    .then(|| "no aligned DKIM signature or SPF pass".to_owned()),
+            }
+        }
+        Ok(None) => Verification::new(Status::None, format!("{from} has no DMARC record")),
+        Err(verification) => verification,
+    };
+    debug!(from, ?verification, "checked DMARC");
+    verification
+}
+
+#[cfg(test)]
+mod tests {
+    use dns::StaticResolver;
+
+    use super::{organizational_domain, verify, Alignment, Policy, Record, Status};
+
+    #[test]
+    fn record() {
+        assert_eq!(
+            Record::parse("v=DMARC1; p=reject; sp=none; adkim=s; pct=50; rua=mailto:d@example.com"),
+            Ok(Record {
+                policy: Policy::Reject,
+                subdomain_policy: Some(Policy::None),
+                dkim: Alignment::Strict,
+                spf: Alignment::Relaxed,
+                percent: 50,
+            })
+        );
+        for invalid in [
+            "p=reject; v=DMARC1",
+            "v=DMARC1",
+            "v=DMARC1; p=bounce",
+            "v=DMARC1; p=none; aspf=x",
+            "v=DMARC1; p=none; pct=101",
+        ] {
+            assert!(Record::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn alignment() {
+        assert_eq!(organizational_domain("mail.example.com"), "example.com");
+        assert_eq!(organizational_domain("example.com."), "example.com");
+        assert_eq!(organizational_domain("a.b.example.co.uk"), "example.co.uk");
+        assert_eq!(organizational_domain("localhost"), "localhost");
+
+        assert!(Alignment::Relaxed.aligned("mail.Example.com", "example.com"));
+        assert!(!Alignment::Strict.aligned("mail.example.com", "example.com"));
+        assert!(!Alignment::Relaxed.aligned("example.co.uk", "other.co.uk"));
+    }
+
+    /// Check mail from `from` with a DKIM signature by `dkim`, if any, and
+    /// the given SPF result for `spf_domain`.
+    async fn check(
+        resolver: &StaticResolver,
+        from: &str,
+        dkim: Option<(dkim::Status, &str)>,
+        spf: (spf::Status, &str),
+    ) -> (Status, Policy) {
+        let dkim: Vec<_> = dkim
+            .into_iter()
+            .map(|(status, domain)| dkim::Verification {
+                status,
+                domain: Some(domain.to_owned()),
+                selector: Some("sel".to_owned()),
+                b: None,
+                reason: None,
+            })
+            .collect();
+        let (status, spf_domain) = spf;
+        let spf = spf::Verification {
+            status,
+            reason: None,
+        };
+        let verification = verify(resolver, from, &dkim, &spf, spf_domain).await;
+        (verification.status, verification.disposition)
+    }
+
+    #[tokio::test]
+    async fn policy() {
+        let resolver = StaticResolver::default()
+            .with_txt("_dmarc.example.com", "v=DMARC1; p=reject; sp=quarantine")
+            .with_txt("_dmarc.example.net", "v=DMARC1; p=none; aspf=s")
+            .with_txt("_dmarc.example.org", "v=DMARC1; p=reject; pct=0")
+            .with_txt("_dmarc.broken.example", "v=DMARC1; p=maybe");
+        let none = (spf::Status::None, "");
+        let pass = dkim::Status::Pass;
+
+        assert_eq!(
+            check(
+                &resolver,
+                "example.com",
+                Some((pass, "mail.example.com")),
+                none
+            )
+            .await,
+            (Status::Pass, Policy::None)
+        );
+        // signed, but by someone else
+        assert_eq!(
+            check(&resolver, "example.com", Some((pass, "example.org")), none).await,
+            (Status::Fail, Policy::Reject)
+        );
+        // subdomains inherit sp=
+        assert_eq!(
+            check(
+                &resolver,
+                "news.example.com",
+                Some((dkim::Status::Fail, "example.com")),
+                none
+            )
+            .await,
+            (Status::Fail, Policy::Quarantine)
+        );
+
+        assert_eq!(
+            check(
+                &resolver,
+                "example.net",
+                None,
+                (spf::Status::Pass, "example.net")
+            )
+            .await,
+            (Status::Pass, Policy::None)
+        );
+        assert_eq!(
+            check(
+                &resolver,
+                "example.net",
+                None,
+                (spf::Status::Pass, "bounces.example.net")
+            )
+            .await,
+            (Status::Fail, Policy::None)
+        );
+        // pct=0 applies the next weaker policy
+        assert_eq!(
+            check(&resolver, "example.org", None, none).await,
+            (Status::Fail, Policy::Quarantine)
+        );
+
+        assert_eq!(
+            check(
+                &resolver,
+                "example.edu",
+                None,
+                (spf::Status::Pass, "example.edu")
+            )
+            .await
+            .0,
+            Status::None
+        );
+        assert_eq!(
+            check(&resolver, "broken.example", None, none).await.0,
+            Status::PermError
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! DNS lookups for mail delivery and authentication.
+//!
+//! Everything goes through the [`Resolver`] trait, so that tests can use a
+//! [`StaticResolver`] instead of the network.
+
+#![warn(clippy::pedantic)]
+
+use std::{collections::HashMap, net::IpAddr};
+
+mod system;
+
+pub use system::SystemResolver;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The domain doesn't exist (`NXDOMAIN`).
+    #[error("no such domain")]
+    NxDomain,
+    #[error("invalid domain name")]
+    InvalidName,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The server answered with an error code other than `NXDOMAIN`.
+    #[error("server failure (rcode {0})")]
+    Server(u8),
+    #[error("malformed response")]
+    Malformed,
+}
+
+impl Error {
+    /// Whether the lookup may succeed if it's tried again later.
+    #[must_use]
+    pub const fn is_temporary(&self) -> bool {
+        !matches!(self, Error::NxDomain | Error::InvalidName)
+    }
+}
+
+/// A mail exchanger ([Section 3.3.9] of RFC 1035).
+///
+/// [Section 3.3.9]: https://datatracker.ietf.org/doc/html/rfc1035#section-3.3.9
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mx {
+    /// Lower is preferred.
+    pub preference: u16,
+    pub exchange: String,
+}
+
+impl Mx {
+    /// Whether this is a null MX ([RFC 7505]), meaning the domain doesn't
+    /// accept mail.
+    ///
+    /// [RFC 7505]: https://datatracker.ietf.org/doc/html/rfc7505
+    #[must_use]
+    pub fn is_null(&self) -> bool {
+        self.exchange.is_empty() || self.exchange == "."
+    }
+}
+
+#[async_trait::async_trait]
+pub trait Resolver: Send + Sync {
+    /// The mail exchangers of `domain`, most preferred first. An empty list
+    /// means that the domain exists but has no MX records.
+    async fn mx(&self, domain: &str) -> Result<Vec<Mx>, Error>;
+
+    /// The TXT records of `name`, with their strings concatenated.
+    async fn txt(&self, name: &str) -> Result<Vec<String>, Error>;
+
+    /// The IPv4 and IPv6 addresses of `host`.
+    async fn ip(&self, host: &str) -> Result<Vec<IpAddr>, Error>;
+}
+
+/// Normalize a domain name for comparison.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// A resolver that answers from records added up front.
+///
+/// ```
+/// # use dns::{Mx, Resolver, StaticResolver};
+/// # tokio_test::block_on(async {
+/// let resolver = StaticResolver::default()
+///     .with_mx("example.com", 10, "mx.example.com")
+///     .with_ip("mx.example.com", [127, 0, 0, 1].into());
+///
+/// assert_eq!(
+///     resolver.mx("Example.COM.").await.unwrap(),
+///     [Mx { preference: 10, exchange: "mx.example.com".to_owned() }],
+/// );
+/// assert!(resolver.mx("mx.example.com").await.unwrap().is_empty());
+/// assert!(resolver.mx("example.net").await.is_err());
+/// # });
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct StaticResolver {
+    mx: HashMap<String, Vec<Mx>>,
+    txt: HashMap<String, Vec<String>>,
+    ip: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    #[must_use]
+    pub fn with_mx(mut self, domain: &str, preference: u16, exchange: &str) -> Self {
+        let mx = self.mx.entry(normalize(domain)).or_default();
+        mx.push(Mx {
+            preference,
+            exchange: normalize(exchange),
+        });
+        mx.sort_by_key(|mx| mx.preference);
+        self
+    }
+
+    #[must_use]
+    pub fn with_txt(mut self, name: &str, txt: impl Into<String>) -> Self {
+        self.txt
+            .entry(normalize(name))
+            .or_default()
+            .push(txt.into());
+        self
+    }
+
+    #[must_use]
+    pub fn with_ip(mut self, host: &str, ip: IpAddr) -> Self {
+        self.ip.entry(normalize(host)).or_default().push(ip);
+        self
+    }
+
+    /// Look up `name` in `records`. Names with records of another type
+    /// exist, everything else is `NXDOMAIN`.
+    fn lookup<T: Clone>(
+        &self,
+        records: &HashMap<String, Vec<T>>,
+        name: &str,
+    ) -> Result<Vec<T>, Error> {
+        let name = normalize(name);
+        if let Some(records) = records.get(&name) {
+            return Ok(records.clone());
+        }
+
+        if self.mx.contains_key(&name)
+            || self.txt.contains_key(&name)
+            || self.ip.contains_key(&name)
+        {
+            Ok(Vec::new())
+        } else {
+            Err(Error::NxDomain)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Resolver for StaticResolver {
+    async fn mx(&self, domain: &str) -> Result<Vec<Mx>, Error> {
+        self.lookup(&self.mx, domain)
+    }
+
+    async fn txt(&self, name: &str) -> Result<Vec<String>, Error> {
+        self.lookup(&self.txt, name)
+    }
+
+    async fn ip(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        self.lookup(&self.ip, host)
+    }
+}
@@ -0,0 +1,108 @@
+use std::net::IpAddr;
+
+use hickory_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    proto::op::ResponseCode,
+    Name, TokioAsyncResolver,
+};
+
+use crate::{Error, Mx, Resolver};
+
+/// A resolver that sends queries to the recursive nameservers in
+/// `/etc/resolv.conf`.
+///
+/// Queries and answers are handled by [`hickory_resolver`], which also
+/// checks that the answers belong to the name that was asked for (or a
+/// CNAME chain leading from it) and caches them.
+#[derive(Clone)]
+pub struct SystemResolver {
+    inner: TokioAsyncResolver,
+}
+
+impl std::fmt::Debug for SystemResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SystemResolver").finish_non_exhaustive()
+    }
+}
+
+impl SystemResolver {
+    /// Use the nameservers and options in `/etc/resolv.conf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `/etc/resolv.conf` can't be read.
+    pub fn from_resolv_conf() -> std::io::Result<Self> {
+        let inner = TokioAsyncResolver::tokio_from_system_conf().map_err(std::io::Error::other)?;
+        Ok(Self { inner })
+    }
+}
+
+/// Parse `name` as a fully qualified name, so that the search domains in
+/// `/etc/resolv.conf` are never appended to it.
+fn fqdn(name: &str) -> Result<Name, Error> {
+    let mut name = Name::from_ascii(name).map_err(|_| Error::InvalidName)?;
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+/// Map "no records" to an empty answer, as long as the name exists.
+fn empty<T>(e: ResolveError) -> Result<Vec<T>, Error> {
+    match e.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => match *response_code {
+            ResponseCode::NoError => Ok(Vec::new()),
+            ResponseCode::NXDomain => Err(Error::NxDomain),
+            code => Err(Error::Server(code.low())),
+        },
+        ResolveErrorKind::Timeout => Err(Error::Io(std::io::ErrorKind::TimedOut.into())),
+        ResolveErrorKind::Proto(_) => Err(Error::Malformed),
+        _ => Err(Error::Io(std::io::Error::other(e))),
+    }
+}
+
+#[async_trait::async_trait]
+impl Resolver for SystemResolver {
+    async fn mx(&self, domain: &str) -> Result<Vec<Mx>, Error> {
+        let mut mx: Vec<_> = match self.inner.mx_lookup(fqdn(domain)?).await {
+            Ok(lookup) => lookup
+                .iter()
+                .map(|mx| Mx {
+                    preference: mx.preference(),
+                    exchange: crate::normalize(&mx.exchange().to_ascii()),
+                })
+                .collect(),
+            Err(e) => empty(e)?,
+        };
+        mx.sort_by_key(|mx| mx.preference);
+        Ok(mx)
+    }
+
+    async fn txt(&self, name: &str) -> Result<Vec<String>, Error> {
+        match self.inner.txt_lookup(fqdn(name)?).await {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|txt| String::from_utf8_lossy(&txt.txt_data().concat()).into_owned())
+                .collect()),
+            Err(e) => empty(e),
+        }
+    }
+
+    async fn ip(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        match self.inner.lookup_ip(fqdn(host)?).await {
+            Ok(lookup) => Ok(lookup.iter().collect()),
+            Err(e) => empty(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fqdn;
+    use crate::Error;
+
+    #[test]
+    fn names_are_absolute() {
+        assert_eq!(fqdn("example.com").unwrap().to_ascii(), "example.com.");
+        assert_eq!(fqdn("example.com.").unwrap().to_ascii(), "example.com.");
+        assert!(matches!(fqdn(&"a".repeat(64)), Err(Error::InvalidName)));
+    }
+}
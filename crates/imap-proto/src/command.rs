@@ -1,13 +1,10 @@
-use std::{
-    borrow::Cow,
-    str::{FromStr, Utf8Error},
-};
+use std::{borrow::Cow, str::FromStr, string::FromUtf8Error};
 
 use auth::sasl::WhichMechanism;
 use nom::{
     bytes::complete::{tag, take_while},
     character::complete::{space0, space1},
-    combinator::{map, map_res, opt},
+    combinator::{map, map_res, opt, rest},
     sequence::delimited,
     IResult,
 };
@@ -16,7 +13,9 @@ use tracing::debug;
 
 use crate::{
     response::{self, StatusResponse, TaggedStatusResponse},
-    sequence, Tag,
+    sequence, utf7,
+    value::{self, is_astring_char},
+    Tag,
 };
 
 use self::capability::Capabilities;
@@ -106,7 +105,7 @@ macro_rules! args {
         impl ParseArgs for $name {
             const SYNTAX: &'static str = $syntax;
 
-            fn parse(i: &str, _is_uid: bool) -> IResult<&str, Self> {
+            fn parse(i: &[u8], _is_uid: bool) -> IResult<&[u8], Self> {
                 $(
                     let (i, $arg) = <$T as ParseArg>::parse_arg(i)?;
                 )+
@@ -144,6 +143,10 @@ args!(Enable {
     capabilities: Capabilities,
 } "<capability> [<capability> ...]");
 
+args!(Compress {
+    mechanism: String,
+} "<mechanism>");
+
 args!(Select {
     mailbox: String,
 } "<mailbox>");
@@ -173,9 +176,13 @@ args!(Unsubscribe {
     mailbox: String,
 } "<mailbox>");
 
+/// A mailbox name that may contain the wildcards `%` and `*`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ListMailbox(pub String);
+
 args!(List {
     reference: String,
-    mailbox: String,
+    mailbox: ListMailbox,
 } "<reference> <mailbox>");
 
 // #[derive(Debug)]
@@ -211,26 +218,26 @@ args!(Append {
 
 #[derive(Debug)]
 pub struct Expunge {
-    is_uid: bool,
+    pub is_uid: bool,
 }
 
 #[derive(Debug)]
 pub struct Fetch {
-    is_uid: bool,
-    sequence_set: sequence::Set,
-    items: fetch::Items,
+    pub is_uid: bool,
+    pub sequence_set: sequence::Set,
+    pub items: fetch::Items,
 }
 
 impl ParseArgs for Fetch {
     const SYNTAX: &'static str = "<sequence set> <fetch attribute> [<fetch attribute> ...]";
 
-    fn parse(i: &str, is_uid: bool) -> IResult<&str, Self>
+    fn parse(i: &[u8], is_uid: bool) -> IResult<&[u8], Self>
     where
         Self: Sized,
     {
-        let (i, sequence_set) = sequence::Set::parse(i)?;
+        let (i, sequence_set) = ascii(i, sequence::Set::parse)?;
         let (i, _) = space1(i)?;
-        let (i, items) = fetch::Items::parse(i)?;
+        let (i, items) = ascii(i, fetch::Items::parse)?;
         Ok((
             i,
             Self {
@@ -245,7 +252,7 @@ impl ParseArgs for Fetch {
 trait ParseArgs {
     const SYNTAX: &'static str;
 
-    fn parse(i: &str, is_uid: bool) -> IResult<&str, Self>
+    fn parse(i: &[u8], is_uid: bool) -> IResult<&[u8], Self>
     where
         Self: Sized;
 }
@@ -279,6 +286,7 @@ pub enum Command {
     Login(Login),
     // Authenticated state
     Enable(Enable),
+    Compress(Compress),
     Select(Select),
     Examine(Examine),
     Create(Create),
@@ -302,7 +310,7 @@ pub enum Command {
     Move { is_uid: bool },
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum CommandName {
     Capability,
     Noop,
@@ -311,6 +319,7 @@ pub enum CommandName {
     Authenticate,
     Login,
     Enable,
+    Compress,
     Select,
     Examine,
     Create,
@@ -343,6 +352,7 @@ impl Command {
             Command::Authenticate(_) => CommandName::Authenticate,
             Command::Login(_) => CommandName::Login,
             Command::Enable(_) => CommandName::Enable,
+            Command::Compress(_) => CommandName::Compress,
             Command::Select(_) => CommandName::Select,
             Command::Examine(_) => CommandName::Examine,
             Command::Create(_) => CommandName::Create,
@@ -358,17 +368,64 @@ impl Command {
             Command::Close => CommandName::Close,
             Command::Unselect => CommandName::Unselect,
             Command::Expunge(_) => CommandName::Expunge,
-            Command::Search { is_uid } => CommandName::Search,
+            Command::Search { .. } => CommandName::Search,
             Command::Fetch(_) => CommandName::Fetch,
-            Command::Store { is_uid } => CommandName::Store,
-            Command::Copy { is_uid } => CommandName::Copy,
-            Command::Move { is_uid } => CommandName::Move,
+            Command::Store { .. } => CommandName::Store,
+            Command::Copy { .. } => CommandName::Copy,
+            Command::Move { .. } => CommandName::Move,
         }
     }
 }
 
-fn parse_command(s: &str, is_uid: bool) -> Result<Command, ParseError> {
-    let (verb, i) = s.split_once(' ').unwrap_or((s, ""));
+impl Command {
+    /// Decode the modified UTF-7 mailbox names of the command in place.
+    /// This should be done unless `UTF8=ACCEPT` has been enabled.
+    ///
+    /// # Errors
+    ///
+    /// If any mailbox name is not valid modified UTF-7, an error is returned.
+    pub fn decode_mailboxes(&mut self) -> Result<(), utf7::DecodeError> {
+        fn decode(mailbox: &mut String) -> Result<(), utf7::DecodeError> {
+            if let Cow::Owned(decoded) = utf7::decode(mailbox)? {
+                *mailbox = decoded;
+            }
+            Ok(())
+        }
+
+        match self {
+            Command::Select(Select { mailbox })
+            | Command::Examine(Examine { mailbox })
+            | Command::Create(Create { mailbox })
+            | Command::Delete(Delete { mailbox })
+            | Command::Subscribe(Subscribe { mailbox })
+            | Command::Unsubscribe(Unsubscribe { mailbox })
+            | Command::Status(Status { mailbox, .. }) => decode(mailbox),
+            Command::Rename(Rename { existing, new }) => {
+                decode(existing)?;
+                decode(new)
+            }
+            Command::List(List {
+                reference,
+                mailbox: ListMailbox(mailbox),
+            }) => {
+                decode(reference)?;
+                decode(mailbox)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn split_once(s: &[u8], delimiter: u8) -> (&[u8], &[u8]) {
+    match s.iter().position(|&b| b == delimiter) {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, b""),
+    }
+}
+
+fn parse_command(s: &[u8], is_uid: bool) -> Result<Command, ParseError> {
+    let (verb, i) = split_once(s, b' ');
+    let verb = std::str::from_utf8(verb).map_err(|_| ParseError::UnrecognizedCommand)?;
     Ok(match (verb.to_ascii_uppercase().as_str(), is_uid) {
         ("CAPABILITY", false) => Command::Capability,
         ("NOOP", false) => Command::Noop,
@@ -377,6 +434,7 @@ fn parse_command(s: &str, is_uid: bool) -> Result<Command, ParseError> {
         ("AUTHENTICATE", false) => parse_args!(Authenticate, i),
         ("LOGIN", false) => parse_args!(Login, i),
         ("ENABLE", false) => parse_args!(Enable, i),
+        ("COMPRESS", false) => parse_args!(Compress, i),
         ("SELECT", false) => parse_args!(Select, i),
         ("EXAMINE", false) => parse_args!(Examine, i),
         ("CREATE", false) => parse_args!(Create, i),
@@ -401,15 +459,25 @@ fn parse_command(s: &str, is_uid: bool) -> Result<Command, ParseError> {
     })
 }
 
+impl Command {
+    /// Parse a command (without the tag).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ParseError`] if the command is unknown or malformed.
+    pub fn parse(i: &[u8]) -> Result<Self, ParseError> {
+        match i.get(0..4) {
+            Some(uid) if uid.eq_ignore_ascii_case(b"UID ") => parse_command(&i[4..], true),
+            _ => parse_command(i, false),
+        }
+    }
+}
+
 impl FromStr for Command {
     type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.get(0..4).map(str::to_ascii_uppercase).as_deref() == Some("UID ") {
-            parse_command(&s[4..], true)
-        } else {
-            parse_command(s, false)
-        }
+        Self::parse(s.as_bytes())
     }
 }
 
@@ -431,117 +499,129 @@ impl From<ParseError> for StatusResponse {
 #[derive(Debug)]
 pub enum Error {
     Bad(TaggedStatusResponse),
-    InvalidUtf8,
 }
 
-impl From<Utf8Error> for Error {
-    fn from(_e: Utf8Error) -> Self {
-        Self::InvalidUtf8
+/// `tag = 1*<any ASTRING-CHAR except "+">`
+fn parse_tag(tag: &[u8]) -> Option<Tag> {
+    if !tag.is_empty() && tag.iter().all(|&b| is_astring_char(b) && b != b'+') {
+        std::str::from_utf8(tag).ok().map(Tag::from)
+    } else {
+        None
     }
 }
 
+/// The tag of a command that may not have been read in full, so that it can
+/// still be answered.
+#[must_use]
+pub fn peek_tag(value: &[u8]) -> Option<Tag> {
+    parse_tag(split_once(value, b' ').0)
+}
+
 impl TryFrom<&[u8]> for TaggedCommand {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let s = std::str::from_utf8(value)?;
-        debug!(?s, "parsing command");
-        let (tag, rest) = s.split_once(' ').unwrap_or((s, ""));
-        match rest.parse() {
-            Ok(kind) => Ok(Self {
-                tag: tag.into(),
-                command: kind,
-            }),
+        debug!(s = ?String::from_utf8_lossy(value), "parsing command");
+        let (tag, rest) = split_once(value, b' ');
+        let Some(tag) = parse_tag(tag) else {
+            return Err(Error::Bad(StatusResponse::bad("Invalid tag").with_tag("*")));
+        };
+        match Command::parse(rest) {
+            Ok(kind) => Ok(Self { tag, command: kind }),
             Err(e) => Err(Error::Bad(StatusResponse::from(e).with_tag(tag))),
         }
     }
 }
 
-fn parse_dquote_str(i: &str) -> IResult<&str, String> {
-    let (i, _) = nom::bytes::complete::tag("\"")(i)?;
-    let (i, s) = nom::bytes::complete::escaped_transform(
-        nom::character::complete::none_of("\\\""),
-        '\\',
-        nom::branch::alt((
-            nom::bytes::complete::tag("\\"),
-            nom::bytes::complete::tag("\""),
-        )),
-    )(i)?;
-    let (i, _) = nom::bytes::complete::tag("\"")(i)?;
-
-    Ok((i, s))
+fn into_string(s: Cow<'_, [u8]>) -> Result<String, FromUtf8Error> {
+    String::from_utf8(s.into_owned())
 }
 
-fn parse_str(i: &str) -> IResult<&str, Cow<'_, str>> {
-    nom::branch::alt((
-        map(parse_dquote_str, Cow::Owned),
-        map(nom::bytes::complete::is_not(" \t\r\n"), Cow::Borrowed),
-    ))(i)
+/// Run a `&str` parser on the ASCII prefix of the input.
+fn ascii<O>(i: &[u8], parser: impl FnOnce(&str) -> IResult<&str, O>) -> IResult<&[u8], O> {
+    let len = i.iter().position(|b| !b.is_ascii()).unwrap_or(i.len());
+    let s = std::str::from_utf8(&i[..len]).expect("ASCII is valid UTF-8");
+    match parser(s) {
+        Ok((rest, out)) => Ok((&i[len - rest.len()..], out)),
+        Err(e) => Err(e.map(|e| nom::error::Error::new(&i[len - e.input.len()..], e.code))),
+    }
 }
 
 trait ParseArg {
-    fn parse_arg(i: &str) -> IResult<&str, Self>
+    fn parse_arg(i: &[u8]) -> IResult<&[u8], Self>
     where
         Self: Sized;
 }
 
 impl<T: ParseArg> ParseArg for Option<T> {
-    fn parse_arg(i: &str) -> IResult<&str, Self> {
+    fn parse_arg(i: &[u8]) -> IResult<&[u8], Self> {
         let (i, _) = space0(i)?;
         opt(T::parse_arg)(i)
     }
 }
 
 impl ParseArg for String {
-    fn parse_arg(i: &str) -> IResult<&str, Self> {
+    fn parse_arg(i: &[u8]) -> IResult<&[u8], Self> {
         let (i, _) = space0(i)?;
-        map(parse_str, Cow::into_owned)(i)
+        map_res(value::astring, into_string)(i)
+    }
+}
+
+impl ParseArg for ListMailbox {
+    fn parse_arg(i: &[u8]) -> IResult<&[u8], Self> {
+        let (i, _) = space0(i)?;
+        map(map_res(value::list_mailbox, into_string), Self)(i)
     }
 }
 
 impl ParseArg for SecretString {
-    fn parse_arg(i: &str) -> IResult<&str, Self> {
+    fn parse_arg(i: &[u8]) -> IResult<&[u8], Self> {
         map(String::parse_arg, SecretString::new)(i)
     }
 }
 
 impl ParseArg for WhichMechanism {
-    fn parse_arg(i: &str) -> IResult<&str, Self> {
+    fn parse_arg(i: &[u8]) -> IResult<&[u8], Self> {
         let (i, _) = space0(i)?;
-        map_res(parse_str, |s| s.parse())(i)
+        map_res(value::atom, str::parse)(i)
     }
 }
 
 impl ParseArg for Capabilities {
-    fn parse_arg(i: &str) -> IResult<&str, Self> {
+    fn parse_arg(i: &[u8]) -> IResult<&[u8], Self> {
         let (i, _) = space0(i)?;
-        Ok(("", i.split(' ').collect()))
+        map(map_res(rest, std::str::from_utf8), |s| {
+            s.split(' ').collect()
+        })(i)
     }
 }
 
 impl ParseArg for status::Items {
-    fn parse_arg(i: &str) -> IResult<&str, Self> {
+    fn parse_arg(i: &[u8]) -> IResult<&[u8], Self> {
         let (i, _) = space0(i)?;
-        let (i, items) = delimited(tag("("), take_while(|c| c != ')'), tag(")"))(i)?;
+        let (i, items) = map_res(
+            delimited(tag("("), take_while(|c| c != b')'), tag(")")),
+            std::str::from_utf8,
+        )(i)?;
 
         Ok((i, items.split(' ').collect()))
     }
 }
 
 impl ParseArg for sequence::Set {
-    fn parse_arg(i: &str) -> IResult<&str, Self>
+    fn parse_arg(i: &[u8]) -> IResult<&[u8], Self>
     where
         Self: Sized,
     {
         let (i, _) = space0(i)?;
-        sequence::Set::parse(i)
+        ascii(i, sequence::Set::parse)
     }
 }
 
 impl ParseArg for fetch::Items {
-    fn parse_arg(i: &str) -> IResult<&str, Self> {
+    fn parse_arg(i: &[u8]) -> IResult<&[u8], Self> {
         let (i, _) = space0(i)?;
-        fetch::Items::parse(i)
+        ascii(i, fetch::Items::parse)
     }
 }
 
@@ -564,40 +644,48 @@ mod tests {
     use super::*;
 
     #[test]
-    fn dquote() {
-        let cases = [
-            ("\"Hello\"", ("", "Hello")),
-            ("\"Hello World!\" rest", (" rest", "Hello World!")),
-            ("\"dquote \\\"\"", ("", "dquote \"")),
-            ("\"backslash \\\\\"", ("", "backslash \\")),
-        ];
+    fn login() {
+        let Ok(Command::Login(Login { username, password })) = "login alice \"hunter 2\"".parse()
+        else {
+            panic!()
+        };
 
-        for (input, (rest, str)) in cases {
-            assert_eq!(super::parse_dquote_str(input), Ok((rest, str.to_owned())));
-        }
-    }
+        assert_eq!(username, "alice");
+        assert_eq!(password.expose_secret(), "hunter 2");
 
-    #[test]
-    fn str() {
-        let cases = [
-            ("Hello World!", (" World!", "Hello")),
-            ("\"Hello World!\"", ("", "Hello World!")),
-        ];
+        let Ok(Command::Login(Login { username, password })) =
+            Command::parse(b"LOGIN {7}\r\ng\xc3\xbcnter {9}\r\n\"quoted\"\\")
+        else {
+            panic!()
+        };
 
-        for (input, (rest, str)) in cases {
-            assert_eq!(super::parse_str(input), Ok((rest, str.into())));
-        }
+        assert_eq!(username, "günter");
+        assert_eq!(password.expose_secret(), "\"quoted\"\\");
     }
 
     #[test]
-    fn login() {
-        let Ok(Command::Login(Login { username, password })) = "login alice \"hunter 2\"".parse()
+    fn tagged() {
+        let Ok(TaggedCommand {
+            tag,
+            command: Command::List(List { reference, mailbox }),
+        }) = TaggedCommand::try_from(&b"A1 LIST \"\" %/*"[..])
         else {
             panic!()
         };
+        assert_eq!(tag, "A1".into());
+        assert_eq!(reference, "");
+        assert_eq!(mailbox, ListMailbox("%/*".to_owned()));
 
-        assert_eq!(username, "alice");
-        assert_eq!(password.expose_secret(), "hunter 2");
+        // non-UTF-8 input is rejected with a tagged BAD
+        let Err(Error::Bad(res)) = TaggedCommand::try_from(&b"A2 SELECT {1}\r\n\xff"[..]) else {
+            panic!()
+        };
+        assert_eq!(res.to_string(), "A2 BAD Syntax: SELECT <mailbox>\r\n");
+
+        let Err(Error::Bad(res)) = TaggedCommand::try_from(&b"A+ NOOP"[..]) else {
+            panic!()
+        };
+        assert_eq!(res.to_string(), "* BAD Invalid tag\r\n");
     }
 
     #[test]
@@ -627,4 +715,18 @@ mod tests {
 
         assert!("status INBOX ()".parse::<Command>().is_ok());
     }
+
+    #[test]
+    fn decode_mailboxes() {
+        let mut cmd: Command = "rename Skr&AOQ-p &AMU-terst&AOQ-llda".parse().unwrap();
+        cmd.decode_mailboxes().unwrap();
+        let Command::Rename(Rename { existing, new }) = cmd else {
+            panic!()
+        };
+        assert_eq!(existing, "Skräp");
+        assert_eq!(new, "Återställda");
+
+        let mut cmd: Command = "select Skr&AOQ".parse().unwrap();
+        assert_eq!(cmd.decode_mailboxes(), Err(utf7::DecodeError));
+    }
 }
@@ -4,7 +4,7 @@ use auth::sasl::WhichMechanism;
 use util::flags;
 
 flags! {
-    pub Capabilities: u8 {
+    pub Capabilities: u16 {
         (1 << 0, "IMAP4", IMAP4); // MUST be the first capability listed (RFC 1730)
         (1 << 1, "IMAP4rev1", IMAP4rev1);
         (1 << 2, "IMAP4rev2", IMAP4rev2);
@@ -12,6 +12,12 @@ flags! {
         (1 << 4, "AUTH=PLAIN", AUTH_PLAIN);
         (1 << 5, "LOGINDISABLED", LOGINDISABLED);
         (1 << 6, "SASL-IR", SASL_IR);
+        (1 << 7, "ENABLE", ENABLE);
+        /// UTF-8 mailbox names and quoted strings
+        /// ([RFC 6855](https://www.rfc-editor.org/rfc/rfc6855)).
+        (1 << 8, "UTF8=ACCEPT", UTF8_ACCEPT);
+        /// DEFLATE compression ([RFC 4978](https://www.rfc-editor.org/rfc/rfc4978)).
+        (1 << 9, "COMPRESS=DEFLATE", COMPRESS_DEFLATE);
     }
 }
 
@@ -34,15 +40,33 @@ impl fmt::Display for Capabilities {
     }
 }
 
+/// The ENABLED response lists the capabilities that were enabled by
+/// an ENABLE command ([RFC 5161](https://www.rfc-editor.org/rfc/rfc5161#section-3.2)).
+pub struct Enabled(pub Capabilities);
+
+impl fmt::Display for Enabled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ENABLED")?;
+        for capability in self.0.names() {
+            write!(f, " {capability}",)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Capabilities;
+    use super::{Capabilities, Enabled};
 
     #[test]
     fn fmt() {
         assert_eq!(
             Capabilities::all().to_string(),
-            "CAPABILITY IMAP4 IMAP4rev1 IMAP4rev2 STARTTLS AUTH=PLAIN LOGINDISABLED SASL-IR"
+            "CAPABILITY IMAP4 IMAP4rev1 IMAP4rev2 STARTTLS AUTH=PLAIN LOGINDISABLED SASL-IR ENABLE UTF8=ACCEPT COMPRESS=DEFLATE"
+        );
+        assert_eq!(
+            Enabled(Capabilities::UTF8_ACCEPT).to_string(),
+            "ENABLED UTF8=ACCEPT"
         );
     }
 
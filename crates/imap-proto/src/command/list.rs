@@ -2,7 +2,7 @@ use std::fmt;
 
 use util::flags;
 
-use crate::fmt_paren_list;
+use crate::{fmt_paren_list, value::Value};
 
 flags! {
     pub Attributes: u16 {
@@ -53,10 +53,12 @@ struct DelimiterDisplay<'a>(&'a Option<char>);
 
 impl fmt::Display for DelimiterDisplay<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.0 {
-            Some(delimiter) => write!(f, "\"{delimiter}\""),
-            None => write!(f, "NIL"),
-        }
+        let mut buf = [0; 4];
+        Value::nstring(
+            self.0
+                .map(|delimiter| delimiter.encode_utf8(&mut buf).as_bytes()),
+        )
+        .fmt(f)
     }
 }
 
@@ -64,10 +66,10 @@ impl fmt::Display for ListItem {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "* LIST {} {} \"{}\"\r\n",
+            "* LIST {} {} {}\r\n",
             self.attributes,
             DelimiterDisplay(&self.hierarchy_delimiter),
-            self.name
+            Value::string(self.name.as_bytes())
         )
     }
 }
@@ -109,6 +111,16 @@ mod tests {
             }
             .to_string(),
             "* LIST (\\Noinferiors \\Noselect) NIL \"INBOX\"\r\n"
-        )
+        );
+
+        assert_eq!(
+            ListItem {
+                attributes: Attributes::empty(),
+                name: "\"Quotes\" \\o/".to_string(),
+                hierarchy_delimiter: Some('\\'),
+            }
+            .to_string(),
+            "* LIST () \"\\\\\" \"\\\"Quotes\\\" \\\\o/\"\r\n"
+        );
     }
 }
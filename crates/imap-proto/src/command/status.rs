@@ -1,6 +1,6 @@
 use std::fmt;
 
-use crate::Uid;
+use crate::{value::Value, Uid};
 
 util::flags! {
     pub Items: u8 {
@@ -64,7 +64,7 @@ impl fmt::Display for Response {
             size,
         } = self;
 
-        write!(f, "STATUS {} (", mailbox)?;
+        write!(f, "STATUS {} (", Value::astring(mailbox))?;
         fmt!(f, {
             ("MESSAGES", messages);
             ("UIDNEXT", uid_next);
@@ -0,0 +1,15 @@
+use std::fmt;
+
+/// The EXPUNGE response reports that the specified message sequence
+/// number has been permanently removed from the mailbox. The message
+/// sequence number for each successive message in the mailbox is
+/// immediately decremented by 1.
+///
+/// <https://www.rfc-editor.org/rfc/rfc9051.html#section-7.5.1>
+pub struct Response(pub u32);
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} EXPUNGE", self.0)
+    }
+}
@@ -1,6 +1,6 @@
 use std::{convert::Infallible, fmt};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Flag {
     Seen,
     Answered,
@@ -6,12 +6,15 @@ use std::{
 use response::StatusResponse;
 
 pub mod exists;
+pub mod expunge;
 pub mod flags;
 pub mod recent;
 
 pub mod command;
 pub mod response;
 pub mod sequence;
+pub mod utf7;
+pub mod value;
 
 /// Format a parenthesized list ([Section 4.4] of RFC9051).
 /// The data items are delimeted by spaces and the list is bounded
@@ -49,7 +52,7 @@ pub fn fmt_paren_list<T: fmt::Display>(
 /// A unique identifier for a message.
 ///
 /// See [RFC 9051](https://datatracker.ietf.org/doc/html/rfc9051#section-2.3.1.1).
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub struct Uid(pub NonZeroU32);
 
 impl fmt::Display for Uid {
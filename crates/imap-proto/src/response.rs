@@ -73,6 +73,9 @@ impl From<ValidationError> for StatusResponse {
     fn from(value: ValidationError) -> Self {
         match value {
             ValidationError::InvalidCredentials => Self::no("invalid credentials"),
+            ValidationError::LockedOut => {
+                Self::no("[UNAVAILABLE] too many failed attempts, try again later")
+            }
             ValidationError::Unknown => Self::bad("invalid identity"),
         }
     }
@@ -0,0 +1,192 @@
+//! Modified UTF-7 mailbox name encoding ([Section 5.1.3] of RFC 3501).
+//!
+//! IMAP4rev1 clients that have not enabled `UTF8=ACCEPT` expect mailbox
+//! names with non-ASCII characters to be encoded this way:
+//!
+//! ```
+//! use imap_proto::utf7;
+//!
+//! assert_eq!(utf7::encode("Skräp"), "Skr&AOQ-p");
+//! assert_eq!(utf7::decode("Skr&AOQ-p").unwrap(), "Skräp");
+//! ```
+//!
+//! [Section 5.1.3]: https://www.rfc-editor.org/rfc/rfc3501#section-5.1.3
+
+use std::{borrow::Cow, fmt};
+
+/// Modified BASE64 alphabet, where `,` is used instead of `/`.
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DecodeError;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid modified UTF-7")
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Printable US-ASCII characters represent themselves (except `&`, which
+/// needs to be escaped).
+const fn is_direct(c: char) -> bool {
+    matches!(c, ' '..='~')
+}
+
+fn flush(out: &mut String, units: &mut Vec<u16>) {
+    if units.is_empty() {
+        return;
+    }
+
+    let mut bits = 0u32;
+    let mut n = 0;
+
+    out.push('&');
+    for byte in units.drain(..).flat_map(u16::to_be_bytes) {
+        bits = (bits << 8) | u32::from(byte);
+        n += 8;
+        while n >= 6 {
+            n -= 6;
+            out.push(ALPHABET[(bits >> n) as usize & 0x3f].into());
+        }
+        bits &= (1 << n) - 1;
+    }
+    if n > 0 {
+        out.push(ALPHABET[(bits << (6 - n)) as usize & 0x3f].into());
+    }
+    out.push('-');
+}
+
+/// Encode a mailbox name in modified UTF-7.
+#[must_use]
+pub fn encode(s: &str) -> Cow<'_, str> {
+    if s.chars().all(|c| is_direct(c) && c != '&') {
+        return Cow::Borrowed(s);
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut units = Vec::new();
+
+    for c in s.chars() {
+        if is_direct(c) {
+            flush(&mut out, &mut units);
+            if c == '&' {
+                out.push_str("&-");
+            } else {
+                out.push(c);
+            }
+        } else {
+            let mut buf = [0; 2];
+            units.extend_from_slice(c.encode_utf16(&mut buf));
+        }
+    }
+    flush(&mut out, &mut units);
+
+    Cow::Owned(out)
+}
+
+fn decode_base64(i: &str, out: &mut String) -> Result<(), DecodeError> {
+    let mut units = Vec::with_capacity(i.len() * 6 / 16);
+    let mut bits = 0u32;
+    let mut n = 0;
+
+    for b in i.bytes() {
+        let v = ALPHABET.iter().position(|&a| a == b).ok_or(DecodeError)?;
+        bits = (bits << 6) | u32::try_from(v).unwrap();
+        n += 6;
+        if n >= 16 {
+            n -= 16;
+            units.push(u16::try_from((bits >> n) & 0xffff).unwrap());
+            bits &= (1 << n) - 1;
+        }
+    }
+
+    // leftover bits are padding and must be zero
+    if n >= 6 || bits != 0 || units.is_empty() {
+        return Err(DecodeError);
+    }
+
+    for c in char::decode_utf16(units) {
+        let c = c.map_err(|_| DecodeError)?;
+        // characters that can be represented directly must not be encoded
+        if is_direct(c) {
+            return Err(DecodeError);
+        }
+        out.push(c);
+    }
+
+    Ok(())
+}
+
+/// Decode a modified UTF-7 mailbox name.
+///
+/// # Errors
+///
+/// Returns [`DecodeError`] if `s` is not valid modified UTF-7.
+pub fn decode(s: &str) -> Result<Cow<'_, str>, DecodeError> {
+    if !s.chars().all(is_direct) {
+        return Err(DecodeError);
+    }
+
+    if !s.contains('&') {
+        return Ok(Cow::Borrowed(s));
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some((direct, shifted)) = rest.split_once('&') {
+        out.push_str(direct);
+        let (base64, after) = shifted.split_once('-').ok_or(DecodeError)?;
+        if base64.is_empty() {
+            out.push('&');
+        } else {
+            decode_base64(base64, &mut out)?;
+        }
+        rest = after;
+    }
+    out.push_str(rest);
+
+    Ok(Cow::Owned(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, DecodeError};
+
+    const CASES: [(&str, &str); 7] = [
+        ("INBOX", "INBOX"),
+        ("Skräp", "Skr&AOQ-p"),
+        ("Återställda", "&AMU-terst&AOQ-llda"),
+        ("Räksmörgås", "R&AOQ-ksm&APY-rg&AOU-s"),
+        ("Tom & Jerry", "Tom &- Jerry"),
+        ("~peter/mail/台北/日本語", "~peter/mail/&U,BTFw-/&ZeVnLIqe-"),
+        ("📬 Post", "&2D3c7A- Post"),
+    ];
+
+    #[test]
+    fn roundtrip() {
+        for (decoded, encoded) in CASES {
+            assert_eq!(encode(decoded), encoded);
+            assert_eq!(decode(encoded).unwrap(), decoded);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let cases = [
+            "Skr&AOQ",   // unterminated
+            "Skr&AO-p",  // too few bits
+            "Skr&AOR-p", // non-zero padding bits
+            "&AGE-",     // "a" must not be encoded
+            "&2D0-",     // unpaired surrogate
+            "&A/Q-",     // `/` is not part of the alphabet
+            "Skräp",     // raw non-ASCII
+        ];
+
+        for input in cases {
+            assert_eq!(decode(input), Err(DecodeError), "{input}");
+        }
+    }
+}
@@ -0,0 +1,423 @@
+//! Values and strings ([Section 4] of RFC 9051).
+//!
+//! Parsing is done on bytes, since literals may contain anything.
+//!
+//! [Section 4]: https://www.rfc-editor.org/rfc/rfc9051.html#name-data-formats
+
+use std::{
+    borrow::Cow,
+    fmt::{self, Write as _},
+    io::Write as _,
+};
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take, take_while1},
+    character::complete::{char, u32},
+    combinator::{map, map_res, opt, recognize},
+    multi::separated_list0,
+    sequence::{delimited, pair},
+    IResult,
+};
+
+use crate::fmt_paren_list;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Value<'a> {
+    Nil,
+    /// An atom. Flags (`\Seen`) are atoms too.
+    Atom(Cow<'a, str>),
+    Quoted(Cow<'a, [u8]>),
+    Literal(Cow<'a, [u8]>),
+    List(Vec<Value<'a>>),
+}
+
+impl<'a> Value<'a> {
+    /// A `string`. Quoted if possible, otherwise a literal.
+    ///
+    /// ```
+    /// use imap_proto::value::Value;
+    ///
+    /// assert_eq!(Value::string(&b"a \"b\""[..]).to_string(), r#""a \"b\"""#);
+    /// assert_eq!(Value::string(&b"a\r\nb"[..]).to_string(), "{4}\r\na\r\nb");
+    /// ```
+    pub fn string(s: impl Into<Cow<'a, [u8]>>) -> Self {
+        let s = s.into();
+        if s.iter().copied().all(is_quoted_char) && s.is_ascii() {
+            Self::Quoted(s)
+        } else {
+            Self::Literal(s)
+        }
+    }
+
+    /// An `astring`. An atom if possible, otherwise a `string`.
+    ///
+    /// ```
+    /// use imap_proto::value::Value;
+    ///
+    /// assert_eq!(Value::astring("INBOX").to_string(), "INBOX");
+    /// assert_eq!(Value::astring("Sent Items").to_string(), "\"Sent Items\"");
+    /// ```
+    #[must_use]
+    pub fn astring(s: &'a str) -> Self {
+        if !s.is_empty() && s.bytes().all(is_astring_char) && !s.eq_ignore_ascii_case("NIL") {
+            Self::Atom(Cow::Borrowed(s))
+        } else {
+            Self::string(s.as_bytes())
+        }
+    }
+
+    /// An `nstring`. `NIL` or a `string`.
+    #[must_use]
+    pub fn nstring(s: Option<&'a [u8]>) -> Self {
+        s.map_or(Self::Nil, Self::string)
+    }
+
+    /// Append the value to `out` byte for byte. Unlike the `Display`
+    /// implementation, this can write strings that aren't UTF-8, such as
+    /// message bodies.
+    ///
+    /// ```
+    /// use imap_proto::value::Value;
+    ///
+    /// let mut out = Vec::new();
+    /// Value::string(&b"\xff\r\n"[..]).write_to(&mut out);
+    /// assert_eq!(out, b"{3}\r\n\xff\r\n");
+    /// ```
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Nil => out.extend_from_slice(b"NIL"),
+            Value::Atom(atom) => out.extend_from_slice(atom.as_bytes()),
+            Value::Quoted(quoted) => {
+                out.push(b'"');
+                for &b in quoted.iter() {
+                    if b == b'"' || b == b'\\' {
+                        out.push(b'\\');
+                    }
+                    out.push(b);
+                }
+                out.push(b'"');
+            }
+            Value::Literal(literal) => {
+                write!(out, "{{{}}}\r\n", literal.len()).expect("writing to a Vec");
+                out.extend_from_slice(literal);
+            }
+            Value::List(list) => {
+                out.push(b'(');
+                for (i, value) in list.iter().enumerate() {
+                    if i > 0 {
+                        out.push(b' ');
+                    }
+                    value.write_to(out);
+                }
+                out.push(b')');
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value<'_> {
+    /// Non-UTF-8 strings are converted lossily, with literals counting the
+    /// replacement characters. Use [`Value::write_to`] to keep the bytes.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "NIL"),
+            Value::Atom(atom) => f.write_str(atom),
+            Value::Quoted(quoted) => {
+                f.write_char('"')?;
+                for c in String::from_utf8_lossy(quoted).chars() {
+                    if c == '"' || c == '\\' {
+                        f.write_char('\\')?;
+                    }
+                    f.write_char(c)?;
+                }
+                f.write_char('"')
+            }
+            Value::Literal(literal) => match std::str::from_utf8(literal) {
+                Ok(text) => write!(f, "{{{}}}\r\n{text}", literal.len()),
+                Err(_) => {
+                    // the length must be that of what is written
+                    let lossy = String::from_utf8_lossy(literal);
+                    write!(f, "{{{}}}\r\n{lossy}", lossy.len())
+                }
+            },
+            Value::List(list) => fmt_paren_list(f, list),
+        }
+    }
+}
+
+/// `atom-specials` (with `CTL`s included).
+const fn is_atom_special(b: u8) -> bool {
+    matches!(
+        b,
+        b'(' | b')' | b'{' | b' ' | b'%' | b'*' | b'"' | b'\\' | b']'
+    ) || b.is_ascii_control()
+}
+
+#[must_use]
+pub const fn is_atom_char(b: u8) -> bool {
+    b.is_ascii() && !is_atom_special(b)
+}
+
+#[must_use]
+pub const fn is_astring_char(b: u8) -> bool {
+    is_atom_char(b) || b == b']'
+}
+
+const fn is_list_char(b: u8) -> bool {
+    is_astring_char(b) || b == b'%' || b == b'*'
+}
+
+/// `QUOTED-CHAR`, including the quoted specials that need to be escaped.
+/// 8-bit bytes are accepted since `UTF8=ACCEPT` allows UTF-8 in quoted
+/// strings.
+const fn is_quoted_char(b: u8) -> bool {
+    !matches!(b, b'\0' | b'\r' | b'\n')
+}
+
+pub fn atom(i: &[u8]) -> IResult<&[u8], &str> {
+    map_res(take_while1(is_atom_char), std::str::from_utf8)(i)
+}
+
+pub fn quoted(i: &[u8]) -> IResult<&[u8], Cow<'_, [u8]>> {
+    let (start, _) = char('"')(i)?;
+    let mut rest = start;
+    let mut owned: Option<Vec<u8>> = None;
+
+    loop {
+        match rest.split_first() {
+            Some((b'"', after)) => {
+                let s = owned.map_or_else(
+                    || Cow::Borrowed(&start[..start.len() - rest.len()]),
+                    Cow::Owned,
+                );
+                return Ok((after, s));
+            }
+            Some((b'\\', after)) => match after.split_first() {
+                Some((&c @ (b'"' | b'\\'), after)) => {
+                    owned
+                        .get_or_insert_with(|| start[..start.len() - rest.len()].to_vec())
+                        .push(c);
+                    rest = after;
+                }
+                _ => break,
+            },
+            Some((&c, after)) if is_quoted_char(c) => {
+                if let Some(owned) = &mut owned {
+                    owned.push(c);
+                }
+                rest = after;
+            }
+            _ => break,
+        }
+    }
+
+    Err(nom::Err::Error(nom::error::Error::new(
+        rest,
+        nom::error::ErrorKind::Escaped,
+    )))
+}
+
+/// The `{<size>}` or `{<size>+}` announcing a literal.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct LiteralHeader {
+    pub len: u32,
+    /// Unless the literal is non-synchronizing (`LITERAL+`/`LITERAL-`),
+    /// the client waits for a continuation request before sending the
+    /// data.
+    pub synchronizing: bool,
+}
+
+impl LiteralHeader {
+    fn parse(i: &[u8]) -> IResult<&[u8], Self> {
+        let (i, _) = char('{')(i)?;
+        let (i, len) = u32(i)?;
+        let (i, non_synchronizing) = opt(char('+'))(i)?;
+        let (i, _) = char('}')(i)?;
+        Ok((
+            i,
+            Self {
+                len,
+                synchronizing: non_synchronizing.is_none(),
+            },
+        ))
+    }
+
+    /// Returns the literal announced at the end of a line, if any.
+    ///
+    /// ```
+    /// use imap_proto::value::LiteralHeader;
+    ///
+    /// assert_eq!(
+    ///     LiteralHeader::at_end(b"A001 LOGIN {11}"),
+    ///     Some(LiteralHeader { len: 11, synchronizing: true }),
+    /// );
+    /// assert_eq!(LiteralHeader::at_end(b"A001 LOGIN alice"), None);
+    /// ```
+    #[must_use]
+    pub fn at_end(line: &[u8]) -> Option<Self> {
+        let start = line.iter().rposition(|&b| b == b'{')?;
+        match Self::parse(&line[start..]) {
+            Ok((b"", header)) => Some(header),
+            _ => None,
+        }
+    }
+}
+
+pub fn literal(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (i, LiteralHeader { len, .. }) = LiteralHeader::parse(i)?;
+    let (i, _) = tag("\r\n")(i)?;
+    take(len)(i)
+}
+
+/// `string = quoted / literal`
+pub fn string(i: &[u8]) -> IResult<&[u8], Cow<'_, [u8]>> {
+    alt((quoted, map(literal, Cow::Borrowed)))(i)
+}
+
+/// `astring = 1*ASTRING-CHAR / string`
+pub fn astring(i: &[u8]) -> IResult<&[u8], Cow<'_, [u8]>> {
+    alt((map(take_while1(is_astring_char), Cow::Borrowed), string))(i)
+}
+
+/// `list-mailbox = 1*list-char / string`
+pub fn list_mailbox(i: &[u8]) -> IResult<&[u8], Cow<'_, [u8]>> {
+    alt((map(take_while1(is_list_char), Cow::Borrowed), string))(i)
+}
+
+fn atom_or_nil(i: &[u8]) -> IResult<&[u8], Value<'_>> {
+    map_res(
+        recognize(pair(opt(char('\\')), take_while1(is_atom_char))),
+        |atom| {
+            std::str::from_utf8(atom).map(|atom| {
+                if atom.eq_ignore_ascii_case("NIL") {
+                    Value::Nil
+                } else {
+                    Value::Atom(Cow::Borrowed(atom))
+                }
+            })
+        },
+    )(i)
+}
+
+/// Parse any [`Value`].
+pub fn value(i: &[u8]) -> IResult<&[u8], Value<'_>> {
+    alt((
+        map(quoted, Value::Quoted),
+        map(literal, |l| Value::Literal(Cow::Borrowed(l))),
+        map(
+            delimited(char('('), separated_list0(char(' '), value), char(')')),
+            Value::List,
+        ),
+        atom_or_nil,
+    ))(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::{astring, literal, quoted, value, LiteralHeader, Value};
+
+    /// Input and expected `(rest, output)`.
+    type Case = (&'static [u8], (&'static [u8], &'static [u8]));
+
+    #[test]
+    fn dquote() {
+        let cases: [Case; 4] = [
+            (b"\"Hello\"", (b"", b"Hello")),
+            (b"\"Hello World!\" rest", (b" rest", b"Hello World!")),
+            (b"\"dquote \\\"\"", (b"", b"dquote \"")),
+            (b"\"backslash \\\\\"", (b"", b"backslash \\")),
+        ];
+
+        for (input, (rest, str)) in cases {
+            assert_eq!(quoted(input), Ok((rest, str.into())));
+        }
+
+        assert!(quoted(b"\"unterminated").is_err());
+        assert!(quoted(b"\"bad \\escape\"").is_err());
+    }
+
+    #[test]
+    fn str() {
+        let cases: [Case; 4] = [
+            (b"Hello World!", (b" World!", b"Hello")),
+            (b"\"Hello World!\"", (b"", b"Hello World!")),
+            (b"{5}\r\nHello World!", (b" World!", b"Hello")),
+            (b"{3+}\r\n\xff\0\" rest", (b" rest", b"\xff\0\"")),
+        ];
+
+        for (input, (rest, str)) in cases {
+            assert_eq!(astring(input), Ok((rest, str.into())));
+        }
+
+        assert!(literal(b"{5}\r\nabc").is_err());
+    }
+
+    #[test]
+    fn parse_value() {
+        assert_eq!(
+            value(b"(\\Seen NIL \"a b\" {1}\r\nc (x))"),
+            Ok((
+                &b""[..],
+                Value::List(vec![
+                    Value::Atom("\\Seen".into()),
+                    Value::Nil,
+                    Value::Quoted(Cow::Borrowed(b"a b")),
+                    Value::Literal(Cow::Borrowed(b"c")),
+                    Value::List(vec![Value::Atom("x".into())]),
+                ])
+            ))
+        );
+    }
+
+    #[test]
+    fn fmt() {
+        let cases = [
+            (Value::astring("Drafts"), "Drafts"),
+            (Value::astring("nil"), "\"nil\""),
+            (Value::astring(""), "\"\""),
+            (Value::astring("a\\b"), "\"a\\\\b\""),
+            (Value::astring("Skräp"), "{6}\r\nSkräp"),
+            (Value::nstring(None), "NIL"),
+            (
+                Value::List(vec![Value::Atom("A".into()), Value::nstring(Some(b"b"))]),
+                "(A \"b\")",
+            ),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(value.to_string(), expected);
+            let mut out = Vec::new();
+            value.write_to(&mut out);
+            assert_eq!(out, expected.as_bytes());
+        }
+
+        // 8-bit bytes are kept, and counted as they are
+        let value = Value::List(vec![
+            Value::Literal(Cow::Borrowed(b"\xe5\xe4")),
+            Value::Quoted(Cow::Borrowed(b"a\"\xf6")),
+        ]);
+        let mut out = Vec::new();
+        value.write_to(&mut out);
+        assert_eq!(out, b"({2}\r\n\xe5\xe4 \"a\\\"\xf6\")");
+        assert_eq!(
+            value.to_string(),
+            "({6}\r\n\u{fffd}\u{fffd} \"a\\\"\u{fffd}\")"
+        );
+    }
+
+    #[test]
+    fn literal_header() {
+        assert_eq!(
+            LiteralHeader::at_end(b"A1 APPEND INBOX {310+}"),
+            Some(LiteralHeader {
+                len: 310,
+                synchronizing: false
+            })
+        );
+        assert_eq!(LiteralHeader::at_end(b"A1 LOGIN \"{3}\""), None);
+        assert_eq!(LiteralHeader::at_end(b"A1 LOGIN {x}"), None);
+    }
+}
@@ -1,22 +1,33 @@
+pub mod mailbox;
 pub mod ops;
 mod queue;
 pub mod session;
 
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
-use imap_proto::command::TaggedCommand;
+use auth::guard::Guard;
+use imap_proto::{
+    command::{peek_tag, TaggedCommand},
+    response::StatusResponse,
+    value::LiteralHeader,
+};
 use line::{
     stream::{MaybeTls, ServerTlsStream},
     ReadLineError,
 };
 pub use session::Session;
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
-use tracing::{debug, instrument};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite};
+use tracing::instrument;
+use util::limit::RateLimiter;
 
 #[derive(Debug)]
 pub struct Context<A: auth::Validator> {
     pub tls: Option<Arc<rustls::ServerConfig>>,
     pub auth: Arc<A>,
+    /// Limits authentication attempts per client.
+    pub limiter: Option<Arc<RateLimiter>>,
+    /// Slows down and locks out password guessing.
+    pub guard: Option<Arc<Guard>>,
 }
 
 impl<A: auth::Validator> Clone for Context<A> {
@@ -24,6 +35,8 @@ impl<A: auth::Validator> Clone for Context<A> {
         Self {
             tls: self.tls.clone(),
             auth: Arc::clone(&self.auth),
+            limiter: self.limiter.clone(),
+            guard: self.guard.clone(),
         }
     }
 }
@@ -38,14 +51,22 @@ impl<A: auth::Validator> Server<A> {
         Self { context }
     }
 
+    /// Start a session with the client at `peer`.
     pub fn accept<IO: AsyncRead + AsyncWrite + Unpin>(
         &self,
         stream: impl Into<MaybeTls<ServerTlsStream<IO>, IO>>,
+        peer: SocketAddr,
     ) -> Session<IO, A> {
-        Session::new(stream, self.context.clone())
+        Session::new(stream, peer, self.context.clone())
     }
 }
 
+/// Literals larger than this are rejected.
+const LITERAL_LIMIT: u32 = 64 * 1024;
+
+/// Commands larger than this, counting all of their literals, are rejected.
+const COMMAND_LIMIT: usize = 256 * 1024;
+
 #[instrument(skip_all)]
 pub async fn read_cmd<S: AsyncRead + AsyncBufRead + AsyncWrite + Unpin>(
     stream: &mut S,
@@ -53,19 +74,107 @@ pub async fn read_cmd<S: AsyncRead + AsyncBufRead + AsyncWrite + Unpin>(
     use imap_proto::command::Error;
 
     let mut buf = Vec::new();
+    let mut line = Vec::new();
     loop {
-        match line::read_line(stream, &mut buf).await {
-            Ok(()) => match TaggedCommand::try_from(&buf[..]) {
-                Ok(cmd) => return Ok(Some(cmd)),
-                Err(Error::Bad(res)) => {
-                    line::write_flush(stream, res.to_string()).await?;
-                }
-                Err(Error::InvalidUtf8) => debug!("invalid utf8"),
-            },
+        // one byte more than allowed, to tell a line that ends right at the
+        // limit from one that goes on
+        let allowed = COMMAND_LIMIT.saturating_sub(buf.len()) + 1;
+        let mut limited = (&mut *stream).take(allowed as u64);
+        match line::read_line(&mut limited, &mut line).await {
+            Ok(()) => buf.extend_from_slice(&line),
             Err(ReadLineError::Eof) => return Ok(None),
             Err(ReadLineError::Io(e)) => return Err(e),
         }
+        if limited.limit() == 0 {
+            line::write_flush(stream, "* BYE [TOOBIG] Command too large\r\n").await?;
+            return Ok(None);
+        }
+
+        // the command continues after a literal
+        if let Some(LiteralHeader { len, synchronizing }) = LiteralHeader::at_end(&line) {
+            line.clear();
+
+            if len > LITERAL_LIMIT || buf.len() + len as usize > COMMAND_LIMIT {
+                if !synchronizing {
+                    // the client won't wait for us, so we can't recover
+                    line::write_flush(stream, "* BYE [TOOBIG] Command too large\r\n").await?;
+                    return Ok(None);
+                }
+                // the client sends nothing more for this command
+                let res = StatusResponse::bad("[TOOBIG] Literal too large")
+                    .with_tag(peek_tag(&buf).unwrap_or_else(|| "*".into()));
+                line::write_flush(stream, res.to_string()).await?;
+                buf.clear();
+                continue;
+            }
+
+            if synchronizing {
+                line::write_flush(stream, "+ Ready for literal data\r\n").await?;
+            }
+
+            buf.extend_from_slice(b"\r\n");
+            let start = buf.len();
+            buf.resize(start + len as usize, 0);
+            stream.read_exact(&mut buf[start..]).await?;
+            continue;
+        }
+
+        match TaggedCommand::try_from(&buf[..]) {
+            Ok(cmd) => return Ok(Some(cmd)),
+            Err(Error::Bad(res)) => {
+                line::write_flush(stream, res.to_string()).await?;
+            }
+        }
 
         buf.clear();
+        line.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use imap_proto::command::Command;
+    use tokio::io::BufReader;
+
+    use super::read_cmd;
+
+    #[tokio::test]
+    async fn oversized_literal_gets_tagged_reply() {
+        let mock = tokio_test::io::Builder::new()
+            .read(b"A1 LOGIN {100000}\r\n")
+            .write(b"A1 BAD [TOOBIG] Literal too large\r\n")
+            .read(b"A2 NOOP\r\n")
+            .build();
+
+        let cmd = read_cmd(&mut BufReader::new(mock)).await.unwrap().unwrap();
+        assert_eq!(cmd.tag.to_string(), "A2");
+        assert!(matches!(cmd.command, Command::Noop));
+    }
+
+    #[tokio::test]
+    async fn many_literals_are_capped() {
+        let literal = format!("{{60000+}}\r\n{}", "a".repeat(60000));
+        // the fifth literal takes the command over the limit
+        let input = format!("A1 LOGIN {} {{60000+}}\r\n", [&*literal; 4].join(" "));
+
+        let mock = tokio_test::io::Builder::new()
+            .read(input.as_bytes())
+            .write(b"* BYE [TOOBIG] Command too large\r\n")
+            .build();
+
+        assert!(read_cmd(&mut BufReader::new(mock)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn long_line_is_capped() {
+        // never ends, but only one byte over the limit is read
+        let input = format!("A1 LOGIN {}", "a".repeat(super::COMMAND_LIMIT));
+
+        let mock = tokio_test::io::Builder::new()
+            .read(&input.as_bytes()[..=super::COMMAND_LIMIT])
+            .write(b"* BYE [TOOBIG] Command too large\r\n")
+            .build();
+
+        assert!(read_cmd(&mut BufReader::new(mock)).await.unwrap().is_none());
     }
 }
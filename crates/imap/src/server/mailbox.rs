@@ -0,0 +1,152 @@
+use std::fmt;
+
+use imap_proto::{exists, expunge, flags, flags::Flag, Uid};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Message {
+    pub uid: Uid,
+    pub flags: Vec<Flag>,
+}
+
+/// The messages of a mailbox at some point in time, ordered by UID (and
+/// thus by message sequence number).
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Snapshot {
+    pub messages: Vec<Message>,
+}
+
+/// An untagged response informing the client about a change to the
+/// selected mailbox.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Update {
+    Expunge(u32),
+    Exists(u32),
+    Flags(u32, Vec<Flag>),
+}
+
+impl fmt::Display for Update {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Update::Expunge(seq) => expunge::Response(*seq).fmt(f),
+            Update::Exists(n) => exists::Response(*n).fmt(f),
+            Update::Flags(seq, flags) => {
+                write!(f, "{seq} FETCH (")?;
+                flags::Response(flags.clone()).fmt(f)?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+fn seq(i: usize) -> u32 {
+    u32::try_from(i + 1).expect("message sequence number should fit in u32")
+}
+
+impl Snapshot {
+    fn get(&self, uid: Uid) -> Option<&Message> {
+        self.messages
+            .binary_search_by_key(&uid, |m| m.uid)
+            .ok()
+            .map(|i| &self.messages[i])
+    }
+
+    /// Bring the snapshot (as seen by the client) up to date with `current`
+    /// and return the updates that the client needs to be told about.
+    ///
+    /// Unless `expunge` is set, removed messages are kept so that message
+    /// sequence numbers don't change. They will be expunged by a later
+    /// update.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the mailbox has more than [`u32::MAX`] messages.
+    pub fn update(&mut self, current: &Snapshot, expunge: bool) -> Vec<Update> {
+        let mut updates = Vec::new();
+
+        if expunge {
+            let mut i = 0;
+            self.messages.retain(|m| {
+                let keep = current.get(m.uid).is_some();
+                if keep {
+                    i += 1;
+                } else {
+                    updates.push(Update::Expunge(seq(i)));
+                }
+                keep
+            });
+        }
+
+        for (i, message) in self.messages.iter_mut().enumerate() {
+            if let Some(Message { flags, .. }) = current.get(message.uid) {
+                if *flags != message.flags {
+                    message.flags.clone_from(flags);
+                    updates.push(Update::Flags(seq(i), message.flags.clone()));
+                }
+            }
+        }
+
+        let last = self.messages.last().map(|m| m.uid);
+        let new = current
+            .messages
+            .iter()
+            .filter(|m| last.is_none_or(|last| m.uid > last));
+        let before = self.messages.len();
+        self.messages.extend(new.cloned());
+        if self.messages.len() != before {
+            updates.push(Update::Exists(
+                u32::try_from(self.messages.len()).expect("message count should fit in u32"),
+            ));
+        }
+
+        updates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use imap_proto::{flags::Flag, Uid};
+
+    use super::{Message, Snapshot, Update};
+
+    fn snapshot(messages: &[(u32, &[Flag])]) -> Snapshot {
+        Snapshot {
+            messages: messages
+                .iter()
+                .map(|(uid, flags)| Message {
+                    uid: Uid((*uid).try_into().unwrap()),
+                    flags: flags.to_vec(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn update() {
+        let mut seen = snapshot(&[(1, &[]), (2, &[]), (3, &[]), (5, &[])]);
+        let current = snapshot(&[(1, &[]), (3, &[Flag::Seen]), (6, &[]), (7, &[])]);
+
+        // expunges are deferred
+        assert_eq!(
+            seen.update(&current, false),
+            [Update::Flags(3, vec![Flag::Seen]), Update::Exists(6)]
+        );
+        assert_eq!(seen.messages.len(), 6);
+
+        assert_eq!(
+            seen.update(&current, true),
+            [Update::Expunge(2), Update::Expunge(3)]
+        );
+        assert_eq!(seen, current);
+        assert_eq!(seen.update(&current, true), []);
+    }
+
+    #[test]
+    fn fmt() {
+        assert_eq!(Update::Expunge(3).to_string(), "3 EXPUNGE");
+        assert_eq!(Update::Exists(4).to_string(), "4 EXISTS");
+        assert_eq!(
+            Update::Flags(1, vec![Flag::Seen, Flag::Deleted]).to_string(),
+            "1 FETCH (FLAGS (\\Seen \\Deleted))"
+        );
+    }
+}
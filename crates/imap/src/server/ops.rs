@@ -12,8 +12,11 @@ pub mod select {
     use imap_proto::{
         command, exists,
         flags::{self, Flag},
-        Tag, Uid,
+        utf7, Tag, Uid,
     };
+    use tokio::sync::watch;
+
+    use crate::server::mailbox::Snapshot;
 
     use super::IntoTaggedResponse;
 
@@ -44,7 +47,11 @@ pub mod select {
     #[derive(Debug)]
     pub struct Response {
         pub flags: Vec<Flag>,
-        pub exists: u32,
+        /// The messages in the mailbox when it was selected.
+        pub snapshot: Snapshot,
+        /// Receives the current state of the mailbox whenever it changes,
+        /// so that the client can be notified.
+        pub updates: watch::Receiver<Snapshot>,
         pub uid_validity: u32,
         pub next_uid: Uid,
         pub mailbox: command::list::ListItem,
@@ -52,19 +59,27 @@ pub mod select {
     }
 
     impl IntoTaggedResponse for Response {
-        fn into_tagged_response(self, tag: Tag) -> String {
+        fn into_tagged_response(self, tag: Tag, utf8: bool) -> String {
             let Self {
                 flags,
-                exists,
+                snapshot,
+                updates: _,
                 uid_validity,
                 next_uid,
-                mailbox,
+                mut mailbox,
                 read_only,
             } = self;
 
+            if !utf8 {
+                mailbox.name = utf7::encode(&mailbox.name).into_owned();
+            }
+
             command::select::Response {
                 flags: flags::Response(flags),
-                exists: exists::Response(exists),
+                exists: exists::Response(
+                    u32::try_from(snapshot.messages.len())
+                        .expect("message count should fit in u32"),
+                ),
                 uid_validity,
                 next_uid,
                 mailbox,
@@ -77,7 +92,7 @@ pub mod select {
 }
 
 pub mod list {
-    use imap_proto::{command, response::StatusResponse, Tag};
+    use imap_proto::{command, response::StatusResponse, utf7, Tag};
 
     use super::IntoTaggedResponse;
 
@@ -96,8 +111,13 @@ pub mod list {
     }
 
     impl IntoTaggedResponse for Response {
-        fn into_tagged_response(self, tag: Tag) -> String {
-            let Self { list_items } = self;
+        fn into_tagged_response(self, tag: Tag, utf8: bool) -> String {
+            let Self { mut list_items } = self;
+            if !utf8 {
+                for item in &mut list_items {
+                    item.name = utf7::encode(&item.name).into_owned();
+                }
+            }
             let res = command::list::Response { list_items };
             let status = StatusResponse::ok("LIST completed").with_tag(tag);
             format!("{res}{status}")
@@ -105,6 +125,35 @@ pub mod list {
     }
 }
 
+pub mod status {
+    use imap_proto::{command, response::StatusResponse, utf7, Tag};
+
+    use super::IntoTaggedResponse;
+
+    #[derive(Debug)]
+    pub struct Request(pub command::Status);
+
+    impl From<command::Status> for Request {
+        fn from(command: command::Status) -> Self {
+            Self(command)
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct Response(pub command::status::Response);
+
+    impl IntoTaggedResponse for Response {
+        fn into_tagged_response(self, tag: Tag, utf8: bool) -> String {
+            let Self(mut res) = self;
+            if !utf8 {
+                res.mailbox = utf7::encode(&res.mailbox).into_owned();
+            }
+            let status = StatusResponse::ok("STATUS completed").with_tag(tag);
+            format!("* {res}\r\n{status}")
+        }
+    }
+}
+
 pub mod fetch {
     use imap_proto::Tag;
 
@@ -122,7 +171,7 @@ pub mod fetch {
     pub struct Response {}
 
     impl IntoTaggedResponse for Response {
-        fn into_tagged_response(self, tag: Tag) -> String {
+        fn into_tagged_response(self, _tag: Tag, _utf8: bool) -> String {
             todo!()
         }
     }
@@ -146,14 +195,16 @@ pub mod create {
     pub struct Response {}
 
     impl super::IntoTaggedResponse for Response {
-        fn into_tagged_response(self, tag: Tag) -> String {
+        fn into_tagged_response(self, _tag: Tag, _utf8: bool) -> String {
             todo!()
         }
     }
 }
 
 pub(crate) trait IntoTaggedResponse {
-    fn into_tagged_response(self, tag: Tag) -> String;
+    /// Format the response. Unless `utf8` is set (i.e. `UTF8=ACCEPT` has
+    /// been enabled), mailbox names are sent in modified UTF-7.
+    fn into_tagged_response(self, tag: Tag, utf8: bool) -> String;
 }
 
 pub trait IntoOperation {
@@ -178,6 +229,7 @@ into_operation!(List(command::List));
 into_operation!(Select(command::Select));
 into_operation!(Select(command::Examine)); // EXAMINE is the same as SELECT, but read-only
 into_operation!(Create(command::Create));
+into_operation!(Status(command::Status));
 
 impl IntoOperation for command::Fetch {
     type Context = SelectedState;
@@ -222,6 +274,7 @@ macro_rules! operations {
 operations! {
     Select,
     List,
+    Status,
     Fetch,
     Create,
 }
@@ -33,6 +33,19 @@ impl<T: Into<ops::Response>> Channel<T> {
     }
 }
 
+/// Commands that neither change the state of the session nor the state of
+/// the mailbox, and can run concurrently with each other.
+const fn is_concurrent(command: CommandName) -> bool {
+    matches!(
+        command,
+        CommandName::Capability
+            | CommandName::Noop
+            | CommandName::Fetch
+            | CommandName::Status
+            | CommandName::List
+    )
+}
+
 pub struct Queue {
     commands: HashMap<Tag, CommandName>,
     tx: mpsc::Sender<Payload>,
@@ -55,8 +68,27 @@ impl Queue {
         }
     }
 
-    pub fn must_wait_before(&self, _command: &CommandName) -> bool {
-        !self.commands.is_empty()
+    /// Returns `true` if there is no command in progress.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Whether the commands in progress need to complete before `command`
+    /// can be executed.
+    ///
+    /// The client may pipeline commands, but the server has to execute them
+    /// in order if there's a possible ambiguity ([Section 5.5] of RFC 9051).
+    /// Only read-only commands may run concurrently. Message sequence numbers
+    /// can only change during a command that must run by itself, so they
+    /// are stable while concurrent commands are in progress.
+    ///
+    /// [Section 5.5]: https://www.rfc-editor.org/rfc/rfc9051.html#section-5.5
+    pub fn must_wait_before(&self, command: CommandName) -> bool {
+        if self.commands.is_empty() {
+            return false;
+        }
+
+        !is_concurrent(command) || self.commands.values().any(|&c| !is_concurrent(c))
     }
 
     pub fn insert<T: Into<ops::Response>>(&mut self, tag: Tag, command: CommandName) -> Channel<T> {
@@ -71,22 +103,56 @@ impl Queue {
         }
     }
 
-    /// Returns the next [`Payload`] if one is ready.
-    pub fn ready(&mut self) -> Option<Payload> {
+    /// Returns the commands in progress.
+    pub fn in_progress(&self) -> impl Iterator<Item = CommandName> + '_ {
+        self.commands.values().copied()
+    }
+
+    fn complete(&mut self, tag: &Tag) -> CommandName {
+        self.commands.remove(tag).expect("tag should be known")
+    }
+
+    /// Returns the next [`Payload`] and the command it completes if one
+    /// is ready.
+    pub fn ready(&mut self) -> Option<(CommandName, Payload)> {
         match self.rx.try_recv() {
-            Ok((tag, res)) => {
-                assert!(self.commands.remove(&tag).is_some(), "tag should be known");
-                Some((tag, res))
-            }
+            Ok((tag, res)) => Some((self.complete(&tag), (tag, res))),
             Err(TryRecvError::Empty) => None,
             Err(TryRecvError::Disconnected) => panic!("channel should not be closed"),
         }
     }
 
-    /// Wait for the next [`Payload`].
-    pub async fn wait(&mut self) -> Payload {
+    /// Wait for the next [`Payload`] and the command it completes.
+    pub async fn wait(&mut self) -> (CommandName, Payload) {
         let (tag, res) = self.rx.recv().await.expect("channel should not be closed");
-        assert!(self.commands.remove(&tag).is_some(), "tag should be known");
-        (tag, res)
+        (self.complete(&tag), (tag, res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use imap_proto::command::CommandName;
+
+    use super::{super::ops, Channel, Queue};
+
+    fn insert(queue: &mut Queue, tag: &str, command: CommandName) {
+        let _: Channel<ops::list::Response> = queue.insert(tag.into(), command);
+    }
+
+    #[test]
+    fn must_wait_before() {
+        let mut queue = Queue::new();
+        assert!(!queue.must_wait_before(CommandName::Store));
+
+        insert(&mut queue, "A1", CommandName::Fetch);
+        insert(&mut queue, "A2", CommandName::List);
+        assert!(!queue.must_wait_before(CommandName::Status));
+        assert!(queue.must_wait_before(CommandName::Store));
+        assert!(queue.must_wait_before(CommandName::Expunge));
+        assert!(queue.must_wait_before(CommandName::Select));
+
+        let mut queue = Queue::new();
+        insert(&mut queue, "A1", CommandName::Expunge);
+        assert!(queue.must_wait_before(CommandName::Fetch));
     }
 }
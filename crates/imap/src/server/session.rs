@@ -1,8 +1,12 @@
-use std::fmt::Display;
+use std::{fmt::Display, net::SocketAddr};
 
-use auth::Identity;
+use auth::{guard::Failures, Identity, Validator};
 use imap_proto::{
-    command::{self, capability::Capabilities, Command, Request, TaggedCommand},
+    command::{
+        self,
+        capability::{Capabilities, Enabled},
+        Command, CommandName, Request, TaggedCommand,
+    },
     response::{Status, StatusResponse, TaggedStatusResponse},
     Tag,
 };
@@ -10,12 +14,16 @@ use line::{
     stream::{MaybeTls, ServerTlsStream},
     Connection,
 };
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tracing::instrument;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::watch,
+};
+use tracing::{debug, instrument};
 
 use crate::authenticate;
 
 use super::{
+    mailbox::Snapshot,
     ops::{self, IntoOperation, IntoTaggedResponse, Operation},
     queue::{self, Queue},
     read_cmd,
@@ -26,6 +34,8 @@ pub struct SelectedState {
     pub mailbox: String,
     pub read_only: bool,
     pub identity: Identity,
+    /// The mailbox as last seen by the client.
+    pub snapshot: Snapshot,
 }
 
 #[derive(Default, PartialEq, Eq)]
@@ -39,10 +49,17 @@ enum State {
 
 pub struct Session<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> {
     connection: Connection<ServerTlsStream<IO>, IO>,
+    peer: SocketAddr,
     state: State,
     queue: Queue,
     greeted: bool,
+    /// Capabilities enabled by the client using ENABLE.
+    enabled: Capabilities,
+    /// The current state of the selected mailbox.
+    mailbox_updates: Option<watch::Receiver<Snapshot>>,
     context: crate::server::Context<A>,
+    /// Failed authentication attempts on this connection.
+    auth_failures: Failures,
 }
 
 macro_rules! operation {
@@ -57,14 +74,19 @@ macro_rules! operation {
 impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
     pub fn new(
         stream: impl Into<MaybeTls<ServerTlsStream<IO>, IO>>,
+        peer: SocketAddr,
         context: crate::server::Context<A>,
     ) -> Self {
         Self {
             connection: Connection::new(stream),
+            peer,
             state: State::default(),
             queue: Queue::new(),
             greeted: false,
+            enabled: Capabilities::empty(),
+            mailbox_updates: None,
             context,
+            auth_failures: Failures::default(),
         }
     }
 
@@ -80,7 +102,10 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
         let mut capabilities = Capabilities::IMAP4rev1
             | Capabilities::IMAP4rev2
             | Capabilities::AUTH_PLAIN
-            | Capabilities::SASL_IR;
+            | Capabilities::SASL_IR
+            | Capabilities::ENABLE
+            | Capabilities::UTF8_ACCEPT
+            | Capabilities::COMPRESS_DEFLATE;
         if self.connection.is_plain() {
             capabilities |= Capabilities::LOGINDISABLED;
             if self.context.tls.is_some() {
@@ -90,21 +115,70 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
         capabilities
     }
 
+    /// Whether mailbox names are sent and received as UTF-8 instead of
+    /// modified UTF-7. `IMAP4rev2` implies `UTF8=ACCEPT`.
+    fn utf8(&self) -> bool {
+        self.enabled
+            .intersects(Capabilities::UTF8_ACCEPT | Capabilities::IMAP4rev2)
+    }
+
     async fn write_untagged(&mut self, data: impl Display) -> std::io::Result<()> {
         self.connection.write(format!("* {data}\r\n")).await
     }
 
+    /// EXPUNGE responses must not be sent while one of these commands is in
+    /// progress, since the client relies on message sequence numbers not
+    /// changing ([Section 7.5.1] of RFC 9051).
+    ///
+    /// [Section 7.5.1]: https://www.rfc-editor.org/rfc/rfc9051.html#section-7.5.1
+    const fn blocks_expunge(command: CommandName) -> bool {
+        matches!(
+            command,
+            CommandName::Fetch | CommandName::Store | CommandName::Search
+        )
+    }
+
+    fn may_expunge(&self, completing: Option<CommandName>) -> bool {
+        !completing.is_some_and(Self::blocks_expunge)
+            && !self.queue.in_progress().any(Self::blocks_expunge)
+    }
+
+    /// Tell the client about changes to the selected mailbox since it last
+    /// heard about it.
+    async fn flush_updates(&mut self, expunge: bool) -> std::io::Result<()> {
+        let (State::Selected(selected), Some(mailbox_updates)) =
+            (&mut self.state, &self.mailbox_updates)
+        else {
+            return Ok(());
+        };
+
+        let updates = selected.snapshot.update(&mailbox_updates.borrow(), expunge);
+        for update in updates {
+            self.write_untagged(update).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the tagged response completing a command, preceded by any
+    /// pending mailbox updates.
+    async fn complete(&mut self, command: Option<CommandName>, res: String) -> std::io::Result<()> {
+        self.flush_updates(self.may_expunge(command)).await?;
+        self.connection.write_flush(res).await
+    }
+
     async fn respond(&mut self, res: TaggedStatusResponse) -> std::io::Result<()> {
-        self.connection.write_flush(res.to_string()).await
+        self.complete(None, res.to_string()).await
     }
 
     async fn respond_with_tag(
         &mut self,
+        command: CommandName,
         tag: Tag,
         res: impl IntoTaggedResponse,
     ) -> std::io::Result<()> {
-        self.connection
-            .write_flush(res.into_tagged_response(tag))
+        let utf8 = self.utf8();
+        self.complete(Some(command), res.into_tagged_response(tag, utf8))
             .await
     }
 
@@ -131,6 +205,12 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
                 .await;
         }
 
+        if self.connection.is_compressed() {
+            return self
+                .respond(req.bad("TLS cannot be started after COMPRESS"))
+                .await;
+        }
+
         let tls_config = match &self.context.tls {
             Some(tls_config) => tls_config.clone(),
             None => {
@@ -138,12 +218,83 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
             }
         };
 
+        // data pipelined after STARTTLS must not be taken as sent over TLS
+        if !self.connection.stream_mut().buffer().is_empty() {
+            return self
+                .respond(req.bad("Data sent after STARTTLS before its response"))
+                .await;
+        }
+
         self.respond(req.bad("Begin TLS negotiation")).await?;
         self.connection.upgrade(&tls_config.into()).await?;
 
         Ok(())
     }
 
+    async fn handle_compress(&mut self, req: Request<command::Compress>) -> std::io::Result<()> {
+        if self.state == State::NotAuthenticated {
+            return self.respond(req.bad("not authenticated")).await;
+        }
+
+        if !req.data.mechanism.eq_ignore_ascii_case("DEFLATE") {
+            return self
+                .respond(req.bad("Unsupported compression mechanism"))
+                .await;
+        }
+
+        if self.connection.is_compressed() {
+            return self
+                .respond(req.no("[COMPRESSIONACTIVE] DEFLATE active via COMPRESS"))
+                .await;
+        }
+
+        // anything the client sent after COMPRESS would be read as
+        // compressed, but it was sent before the client saw our response
+        if !self.connection.stream_mut().buffer().is_empty() {
+            return self
+                .respond(req.bad("Data sent after COMPRESS before its response"))
+                .await;
+        }
+
+        // the tagged response is the last thing sent uncompressed
+        self.respond(req.ok("DEFLATE active")).await?;
+        self.connection.compress();
+
+        Ok(())
+    }
+
+    /// Count an authentication attempt, or refuse it if the client has
+    /// tried too often ([RFC 5530]).
+    ///
+    /// [RFC 5530]: https://datatracker.ietf.org/doc/html/rfc5530#section-3
+    fn auth_attempt(&self) -> Result<(), StatusResponse> {
+        let Some(limiter) = &self.context.limiter else {
+            return Ok(());
+        };
+        limiter.auth_attempt(self.peer.ip()).map_err(|exceeded| {
+            debug!(peer = %self.peer, ?exceeded, "authentication rate exceeded");
+            StatusResponse::no(format!(
+                "[LIMIT] Too many authentication attempts, try again in {} seconds",
+                exceeded.retry_secs()
+            ))
+        })
+    }
+
+    /// Close the connection if it has failed to authenticate too often.
+    async fn bye_if_exhausted(&mut self) -> std::io::Result<()> {
+        let Some(guard) = &self.context.guard else {
+            return Ok(());
+        };
+        if !guard.exhausted(&self.auth_failures) {
+            return Ok(());
+        }
+        self.state = State::Logout;
+        self.connection
+            .write_flush("* BYE Too many failed authentication attempts\r\n")
+            .await?;
+        self.connection.stream_mut().shutdown().await
+    }
+
     async fn auth_success(&mut self, req: Request<()>, identity: Identity) -> std::io::Result<()> {
         self.respond(req.ok("Logged in")).await?;
         self.state = State::Authenticated(identity);
@@ -159,13 +310,19 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
         }
 
         let (data, req) = req.into_parts();
-        match authenticate::authenticate(
-            self.connection.stream_mut(),
-            data,
-            self.context.auth.as_ref(),
-        )
-        .await
-        {
+        if let Err(res) = self.auth_attempt() {
+            return self.respond(res.with_tag(req.tag)).await;
+        }
+        let stream = self.connection.stream_mut();
+        let auth = self.context.auth.as_ref();
+        let result = match &self.context.guard {
+            Some(guard) => {
+                let validator = guard.validator(auth, self.peer.ip(), &self.auth_failures);
+                authenticate::authenticate(stream, data, &validator).await
+            }
+            None => authenticate::authenticate(stream, data, auth).await,
+        };
+        match result {
             Ok(identity) => {
                 self.auth_success(req, identity).await?;
             }
@@ -173,6 +330,7 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
             Err(authenticate::Error::Mechanism(mechanism)) => {
                 self.respond(StatusResponse::from(mechanism).with_tag(req.tag))
                     .await?;
+                self.bye_if_exhausted().await?;
             }
         }
         Ok(())
@@ -184,11 +342,24 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
         }
 
         let (data, req) = req.into_parts();
-        match self.context.auth.validate(&data.into()).await {
+        if let Err(res) = self.auth_attempt() {
+            return self.respond(res.with_tag(req.tag)).await;
+        }
+        let credentials = data.into();
+        let auth = self.context.auth.as_ref();
+        let result = match &self.context.guard {
+            Some(guard) => {
+                let validator = guard.validator(auth, self.peer.ip(), &self.auth_failures);
+                validator.validate(&credentials).await
+            }
+            None => auth.validate(&credentials).await,
+        };
+        match result {
             Ok(identity) => self.auth_success(req, identity).await?,
             Err(e) => {
                 self.respond(StatusResponse::from(e).with_tag(req.tag))
                     .await?;
+                self.bye_if_exhausted().await?;
             }
         }
         Ok(())
@@ -199,17 +370,32 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
             return self.respond(req.bad("No capabilities specified")).await;
         }
 
-        self.respond(req.bad("ENABLE not supported")).await
+        if self.state == State::NotAuthenticated {
+            return self.respond(req.bad("not authenticated")).await;
+        }
+
+        let (data, req) = req.into_parts();
+        let enabled = data.capabilities
+            & (Capabilities::UTF8_ACCEPT | Capabilities::IMAP4rev2)
+            & !self.enabled;
+        self.enabled |= enabled;
+
+        self.write_untagged(Enabled(enabled)).await?;
+        self.respond(req.ok("ENABLE completed")).await
     }
 
     /// Consume a ready payload from the queue.
-    async fn consume_ready(&mut self, (tag, res): queue::Payload) -> std::io::Result<()> {
+    async fn consume_ready(
+        &mut self,
+        (command, (tag, res)): (CommandName, queue::Payload),
+    ) -> std::io::Result<()> {
         use ops::Response;
 
         match res {
             Ok(Response::Select(res)) => {
                 let identity = match &self.state {
-                    State::Authenticated(identity) => identity.clone(),
+                    State::Authenticated(identity)
+                    | State::Selected(SelectedState { identity, .. }) => identity.clone(),
                     _ => unreachable!(),
                 };
 
@@ -217,21 +403,27 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
                     mailbox: res.mailbox.name.clone(),
                     read_only: res.read_only,
                     identity,
+                    snapshot: res.snapshot.clone(),
                 });
+                self.mailbox_updates = Some(res.updates.clone());
 
-                self.respond_with_tag(tag, res).await?;
+                self.respond_with_tag(command, tag, res).await?;
             }
             Ok(Response::List(res)) => {
-                self.respond_with_tag(tag, res).await?;
+                self.respond_with_tag(command, tag, res).await?;
+            }
+            Ok(Response::Status(res)) => {
+                self.respond_with_tag(command, tag, res).await?;
             }
             Ok(Response::Fetch(res)) => {
-                self.respond_with_tag(tag, res).await?;
+                self.respond_with_tag(command, tag, res).await?;
             }
             Ok(Response::Create(res)) => {
-                self.respond_with_tag(tag, res).await?;
+                self.respond_with_tag(command, tag, res).await?;
             }
             Err(err) => {
-                self.respond(err.with_tag(tag)).await?;
+                self.complete(Some(command), err.with_tag(tag).to_string())
+                    .await?;
             }
         }
 
@@ -243,11 +435,25 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
             self.consume_ready(payload).await?;
         }
 
+        // write the responses of commands in progress as they complete,
+        // until the client sends something
+        loop {
+            tokio::select! {
+                payload = self.queue.wait(), if !self.queue.is_empty() => {
+                    self.consume_ready(payload).await?;
+                }
+                res = self.connection.stream_mut().fill_buf() => {
+                    res?;
+                    break;
+                }
+            }
+        }
+
         let tagged = read_cmd(self.connection.stream_mut())
             .await?
             .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
 
-        while self.queue.must_wait_before(&tagged.command.name()) {
+        while self.queue.must_wait_before(tagged.command.name()) {
             let payload = self.queue.wait().await;
             self.consume_ready(payload).await?;
         }
@@ -273,7 +479,13 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
                 return Ok(None);
             }
 
-            let TaggedCommand { tag, command } = self.next_cmd().await?;
+            let TaggedCommand { tag, mut command } = self.next_cmd().await?;
+
+            if !self.utf8() && command.decode_mailboxes().is_err() {
+                self.respond(Request::from(tag).bad("Invalid modified UTF-7 mailbox name"))
+                    .await?;
+                continue;
+            }
 
             match command {
                 Command::Capability => self.handle_capability(tag.into()).await?,
@@ -286,19 +498,25 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
                 }
                 Command::Login(login) => self.handle_login(Request::new(tag, login)).await?,
                 Command::Enable(enable) => self.handle_enable(Request::new(tag, enable)).await?,
+                Command::Compress(compress) => {
+                    self.handle_compress(Request::new(tag, compress)).await?;
+                }
                 Command::Select(select) => {
-                    let identity = match &self.state {
-                        State::NotAuthenticated => {
-                            self.respond(Request::from(tag).bad("not authenticated"))
-                                .await?;
-                            continue;
-                        }
-                        State::Authenticated(identity) => todo!(),
-                        State::Selected(SelectedState { identity, .. }) => identity,
-                        State::Logout => unreachable!(),
-                    };
+                    if self.state == State::NotAuthenticated {
+                        self.respond(Request::from(tag).bad("not authenticated"))
+                            .await?;
+                        continue;
+                    }
+                    operation!(select, &mut self.queue, tag)
+                }
+                Command::Examine(examine) => {
+                    if self.state == State::NotAuthenticated {
+                        self.respond(Request::from(tag).bad("not authenticated"))
+                            .await?;
+                        continue;
+                    }
+                    operation!(examine, &mut self.queue, tag)
                 }
-                Command::Examine(examine) => operation!(examine, &mut self.queue, tag),
                 Command::Create(_) => todo!(),
                 Command::Delete(_) => todo!(),
                 Command::Rename(_) => todo!(),
@@ -306,13 +524,23 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
                 Command::Unsubscribe(_) => todo!(),
                 Command::List(list) => operation!(list, &mut self.queue, tag),
                 Command::Namespace => todo!(),
-                Command::Status(_) => todo!(),
+                Command::Status(status) => {
+                    if self.state == State::NotAuthenticated {
+                        self.respond(Request::from(tag).bad("not authenticated"))
+                            .await?;
+                        continue;
+                    }
+                    operation!(status, &mut self.queue, tag)
+                }
                 Command::Append => todo!(),
                 Command::Idle => todo!(),
                 Command::Close => todo!(),
                 Command::Unselect => todo!(),
                 Command::Expunge(_) => todo!(),
-                Command::Search { is_uid } => todo!(),
+                Command::Search { .. } => {
+                    self.respond(Request::from(tag).no("SEARCH is not supported yet"))
+                        .await?;
+                }
                 Command::Fetch(fetch) => match &self.state {
                     State::Selected(selected) => {
                         operation!(fetch, &mut self.queue, tag, selected.clone())
@@ -322,10 +550,189 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
                             .await?;
                     }
                 },
-                Command::Store { is_uid } => todo!(),
-                Command::Copy { is_uid } => todo!(),
-                Command::Move { is_uid } => todo!(),
+                Command::Store { .. } => todo!(),
+                Command::Copy { .. } => todo!(),
+                Command::Move { .. } => todo!(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, num::NonZeroU32, sync::Arc};
+
+    use imap_proto::{
+        command::list::{Attributes, ListItem},
+        flags::Flag,
+        Uid,
+    };
+    use line::compress::Deflate;
+    use tokio::{
+        io::{duplex, AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+        sync::watch,
+    };
+
+    use super::Session;
+    use crate::server::{
+        mailbox::{Message, Snapshot},
+        ops::{select, Operation},
+        Context,
+    };
+
+    struct AcceptAll;
+
+    #[async_trait::async_trait]
+    impl auth::Validator for AcceptAll {
+        async fn validate(
+            &self,
+            credentials: &auth::Credentials,
+        ) -> Result<auth::Identity, auth::ValidationError> {
+            Ok(auth::Identity(credentials.username.clone()))
+        }
+    }
+
+    fn snapshot(messages: &[(u32, &[Flag])]) -> Snapshot {
+        Snapshot {
+            messages: messages
+                .iter()
+                .map(|&(uid, flags)| Message {
+                    uid: Uid(NonZeroU32::new(uid).unwrap()),
+                    flags: flags.to_vec(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Run a session on one end of a pipe and return the other end. SELECT
+    /// opens a mailbox whose contents are published on `mailbox`.
+    async fn connect(mailbox: watch::Receiver<Snapshot>) -> BufReader<tokio::io::DuplexStream> {
+        let (client, server) = duplex(64 * 1024);
+        let context = Context {
+            tls: None,
+            auth: Arc::new(AcceptAll),
+            limiter: None,
+            guard: None,
+        };
+        let mut session = Session::new(server, SocketAddr::from(([127, 0, 0, 1], 143)), context);
+
+        tokio::spawn(async move {
+            while let Ok(Some(op)) = session.next_op().await {
+                let Operation::Select(req, channel) = op else {
+                    unreachable!("only SELECT is used");
+                };
+                let snapshot = mailbox.borrow().clone();
+                let res = select::Response {
+                    flags: vec![],
+                    next_uid: Uid(NonZeroU32::new(100).unwrap()),
+                    snapshot,
+                    updates: mailbox.clone(),
+                    uid_validity: 1,
+                    mailbox: ListItem {
+                        name: req.mailbox,
+                        attributes: Attributes::empty(),
+                        hierarchy_delimiter: None,
+                    },
+                    read_only: req.read_only,
+                };
+                channel.send(Ok(res)).await.unwrap();
+            }
+        });
+
+        let mut client = BufReader::new(client);
+        let mut greeting = String::new();
+        client.read_line(&mut greeting).await.unwrap();
+        assert!(greeting.starts_with("* OK"), "{greeting}");
+        client
+    }
+
+    /// Send `input` and return the response lines up to and including the
+    /// tagged one for `tag`.
+    async fn send<S: AsyncBufRead + AsyncWrite + Unpin>(
+        client: &mut S,
+        input: &str,
+        tag: &str,
+    ) -> Vec<String> {
+        client.write_all(input.as_bytes()).await.unwrap();
+        client.flush().await.unwrap();
+
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            assert!(!line.is_empty(), "connection closed");
+            let done = line.starts_with(&format!("{tag} "));
+            lines.push(line.trim_end().to_owned());
+            if done {
+                return lines;
             }
         }
     }
+
+    async fn login<S: AsyncBufRead + AsyncWrite + Unpin>(client: &mut S) {
+        let res = send(client, "A1 LOGIN user pass\r\n", "A1").await;
+        assert_eq!(res, ["A1 OK Logged in"]);
+    }
+
+    #[tokio::test]
+    async fn mailbox_updates() {
+        let (tx, rx) = watch::channel(snapshot(&[(1, &[]), (2, &[])]));
+        let mut client = connect(rx).await;
+        login(&mut client).await;
+
+        let res = send(&mut client, "A2 SELECT INBOX\r\n", "A2").await;
+        assert!(res.contains(&"* 2 EXISTS".to_owned()), "{res:?}");
+
+        tx.send_replace(snapshot(&[(1, &[]), (2, &[]), (3, &[])]));
+        let res = send(&mut client, "A3 NOOP\r\n", "A3").await;
+        assert_eq!(res, ["* 3 EXISTS", "A3 OK NOOP completed"]);
+
+        tx.send_replace(snapshot(&[(1, &[Flag::Seen]), (3, &[])]));
+        let res = send(&mut client, "A4 NOOP\r\n", "A4").await;
+        assert_eq!(
+            res,
+            [
+                "* 2 EXPUNGE",
+                "* 1 FETCH (FLAGS (\\Seen))",
+                "A4 OK NOOP completed"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn compress() {
+        let (_tx, rx) = watch::channel(Snapshot::default());
+        let mut client = connect(rx).await;
+        login(&mut client).await;
+
+        let res = send(&mut client, "A2 COMPRESS DEFLATE\r\n", "A2").await;
+        assert_eq!(res, ["A2 OK DEFLATE active"]);
+
+        let mut client = BufReader::new(Deflate::new(client.into_inner()));
+        let res = send(&mut client, "A3 NOOP\r\n", "A3").await;
+        assert_eq!(res, ["A3 OK NOOP completed"]);
+
+        let res = send(&mut client, "A4 COMPRESS DEFLATE\r\n", "A4").await;
+        assert_eq!(
+            res,
+            ["A4 NO [COMPRESSIONACTIVE] DEFLATE active via COMPRESS"]
+        );
+    }
+
+    #[tokio::test]
+    async fn compress_with_pipelined_data() {
+        let (_tx, rx) = watch::channel(Snapshot::default());
+        let mut client = connect(rx).await;
+        login(&mut client).await;
+
+        // the NOOP is sent before the client knows whether COMPRESS succeeded
+        let res = send(&mut client, "A2 COMPRESS DEFLATE\r\nA3 NOOP\r\n", "A3").await;
+        assert_eq!(
+            res,
+            [
+                "A2 BAD Data sent after COMPRESS before its response",
+                "A3 OK NOOP completed"
+            ]
+        );
+    }
 }
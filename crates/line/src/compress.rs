@@ -0,0 +1,296 @@
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const BUF_SIZE: usize = 8 * 1024;
+
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
+/// A stream compressed with raw DEFLATE ([RFC 1951]) in both directions,
+/// as used by IMAP `COMPRESS=DEFLATE` ([RFC 4978]).
+///
+/// Every flush ends the current DEFLATE block with a sync flush, so that
+/// the peer can decompress everything written so far.
+///
+/// [RFC 1951]: https://www.rfc-editor.org/rfc/rfc1951
+/// [RFC 4978]: https://www.rfc-editor.org/rfc/rfc4978
+pub struct Deflate<S> {
+    inner: S,
+    compress: Compress,
+    decompress: Decompress,
+    /// Compressed data read from `inner` that has not been decompressed yet.
+    read_buf: Box<[u8]>,
+    read_pos: usize,
+    read_len: usize,
+    /// Compressed data that has not been written to `inner` yet.
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    /// Whether data has been compressed since the last sync flush.
+    needs_sync: bool,
+}
+
+impl<S> Deflate<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            read_buf: vec![0; BUF_SIZE].into_boxed_slice(),
+            read_pos: 0,
+            read_len: 0,
+            write_buf: Vec::with_capacity(BUF_SIZE),
+            write_pos: 0,
+            needs_sync: false,
+        }
+    }
+
+    pub const fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Compress `input` into the write buffer.
+    fn compress(&mut self, mut input: &[u8], flush: FlushCompress) -> std::io::Result<()> {
+        loop {
+            self.write_buf.reserve(BUF_SIZE);
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(input, &mut self.write_buf, flush)
+                .map_err(invalid_data)?;
+            let consumed = usize::try_from(self.compress.total_in() - before)
+                .expect("consumed input should fit in usize");
+            input = &input[consumed..];
+
+            // the output buffer not being full means that everything
+            // (including the flush) has been written
+            if input.is_empty() && self.write_buf.len() < self.write_buf.capacity() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Deflate<S> {
+    /// Write the write buffer to `inner`.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Deflate<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+
+        loop {
+            if this.read_pos == this.read_len {
+                let mut read_buf = ReadBuf::new(&mut this.read_buf);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+                this.read_pos = 0;
+                this.read_len = read_buf.filled().len();
+                if this.read_len == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            let (before_in, before_out) = (this.decompress.total_in(), this.decompress.total_out());
+            let status = this
+                .decompress
+                .decompress(
+                    &this.read_buf[this.read_pos..this.read_len],
+                    buf.initialize_unfilled(),
+                    FlushDecompress::None,
+                )
+                .map_err(invalid_data)?;
+            let consumed = usize::try_from(this.decompress.total_in() - before_in)
+                .expect("consumed input should fit in usize");
+            let produced = usize::try_from(this.decompress.total_out() - before_out)
+                .expect("produced output should fit in usize");
+            this.read_pos += consumed;
+            buf.advance(produced);
+
+            if produced > 0 || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if status == Status::StreamEnd {
+                return Poll::Ready(Err(invalid_data("unexpected end of DEFLATE stream")));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deflate<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        ready!(self.poll_drain(cx))?;
+        self.compress(buf, FlushCompress::None)?;
+        self.needs_sync = true;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.needs_sync {
+            self.compress(&[], FlushCompress::Sync)?;
+            self.needs_sync = false;
+        }
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+enum Inner<S> {
+    Plain(S),
+    Deflate(Deflate<S>),
+    Empty,
+}
+
+/// A stream that may or may not be compressed.
+///
+/// Compression can be started mid-session, like TLS with
+/// [`MaybeTls`](crate::stream::MaybeTls).
+pub struct MaybeCompressed<S> {
+    inner: Inner<S>,
+}
+
+impl<S> MaybeCompressed<S> {
+    pub const fn new(inner: S) -> Self {
+        Self {
+            inner: Inner::Plain(inner),
+        }
+    }
+
+    pub const fn is_compressed(&self) -> bool {
+        matches!(self.inner, Inner::Deflate(_))
+    }
+
+    /// Start compressing the stream.
+    ///
+    /// If the stream is already compressed, this is a no-op.
+    pub fn compress(&mut self) {
+        self.inner = match std::mem::replace(&mut self.inner, Inner::Empty) {
+            Inner::Plain(inner) => Inner::Deflate(Deflate::new(inner)),
+            inner @ Inner::Deflate(_) => inner,
+            Inner::Empty => unreachable!(),
+        };
+    }
+
+    pub const fn get_ref(&self) -> &S {
+        match &self.inner {
+            Inner::Plain(inner) => inner,
+            Inner::Deflate(deflate) => deflate.get_ref(),
+            Inner::Empty => unreachable!(),
+        }
+    }
+
+    /// Returns the underlying stream, unless it is compressed.
+    pub fn get_uncompressed_mut(&mut self) -> Option<&mut S> {
+        match &mut self.inner {
+            Inner::Plain(inner) => Some(inner),
+            Inner::Deflate(_) => None,
+            Inner::Empty => unreachable!(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeCompressed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            Inner::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Inner::Deflate(stream) => Pin::new(stream).poll_read(cx, buf),
+            Inner::Empty => unreachable!(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeCompressed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut self.inner {
+            Inner::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Inner::Deflate(stream) => Pin::new(stream).poll_write(cx, buf),
+            Inner::Empty => unreachable!(),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            Inner::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Inner::Deflate(stream) => Pin::new(stream).poll_flush(cx),
+            Inner::Empty => unreachable!(),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.inner {
+            Inner::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Inner::Deflate(stream) => Pin::new(stream).poll_shutdown(cx),
+            Inner::Empty => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::Deflate;
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let (a, b) = duplex(64);
+        let (mut a, mut b) = (Deflate::new(a), Deflate::new(b));
+
+        let line = b"* 1 FETCH (FLAGS (\\Seen))\r\n".repeat(100);
+        let (_, received) = tokio::join!(
+            async {
+                a.write_all(&line).await.unwrap();
+                a.flush().await.unwrap();
+                a.write_all(b"A1 OK done\r\n").await.unwrap();
+                a.flush().await.unwrap();
+            },
+            async {
+                let mut received = vec![0; line.len() + 12];
+                b.read_exact(&mut received).await.unwrap();
+                received
+            }
+        );
+
+        assert_eq!(&received[..line.len()], line);
+        assert_eq!(&received[line.len()..], b"A1 OK done\r\n");
+    }
+}
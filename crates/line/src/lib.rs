@@ -1,5 +1,7 @@
+pub mod compress;
 pub mod stream;
 
+use compress::MaybeCompressed;
 use stream::{MaybeTls, Tls};
 use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tracing::debug;
@@ -55,17 +57,17 @@ pub async fn read_line<R: AsyncBufRead + Unpin>(
 }
 
 pub struct Connection<T: Tls<IO>, IO: AsyncRead + AsyncWrite + Unpin> {
-    stream: BufReader<MaybeTls<T, IO>>,
+    stream: BufReader<MaybeCompressed<MaybeTls<T, IO>>>,
 }
 
 impl<T: Tls<IO>, IO: AsyncRead + AsyncWrite + Unpin> Connection<T, IO> {
     pub fn new(stream: impl Into<MaybeTls<T, IO>>) -> Self {
         Self {
-            stream: BufReader::new(stream.into()),
+            stream: BufReader::new(MaybeCompressed::new(stream.into())),
         }
     }
 
-    pub fn stream_mut(&mut self) -> &mut BufReader<MaybeTls<T, IO>> {
+    pub fn stream_mut(&mut self) -> &mut BufReader<MaybeCompressed<MaybeTls<T, IO>>> {
         &mut self.stream
     }
 
@@ -79,14 +81,34 @@ impl<T: Tls<IO>, IO: AsyncRead + AsyncWrite + Unpin> Connection<T, IO> {
 
     pub async fn upgrade(&mut self, tls_config: T::Config<'_>) -> std::io::Result<()> {
         assert!(self.stream.buffer().is_empty(), "buffer must be empty");
-        self.stream.get_mut().upgrade(tls_config).await
+        self.stream
+            .get_mut()
+            .get_uncompressed_mut()
+            .expect("TLS cannot be started after compression")
+            .upgrade(tls_config)
+            .await
+    }
+
+    /// Start compressing the connection with DEFLATE. Stacks on top of TLS.
+    pub fn compress(&mut self) {
+        assert!(self.stream.buffer().is_empty(), "buffer must be empty");
+        self.stream.get_mut().compress();
     }
 
     pub fn is_plain(&self) -> bool {
-        self.stream.get_ref().is_plain()
+        self.stream.get_ref().get_ref().is_plain()
     }
 
     pub fn is_tls(&self) -> bool {
-        self.stream.get_ref().is_tls()
+        self.stream.get_ref().get_ref().is_tls()
+    }
+
+    /// The TLS stream, e.g. to inspect the negotiated parameters.
+    pub fn tls(&self) -> Option<&T> {
+        self.stream.get_ref().get_ref().as_tls()
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.stream.get_ref().is_compressed()
     }
 }
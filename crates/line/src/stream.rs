@@ -77,6 +77,14 @@ impl<T: Tls<IO>, IO> MaybeTls<T, IO> {
     pub const fn is_tls(&self) -> bool {
         matches!(self.inner, Inner::Tls(_))
     }
+
+    /// The TLS stream, if the stream is encrypted.
+    pub const fn as_tls(&self) -> Option<&T> {
+        match &self.inner {
+            Inner::Tls(tls) => Some(tls),
+            _ => None,
+        }
+    }
 }
 
 async fn upgrade<T: Tls<IO>, IO>(
@@ -0,0 +1,545 @@
+//! SMTP client for relaying messages and testing.
+//!
+//! ```no_run
+//! # use std::str::FromStr;
+//! # use email_address::EmailAddress;
+//! # use smtp::{client::Client, command::params::{MailParams, RcptParams}, message::Envelope};
+//! # tokio_test::block_on(async {
+//! let stream = tokio::net::TcpStream::connect("mx.example.com:25").await?;
+//! let mut client = Client::connect(stream, "mail.example.org").await?;
+//!
+//! let mut envelope = Envelope::new(
+//!     Some(EmailAddress::from_str("alice@example.org")?),
+//!     MailParams::default(),
+//! );
+//! envelope.add_recipient(EmailAddress::from_str("bob@example.com")?, RcptParams::default());
+//!
+//! let delivery = client.send(&envelope, b"Subject: hi\r\n\r\nhello\r\n").await?;
+//! for reply in delivery.replies() {
+//!     println!("{reply}");
+//! }
+//! client.quit().await?;
+//! # anyhow::Ok(())
+//! # });
+//! ```
+
+use auth::Credentials;
+use base64::Engine;
+use line::{
+    stream::{ClientTlsStream, MaybeTls, Tls},
+    Connection,
+};
+use secrecy::ExposeSecret;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, instrument};
+
+use crate::{
+    command::params::{Body, MailParams, RcptParams},
+    ehlo::{self, Extensions},
+    message::Envelope,
+    reply::{ParseError, Reply},
+};
+
+const BASE64: base64::engine::GeneralPurpose = base64::engine::general_purpose::STANDARD;
+
+/// The size of the chunks sent with `BDAT`.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("malformed reply")]
+    Syntax,
+    /// The server replied with an error, or with something else than
+    /// expected.
+    #[error("unexpected reply {}", .0.code)]
+    Reply(Reply),
+    #[error("{0} is not supported by the server")]
+    Unsupported(&'static str),
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::Io(e) => Self::Io(e),
+            ParseError::Syntax => Self::Syntax,
+        }
+    }
+}
+
+/// The outcome of sending a message.
+#[derive(Debug)]
+pub struct Delivery {
+    /// The replies to `RCPT`, in the order of the envelope's recipients.
+    pub recipients: Vec<Reply>,
+    /// The reply to the message data, or `None` if no recipient was
+    /// accepted.
+    pub data: Option<Reply>,
+}
+
+impl Delivery {
+    /// The final reply for each recipient: the reply to `RCPT` if it was
+    /// rejected, otherwise the reply to the message data.
+    pub fn replies(&self) -> impl Iterator<Item = &Reply> {
+        self.recipients.iter().map(|rcpt| match &self.data {
+            Some(data) if rcpt.is_positive() => data,
+            _ => rcpt,
+        })
+    }
+}
+
+/// SMTP session with a server.
+pub struct Client<IO: AsyncRead + AsyncWrite + Unpin> {
+    connection: Connection<ClientTlsStream<IO>, IO>,
+    /// The domain sent with EHLO.
+    hostname: String,
+    ehlo: ehlo::Response,
+}
+
+/// Escape lines starting with a dot and terminate the data with
+/// `<CRLF>.<CRLF>` ([Section 4.5.2] of RFC 5321).
+///
+/// [Section 4.5.2]: https://datatracker.ietf.org/doc/html/rfc5321#section-4.5.2
+fn dot_stuff(message: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(message.len() + 5);
+    let mut line_start = true;
+
+    for &b in message {
+        if line_start && b == b'.' {
+            out.push(b'.');
+        }
+        out.push(b);
+        line_start = b == b'\n';
+    }
+
+    if !out.is_empty() && !out.ends_with(b"\r\n") {
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b".\r\n");
+    out
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Client<IO> {
+    /// Read the greeting and introduce ourselves as `hostname`. Falls back
+    /// to HELO if the server doesn't support EHLO.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Reply`] if the server doesn't accept the
+    /// connection.
+    pub async fn connect(
+        stream: impl Into<MaybeTls<ClientTlsStream<IO>, IO>>,
+        hostname: impl Into<String>,
+    ) -> Result<Self, Error> {
+        let mut client = Self {
+            connection: Connection::new(stream),
+            hostname: hostname.into(),
+            ehlo: ehlo::Response {
+                domain: String::new(),
+                extensions: Extensions::empty(),
+                size: None,
+                auth: ehlo::Auth::empty(),
+            },
+        };
+
+        let greeting = client.read_reply().await?;
+        if greeting.code != 220 {
+            return Err(Error::Reply(greeting));
+        }
+        client.ehlo().await?;
+
+        Ok(client)
+    }
+
+    /// What the server advertised in its reply to EHLO.
+    pub fn extensions(&self) -> &ehlo::Response {
+        &self.ehlo
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.connection.is_tls()
+    }
+
+    async fn ehlo(&mut self) -> Result<(), Error> {
+        // replies to EHLO don't have enhanced status codes
+        self.ehlo.extensions = Extensions::empty();
+        let reply = self.command(format!("EHLO {}\r\n", self.hostname)).await?;
+        if reply.code == 250 {
+            self.ehlo = ehlo::Response::from_reply(&reply)?;
+            return Ok(());
+        }
+
+        debug!(?reply, "EHLO rejected, trying HELO");
+        let reply = self.command(format!("HELO {}\r\n", self.hostname)).await?;
+        if reply.code != 250 {
+            return Err(Error::Reply(reply));
+        }
+        self.ehlo = ehlo::Response {
+            domain: reply.lines[0]
+                .split(' ')
+                .next()
+                .unwrap_or_default()
+                .to_owned(),
+            extensions: Extensions::empty(),
+            size: None,
+            auth: ehlo::Auth::empty(),
+        };
+        Ok(())
+    }
+
+    async fn read_reply(&mut self) -> Result<Reply, Error> {
+        let enhanced = self
+            .ehlo
+            .extensions
+            .contains(Extensions::ENHANCEDSTATUSCODES);
+        Ok(Reply::read(self.connection.stream_mut(), enhanced).await?)
+    }
+
+    /// Send a command, or a batch of pipelined commands, and read the
+    /// first reply.
+    async fn command(&mut self, command: impl AsRef<[u8]>) -> Result<Reply, Error> {
+        self.connection.write_flush(command).await?;
+        self.read_reply().await
+    }
+
+    /// Like [`Client::command`], but fails unless the reply has the given
+    /// code.
+    async fn expect(&mut self, command: impl AsRef<[u8]>, code: u16) -> Result<Reply, Error> {
+        let reply = self.command(command).await?;
+        if reply.code == code {
+            Ok(reply)
+        } else {
+            Err(Error::Reply(reply))
+        }
+    }
+
+    /// Upgrade the connection with STARTTLS
+    /// ([RFC 3207](https://datatracker.ietf.org/doc/html/rfc3207)) and
+    /// repeat EHLO.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Unsupported`] if the server didn't advertise
+    /// STARTTLS, and [`Error::Io`] if the handshake fails.
+    pub async fn starttls(
+        &mut self,
+        config: <ClientTlsStream<IO> as Tls<IO>>::Config<'_>,
+    ) -> Result<(), Error> {
+        if !self.ehlo.extensions.contains(Extensions::STARTTLS) {
+            return Err(Error::Unsupported("STARTTLS"));
+        }
+
+        self.expect("STARTTLS\r\n", 220).await?;
+        self.connection.upgrade(config).await?;
+
+        // the server forgets everything it knew about us
+        self.ehlo().await
+    }
+
+    /// Authenticate with AUTH PLAIN, or AUTH LOGIN if that's all the
+    /// server supports ([RFC 4954](https://datatracker.ietf.org/doc/html/rfc4954)).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Unsupported`] if the server supports neither
+    /// mechanism, and [`Error::Reply`] if the credentials were rejected.
+    #[instrument(skip_all, fields(username = credentials.username))]
+    pub async fn auth(&mut self, credentials: &Credentials) -> Result<(), Error> {
+        let username = credentials.username.as_bytes();
+        let password = credentials.password.expose_secret().as_bytes();
+
+        if self.ehlo.auth.contains(ehlo::Auth::PLAIN) {
+            let response = [b"\0", username, b"\0", password].concat();
+            self.expect(format!("AUTH PLAIN {}\r\n", BASE64.encode(response)), 235)
+                .await?;
+        } else if self.ehlo.auth.contains(ehlo::Auth::LOGIN) {
+            self.expect("AUTH LOGIN\r\n", 334).await?;
+            self.expect(format!("{}\r\n", BASE64.encode(username)), 334)
+                .await?;
+            self.expect(format!("{}\r\n", BASE64.encode(password)), 235)
+                .await?;
+        } else {
+            return Err(Error::Unsupported("AUTH"));
+        }
+
+        Ok(())
+    }
+
+    /// The parameters of MAIL that the server supports.
+    fn mail_params(&self, params: &MailParams, size: usize) -> MailParams {
+        let extensions = self.ehlo.extensions;
+        let dsn = extensions.contains(Extensions::DSN);
+
+        MailParams {
+            size: self.ehlo.size.map(|_| size as u64),
+            body: if extensions.contains(Extensions::_8BITMIME) {
+                params.body
+            } else {
+                Body::SevenBit
+            },
+            smtputf8: params.smtputf8 && extensions.contains(Extensions::SMTPUTF8),
+            ret: params.ret.filter(|_| dsn),
+            envid: params.envid.clone().filter(|_| dsn),
+        }
+    }
+
+    /// The parameters of RCPT that the server supports.
+    fn rcpt_params(&self, params: &RcptParams) -> RcptParams {
+        if self.ehlo.extensions.contains(Extensions::DSN) {
+            params.clone()
+        } else {
+            RcptParams::default()
+        }
+    }
+
+    /// Send a message. MAIL and RCPT are pipelined if the server supports
+    /// it, and the message is sent with BDAT if the server supports
+    /// CHUNKING, otherwise with DATA.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Reply`] if MAIL is rejected. Rejected recipients
+    /// and messages are reported in the returned [`Delivery`].
+    #[instrument(skip_all, fields(from = ?envelope.from))]
+    pub async fn send(&mut self, envelope: &Envelope, message: &[u8]) -> Result<Delivery, Error> {
+        let params = self.mail_params(&envelope.params, message.len());
+        let from = envelope
+            .from
+            .as_ref()
+            .map_or_else(String::new, ToString::to_string);
+        let mail = format!("MAIL FROM:<{from}>{params}\r\n");
+        let rcpts = envelope.recipients.iter().map(|recipient| {
+            let params = self.rcpt_params(&recipient.params);
+            format!("RCPT TO:<{}>{params}\r\n", recipient.address)
+        });
+
+        let mut recipients = Vec::with_capacity(envelope.recipients.len());
+        if self.ehlo.extensions.contains(Extensions::PIPELINING) {
+            let batch: String = std::iter::once(mail).chain(rcpts).collect();
+            let mail = self.command(batch).await?;
+            for _ in &envelope.recipients {
+                recipients.push(self.read_reply().await?);
+            }
+            if !mail.is_positive() {
+                return Err(Error::Reply(mail));
+            }
+        } else {
+            let rcpts: Vec<_> = rcpts.collect();
+            let mail = self.command(mail).await?;
+            if !mail.is_positive() {
+                return Err(Error::Reply(mail));
+            }
+            for rcpt in rcpts {
+                recipients.push(self.command(rcpt).await?);
+            }
+        }
+
+        if !recipients.iter().any(Reply::is_positive) {
+            debug!("no recipients accepted");
+            self.rset().await?;
+            return Ok(Delivery {
+                recipients,
+                data: None,
+            });
+        }
+
+        let data = if self.ehlo.extensions.contains(Extensions::CHUNKING) {
+            self.bdat(message).await?
+        } else {
+            self.data(message).await?
+        };
+
+        Ok(Delivery {
+            recipients,
+            data: Some(data),
+        })
+    }
+
+    async fn data(&mut self, message: &[u8]) -> Result<Reply, Error> {
+        let reply = self.command("DATA\r\n").await?;
+        if reply.code != 354 {
+            return Ok(reply);
+        }
+        self.command(dot_stuff(message)).await
+    }
+
+    async fn bdat(&mut self, message: &[u8]) -> Result<Reply, Error> {
+        let count = message.len().div_ceil(CHUNK_SIZE).max(1);
+
+        for (i, chunk) in message
+            .chunks(CHUNK_SIZE)
+            .chain([&[][..]])
+            .take(count)
+            .enumerate()
+        {
+            let last = i + 1 == count;
+            let header = if last {
+                format!("BDAT {} LAST\r\n", chunk.len())
+            } else {
+                format!("BDAT {}\r\n", chunk.len())
+            };
+
+            self.connection.write(header).await?;
+            let reply = self.command(chunk).await?;
+            if last {
+                return Ok(reply);
+            }
+            if !reply.is_positive() {
+                self.rset().await?;
+                return Ok(reply);
+            }
+        }
+
+        unreachable!("the last chunk returns")
+    }
+
+    /// Abort the current mail transaction.
+    async fn rset(&mut self) -> Result<(), Error> {
+        self.expect("RSET\r\n", 250).await?;
+        Ok(())
+    }
+
+    /// End the session and close the connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server doesn't reply with 221.
+    pub async fn quit(mut self) -> Result<(), Error> {
+        self.expect("QUIT\r\n", 221).await?;
+        self.connection.stream_mut().shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use email_address::EmailAddress;
+    use secrecy::SecretString;
+    use tokio::io::AsyncReadExt;
+
+    use super::{dot_stuff, Client};
+    use crate::{
+        command::params::{MailParams, RcptParams},
+        message::Envelope,
+        reply::{EnhancedCode, Reply},
+        server::{policy::AcceptAll, Context, Profile, Server},
+    };
+
+    struct Validator;
+
+    #[async_trait::async_trait]
+    impl auth::Validator for Validator {
+        async fn validate(
+            &self,
+            _credentials: &auth::Credentials,
+        ) -> Result<auth::Identity, auth::ValidationError> {
+            Err(auth::ValidationError::InvalidCredentials)
+        }
+    }
+
+    fn envelope() -> Envelope {
+        let mut envelope = Envelope::new(
+            Some(EmailAddress::from_str("alice@example.org").unwrap()),
+            MailParams::default(),
+        );
+        for to in ["bob@example.com", "carol@example.com"] {
+            envelope.add_recipient(EmailAddress::from_str(to).unwrap(), RcptParams::default());
+        }
+        envelope
+    }
+
+    #[test]
+    fn dot_stuffing() {
+        assert_eq!(dot_stuff(b""), b".\r\n");
+        assert_eq!(dot_stuff(b"a\r\n"), b"a\r\n.\r\n");
+        assert_eq!(dot_stuff(b".a\r\nb.\r\n."), b"..a\r\nb.\r\n..\r\n.\r\n");
+    }
+
+    #[tokio::test]
+    async fn data() {
+        let stream = tokio_test::io::Builder::new()
+            .read(b"220 mx.example.com\r\n")
+            .write(b"EHLO mail.example.org\r\n")
+            .read(b"250-mx.example.com\r\n250-ENHANCEDSTATUSCODES\r\n250 AUTH LOGIN\r\n")
+            .write(b"AUTH LOGIN\r\n")
+            .read(b"334 VXNlcm5hbWU6\r\n")
+            .write(b"YWxpY2U=\r\n")
+            .read(b"334 UGFzc3dvcmQ6\r\n")
+            .write(b"c2VjcmV0\r\n")
+            .read(b"235 2.7.0 ok\r\n")
+            .write(b"MAIL FROM:<alice@example.org>\r\n")
+            .read(b"250 2.1.0 ok\r\n")
+            .write(b"RCPT TO:<bob@example.com>\r\n")
+            .read(b"550 5.1.1 no such user\r\n")
+            .write(b"RCPT TO:<carol@example.com>\r\n")
+            .read(b"250 2.1.5 ok\r\n")
+            .write(b"DATA\r\n")
+            .read(b"354 go ahead\r\n")
+            .write(b"..\r\n.\r\n")
+            .read(b"250 2.0.0 queued\r\n")
+            .write(b"QUIT\r\n")
+            .read(b"221 2.0.0 bye\r\n")
+            .build();
+
+        let mut client = Client::connect(stream, "mail.example.org").await.unwrap();
+        client
+            .auth(&auth::Credentials {
+                username: "alice".to_owned(),
+                password: SecretString::new("secret".to_owned()),
+            })
+            .await
+            .unwrap();
+
+        let delivery = client.send(&envelope(), b".").await.unwrap();
+        assert_eq!(
+            delivery.replies().collect::<Vec<_>>(),
+            [
+                &Reply::new(550, EnhancedCode::new(5, 1, 1), "no such user"),
+                &Reply::new(250, EnhancedCode::new(2, 0, 0), "queued"),
+            ]
+        );
+
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pipelining_and_chunking() {
+        let (client_io, server_io) = tokio::io::duplex(1024);
+
+        let server = tokio::spawn(async move {
+            let server = Server::new(Context {
+                hostname: "mx.example.com".to_owned(),
+                tls: None,
+                auth: Arc::new(Validator),
+                max_message_size: Some(1024 * 1024),
+                recipients: Arc::new(AcceptAll),
+                greylist: None,
+                profile: Profile::Mx,
+                milter: None,
+                limiter: None,
+                guard: None,
+            });
+            let mut session = server.accept(server_io, "192.0.2.1:50000".parse().unwrap());
+            let mut message = session.next_message().await.unwrap().unwrap();
+            let recipients = message.envelope().recipients.len();
+            let mut out = Vec::new();
+            message.read_to_end(&mut out).await.unwrap();
+            message.accept().await.unwrap();
+            assert!(session.next_message().await.unwrap().is_none());
+            (recipients, out)
+        });
+
+        let message = vec![b'.'; 100_000];
+        let mut client = Client::connect(client_io, "mail.example.org")
+            .await
+            .unwrap();
+        let delivery = client.send(&envelope(), &message).await.unwrap();
+        assert!(delivery.replies().all(|reply| reply.code == 250));
+        client.quit().await.unwrap();
+
+        assert_eq!(server.await.unwrap(), (2, message));
+    }
+}
@@ -4,7 +4,7 @@ use std::{
 };
 
 use email_address::EmailAddress;
-use line::{read_line, write_flush, ReadLineError};
+use line::{read_line, ReadLineError};
 use nom::{
     bytes::streaming::{tag, take_until},
     combinator::map_res,
@@ -17,7 +17,14 @@ use tokio::{
 };
 use tracing::debug;
 
-use crate::LINE_LIMIT;
+use crate::{
+    reply::{EnhancedCode, Reply},
+    LINE_LIMIT,
+};
+
+use self::params::{MailParams, RcptParams};
+
+pub mod params;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Command {
@@ -27,11 +34,21 @@ pub enum Command {
     Ehlo {
         domain: String,
     },
+    /// The LMTP greeting, which replaces HELO and EHLO
+    /// ([Section 4.1] of RFC 2033).
+    ///
+    /// [Section 4.1]: https://datatracker.ietf.org/doc/html/rfc2033#section-4.1
+    Lhlo {
+        domain: String,
+    },
     Mail {
-        from: EmailAddress,
+        /// `None` for the null reverse-path `<>`, used for bounces.
+        from: Option<EmailAddress>,
+        params: MailParams,
     },
     Rcpt {
         to: EmailAddress,
+        params: RcptParams,
     },
     Rset,
     Data,
@@ -52,9 +69,12 @@ pub enum Command {
     },
 }
 
+#[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     UnrecognizedCommand,
     Syntax(&'static str),
+    /// An unknown or unsupported ESMTP parameter.
+    UnrecognizedParameter,
     InvalidUtf8,
 }
 
@@ -64,6 +84,25 @@ impl From<Utf8Error> for Error {
     }
 }
 
+impl From<Error> for Reply {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::UnrecognizedCommand => {
+                Reply::new(500, EnhancedCode::new(5, 5, 2), "Unrecognized command")
+            }
+            Error::Syntax(correct) => Reply::new(
+                501,
+                EnhancedCode::new(5, 5, 4),
+                format!("Syntax: {correct}"),
+            ),
+            Error::UnrecognizedParameter => {
+                Reply::new(555, EnhancedCode::new(5, 5, 4), "Unrecognized parameter")
+            }
+            Error::InvalidUtf8 => Reply::new(500, EnhancedCode::new(5, 5, 2), "Invalid UTF-8"),
+        }
+    }
+}
+
 impl TryFrom<&[u8]> for Command {
     type Error = Error;
 
@@ -79,12 +118,25 @@ impl TryFrom<&[u8]> for Command {
             "EHLO" => Command::Ehlo {
                 domain: args.to_owned(),
             },
-            "MAIL" => Command::Mail {
-                from: mailbox(args).map_err(|()| Error::Syntax("MAIL FROM:<address>"))?,
-            },
-            "RCPT" => Command::Rcpt {
-                to: mailbox(args).map_err(|()| Error::Syntax("RCPT TO:<address>"))?,
+            "LHLO" => Command::Lhlo {
+                domain: args.to_owned(),
             },
+            "MAIL" => {
+                let (params, from) =
+                    reverse_path(args).map_err(|()| Error::Syntax("MAIL FROM:<address>"))?;
+                Command::Mail {
+                    from,
+                    params: MailParams::parse(params)?,
+                }
+            }
+            "RCPT" => {
+                let (params, to) =
+                    mailbox(args).map_err(|()| Error::Syntax("RCPT TO:<address>"))?;
+                Command::Rcpt {
+                    to,
+                    params: RcptParams::parse(params)?,
+                }
+            }
             "DATA" => Command::Data,
             "RSET" => Command::Rset,
             "NOOP" => Command::Noop,
@@ -99,9 +151,7 @@ impl TryFrom<&[u8]> for Command {
                         .next()
                         .and_then(|s| s.parse().ok())
                         .ok_or(Error::Syntax("BDAT <size>"))?,
-                    last: args
-                        .next()
-                        .map_or(false, |s| s.to_ascii_uppercase() == "LAST"),
+                    last: args.next().is_some_and(|s| s.eq_ignore_ascii_case("LAST")),
                 }
             }
             "STARTTLS" => Command::Starttls,
@@ -125,40 +175,27 @@ impl TryFrom<&[u8]> for Command {
 
 async fn read_cmd_inner<S: AsyncRead + AsyncBufRead + AsyncWrite + Unpin>(
     stream: &mut S,
-) -> std::io::Result<Option<Command>> {
+) -> std::io::Result<Option<Result<Command, Error>>> {
     let mut buf = Vec::new();
-    loop {
-        match read_line(&mut stream.take(LINE_LIMIT as _), &mut buf).await {
-            Ok(()) => (),
-            Err(ReadLineError::Eof) => return Ok(None),
-            Err(ReadLineError::Io(e)) => return Err(e),
-        }
-
-        match Command::try_from(buf.as_ref()) {
-            Ok(cmd) => return Ok(Some(cmd)),
-            Err(Error::InvalidUtf8) => debug!("invalid utf8"),
-            Err(Error::Syntax(correct)) => {
-                write_flush(stream, format!("501 Syntax: {correct}\r\n")).await?;
-            }
-            Err(Error::UnrecognizedCommand) => {
-                write_flush(stream, "500 Unrecognized command\r\n").await?;
-            }
-        }
-
-        buf.clear();
+    match read_line(&mut stream.take(LINE_LIMIT as _), &mut buf).await {
+        Ok(()) => Ok(Some(Command::try_from(buf.as_ref()))),
+        Err(ReadLineError::Eof) => Ok(None),
+        Err(ReadLineError::Io(e)) => Err(e),
     }
 }
 
-/// Read the next command from the stream.
+/// Read the next command from the stream. A line that isn't a valid
+/// command is returned as an [`Error`], for the caller to reply to in turn
+/// with the replies to the commands before it.
 ///
 /// # Errors
 ///
 /// - Any I/O errors will be returned.
-/// - If no valid command is received within 5 minutes, a timeout error is returned.
+/// - If no command is received within 5 minutes, a timeout error is returned.
 pub async fn read_cmd<S: AsyncRead + AsyncBufRead + AsyncWrite + Unpin>(
     stream: &mut S,
-) -> std::io::Result<Option<Command>> {
-    match timeout(Duration::from_secs(300), read_cmd_inner(stream)).await {
+) -> std::io::Result<Option<Result<Command, Error>>> {
+    match timeout(Duration::from_secs(5 * 60), read_cmd_inner(stream)).await {
         Ok(Ok(cmd)) => Ok(cmd),
         Ok(Err(e)) => Err(e),
         Err(_) => {
@@ -176,9 +213,10 @@ fn parse_mailbox(i: &str) -> IResult<&str, EmailAddress> {
     )(i)
 }
 
-fn mailbox(i: &str) -> Result<EmailAddress, ()> {
+/// Parse the mailbox of `MAIL`/`RCPT` and return the parameters after it.
+fn mailbox(i: &str) -> Result<(&str, EmailAddress), ()> {
     match parse_mailbox(i) {
-        Ok((_, mailbox)) => Ok(mailbox),
+        Ok(res) => Ok(res),
         Err(e) => {
             debug!(%e, "failed to parse mailbox string {i:?}");
             Err(())
@@ -186,6 +224,14 @@ fn mailbox(i: &str) -> Result<EmailAddress, ()> {
     }
 }
 
+/// Parse the reverse-path of `MAIL`, which may be the null path `<>`.
+fn reverse_path(i: &str) -> Result<(&str, Option<EmailAddress>), ()> {
+    match i.find('<') {
+        Some(start) if i[start..].starts_with("<>") => Ok((&i[start + 2..], None)),
+        _ => mailbox(i).map(|(params, address)| (params, Some(address))),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
@@ -200,12 +246,30 @@ mod tests {
     fn mailbox() {
         assert_eq!(
             super::mailbox("TO:<alice@example.com>"),
-            Ok(EmailAddress::from_str("alice@example.com").unwrap())
+            Ok(("", EmailAddress::from_str("alice@example.com").unwrap()))
         );
 
         assert_eq!(
             super::mailbox("FROM:<günter@bahn.de> SMTPUTF8 BODY=8BITMIME"),
-            Ok(EmailAddress::from_str("günter@bahn.de").unwrap())
+            Ok((
+                " SMTPUTF8 BODY=8BITMIME",
+                EmailAddress::from_str("günter@bahn.de").unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn reverse_path() {
+        assert_eq!(
+            super::reverse_path("FROM:<> RET=HDRS"),
+            Ok((" RET=HDRS", None))
+        );
+        assert_eq!(
+            super::reverse_path("FROM:<alice@example.com>"),
+            Ok((
+                "",
+                Some(EmailAddress::from_str("alice@example.com").unwrap())
+            ))
         );
     }
 
@@ -218,13 +282,13 @@ mod tests {
 
             assert_eq!(
                 read_cmd(&mut server).await?,
-                Some(Command::Helo {
+                Some(Ok(Command::Helo {
                     domain: "world".to_owned()
-                })
+                }))
             );
             write_flush(&mut server, "250 yo\r\n").await?;
 
-            assert_eq!(read_cmd(&mut server).await?, Some(Command::Quit));
+            assert_eq!(read_cmd(&mut server).await?, Some(Ok(Command::Quit)));
             write_flush(&mut server, "221 bye\r\n").await?;
 
             assert_eq!(read_cmd(&mut server).await?, None);
@@ -0,0 +1,370 @@
+//! ESMTP parameters of the `MAIL` and `RCPT` commands
+//! ([Section 4.1.2] of RFC 5321).
+//!
+//! [Section 4.1.2]: https://datatracker.ietf.org/doc/html/rfc5321#section-4.1.2
+
+use std::{
+    fmt::{self, Write},
+    str::FromStr,
+};
+
+use util::flags;
+
+use super::Error;
+
+/// The body type declared with `BODY=`
+/// ([RFC 6152](https://datatracker.ietf.org/doc/html/rfc6152)).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Body {
+    #[default]
+    SevenBit,
+    EightBitMime,
+}
+
+/// Parameters of `MAIL FROM:<reverse-path> [parameters]`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MailParams {
+    /// The size of the message declared by the client
+    /// ([RFC 1870](https://datatracker.ietf.org/doc/html/rfc1870)).
+    pub size: Option<u64>,
+    pub body: Body,
+    /// Whether the message may contain UTF-8 addresses and headers
+    /// ([RFC 6531](https://datatracker.ietf.org/doc/html/rfc6531)).
+    pub smtputf8: bool,
+    /// How much of the message to return in a failure DSN.
+    pub ret: Option<Ret>,
+    /// An identifier for the transaction that is included in DSNs.
+    pub envid: Option<String>,
+}
+
+/// `RET=` ([Section 4.3] of RFC 3461).
+///
+/// [Section 4.3]: https://datatracker.ietf.org/doc/html/rfc3461#section-4.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ret {
+    /// Return the full message.
+    Full,
+    /// Return only the headers.
+    Hdrs,
+}
+
+flags! {
+    /// When DSNs are requested for a recipient with `NOTIFY=`
+    /// ([Section 4.1] of RFC 3461). `NOTIFY=NEVER` is the empty set.
+    ///
+    /// [Section 4.1]: https://datatracker.ietf.org/doc/html/rfc3461#section-4.1
+    pub Notify: u8 {
+        (1 << 0, "SUCCESS", SUCCESS);
+        (1 << 1, "FAILURE", FAILURE);
+        (1 << 2, "DELAY", DELAY);
+    }
+}
+
+/// The original recipient given with `ORCPT=`, e.g. `rfc822;bob@example.com`
+/// ([Section 4.2] of RFC 3461).
+///
+/// [Section 4.2]: https://datatracker.ietf.org/doc/html/rfc3461#section-4.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginalRecipient {
+    pub addr_type: String,
+    pub address: String,
+}
+
+/// Parameters of `RCPT TO:<forward-path> [parameters]`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RcptParams {
+    /// `None` if the client didn't say, in which case DSNs are sent on
+    /// failure and delay.
+    pub notify: Option<Notify>,
+    pub orcpt: Option<OriginalRecipient>,
+}
+
+impl RcptParams {
+    /// Whether a DSN should be sent for the given outcome.
+    #[must_use]
+    pub fn notify(&self, outcome: Notify) -> bool {
+        self.notify
+            .unwrap_or(Notify::FAILURE | Notify::DELAY)
+            .contains(outcome)
+    }
+}
+
+/// Decode `xtext` ([Section 4] of RFC 3461), where `+XX` encodes a byte.
+///
+/// [Section 4]: https://datatracker.ietf.org/doc/html/rfc3461#section-4
+fn xtext(i: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(i.len());
+    let mut bytes = i.bytes();
+
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'!'..=b'~' if b != b'=' => out.push(b),
+            _ => return None,
+        }
+    }
+
+    String::from_utf8(out).ok()
+}
+
+/// Encode `xtext`, the inverse of [`xtext`].
+fn fmt_xtext(f: &mut fmt::Formatter<'_>, i: &str) -> fmt::Result {
+    for b in i.bytes() {
+        match b {
+            b'!'..=b'~' if b != b'=' && b != b'+' => f.write_char(b.into())?,
+            _ => write!(f, "+{b:02X}")?,
+        }
+    }
+    Ok(())
+}
+
+/// Split parameters into keywords and optional values.
+fn params(i: &str) -> impl Iterator<Item = (String, Option<&str>)> {
+    i.split(' ').filter(|p| !p.is_empty()).map(|p| {
+        let (keyword, value) = p
+            .split_once('=')
+            .map_or((p, None), |(keyword, value)| (keyword, Some(value)));
+        (keyword.to_ascii_uppercase(), value)
+    })
+}
+
+impl MailParams {
+    pub(super) fn parse(i: &str) -> Result<Self, Error> {
+        let mut out = Self::default();
+
+        for (keyword, value) in params(i) {
+            match (keyword.as_str(), value) {
+                ("SIZE", Some(size)) => {
+                    out.size = Some(size.parse().map_err(|_| Error::Syntax("SIZE=<size>"))?);
+                }
+                ("BODY", Some(body)) if body.eq_ignore_ascii_case("7BIT") => {
+                    out.body = Body::SevenBit;
+                }
+                ("BODY", Some(body)) if body.eq_ignore_ascii_case("8BITMIME") => {
+                    out.body = Body::EightBitMime;
+                }
+                ("SMTPUTF8", None) => out.smtputf8 = true,
+                ("RET", Some(ret)) if ret.eq_ignore_ascii_case("FULL") => out.ret = Some(Ret::Full),
+                ("RET", Some(ret)) if ret.eq_ignore_ascii_case("HDRS") => out.ret = Some(Ret::Hdrs),
+                ("ENVID", Some(envid)) => {
+                    out.envid = Some(xtext(envid).ok_or(Error::Syntax("ENVID=<xtext>"))?);
+                }
+                _ => return Err(Error::UnrecognizedParameter),
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+impl RcptParams {
+    pub(super) fn parse(i: &str) -> Result<Self, Error> {
+        let mut out = Self::default();
+
+        for (keyword, value) in params(i) {
+            match (keyword.as_str(), value) {
+                ("NOTIFY", Some(notify)) => {
+                    out.notify = Some(parse_notify(notify).ok_or(Error::Syntax(
+                        "NOTIFY=NEVER or NOTIFY=SUCCESS,FAILURE,DELAY",
+                    ))?);
+                }
+                ("ORCPT", Some(orcpt)) => {
+                    let (addr_type, address) = orcpt
+                        .split_once(';')
+                        .and_then(|(addr_type, address)| Some((addr_type, xtext(address)?)))
+                        .ok_or(Error::Syntax("ORCPT=<addr-type>;<xtext>"))?;
+                    out.orcpt = Some(OriginalRecipient {
+                        addr_type: addr_type.to_owned(),
+                        address,
+                    });
+                }
+                _ => return Err(Error::UnrecognizedParameter),
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Formats the parameters with a leading space each, as they follow the
+/// path in the command.
+///
+/// ```
+/// # use smtp::command::params::{Body, MailParams};
+/// let params = MailParams {
+///     size: Some(1024),
+///     body: Body::EightBitMime,
+///     ..MailParams::default()
+/// };
+/// assert_eq!(params.to_string(), " SIZE=1024 BODY=8BITMIME");
+/// ```
+impl fmt::Display for MailParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(size) = self.size {
+            write!(f, " SIZE={size}")?;
+        }
+        if self.body == Body::EightBitMime {
+            write!(f, " BODY=8BITMIME")?;
+        }
+        if self.smtputf8 {
+            write!(f, " SMTPUTF8")?;
+        }
+        match self.ret {
+            Some(Ret::Full) => write!(f, " RET=FULL")?,
+            Some(Ret::Hdrs) => write!(f, " RET=HDRS")?,
+            None => (),
+        }
+        if let Some(envid) = &self.envid {
+            write!(f, " ENVID=")?;
+            fmt_xtext(f, envid)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RcptParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.notify {
+            Some(notify) if notify.is_empty() => write!(f, " NOTIFY=NEVER")?,
+            Some(notify) => {
+                write!(f, " NOTIFY=")?;
+                for (i, name) in notify.names().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    f.write_str(name)?;
+                }
+            }
+            None => (),
+        }
+        if let Some(orcpt) = &self.orcpt {
+            write!(f, " ORCPT={};", orcpt.addr_type)?;
+            fmt_xtext(f, &orcpt.address)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for MailParams {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl FromStr for RcptParams {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+fn parse_notify(i: &str) -> Option<Notify> {
+    if i.eq_ignore_ascii_case("NEVER") {
+        return Some(Notify::empty());
+    }
+
+    i.split(',')
+        .map(|name| match name.to_ascii_uppercase().as_str() {
+            "SUCCESS" => Some(Notify::SUCCESS),
+            "FAILURE" => Some(Notify::FAILURE),
+            "DELAY" => Some(Notify::DELAY),
+            _ => None,
+        })
+        .try_fold(Notify::empty(), |notify, n| Some(notify | n?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Body, MailParams, Notify, OriginalRecipient, RcptParams, Ret};
+    use crate::command::Error;
+
+    #[test]
+    fn mail() {
+        assert_eq!(MailParams::parse("").unwrap(), MailParams::default());
+        assert_eq!(
+            MailParams::parse("SIZE=1024 body=8BITMIME SMTPUTF8").unwrap(),
+            MailParams {
+                size: Some(1024),
+                body: Body::EightBitMime,
+                smtputf8: true,
+                ..MailParams::default()
+            }
+        );
+        assert_eq!(
+            MailParams::parse("RET=HDRS ENVID=QQ314159+2B1").unwrap(),
+            MailParams {
+                ret: Some(Ret::Hdrs),
+                envid: Some("QQ314159+1".to_owned()),
+                ..MailParams::default()
+            }
+        );
+        let params = MailParams {
+            size: Some(1),
+            body: Body::EightBitMime,
+            smtputf8: true,
+            ret: Some(Ret::Full),
+            envid: Some("a+b=c d".to_owned()),
+        };
+        assert_eq!(
+            params.to_string(),
+            " SIZE=1 BODY=8BITMIME SMTPUTF8 RET=FULL ENVID=a+2Bb+3Dc+20d"
+        );
+        assert_eq!(MailParams::parse(&params.to_string()).unwrap(), params);
+        assert!(matches!(
+            MailParams::parse("SIZE=big"),
+            Err(Error::Syntax(_))
+        ));
+        assert!(matches!(
+            MailParams::parse("BODY=BINARYMIME"),
+            Err(Error::UnrecognizedParameter)
+        ));
+    }
+
+    #[test]
+    fn rcpt() {
+        assert_eq!(RcptParams::parse("").unwrap(), RcptParams::default());
+        assert_eq!(
+            RcptParams::parse("NOTIFY=SUCCESS,failure ORCPT=rfc822;bob+2Bdsn@example.com").unwrap(),
+            RcptParams {
+                notify: Some(Notify::SUCCESS | Notify::FAILURE),
+                orcpt: Some(OriginalRecipient {
+                    addr_type: "rfc822".to_owned(),
+                    address: "bob+dsn@example.com".to_owned(),
+                }),
+            }
+        );
+        let params = RcptParams {
+            notify: Some(Notify::SUCCESS | Notify::DELAY),
+            orcpt: Some(OriginalRecipient {
+                addr_type: "rfc822".to_owned(),
+                address: "bob+dsn@example.com".to_owned(),
+            }),
+        };
+        assert_eq!(
+            params.to_string(),
+            " NOTIFY=SUCCESS,DELAY ORCPT=rfc822;bob+2Bdsn@example.com"
+        );
+        assert_eq!(RcptParams::parse(&params.to_string()).unwrap(), params);
+        assert_eq!(
+            RcptParams::parse("NOTIFY=NEVER").unwrap().notify,
+            Some(Notify::empty())
+        );
+        assert!(matches!(
+            RcptParams::parse("NOTIFY=SOMETIMES"),
+            Err(Error::Syntax(_))
+        ));
+        assert!(RcptParams::default().notify(Notify::FAILURE));
+        assert!(!RcptParams::default().notify(Notify::SUCCESS));
+        assert!(matches!(
+            RcptParams::parse("FOO=BAR"),
+            Err(Error::UnrecognizedParameter)
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+//! Dates in message headers ([Section 3.3] of RFC 5322).
+//!
+//! [Section 3.3]: https://datatracker.ietf.org/doc/html/rfc5322#section-3.3
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Convert days since the Unix epoch to a `(year, month, day)` date.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+const fn civil_from_days(days: i64) -> (i64, usize, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    (year, month as usize, day)
+}
+
+/// Format a time as an RFC 5322 `date-time` in UTC.
+///
+/// ```
+/// # use std::time::{Duration, UNIX_EPOCH};
+/// assert_eq!(
+///     smtp::date::format(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+///     "Tue, 14 Nov 2023 22:13:20 +0000",
+/// );
+/// ```
+#[must_use]
+pub fn format(time: SystemTime) -> String {
+    #[allow(clippy::cast_possible_wrap)]
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    };
+
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    let (year, month, day) = civil_from_days(days);
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let weekday = DAYS[days.rem_euclid(7) as usize];
+
+    format!(
+        "{weekday}, {day} {} {year} {:02}:{:02}:{:02} +0000",
+        MONTHS[month - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+    )
+}
+
+/// The current time as an RFC 5322 `date-time`.
+#[must_use]
+pub fn now() -> String {
+    format(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::format;
+
+    #[test]
+    fn fmt() {
+        let cases = [
+            (0, "Thu, 1 Jan 1970 00:00:00 +0000"),
+            (951_782_400, "Tue, 29 Feb 2000 00:00:00 +0000"),
+            (1_709_251_199, "Thu, 29 Feb 2024 23:59:59 +0000"),
+            (4_102_444_800, "Fri, 1 Jan 2100 00:00:00 +0000"),
+        ];
+
+        for (secs, expected) in cases {
+            assert_eq!(format(UNIX_EPOCH + Duration::from_secs(secs)), expected);
+        }
+    }
+}
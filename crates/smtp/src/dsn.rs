@@ -0,0 +1,399 @@
+//! Delivery status notifications ([RFC 3464]), sent to the sender of a
+//! message when delivery fails after it was accepted (or when otherwise
+//! requested with `NOTIFY=`).
+//!
+//! [RFC 3464]: https://datatracker.ietf.org/doc/html/rfc3464
+
+use std::{
+    fmt::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use email_address::EmailAddress;
+
+use crate::{
+    command::params::{Notify, Ret},
+    date,
+    message::{Envelope, Recipient},
+    reply::{EnhancedCode, Reply},
+};
+
+/// What happened to the message for a recipient
+/// ([Section 2.3.3] of RFC 3464).
+///
+/// [Section 2.3.3]: https://datatracker.ietf.org/doc/html/rfc3464#section-2.3.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Failed,
+    Delayed,
+    Delivered,
+    Relayed,
+    Expanded,
+}
+
+impl Action {
+    /// The `NOTIFY=` condition under which the action is reported.
+    #[must_use]
+    pub const fn condition(self) -> Notify {
+        match self {
+            Action::Failed => Notify::FAILURE,
+            Action::Delayed => Notify::DELAY,
+            Action::Delivered | Action::Relayed | Action::Expanded => Notify::SUCCESS,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Failed => "failed",
+            Action::Delayed => "delayed",
+            Action::Delivered => "delivered",
+            Action::Relayed => "relayed",
+            Action::Expanded => "expanded",
+        })
+    }
+}
+
+/// The outcome of delivery to one recipient.
+#[derive(Debug, Clone)]
+pub struct RecipientStatus<'a> {
+    pub recipient: &'a Recipient,
+    pub action: Action,
+    pub status: EnhancedCode,
+    /// The MTA that the message was relayed to, if any.
+    pub remote_mta: Option<&'a str>,
+    /// The reply of the remote MTA, if any.
+    pub diagnostic: Option<&'a Reply>,
+}
+
+/// A `multipart/report` delivery status notification.
+#[derive(Debug, Clone)]
+pub struct Report<'a> {
+    /// The host name of this MTA.
+    pub reporting_mta: &'a str,
+    pub envelope: &'a Envelope,
+    pub arrival_date: SystemTime,
+    /// The date of the report.
+    pub date: SystemTime,
+    pub recipients: Vec<RecipientStatus<'a>>,
+}
+
+/// The header section of a message, including the empty line after it.
+fn headers(message: &[u8]) -> &[u8] {
+    message
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(message, |i| &message[..i + 4])
+}
+
+/// A reply on a single line, as used in `Diagnostic-Code`.
+fn diagnostic(reply: &Reply) -> String {
+    let mut out = reply.code.to_string();
+    if let Some(enhanced) = reply.enhanced {
+        write!(out, " {enhanced}").unwrap();
+    }
+    for line in &reply.lines {
+        write!(out, " {line}").unwrap();
+    }
+    out
+}
+
+impl Report<'_> {
+    /// The recipients that asked to be notified about what happened.
+    fn notified(&self) -> impl Iterator<Item = &RecipientStatus<'_>> {
+        self.recipients
+            .iter()
+            .filter(|r| r.recipient.params.notify(r.action.condition()))
+    }
+
+    fn boundary(&self, message: &[u8]) -> String {
+        let nanos = self
+            .date
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let mut boundary = format!("{nanos:x}/{}", self.reporting_mta);
+        while message
+            .windows(boundary.len())
+            .any(|w| w == boundary.as_bytes())
+        {
+            boundary.push('=');
+        }
+        boundary
+    }
+
+    /// Generate the report for `message`, or `None` if none of the
+    /// recipients asked to be notified or the message itself was a bounce.
+    ///
+    /// The report should be sent with an empty reverse-path to the sender
+    /// of the message.
+    #[must_use]
+    pub fn generate(&self, message: &[u8]) -> Option<Vec<u8>> {
+        let from = self.envelope.from.as_ref()?;
+        let notified: Vec<_> = self.notified().collect();
+        let subject = if notified.iter().any(|r| r.action == Action::Failed) {
+            "failure"
+        } else if notified.iter().any(|r| r.action == Action::Delayed) {
+            "delay"
+        } else {
+            "success"
+        };
+        if notified.is_empty() {
+            return None;
+        }
+
+        let boundary = self.boundary(message);
+        let mut out = String::new();
+
+        // writing to a String can't fail
+        let _ = self.write(&mut out, from, &notified, subject, &boundary);
+
+        let mut out = out.into_bytes();
+        match self.envelope.params.ret {
+            Some(Ret::Full) => {
+                out.extend_from_slice(b"Content-Type: message/rfc822\r\n\r\n");
+                out.extend_from_slice(message);
+            }
+            Some(Ret::Hdrs) | None => {
+                out.extend_from_slice(b"Content-Type: text/rfc822-headers\r\n\r\n");
+                out.extend_from_slice(headers(message));
+            }
+        }
+        if !out.ends_with(b"\r\n") {
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+
+        Some(out)
+    }
+
+    /// Write everything but the returned message.
+    fn write(
+        &self,
+        out: &mut String,
+        from: &EmailAddress,
+        notified: &[&RecipientStatus<'_>],
+        subject: &str,
+        boundary: &str,
+    ) -> fmt::Result {
+        let mta = self.reporting_mta;
+
+        write!(out, "From: Mail Delivery System <MAILER-DAEMON@{mta}>\r\n")?;
+        write!(out, "To: <{from}>\r\n")?;
+        write!(out, "Subject: Delivery status notification ({subject})\r\n")?;
+        write!(out, "Date: {}\r\n", date::format(self.date))?;
+        write!(out, "Auto-Submitted: auto-replied\r\n")?;
+        write!(out, "MIME-Version: 1.0\r\n")?;
+        write!(
+            out,
+            "Content-Type: multipart/report; report-type=delivery-status;\r\n\
+            \tboundary=\"{boundary}\"\r\n\r\n"
+        )?;
+
+        write!(out, "--{boundary}\r\n")?;
+        write!(out, "Content-Type: text/plain; charset=utf-8\r\n\r\n")?;
+        write!(out, "This is the mail system at {mta}.\r\n\r\n")?;
+        write!(
+            out,
+            "Delivery of your message to the following recipients:\r\n\r\n"
+        )?;
+        for status in notified {
+            write!(out, "<{}>: {}", status.recipient.address, status.action)?;
+            if let Some(reply) = status.diagnostic {
+                write!(out, " ({})", diagnostic(reply))?;
+            }
+            write!(out, "\r\n")?;
+        }
+        write!(out, "\r\n")?;
+
+        write!(out, "--{boundary}\r\n")?;
+        write!(out, "Content-Type: message/delivery-status\r\n\r\n")?;
+        write!(out, "Reporting-MTA: dns; {mta}\r\n")?;
+        if let Some(envid) = &self.envelope.params.envid {
+            write!(out, "Original-Envelope-Id: {envid}\r\n")?;
+        }
+        write!(out, "Arrival-Date: {}\r\n", date::format(self.arrival_date))?;
+        for status in notified {
+            write!(out, "\r\n")?;
+            let recipient = status.recipient;
+            write!(out, "Final-Recipient: rfc822; {}\r\n", recipient.address)?;
+            if let Some(orcpt) = &recipient.params.orcpt {
+                write!(
+                    out,
+                    "Original-Recipient: {}; {}\r\n",
+                    orcpt.addr_type, orcpt.address
+                )?;
+            }
+            write!(out, "Action: {}\r\n", status.action)?;
+            write!(out, "Status: {}\r\n", status.status)?;
+            if let Some(remote_mta) = status.remote_mta {
+                write!(out, "Remote-MTA: dns; {remote_mta}\r\n")?;
+            }
+            if let Some(reply) = status.diagnostic {
+                write!(out, "Diagnostic-Code: smtp; {}\r\n", diagnostic(reply))?;
+            }
+        }
+        write!(out, "\r\n")?;
+
+        write!(out, "--{boundary}\r\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use email_address::EmailAddress;
+
+    use super::{Action, RecipientStatus, Report};
+    use crate::{
+        command::params::{MailParams, Notify, OriginalRecipient, RcptParams, Ret},
+        message::Envelope,
+        reply::{EnhancedCode, Reply},
+    };
+
+    const MESSAGE: &[u8] = b"Subject: hi\r\n\r\nhello\r\n";
+
+    fn envelope(ret: Option<Ret>) -> Envelope {
+        let mut envelope = Envelope::new(
+            Some(EmailAddress::from_str("alice@example.com").unwrap()),
+            MailParams {
+                ret,
+                envid: Some("QQ314159".to_owned()),
+                ..MailParams::default()
+            },
+        );
+        envelope.add_recipient(
+            EmailAddress::from_str("bob@example.net").unwrap(),
+            RcptParams {
+                notify: None,
+                orcpt: Some(OriginalRecipient {
+                    addr_type: "rfc822".to_owned(),
+                    address: "bob@example.net".to_owned(),
+                }),
+            },
+        );
+        envelope.add_recipient(
+            EmailAddress::from_str("carol@example.net").unwrap(),
+            RcptParams {
+                notify: Some(Notify::empty()),
+                orcpt: None,
+            },
+        );
+        envelope
+    }
+
+    #[test]
+    fn generate() {
+        let envelope = envelope(None);
+        let reply = Reply::new(550, EnhancedCode::new(5, 1, 1), "no such user");
+        let report = Report {
+            reporting_mta: "mx.example.com",
+            envelope: &envelope,
+            arrival_date: UNIX_EPOCH,
+            date: UNIX_EPOCH + Duration::from_secs(90),
+            recipients: envelope
+                .recipients
+                .iter()
+                .map(|recipient| RecipientStatus {
+                    recipient,
+                    action: Action::Failed,
+                    status: EnhancedCode::new(5, 1, 1),
+                    remote_mta: Some("mx.example.net"),
+                    diagnostic: Some(&reply),
+                })
+                .collect(),
+        };
+
+        let generated = String::from_utf8(report.generate(MESSAGE).unwrap()).unwrap();
+        assert_eq!(
+            generated,
+            "From: Mail Delivery System <MAILER-DAEMON@mx.example.com>\r\n\
+            To: <alice@example.com>\r\n\
+            Subject: Delivery status notification (failure)\r\n\
+            Date: Thu, 1 Jan 1970 00:01:30 +0000\r\n\
+            Auto-Submitted: auto-replied\r\n\
+            MIME-Version: 1.0\r\n\
+            Content-Type: multipart/report; report-type=delivery-status;\r\n\
+            \tboundary=\"14f46b0400/mx.example.com\"\r\n\
+            \r\n\
+            --14f46b0400/mx.example.com\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            \r\n\
+            This is the mail system at mx.example.com.\r\n\
+            \r\n\
+            Delivery of your message to the following recipients:\r\n\
+            \r\n\
+            <bob@example.net>: failed (550 5.1.1 no such user)\r\n\
+            \r\n\
+            --14f46b0400/mx.example.com\r\n\
+            Content-Type: message/delivery-status\r\n\
+            \r\n\
+            Reporting-MTA: dns; mx.example.com\r\n\
+            Original-Envelope-Id: QQ314159\r\n\
+            Arrival-Date: Thu, 1 Jan 1970 00:00:00 +0000\r\n\
+            \r\n\
+            Final-Recipient: rfc822; bob@example.net\r\n\
+            Original-Recipient: rfc822; bob@example.net\r\n\
+            Action: failed\r\n\
+            Status: 5.1.1\r\n\
+            Remote-MTA: dns; mx.example.net\r\n\
+            Diagnostic-Code: smtp; 550 5.1.1 no such user\r\n\
+            \r\n\
+            --14f46b0400/mx.example.com\r\n\
+            Content-Type: text/rfc822-headers\r\n\
+            \r\n\
+            Subject: hi\r\n\
+            \r\n\
+            --14f46b0400/mx.example.com--\r\n"
+        );
+    }
+
+    #[test]
+    fn notify() {
+        let envelope = envelope(Some(Ret::Full));
+        let mut report = Report {
+            reporting_mta: "mx.example.com",
+            envelope: &envelope,
+            arrival_date: UNIX_EPOCH,
+            date: UNIX_EPOCH,
+            recipients: envelope
+                .recipients
+                .iter()
+                .map(|recipient| RecipientStatus {
+                    recipient,
+                    action: Action::Delivered,
+                    status: EnhancedCode::new(2, 0, 0),
+                    remote_mta: None,
+                    diagnostic: None,
+                })
+                .collect(),
+        };
+
+        // success is only reported if requested
+        assert_eq!(report.generate(MESSAGE), None);
+
+        report.recipients[1].action = Action::Failed;
+        assert_eq!(report.generate(MESSAGE), None);
+
+        report.recipients[0].action = Action::Delayed;
+
+        // bounces are never bounced
+        let bounce = Envelope::new(None, MailParams::default());
+        assert_eq!(
+            Report {
+                envelope: &bounce,
+                ..report.clone()
+            }
+            .generate(MESSAGE),
+            None
+        );
+
+        let generated = report.generate(MESSAGE).unwrap();
+        assert!(generated.ends_with(b"Content-Type: message/rfc822\r\n\r\nSubject: hi\r\n\r\nhello\r\n--0/mx.example.com--\r\n"));
+    }
+}
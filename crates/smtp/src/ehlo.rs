@@ -18,12 +18,12 @@
 //! 250 STARTTLS
 //! ```
 
-use std::{borrow::Cow, fmt, iter};
+use std::{borrow::Cow, fmt};
 
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncBufRead, AsyncRead};
 use util::flags;
 
-use crate::LINE_LIMIT;
+use crate::reply::Reply;
 
 /// SMTP EHLO response.
 ///
@@ -53,7 +53,8 @@ pub struct Response {
     /// Maximum message size in bytes
     /// ([RFC 1870](https://datatracker.ietf.org/doc/html/rfc1870)).
     pub size: Option<u64>,
-    /// AUTH mechanisms supported.
+    /// AUTH mechanisms supported. The `AUTH` line is left out if there
+    /// are none.
     pub auth: Auth,
 }
 
@@ -95,6 +96,12 @@ flags! {
         /// [`Command::Starttls`]: crate::command::Command#variant.Starttls
         (1 << 3, "STARTTLS", STARTTLS);
         (1 << 4, "ENHANCEDSTATUSCODES", ENHANCEDSTATUSCODES);
+        /// Command pipelining
+        /// ([RFC 2920](https://datatracker.ietf.org/doc/html/rfc2920)).
+        (1 << 5, "PIPELINING", PIPELINING);
+        /// Delivery status notifications
+        /// ([RFC 3461](https://datatracker.ietf.org/doc/html/rfc3461)).
+        (1 << 6, "DSN", DSN);
     }
 }
 
@@ -106,7 +113,7 @@ impl fmt::Display for Response {
             .names()
             .map(Cow::Borrowed)
             .chain(self.size.map(|s| Cow::Owned(format!("SIZE {s}"))))
-            .chain(iter::once(self.auth.to_string().into()))
+            .chain((!self.auth.is_empty()).then(|| self.auth.to_string().into()))
             .peekable();
 
         while let Some(ehlo_line) = lines.next() {
@@ -121,13 +128,7 @@ impl fmt::Display for Response {
     }
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum ParseError {
-    #[error(transparent)]
-    Io(#[from] std::io::Error),
-    #[error("syntax error")]
-    Syntax,
-}
+pub use crate::reply::ParseError;
 
 impl Response {
     /// Parse an SMTP EHLO response asynchonously.
@@ -167,68 +168,56 @@ impl Response {
     pub async fn read<R: AsyncRead + AsyncBufRead + Unpin>(
         reader: &mut R,
     ) -> Result<Self, ParseError> {
-        let mut line = Vec::new();
-        let mut domain = None;
+        Self::from_reply(&Reply::read(reader, false).await?)
+    }
+
+    /// Parse the lines of a positive reply to EHLO.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ParseError::Syntax`] if the reply is not a 250 reply or
+    /// is malformed.
+    pub fn from_reply(reply: &Reply) -> Result<Self, ParseError> {
+        if reply.code != 250 {
+            return Err(ParseError::Syntax);
+        }
+
+        let mut lines = reply.lines.iter();
+        let domain = lines.next().ok_or(ParseError::Syntax)?;
+        let domain = domain.split(' ').next().unwrap_or_default().to_owned();
         let mut size = None;
-        let mut eol = false;
         let mut extensions = Extensions::empty();
         let mut auth = Auth::empty();
 
-        loop {
-            if eol {
-                return Ok(Self {
-                    domain: domain.ok_or(ParseError::Syntax)?,
-                    extensions,
-                    size,
-                    auth,
-                });
-            }
+        for line in lines {
+            let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
 
-            line.clear();
-            if reader
-                .take(LINE_LIMIT as _)
-                .read_until(b'\n', &mut line)
-                .await?
-                < 5
-            {
-                return Err(ParseError::Syntax);
-            }
-
-            match line.get(3) {
-                Some(b'-') => (),
-                Some(b' ') => {
-                    eol = true;
+            extensions |= match keyword.to_ascii_uppercase().as_str() {
+                "8BITMIME" => Extensions::_8BITMIME,
+                "SMTPUTF8" => Extensions::SMTPUTF8,
+                "CHUNKING" => Extensions::CHUNKING,
+                "STARTTLS" => Extensions::STARTTLS,
+                "ENHANCEDSTATUSCODES" => Extensions::ENHANCEDSTATUSCODES,
+                "PIPELINING" => Extensions::PIPELINING,
+                "DSN" => Extensions::DSN,
+                "SIZE" => {
+                    size = Some(args.parse().map_err(|_| ParseError::Syntax)?);
+                    continue;
                 }
-                _ => return Err(ParseError::Syntax),
-            }
-
-            let line = std::str::from_utf8(&line[4..])
-                .map_err(|_| ParseError::Syntax)?
-                .trim_end();
-
-            if domain.is_none() {
-                domain = Some(line.to_owned());
-            } else {
-                let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
-
-                extensions |= match keyword {
-                    "8BITMIME" => Extensions::_8BITMIME,
-                    "SMTPUTF8" => Extensions::SMTPUTF8,
-                    "CHUNKING" => Extensions::CHUNKING,
-                    "STARTTLS" => Extensions::STARTTLS,
-                    "ENHANCEDSTATUSCODES" => Extensions::ENHANCEDSTATUSCODES,
-                    "SIZE" => {
-                        size = Some(args.parse().map_err(|_| ParseError::Syntax)?);
-                        continue;
-                    }
-                    "AUTH" => {
-                        auth = args.split(' ').collect();
-                        continue;
-                    }
-                    _ => continue,
+                "AUTH" => {
+                    auth = args.split(' ').collect();
+                    continue;
                 }
+                _ => continue,
             }
         }
+
+        Ok(Self {
+            domain,
+            extensions,
+            size,
+            auth,
+        })
     }
 }
 
@@ -256,6 +245,8 @@ mod tests {
                 "250-CHUNKING",
                 "250-STARTTLS",
                 "250-ENHANCEDSTATUSCODES",
+                "250-PIPELINING",
+                "250-DSN",
                 "250-SIZE 1024",
                 "250 AUTH LOGIN PLAIN",
                 ""
@@ -289,8 +280,10 @@ mod tests {
                 extensions: Extensions::_8BITMIME
                     | Extensions::CHUNKING
                     | Extensions::STARTTLS
-                    | Extensions::ENHANCEDSTATUSCODES,
-                size: Some(52428800),
+                    | Extensions::ENHANCEDSTATUSCODES
+                    | Extensions::PIPELINING
+                    | Extensions::DSN,
+                size: Some(52_428_800),
                 auth: Auth::PLAIN | Auth::LOGIN,
             }
         );
@@ -0,0 +1,67 @@
+//! Locating the header fields of a message ([Section 2.2] of RFC 5322).
+//!
+//! [Section 2.2]: https://datatracker.ietf.org/doc/html/rfc5322#section-2.2
+
+use std::ops::Range;
+
+/// A header field of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Field {
+    pub name: String,
+    /// Where the field is in the message, including any folding and the
+    /// final line break.
+    pub range: Range<usize>,
+}
+
+impl Field {
+    /// The value after the colon with folding removed.
+    pub fn value(&self, message: &[u8]) -> String {
+        let raw = String::from_utf8_lossy(&message[self.range.clone()]);
+        let (_, value) = raw.split_once(':').unwrap_or_default();
+        value.replace("\r\n", "").replace('\n', "")
+    }
+
+    /// The value after the colon without leading whitespace and the final
+    /// line break. Folding is kept, with LF line breaks.
+    pub fn raw_value(&self, message: &[u8]) -> String {
+        let raw = String::from_utf8_lossy(&message[self.range.clone()]);
+        let (_, value) = raw.split_once(':').unwrap_or_default();
+        value
+            .trim_start_matches([' ', '\t'])
+            .trim_end_matches(['\r', '\n'])
+            .replace("\r\n", "\n")
+    }
+}
+
+/// The header fields of `message` and the offset of the blank line ending
+/// them, where new fields can be added.
+pub(crate) fn fields(message: &[u8]) -> (Vec<Field>, usize) {
+    let mut fields: Vec<Field> = Vec::new();
+    let mut pos = 0;
+
+    while pos < message.len() {
+        let end = message[pos..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map_or(message.len(), |i| pos + i + 1);
+        let line = &message[pos..end];
+
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        if matches!(line[0], b' ' | b'\t') {
+            // continuation of the previous field
+            if let Some(field) = fields.last_mut() {
+                field.range.end = end;
+            }
+        } else if let Some(colon) = line.iter().position(|&c| c == b':') {
+            fields.push(Field {
+                name: String::from_utf8_lossy(&line[..colon]).trim().to_owned(),
+                range: pos..end,
+            });
+        }
+        pos = end;
+    }
+
+    (fields, pos)
+}
@@ -1,10 +1,13 @@
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+use crate::reply::{EnhancedCode, Reply};
+
 /// Send a 221 response and shutdown the connection.
 ///
 /// This is the only acceptable way to respond to a `QUIT` command.
 pub async fn bye<S: AsyncWrite + Unpin>(stream: &mut S) -> std::io::Result<()> {
-    stream.write_all(b"221 Bye\r\n").await?;
+    let reply = Reply::new(221, EnhancedCode::new(2, 0, 0), "Bye");
+    stream.write_all(reply.to_string().as_bytes()).await?;
     stream.shutdown().await?;
     Ok(())
 }
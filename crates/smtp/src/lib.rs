@@ -2,13 +2,19 @@
 
 #![warn(clippy::pedantic)]
 
+pub mod client;
 pub mod command;
+pub mod date;
+pub mod dsn;
 pub mod ehlo;
 pub mod message;
+pub mod reply;
 pub mod server;
+pub mod trace;
 
 pub use server::Server;
 
+mod header;
 mod io;
 
 /// The maximum number of bytes in a line including the CRLF.
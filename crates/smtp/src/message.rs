@@ -1,11 +1,17 @@
-use std::{collections::HashSet, pin::Pin};
+use std::{fmt, io::Cursor, net::SocketAddr, pin::Pin, task::Poll};
 
+use auth::Identity;
 use email_address::EmailAddress;
 use line::write_flush;
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
-use tracing::instrument;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite};
+use tracing::{instrument, warn};
 
 use self::{bdat::Bdat, data::Data};
+use crate::{
+    command::params::{MailParams, RcptParams},
+    reply::Reply,
+    server::milter::{self, Milter, Outcome, Response},
+};
 
 mod bdat;
 mod data;
@@ -13,22 +19,71 @@ mod data;
 #[cfg(fuzzing)]
 pub use data::fuzz as data_fuzz;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recipient {
+    pub address: EmailAddress,
+    pub params: RcptParams,
+}
+
 #[derive(Debug)]
 pub struct Envelope {
-    pub from: EmailAddress,
-    pub recipients: HashSet<EmailAddress>,
+    /// `None` for the null reverse-path, used for bounces.
+    pub from: Option<EmailAddress>,
+    pub params: MailParams,
+    pub recipients: Vec<Recipient>,
 }
 
 impl Envelope {
     #[must_use]
-    pub fn new(from: EmailAddress) -> Self {
+    pub fn new(from: Option<EmailAddress>, params: MailParams) -> Self {
         Self {
             from,
-            recipients: HashSet::new(),
+            params,
+            recipients: Vec::new(),
+        }
+    }
+
+    /// Add a recipient, unless it was already added.
+    pub fn add_recipient(&mut self, address: EmailAddress, params: RcptParams) {
+        if !self.recipients.iter().any(|r| r.address == address) {
+            self.recipients.push(Recipient { address, params });
         }
     }
 }
 
+/// The client a message came from, as needed to authenticate it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Client {
+    pub peer: SocketAddr,
+    /// The domain or address literal from HELO or EHLO.
+    pub helo: String,
+    /// The user the client authenticated as, if any.
+    pub identity: Option<Identity>,
+}
+
+/// The message exceeds the maximum message size. The rest of it is
+/// discarded before this error is returned from the reader.
+#[derive(Debug)]
+pub struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "message exceeds fixed maximum message size")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+impl TooLarge {
+    fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, self)
+    }
+
+    fn is(err: &std::io::Error) -> bool {
+        matches!(err.get_ref(), Some(e) if e.is::<TooLarge>())
+    }
+}
+
 enum Inner<'a, S: AsyncRead + AsyncWrite + Unpin> {
     Data(Data<'a, S>),
     Bdat(Bdat<'a, S>),
@@ -36,29 +91,82 @@ enum Inner<'a, S: AsyncRead + AsyncWrite + Unpin> {
 
 pub struct Incoming<'a, S: AsyncRead + AsyncWrite + Unpin> {
     envelope: Envelope,
+    client: Client,
     inner: Inner<'a, S>,
+    too_large: bool,
+    /// Trace headers to prepend to the message.
+    trace: Vec<u8>,
+    /// Whether the message came over LMTP, which replies once for each
+    /// recipient.
+    lmtp: bool,
+    milter: Option<&'a mut Milter>,
 }
 
 impl<'a, S: AsyncRead + AsyncWrite + Unpin> Incoming<'a, S> {
-    pub(crate) fn data(envelope: Envelope, stream: &'a mut S) -> Self {
+    pub(crate) fn data(
+        envelope: Envelope,
+        client: Client,
+        trace: String,
+        stream: &'a mut S,
+        max_size: Option<u64>,
+    ) -> Self {
         Self {
             envelope,
-            inner: Inner::Data(Data::new(stream)),
+            client,
+            inner: Inner::Data(Data::new(stream, max_size)),
+            too_large: false,
+            trace: trace.into_bytes(),
+            lmtp: false,
+            milter: None,
         }
     }
 
-    pub(crate) fn bdat(envelope: Envelope, remaining: u64, last: bool, stream: &'a mut S) -> Self {
+    pub(crate) fn bdat(
+        envelope: Envelope,
+        client: Client,
+        trace: String,
+        remaining: u64,
+        last: bool,
+        stream: &'a mut S,
+        max_size: Option<u64>,
+    ) -> Self {
         Self {
             envelope,
-            inner: Inner::Bdat(Bdat::new(stream, remaining, last)),
+            client,
+            inner: Inner::Bdat(Bdat::new(stream, remaining, last, max_size)),
+            too_large: false,
+            trace: trace.into_bytes(),
+            lmtp: false,
+            milter: None,
         }
     }
 
+    pub(crate) fn with_lmtp(mut self, lmtp: bool) -> Self {
+        self.lmtp = lmtp;
+        self
+    }
+
+    pub(crate) fn with_milter(mut self, milter: Option<&'a mut Milter>) -> Self {
+        self.milter = milter;
+        self
+    }
+
     #[must_use]
     pub fn envelope(&self) -> &Envelope {
         &self.envelope
     }
 
+    #[must_use]
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The `Received:` header the server prepends to the message.
+    #[must_use]
+    pub fn trace(&self) -> &[u8] {
+        &self.trace
+    }
+
     fn take_stream(self) -> Option<&'a mut S> {
         match self.inner {
             Inner::Data(data) => Some(data.into_stream()),
@@ -66,14 +174,93 @@ impl<'a, S: AsyncRead + AsyncWrite + Unpin> Incoming<'a, S> {
         }
     }
 
+    /// Pass `message`, as read from here, through the server's milter and
+    /// apply the changes it asks for. Without a milter, the message is
+    /// left alone.
+    #[instrument(skip_all)]
+    pub async fn filter(&mut self, message: &mut Vec<u8>) -> Outcome {
+        let Some(milter) = self.milter.as_mut() else {
+            return Outcome::default();
+        };
+        match milter.message(message).await {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                warn!(%e, "milter failed");
+                Outcome {
+                    response: Response::Reject(milter::unavailable()),
+                    quarantine: None,
+                }
+            }
+        }
+    }
+
+    /// Whether reading stopped because the message exceeds the maximum
+    /// message size. Such a message can't be accepted.
+    #[must_use]
+    pub const fn is_too_large(&self) -> bool {
+        self.too_large
+    }
+
+    async fn reply(self, reply: Reply) -> std::io::Result<()> {
+        let replies = vec![reply; self.envelope.recipients.len()];
+        self.reply_each(replies).await
+    }
+
     #[instrument(skip_all)]
     pub async fn accept(self) -> std::io::Result<()> {
-        write_flush(self.take_stream().unwrap(), "250 ok\r\n").await
+        self.reply(Reply::ok()).await
     }
 
+    /// Reject the message, e.g. with [`Reply::rejected`]. Messages that
+    /// are too large are always rejected with [`Reply::too_large`].
     #[instrument(skip_all)]
-    pub async fn reject(self) -> std::io::Result<()> {
-        write_flush(self.take_stream().unwrap(), "554 nope\r\n").await
+    pub async fn reject(self, reply: Reply) -> std::io::Result<()> {
+        self.reply(reply).await
+    }
+
+    /// Reply for each recipient, in the order of
+    /// [`Envelope::recipients`]. LMTP sends each of the replies
+    /// ([Section 4.2] of RFC 2033). SMTP has a single reply for the whole
+    /// message, so the message is accepted if any recipient accepted it
+    /// and it's up to the caller to bounce it for the others.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there isn't exactly one reply for each recipient.
+    ///
+    /// [Section 4.2]: https://datatracker.ietf.org/doc/html/rfc2033#section-4.2
+    #[instrument(skip_all)]
+    pub async fn reply_each(self, replies: Vec<Reply>) -> std::io::Result<()> {
+        assert_eq!(
+            replies.len(),
+            self.envelope.recipients.len(),
+            "one reply per recipient"
+        );
+
+        let replies = if self.too_large {
+            vec![Reply::too_large(); replies.len()]
+        } else {
+            replies
+        };
+        let out = if self.lmtp {
+            replies.iter().map(ToString::to_string).collect()
+        } else {
+            replies
+                .iter()
+                .find(|reply| reply.is_positive())
+                .or(replies.first())
+                .map(ToString::to_string)
+                .unwrap_or_default()
+        };
+        write_flush(self.take_stream().unwrap(), out).await
+    }
+}
+
+impl<'a, S: AsyncRead + AsyncBufRead + AsyncWrite + Unpin + Send + Sync> Incoming<'a, S> {
+    /// Read the message with the trace headers prepended. Reading the
+    /// `Incoming` directly yields the message as it was received.
+    pub fn with_trace(&mut self) -> impl AsyncRead + Unpin + use<'_, 'a, S> {
+        Cursor::new(self.trace.clone()).chain(self)
     }
 }
 
@@ -82,10 +269,14 @@ impl<S: AsyncRead + AsyncBufRead + AsyncWrite + Unpin + Send + Sync> AsyncRead f
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        match &mut self.inner {
+    ) -> Poll<std::io::Result<()>> {
+        let res = match &mut self.inner {
             Inner::Data(data) => Pin::new(data).poll_read(cx, buf),
             Inner::Bdat(bdat) => Pin::new(bdat).poll_read(cx, buf),
+        };
+        if let Poll::Ready(Err(err)) = &res {
+            self.too_large |= TooLarge::is(err);
         }
+        res
     }
 }
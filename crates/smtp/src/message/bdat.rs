@@ -2,12 +2,14 @@ use std::{pin::Pin, task::Poll};
 
 use futures_util::{future::BoxFuture, ready, FutureExt};
 use line::write_flush;
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 use tracing::{debug, instrument};
 
+use super::TooLarge;
 use crate::{
     command::{read_cmd, Command},
     io::bye,
+    reply::Reply,
 };
 
 // future that returns the stream politely
@@ -24,15 +26,40 @@ pub enum Inner<'a, S: AsyncRead + AsyncWrite + Unpin> {
 
 pub struct Bdat<'a, S: AsyncRead + AsyncWrite + Unpin> {
     inner: Inner<'a, S>,
+    /// How many more bytes may be announced by BDAT commands.
+    remaining: u64,
+    /// Set once the message is too large. The remaining chunks are
+    /// discarded.
+    too_large: bool,
 }
 
 impl<'a, S: AsyncRead + AsyncWrite + Unpin> Bdat<'a, S> {
-    pub fn new(stream: &'a mut S, size: u64, last: bool) -> Self {
-        Self {
+    pub fn new(stream: &'a mut S, size: u64, last: bool, max_size: Option<u64>) -> Self {
+        let mut bdat = Self {
             inner: Inner::Read {
                 stream: stream.take(size),
                 last,
             },
+            remaining: max_size.unwrap_or(u64::MAX),
+            too_large: false,
+        };
+        bdat.announce(size);
+        bdat
+    }
+
+    /// Account for a chunk of `size` bytes.
+    fn announce(&mut self, size: u64) {
+        match self.remaining.checked_sub(size) {
+            Some(remaining) => self.remaining = remaining,
+            None => self.too_large = true,
+        }
+    }
+
+    fn end(&self) -> Poll<std::io::Result<()>> {
+        if self.too_large {
+            Poll::Ready(Err(TooLarge.into_io()))
+        } else {
+            Poll::Ready(Ok(()))
         }
     }
 
@@ -48,50 +75,63 @@ impl<'a, S: AsyncRead + AsyncWrite + Unpin> Bdat<'a, S> {
 async fn next_bdat<S: AsyncRead + AsyncBufRead + AsyncWrite + Unpin>(
     stream: &mut S,
 ) -> std::io::Result<(u64, bool)> {
-    write_flush(stream, "250 ok\r\n").await?; // request more data
+    write_flush(stream, Reply::ok().to_string()).await?; // request more data
 
     loop {
         match read_cmd(stream).await? {
-            Some(Command::Bdat { size, last }) => {
+            Some(Ok(Command::Bdat { size, last })) => {
                 debug!(?size, ?last, "got bdat command");
                 return Ok((size, last));
             }
-            Some(Command::Quit) => {
+            Some(Ok(Command::Quit)) => {
                 bye(stream).await?;
                 return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
             }
-            Some(Command::Rset) | None => {
+            Some(Ok(Command::Rset)) | None => {
                 return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
             }
-            Some(Command::Noop) => write_flush(stream, "250 ok\r\n").await?,
-            Some(cmd) => {
+            Some(Ok(Command::Noop)) => write_flush(stream, Reply::ok().to_string()).await?,
+            Some(Err(e)) => write_flush(stream, Reply::from(e).to_string()).await?,
+            Some(Ok(cmd)) => {
                 debug!(?cmd, "unexpected command");
-                write_flush(stream, "503 expected BDAT\r\n").await?;
+                write_flush(stream, Reply::bad_sequence("expected BDAT").to_string()).await?;
             }
         }
     }
 }
 
-impl<'a, T: AsyncRead + AsyncBufRead + AsyncWrite + Unpin + Send + Sync> AsyncRead for Bdat<'a, T> {
+impl<T: AsyncRead + AsyncBufRead + AsyncWrite + Unpin + Send + Sync> AsyncRead for Bdat<'_, T> {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
         loop {
+            let too_large = self.too_large;
             match &mut self.inner {
                 Inner::Read { stream, last } => {
-                    let before = buf.filled().len();
                     let mut stream = Pin::new(stream);
-                    ready!(stream.as_mut().poll_read(cx, buf))?;
 
-                    if before != buf.filled().len() {
-                        return Poll::Ready(Ok(()));
+                    if too_large {
+                        let mut discard = [0; 1024];
+                        let mut discard = ReadBuf::new(&mut discard);
+                        ready!(stream.as_mut().poll_read(cx, &mut discard))?;
+
+                        if !discard.filled().is_empty() {
+                            continue;
+                        }
+                    } else {
+                        let before = buf.filled().len();
+                        ready!(stream.as_mut().poll_read(cx, buf))?;
+
+                        if before != buf.filled().len() {
+                            return Poll::Ready(Ok(()));
+                        }
                     }
 
                     if stream.limit() == 0 {
                         if *last {
-                            return std::task::Poll::Ready(Ok(()));
+                            return self.end();
                         }
 
                         let stream = self.take_stream().unwrap();
@@ -112,8 +152,9 @@ impl<'a, T: AsyncRead + AsyncBufRead + AsyncWrite + Unpin + Send + Sync> AsyncRe
                     let (stream, result) = futures_util::ready!(future.poll_unpin(cx));
                     let (size, last) = result?;
 
+                    self.announce(size);
                     if size == 0 && last {
-                        return Poll::Ready(Ok(()));
+                        return self.end();
                     }
 
                     self.inner = Inner::Read {
@@ -132,6 +173,7 @@ mod tests {
     use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 
     use super::Bdat;
+    use crate::message::TooLarge;
 
     #[tokio::test]
     async fn bdat() -> anyhow::Result<()> {
@@ -139,7 +181,7 @@ mod tests {
 
         let task = tokio::spawn(async move {
             let mut server = BufReader::new(server);
-            let mut bdat = Bdat::new(&mut server, 4, false); // C: BDAT 4
+            let mut bdat = Bdat::new(&mut server, 4, false, None); // C: BDAT 4
 
             let mut buf = Vec::new();
             bdat.read_to_end(&mut buf).await?;
@@ -156,7 +198,31 @@ mod tests {
 
         let mut from_server = String::new();
         client.read_to_string(&mut from_server).await?;
-        assert_eq!(from_server, "250 ok\r\n250 ok\r\n");
+        assert_eq!(from_server, "250 2.0.0 ok\r\n250 2.0.0 ok\r\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn too_large() -> anyhow::Result<()> {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        let task = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            let mut bdat = Bdat::new(&mut server, 4, false, Some(6)); // C: BDAT 4
+
+            let mut buf = Vec::new();
+            let err = bdat.read_to_end(&mut buf).await.unwrap_err();
+            assert!(TooLarge::is(&err));
+            anyhow::Ok(buf)
+        });
+
+        client.write_all(b"Edel").await?;
+        client.write_all(b"BDAT 3 LAST\r\n").await?;
+        client.write_all(b"wei").await?;
+
+        // the last chunk was discarded
+        assert_eq!(task.await??, b"Edel");
 
         Ok(())
     }
@@ -6,6 +6,8 @@ use std::{
 use futures_util::{ready, FutureExt};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 
+use super::TooLarge;
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 enum State {
     #[default]
@@ -69,13 +71,19 @@ impl State {
 pub struct Data<'a, S: AsyncRead + AsyncWrite + Unpin> {
     pub stream: &'a mut S,
     state: State,
+    /// How many more bytes may be read.
+    remaining: u64,
+    /// Set once the message is too large. The rest of it is discarded.
+    too_large: bool,
 }
 
 impl<'a, S: AsyncRead + AsyncWrite + Unpin> Data<'a, S> {
-    pub fn new(stream: &'a mut S) -> Self {
+    pub fn new(stream: &'a mut S, max_size: Option<u64>) -> Self {
         Self {
             stream,
             state: State::default(),
+            remaining: max_size.unwrap_or(u64::MAX),
+            too_large: false,
         }
     }
 
@@ -84,7 +92,7 @@ impl<'a, S: AsyncRead + AsyncWrite + Unpin> Data<'a, S> {
     }
 }
 
-impl<'a, S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Data<'a, S> {
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Data<'_, S> {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -109,11 +117,22 @@ impl<'a, S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Data<'a, S> {
 
             this.state.advance(buf, b);
 
-            if buf.filled().len() != filled_before {
+            let n = (buf.filled().len() - filled_before) as u64;
+            if this.too_large || n > this.remaining {
+                // keep reading until the end of the message, without
+                // returning anything
+                this.too_large = true;
+                buf.set_filled(filled_before);
+            } else if n > 0 {
+                this.remaining -= n;
                 return Poll::Ready(Ok(()));
             }
         }
 
+        if this.too_large {
+            return Poll::Ready(Err(TooLarge.into_io()));
+        }
+
         Poll::Ready(Ok(()))
     }
 }
@@ -124,7 +143,7 @@ pub async fn fuzz(data: Box<[u8]>) {
 
     let (mut client, server) = tokio::io::duplex(1024);
     let mut server = BufReader::new(server);
-    let mut reader = Data::new(&mut server);
+    let mut reader = Data::new(&mut server, None);
 
     client.write_all(&data).await.unwrap();
     client.shutdown().await.unwrap();
@@ -138,12 +157,13 @@ mod tests {
     use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 
     use super::Data;
+    use crate::message::TooLarge;
 
     #[tokio::test]
     async fn data() -> anyhow::Result<()> {
         let (mut client, server) = tokio::io::duplex(1024);
         let mut server = BufReader::new(server);
-        let mut reader = Data::new(&mut server);
+        let mut reader = Data::new(&mut server, None);
 
         client
             .write_all(b"We've been trying to reach you\r\n")
@@ -164,4 +184,27 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn too_large() -> anyhow::Result<()> {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = BufReader::new(server);
+        let mut reader = Data::new(&mut server, Some(8));
+
+        client
+            .write_all(b"Subject: too long\r\n.\r\nQUIT\r\n")
+            .await?;
+
+        let mut message = Vec::new();
+        let err = reader.read_to_end(&mut message).await.unwrap_err();
+        assert!(TooLarge::is(&err));
+        assert_eq!(message, b"Subject:");
+
+        // the rest of the message was discarded
+        let mut rest = [0; 6];
+        server.read_exact(&mut rest).await?;
+        assert_eq!(&rest, b"QUIT\r\n");
+
+        Ok(())
+    }
 }
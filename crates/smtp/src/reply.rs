@@ -0,0 +1,290 @@
+//! SMTP replies ([Section 4.2] of RFC 5321) with enhanced status codes
+//! ([RFC 2034], [RFC 3463]).
+//!
+//! [Section 4.2]: https://datatracker.ietf.org/doc/html/rfc5321#section-4.2
+//! [RFC 2034]: https://datatracker.ietf.org/doc/html/rfc2034
+//! [RFC 3463]: https://datatracker.ietf.org/doc/html/rfc3463
+
+use std::{borrow::Cow, fmt};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt};
+
+use crate::LINE_LIMIT;
+
+/// An enhanced status code, `class.subject.detail`.
+///
+/// ```
+/// # use smtp::reply::EnhancedCode;
+/// assert_eq!(EnhancedCode::new(5, 1, 1).to_string(), "5.1.1");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnhancedCode {
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl EnhancedCode {
+    #[must_use]
+    pub const fn new(class: u8, subject: u16, detail: u16) -> Self {
+        Self {
+            class,
+            subject,
+            detail,
+        }
+    }
+}
+
+impl fmt::Display for EnhancedCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+/// An SMTP reply. Replies with more than one line of text are sent as
+/// multiline replies.
+///
+/// ```
+/// # use smtp::reply::{EnhancedCode, Reply};
+/// let reply = Reply::new(550, EnhancedCode::new(5, 1, 1), "no such user")
+///     .with_line("try again later");
+///
+/// assert_eq!(
+///     reply.to_string(),
+///     "550-5.1.1 no such user\r\n\
+///     550 5.1.1 try again later\r\n"
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    /// The basic reply code, e.g. `250`.
+    pub code: u16,
+    /// Enhanced status codes must not be used in the greeting and in
+    /// replies to HELO and EHLO.
+    pub enhanced: Option<EnhancedCode>,
+    pub lines: Vec<Cow<'static, str>>,
+}
+
+impl Reply {
+    pub fn new(
+        code: u16,
+        enhanced: impl Into<Option<EnhancedCode>>,
+        text: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        Self {
+            code,
+            enhanced: enhanced.into(),
+            lines: vec![text.into()],
+        }
+    }
+
+    /// Add a line of text.
+    #[must_use]
+    pub fn with_line(mut self, text: impl Into<Cow<'static, str>>) -> Self {
+        self.lines.push(text.into());
+        self
+    }
+
+    /// Whether the reply indicates success (`2yz`) or an intermediate
+    /// state (`3yz`).
+    #[must_use]
+    pub const fn is_positive(&self) -> bool {
+        self.code < 400
+    }
+
+    /// `250 2.0.0 ok`
+    #[must_use]
+    pub fn ok() -> Self {
+        Self::new(250, EnhancedCode::new(2, 0, 0), "ok")
+    }
+
+    /// `503 5.5.1`: the command is not allowed at this point.
+    pub fn bad_sequence(text: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(503, EnhancedCode::new(5, 5, 1), text)
+    }
+
+    /// `552 5.3.4`: the message is larger than the maximum message size.
+    #[must_use]
+    pub fn too_large() -> Self {
+        Self::new(
+            552,
+            EnhancedCode::new(5, 3, 4),
+            "message size exceeds fixed maximum message size",
+        )
+    }
+
+    /// `554 5.0.0`: the message was not accepted for delivery.
+    #[must_use]
+    pub fn rejected() -> Self {
+        Self::new(554, EnhancedCode::new(5, 0, 0), "transaction failed")
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("syntax error")]
+    Syntax,
+}
+
+/// Split `x.y.z ` off the start of a reply line.
+fn enhanced_code(line: &str) -> Option<(EnhancedCode, &str)> {
+    let (code, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let mut parts = code.split('.');
+    let class = parts.next()?.parse().ok()?;
+    let subject = parts.next()?.parse().ok()?;
+    let detail = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((EnhancedCode::new(class, subject, detail), rest))
+}
+
+impl Reply {
+    /// Read a possibly multiline reply asynchronously. If `enhanced` is
+    /// set, i.e. the server supports `ENHANCEDSTATUSCODES`, the enhanced
+    /// status code is split off the text.
+    ///
+    /// ```
+    /// # use smtp::reply::{EnhancedCode, Reply};
+    /// # tokio_test::block_on(async {
+    /// let mut reply = tokio::io::BufReader::new(
+    ///     "550-5.1.1 no such user\r\n550 5.1.1 try again later\r\n".as_bytes(),
+    /// );
+    ///
+    /// assert_eq!(
+    ///     Reply::read(&mut reply, true).await.unwrap(),
+    ///     Reply::new(550, EnhancedCode::new(5, 1, 1), "no such user")
+    ///         .with_line("try again later"),
+    /// );
+    /// # });
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ParseError::Syntax`] if the reply is malformed or the
+    /// connection was closed.
+    pub async fn read<R: AsyncRead + AsyncBufRead + Unpin>(
+        reader: &mut R,
+        enhanced: bool,
+    ) -> Result<Self, ParseError> {
+        let mut line = Vec::new();
+        let mut reply: Option<Self> = None;
+
+        loop {
+            line.clear();
+            if reader
+                .take(LINE_LIMIT as _)
+                .read_until(b'\n', &mut line)
+                .await?
+                < 4
+            {
+                return Err(ParseError::Syntax);
+            }
+
+            let line = std::str::from_utf8(&line)
+                .map_err(|_| ParseError::Syntax)?
+                .trim_end_matches(['\r', '\n']);
+            let code = line
+                .get(..3)
+                .filter(|code| code.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|code| code.parse().ok())
+                .ok_or(ParseError::Syntax)?;
+            let last = match line.as_bytes().get(3) {
+                Some(b' ') | None => true,
+                Some(b'-') => false,
+                _ => return Err(ParseError::Syntax),
+            };
+
+            let mut text = line.get(4..).unwrap_or_default();
+            let mut line_enhanced = None;
+            if enhanced {
+                if let Some((code, rest)) = enhanced_code(text) {
+                    line_enhanced = Some(code);
+                    text = rest;
+                }
+            }
+
+            match &mut reply {
+                None => {
+                    reply = Some(Self {
+                        code,
+                        enhanced: line_enhanced,
+                        lines: vec![text.to_owned().into()],
+                    });
+                }
+                Some(reply) if reply.code == code => reply.lines.push(text.to_owned().into()),
+                Some(_) => return Err(ParseError::Syntax),
+            }
+
+            if last {
+                return reply.ok_or(ParseError::Syntax);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = self.lines.len().saturating_sub(1);
+        for (i, line) in self.lines.iter().enumerate() {
+            let separator = if i == last { ' ' } else { '-' };
+            write!(f, "{}{separator}", self.code)?;
+            if let Some(enhanced) = self.enhanced {
+                write!(f, "{enhanced} ")?;
+            }
+            write!(f, "{line}\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::BufReader;
+
+    use super::{EnhancedCode, ParseError, Reply};
+
+    #[test]
+    fn fmt() {
+        assert_eq!(Reply::ok().to_string(), "250 2.0.0 ok\r\n");
+        assert_eq!(
+            Reply::new(354, None, "go ahead").to_string(),
+            "354 go ahead\r\n"
+        );
+        assert_eq!(
+            Reply::new(250, EnhancedCode::new(2, 1, 5), "a")
+                .with_line("b")
+                .with_line("c")
+                .to_string(),
+            "250-2.1.5 a\r\n250-2.1.5 b\r\n250 2.1.5 c\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn read() {
+        let reply = Reply::new(550, EnhancedCode::new(5, 1, 1), "a")
+            .with_line("b")
+            .to_string();
+        assert_eq!(
+            Reply::read(&mut BufReader::new(reply.as_bytes()), false)
+                .await
+                .unwrap(),
+            Reply::new(550, None, "5.1.1 a").with_line("5.1.1 b")
+        );
+        assert_eq!(
+            Reply::read(&mut BufReader::new("220\r\n".as_bytes()), true)
+                .await
+                .unwrap(),
+            Reply::new(220, None, "")
+        );
+
+        for invalid in ["250-a\r\n251 b\r\n", "250-a\r\n", "25x a\r\n", "250_a\r\n"] {
+            assert!(matches!(
+                Reply::read(&mut BufReader::new(invalid.as_bytes()), true).await,
+                Err(ParseError::Syntax)
+            ));
+        }
+    }
+}
@@ -1,17 +1,59 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
+use auth::guard::Guard;
+use line::stream::{MaybeTls, ServerTlsStream};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::rustls;
+use util::limit::RateLimiter;
 
-use self::session::Session;
+use self::{
+    policy::{GreylistPolicy, RecipientPolicy, SenderPolicy},
+    session::Session,
+};
 
+pub mod milter;
+pub mod policy;
 pub mod session;
+pub mod submission;
+
+/// What a server is for, which decides what clients must do before they
+/// may send mail.
+#[derive(Debug, Clone)]
+pub enum Profile {
+    /// Relaying mail from other servers on port 25
+    /// ([RFC 5321](https://datatracker.ietf.org/doc/html/rfc5321)).
+    Mx,
+    /// Message submission by our users on port 587 or 465 ([RFC 6409]).
+    /// Clients must use TLS and authenticate before `MAIL`, and may only
+    /// send as the addresses `senders` says they own.
+    ///
+    /// [RFC 6409]: https://datatracker.ietf.org/doc/html/rfc6409
+    Submission { senders: Arc<dyn SenderPolicy> },
+    /// Local delivery for an MTA in front of us ([RFC 2033]). Clients
+    /// greet with `LHLO` and get one reply per recipient after the message.
+    ///
+    /// [RFC 2033]: https://datatracker.ietf.org/doc/html/rfc2033
+    Lmtp,
+}
 
 #[derive(Debug)]
 pub struct Context<A: auth::Validator> {
     pub hostname: String,
     pub tls: Option<Arc<rustls::ServerConfig>>,
     pub auth: Arc<A>,
+    /// The maximum message size in bytes, advertised with `SIZE`.
+    pub max_message_size: Option<u64>,
+    /// Decides which recipients to accept.
+    pub recipients: Arc<dyn RecipientPolicy>,
+    /// Defers accepted recipients for unauthenticated clients.
+    pub greylist: Option<Arc<dyn GreylistPolicy>>,
+    pub profile: Profile,
+    /// A content filter to consult at each step of the conversation.
+    pub milter: Option<milter::Config>,
+    /// Limits messages per client and recipients per message.
+    pub limiter: Option<Arc<RateLimiter>>,
+    /// Slows down and locks out password guessing.
+    pub guard: Option<Arc<Guard>>,
 }
 
 impl<A: auth::Validator> Clone for Context<A> {
@@ -20,6 +62,13 @@ impl<A: auth::Validator> Clone for Context<A> {
             hostname: self.hostname.clone(),
             tls: self.tls.clone(),
             auth: Arc::clone(&self.auth),
+            max_message_size: self.max_message_size,
+            recipients: Arc::clone(&self.recipients),
+            greylist: self.greylist.clone(),
+            profile: self.profile.clone(),
+            milter: self.milter.clone(),
+            limiter: self.limiter.clone(),
+            guard: self.guard.clone(),
         }
     }
 }
@@ -34,8 +83,13 @@ impl<A: auth::Validator> Server<A> {
         Self { context }
     }
 
+    /// Start a session with the client at `peer`.
     #[must_use]
-    pub fn accept<IO: AsyncRead + AsyncWrite + Unpin>(&self, stream: IO) -> Session<IO, A> {
-        Session::new(stream, self.context.clone())
+    pub fn accept<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: impl Into<MaybeTls<ServerTlsStream<IO>, IO>>,
+        peer: SocketAddr,
+    ) -> Session<IO, A> {
+        Session::new(stream, peer, self.context.clone())
     }
 }
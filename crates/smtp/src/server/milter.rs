@@ -0,0 +1,761 @@
+//! A client for the Sendmail milter protocol, which lets external content
+//! filters such as rspamd and opendkim take part in the SMTP conversation.
+//!
+//! The protocol is only documented by libmilter; this implements version
+//! 6. Each packet is a 32-bit big-endian length, a command byte and the
+//! command's data. The server sends one packet for each step of the
+//! conversation and, unless the milter asked not to, waits for the
+//! milter's response before replying to the client.
+
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use auth::Identity;
+use email_address::EmailAddress;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    time::timeout,
+};
+use tracing::{debug, instrument};
+
+use crate::{
+    header,
+    reply::{EnhancedCode, Reply},
+};
+
+const VERSION: u32 = 6;
+
+/// The largest packet accepted from a milter.
+const MAX_PACKET: usize = 1 << 20;
+
+/// The largest body chunk sent in one packet.
+const MAX_CHUNK: usize = 65535;
+
+// Actions the milter may take at the end of the message (`SMFIF_*`).
+const ADD_HEADERS: u32 = 0x01;
+const CHANGE_HEADERS: u32 = 0x10;
+const QUARANTINE: u32 = 0x20;
+
+/// A step the server tells the milter about. The milter may ask for it to
+/// be skipped (`SMFIP_NO*`) or not to be replied to (`SMFIP_NR_*`).
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    command: u8,
+    skip: u32,
+    no_reply: u32,
+}
+
+const CONNECT: Step = Step {
+    command: b'C',
+    skip: 0x01,
+    no_reply: 0x1000,
+};
+const HELO: Step = Step {
+    command: b'H',
+    skip: 0x02,
+    no_reply: 0x2000,
+};
+const MAIL: Step = Step {
+    command: b'M',
+    skip: 0x04,
+    no_reply: 0x4000,
+};
+const RCPT: Step = Step {
+    command: b'R',
+    skip: 0x08,
+    no_reply: 0x8000,
+};
+const BODY: Step = Step {
+    command: b'B',
+    skip: 0x10,
+    no_reply: 0x8_0000,
+};
+const HEADER: Step = Step {
+    command: b'L',
+    skip: 0x20,
+    no_reply: 0x80,
+};
+const END_OF_HEADERS: Step = Step {
+    command: b'N',
+    skip: 0x40,
+    no_reply: 0x4_0000,
+};
+const DATA: Step = Step {
+    command: b'T',
+    skip: 0x200,
+    no_reply: 0x1_0000,
+};
+
+/// The protocol flags we can honor.
+const PROTOCOL: u32 = {
+    let steps = [
+        CONNECT,
+        HELO,
+        MAIL,
+        RCPT,
+        BODY,
+        HEADER,
+        END_OF_HEADERS,
+        DATA,
+    ];
+    let mut flags = 0;
+    let mut i = 0;
+    while i < steps.len() {
+        flags |= steps[i].skip | steps[i].no_reply;
+        i += 1;
+    }
+    flags
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("milter timed out")]
+    Timeout,
+    #[error("malformed milter packet")]
+    Malformed,
+    #[error("unexpected milter response {0:?}")]
+    Unexpected(char),
+    #[error("milter asked for unsupported protocol features")]
+    Unsupported,
+    #[error("invalid milter address {0:?}, expected unix:<path> or inet:<host>:<port>")]
+    InvalidAddress(String),
+}
+
+/// Where a milter listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// Parse an address in the form Postfix uses, `unix:<path>` or
+/// `inet:<host>:<port>`.
+///
+/// ```
+/// # use smtp::server::milter::Address;
+/// assert_eq!(
+///     "inet:localhost:11332".parse::<Address>().unwrap(),
+///     Address::Tcp("localhost:11332".to_owned()),
+/// );
+/// assert_eq!(
+///     "unix:/run/opendkim.sock".parse::<Address>().unwrap(),
+///     Address::Unix("/run/opendkim.sock".into()),
+/// );
+/// ```
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(path.into())),
+            Some(("inet", addr)) if addr.contains(':') => Ok(Self::Tcp(addr.to_owned())),
+            _ => Err(Error::InvalidAddress(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "inet:{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub address: Address,
+    /// How long to wait for each response.
+    pub timeout: Duration,
+}
+
+/// What the milter decided at a step.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Response {
+    /// Go on with the next step.
+    #[default]
+    Continue,
+    /// Accept without consulting the milter again, for the rest of the
+    /// connection if given in response to the connection or HELO, and for
+    /// the rest of the transaction otherwise.
+    Accept,
+    /// Accept the message, but don't deliver it.
+    Discard,
+    /// Reject the command, or the message at the end of it. The reply is
+    /// `4yz` if the client should try again later.
+    Reject(Reply),
+}
+
+/// What the milter decided about a message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outcome {
+    pub response: Response,
+    /// Set if the milter asked for the message to be quarantined.
+    pub quarantine: Option<String>,
+}
+
+/// A step of the SMTP conversation before the message.
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    Connect {
+        /// The client's host name, or its address in brackets.
+        hostname: &'a str,
+        peer: SocketAddr,
+    },
+    Helo(&'a str),
+    Mail {
+        from: Option<&'a EmailAddress>,
+        /// The user the client authenticated as, passed to the milter as
+        /// the `{auth_authen}` macro.
+        identity: Option<&'a Identity>,
+    },
+    Rcpt(&'a EmailAddress),
+}
+
+/// A change to the message requested at the end of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Modification {
+    /// Add a header field after the others.
+    Add {
+        name: String,
+        value: String,
+    },
+    /// Insert a header field before the one at `index`.
+    Insert {
+        index: usize,
+        name: String,
+        value: String,
+    },
+    /// Replace the `index`th field named `name`, counting from 1. An empty
+    /// value removes the field.
+    Change {
+        index: usize,
+        name: String,
+        value: String,
+    },
+    Quarantine(String),
+}
+
+/// `451 4.7.1`: the milter couldn't be reached or failed.
+pub(crate) fn unavailable() -> Reply {
+    Reply::new(
+        451,
+        EnhancedCode::new(4, 7, 1),
+        "content filter unavailable, try again later",
+    )
+}
+
+pub trait Stream: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Sync + Unpin> Stream for S {}
+
+/// A connection to a milter.
+pub struct Milter {
+    stream: Box<dyn Stream>,
+    timeout: Duration,
+    /// The protocol flags the milter asked for.
+    protocol: u32,
+    /// Set once the milter accepted the connection, after which it isn't
+    /// consulted again.
+    accepted: bool,
+    /// The milter's decision about the current transaction, if it made
+    /// one before the end of the message.
+    decided: Option<Response>,
+    /// Whether the milter has to be told to abort the transaction before
+    /// the next one starts.
+    transaction: bool,
+}
+
+impl Milter {
+    /// Connect to the milter at `config.address` and negotiate the
+    /// protocol.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the connection or the negotiation fails.
+    #[instrument(skip_all, fields(address = %config.address))]
+    pub async fn connect(config: &Config) -> Result<Self, Error> {
+        let stream: Box<dyn Stream> = match &config.address {
+            Address::Tcp(addr) => Box::new(
+                timeout(config.timeout, TcpStream::connect(addr))
+                    .await
+                    .map_err(|_| Error::Timeout)??,
+            ),
+            Address::Unix(path) => Box::new(
+                timeout(config.timeout, UnixStream::connect(path))
+                    .await
+                    .map_err(|_| Error::Timeout)??,
+            ),
+        };
+        Self::new(stream, config.timeout).await
+    }
+
+    /// Negotiate the protocol with the milter on `stream`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the milter doesn't speak a compatible version of
+    /// the protocol.
+    pub async fn new(stream: impl Stream + 'static, timeout: Duration) -> Result<Self, Error> {
+        let mut milter = Self {
+            stream: Box::new(stream),
+            timeout,
+            protocol: 0,
+            accepted: false,
+            decided: None,
+            transaction: false,
+        };
+
+        let mut options = Vec::with_capacity(12);
+        options.extend_from_slice(&VERSION.to_be_bytes());
+        options.extend_from_slice(&(ADD_HEADERS | CHANGE_HEADERS | QUARANTINE).to_be_bytes());
+        options.extend_from_slice(&PROTOCOL.to_be_bytes());
+        milter.send(b'O', &options).await?;
+
+        let (command, data) = milter.receive().await?;
+        if command != b'O' {
+            return Err(Error::Unexpected(command.into()));
+        }
+        let word = |i: usize| -> Result<u32, Error> {
+            let bytes = data.get(i * 4..i * 4 + 4).ok_or(Error::Malformed)?;
+            Ok(u32::from_be_bytes(bytes.try_into().unwrap_or_default()))
+        };
+        let (version, protocol) = (word(0)?, word(2)?);
+        if version < 2 || protocol & !PROTOCOL != 0 {
+            return Err(Error::Unsupported);
+        }
+        debug!(version, protocol, "negotiated milter protocol");
+        milter.protocol = protocol;
+
+        Ok(milter)
+    }
+
+    async fn send(&mut self, command: u8, data: &[u8]) -> Result<(), Error> {
+        let len = u32::try_from(data.len() + 1).map_err(|_| Error::Malformed)?;
+        let mut packet = Vec::with_capacity(data.len() + 5);
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.push(command);
+        packet.extend_from_slice(data);
+
+        let write = async {
+            self.stream.write_all(&packet).await?;
+            self.stream.flush().await
+        };
+        timeout(self.timeout, write)
+            .await
+            .map_err(|_| Error::Timeout)??;
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        let read = async {
+            let len = self.stream.read_u32().await? as usize;
+            if len == 0 || len > MAX_PACKET {
+                return Err(Error::Malformed);
+            }
+            let mut packet = vec![0; len];
+            self.stream.read_exact(&mut packet).await?;
+            let data = packet.split_off(1);
+            Ok((packet[0], data))
+        };
+        timeout(self.timeout, read)
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// Read responses until one that decides `step`.
+    async fn response(&mut self) -> Result<Response, Error> {
+        loop {
+            let (command, data) = self.receive().await?;
+            if command == b'p' {
+                // progress, the milter needs more time
+                continue;
+            }
+            return decide(command, &data).await;
+        }
+    }
+
+    async fn step(&mut self, step: Step, data: &[u8]) -> Result<Response, Error> {
+        if self.protocol & step.skip != 0 {
+            return Ok(Response::Continue);
+        }
+        self.send(step.command, data).await?;
+        if self.protocol & step.no_reply != 0 {
+            return Ok(Response::Continue);
+        }
+        self.response().await
+    }
+
+    /// Tell the milter about `event`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if talking to the milter fails.
+    pub async fn event(&mut self, event: Event<'_>) -> Result<Response, Error> {
+        if let Event::Mail { .. } = event {
+            if self.transaction {
+                self.send(b'A', &[]).await?;
+                self.transaction = false;
+            }
+            self.decided = None;
+        }
+        if self.accepted || self.decided.is_some() {
+            return Ok(Response::Continue);
+        }
+
+        let response = match event {
+            Event::Connect { hostname, peer } => {
+                let mut data = cstring(hostname);
+                data.push(if peer.is_ipv4() { b'4' } else { b'6' });
+                data.extend_from_slice(&peer.port().to_be_bytes());
+                data.extend(cstring(&peer.ip().to_string()));
+                self.step(CONNECT, &data).await?
+            }
+            Event::Helo(helo) => self.step(HELO, &cstring(helo)).await?,
+            Event::Mail { from, identity } => {
+                if let Some(identity) = identity {
+                    let mut data = vec![MAIL.command];
+                    data.extend(cstring("{auth_authen}"));
+                    data.extend(cstring(&identity.0));
+                    self.send(b'D', &data).await?;
+                }
+                self.transaction = true;
+                let from = from.map(ToString::to_string).unwrap_or_default();
+                self.step(MAIL, &cstring(&format!("<{from}>"))).await?
+            }
+            Event::Rcpt(to) => self.step(RCPT, &cstring(&format!("<{to}>"))).await?,
+        };
+
+        match (&response, event) {
+            (Response::Accept, Event::Connect { .. } | Event::Helo(_)) => self.accepted = true,
+            (Response::Accept | Response::Discard, _) => self.decided = Some(response.clone()),
+            _ => (),
+        }
+        Ok(response)
+    }
+
+    /// Pass `message` to the milter and apply the changes it asks for.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if talking to the milter fails.
+    pub async fn message(&mut self, message: &mut Vec<u8>) -> Result<Outcome, Error> {
+        if self.accepted {
+            return Ok(Outcome::default());
+        }
+        if let Some(response) = self.decided.take() {
+            return Ok(Outcome {
+                response,
+                quarantine: None,
+            });
+        }
+
+        let response = self.send_message(message).await?;
+        if response != Response::Continue {
+            return Ok(Outcome {
+                response,
+                quarantine: None,
+            });
+        }
+
+        self.send(b'E', &[]).await?;
+        let mut modifications = Vec::new();
+        let response = loop {
+            let (command, data) = self.receive().await?;
+            let modification = match command {
+                b'p' => continue,
+                b'h' => match strings(&data).as_slice() {
+                    [name, value, ..] => Modification::Add {
+                        name: name.clone(),
+                        value: value.clone(),
+                    },
+                    _ => return Err(Error::Malformed),
+                },
+                b'i' | b'm' => {
+                    let (index, rest) = data.split_at_checked(4).ok_or(Error::Malformed)?;
+                    let index = u32::from_be_bytes(index.try_into().unwrap_or_default()) as usize;
+                    let strings = strings(rest);
+                    let [name, value, ..] = strings.as_slice() else {
+                        return Err(Error::Malformed);
+                    };
+                    let (name, value) = (name.clone(), value.clone());
+                    if command == b'i' {
+                        Modification::Insert { index, name, value }
+                    } else {
+                        Modification::Change { index, name, value }
+                    }
+                }
+                b'q' => Modification::Quarantine(strings(&data).swap_remove(0)),
+                _ => break decide(command, &data).await?,
+            };
+            modifications.push(modification);
+        };
+        self.transaction = false;
+
+        let mut outcome = Outcome {
+            response,
+            quarantine: None,
+        };
+        if matches!(outcome.response, Response::Continue | Response::Accept) {
+            for modification in modifications {
+                debug!(?modification, "modifying message");
+                match modification {
+                    Modification::Quarantine(reason) => outcome.quarantine = Some(reason),
+                    modification => modify(message, modification),
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Send the header fields and body of `message`, stopping early if the
+    /// milter decides.
+    async fn send_message(&mut self, message: &[u8]) -> Result<Response, Error> {
+        let response = self.step(DATA, &[]).await?;
+        if response != Response::Continue {
+            return Ok(response);
+        }
+
+        let (fields, end) = header::fields(message);
+        for field in &fields {
+            let mut data = cstring(&field.name);
+            let value = field.raw_value(message);
+            data.extend(cstring(&value));
+            let response = self.step(HEADER, &data).await?;
+            if response != Response::Continue {
+                return Ok(response);
+            }
+        }
+        let response = self.step(END_OF_HEADERS, &[]).await?;
+        if response != Response::Continue {
+            return Ok(response);
+        }
+
+        let body = &message[end..];
+        let body = body
+            .strip_prefix(b"\r\n")
+            .or_else(|| body.strip_prefix(b"\n"))
+            .unwrap_or(body);
+        for chunk in body.chunks(MAX_CHUNK) {
+            let response = self.step(BODY, chunk).await?;
+            if response != Response::Continue {
+                return Ok(response);
+            }
+        }
+        Ok(Response::Continue)
+    }
+}
+
+/// The NUL-terminated strings in `data`.
+fn strings(data: &[u8]) -> Vec<String> {
+    data.split(|&c| c == 0)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// Data terminated by a NUL byte.
+fn cstring(s: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(s.len() + 1);
+    data.extend(s.bytes().filter(|&c| c != 0));
+    data.push(0);
+    data
+}
+
+/// Decode a response that decides a step.
+async fn decide(command: u8, data: &[u8]) -> Result<Response, Error> {
+    Ok(match command {
+        b'c' => Response::Continue,
+        b'a' => Response::Accept,
+        b'd' => Response::Discard,
+        b'r' => Response::Reject(Reply::new(
+            550,
+            EnhancedCode::new(5, 7, 1),
+            "rejected by content filter",
+        )),
+        b't' => Response::Reject(unavailable()),
+        b'y' => {
+            let text = String::from_utf8_lossy(data);
+            let text = format!("{}\r\n", text.trim_end_matches(['\0', '\r', '\n']));
+            let reply = Reply::read(&mut text.as_bytes(), true)
+                .await
+                .map_err(|_| Error::Malformed)?;
+            if !(400..600).contains(&reply.code) {
+                return Err(Error::Malformed);
+            }
+            Response::Reject(reply)
+        }
+        command => return Err(Error::Unexpected(command.into())),
+    })
+}
+
+/// A header field with line breaks normalized to CRLF.
+fn format_field(name: &str, value: &str) -> Vec<u8> {
+    let value = value.replace("\r\n", "\n").replace('\n', "\r\n");
+    format!("{name}: {value}\r\n").into_bytes()
+}
+
+fn modify(message: &mut Vec<u8>, modification: Modification) {
+    let (fields, end) = header::fields(message);
+    match modification {
+        Modification::Add { name, value } => {
+            message.splice(end..end, format_field(&name, &value));
+        }
+        Modification::Insert { index, name, value } => {
+            let at = fields.get(index).map_or(end, |field| field.range.start);
+            message.splice(at..at, format_field(&name, &value));
+        }
+        Modification::Change { index, name, value } => {
+            let field = fields
+                .iter()
+                .filter(|field| field.name.eq_ignore_ascii_case(&name))
+                .nth(index.saturating_sub(1));
+            match field {
+                Some(field) if value.is_empty() => {
+                    message.drain(field.range.clone());
+                }
+                Some(field) => {
+                    message.splice(field.range.clone(), format_field(&name, &value));
+                }
+                // a change to a field that doesn't exist adds it
+                None if !value.is_empty() => {
+                    message.splice(end..end, format_field(&name, &value));
+                }
+                None => (),
+            }
+        }
+        Modification::Quarantine(_) => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use auth::Identity;
+    use email_address::EmailAddress;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::{Event, Milter, Outcome, Response};
+    use crate::reply::{EnhancedCode, Reply};
+
+    async fn send(stream: &mut DuplexStream, command: u8, data: &[u8]) {
+        let len = u32::try_from(data.len() + 1).unwrap();
+        stream.write_all(&len.to_be_bytes()).await.unwrap();
+        stream.write_u8(command).await.unwrap();
+        stream.write_all(data).await.unwrap();
+    }
+
+    /// A milter that skips HELO, rejects spam@ recipients and marks every
+    /// message as spam. Returns the commands it received.
+    async fn fake(mut stream: DuplexStream) -> Vec<(char, Vec<u8>)> {
+        let mut received = Vec::new();
+        while let Ok(len) = stream.read_u32().await {
+            let mut packet = vec![0; len as usize];
+            stream.read_exact(&mut packet).await.unwrap();
+            let data = packet.split_off(1);
+            let command = packet[0];
+            received.push((command as char, data.clone()));
+
+            match command {
+                b'O' => {
+                    let mut options = Vec::new();
+                    options.extend_from_slice(&6_u32.to_be_bytes());
+                    options.extend_from_slice(&0x31_u32.to_be_bytes());
+                    // no HELO, no reply to headers
+                    options.extend_from_slice(&0x82_u32.to_be_bytes());
+                    send(&mut stream, b'O', &options).await;
+                }
+                b'R' if data.starts_with(b"<spam@") => {
+                    send(&mut stream, b'p', &[]).await;
+                    send(&mut stream, b'y', b"550 5.7.1 no spam here\0").await;
+                }
+                b'E' => {
+                    send(&mut stream, b'h', b"X-Spam\0yes\0").await;
+                    let mut change = 1_u32.to_be_bytes().to_vec();
+                    change.extend_from_slice(b"Subject\0[SPAM] hi\0");
+                    send(&mut stream, b'm', &change).await;
+                    let mut insert = 0_u32.to_be_bytes().to_vec();
+                    insert.extend_from_slice(b"X-Filter\0fake\0");
+                    send(&mut stream, b'i', &insert).await;
+                    send(&mut stream, b'q', b"looks spammy\0").await;
+                    send(&mut stream, b'c', &[]).await;
+                }
+                b'D' | b'A' | b'L' => (),
+                _ => send(&mut stream, b'c', &[]).await,
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn milter() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let fake = tokio::spawn(fake(server));
+
+        let mut milter = Milter::new(client, Duration::from_secs(5)).await.unwrap();
+        let connect = Event::Connect {
+            hostname: "[192.0.2.1]",
+            peer: "192.0.2.1:50000".parse().unwrap(),
+        };
+        assert_eq!(milter.event(connect).await.unwrap(), Response::Continue);
+        assert_eq!(
+            milter
+                .event(Event::Helo("client.example.org"))
+                .await
+                .unwrap(),
+            Response::Continue
+        );
+
+        let from = EmailAddress::new_unchecked("alice@example.org");
+        let identity = Identity("alice".to_owned());
+        let mail = Event::Mail {
+            from: Some(&from),
+            identity: Some(&identity),
+        };
+        assert_eq!(milter.event(mail).await.unwrap(), Response::Continue);
+        let spam = EmailAddress::new_unchecked("spam@example.com");
+        assert_eq!(
+            milter.event(Event::Rcpt(&spam)).await.unwrap(),
+            Response::Reject(Reply::new(550, EnhancedCode::new(5, 7, 1), "no spam here"))
+        );
+        let bob = EmailAddress::new_unchecked("bob@example.com");
+        assert_eq!(
+            milter.event(Event::Rcpt(&bob)).await.unwrap(),
+            Response::Continue
+        );
+
+        let mut message = b"Subject: hi\r\n\r\nhello\r\n".to_vec();
+        let outcome = milter.message(&mut message).await.unwrap();
+        assert_eq!(
+            outcome,
+            Outcome {
+                response: Response::Continue,
+                quarantine: Some("looks spammy".to_owned()),
+            }
+        );
+        assert_eq!(
+            message,
+            b"X-Filter: fake\r\n\
+            Subject: [SPAM] hi\r\n\
+            X-Spam: yes\r\n\
+            \r\n\
+            hello\r\n"
+        );
+
+        // the next transaction aborts nothing, as the last one is complete
+        assert_eq!(milter.event(mail).await.unwrap(), Response::Continue);
+        drop(milter);
+
+        let received = fake.await.unwrap();
+        let commands: String = received.iter().map(|(command, _)| command).collect();
+        assert_eq!(commands, "OCDMRRTLNBEDM");
+        assert_eq!(received[1].1, b"[192.0.2.1]\x004\xc3\x50192.0.2.1\0");
+        assert_eq!(received[2].1, b"M{auth_authen}\0alice\0");
+        assert_eq!(received[3].1, b"<alice@example.org>\0");
+        assert_eq!(received[7].1, b"Subject\0hi\0");
+        assert_eq!(received[9].1, b"hello\r\n");
+    }
+}
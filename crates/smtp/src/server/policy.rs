@@ -0,0 +1,123 @@
+//! Deciding which recipients to accept at `RCPT` time, so that mail for
+//! nonexistent users is rejected instead of bounced later, and which
+//! addresses submitting users may send as.
+
+use std::{fmt, net::IpAddr};
+
+use auth::Identity;
+use email_address::EmailAddress;
+
+use crate::{
+    message::Envelope,
+    reply::{EnhancedCode, Reply},
+};
+
+/// Whether to accept a recipient.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// `550 5.1.1`: the recipient is in a local domain but doesn't exist.
+    NoSuchUser,
+    /// `551 5.7.1`: the recipient isn't local and the client may not relay.
+    RelayDenied,
+    /// `452 4.5.3`: the transaction has too many recipients. The client
+    /// should send the message and try the remaining recipients later.
+    TooManyRecipients,
+    /// `451 4.3.0`: the recipient can't be checked right now.
+    TemporaryFailure,
+    /// `451 4.7.1`: the client hasn't tried this recipient before and
+    /// should come back later.
+    Greylisted,
+}
+
+impl From<Verdict> for Reply {
+    fn from(verdict: Verdict) -> Self {
+        match verdict {
+            Verdict::Accept => Reply::new(250, EnhancedCode::new(2, 1, 5), "ok"),
+            Verdict::NoSuchUser => Reply::new(550, EnhancedCode::new(5, 1, 1), "no such user"),
+            Verdict::RelayDenied => Reply::new(551, EnhancedCode::new(5, 7, 1), "relaying denied"),
+            Verdict::TooManyRecipients => {
+                Reply::new(452, EnhancedCode::new(4, 5, 3), "too many recipients")
+            }
+            Verdict::TemporaryFailure => Reply::new(
+                451,
+                EnhancedCode::new(4, 3, 0),
+                "temporary failure, try again later",
+            ),
+            Verdict::Greylisted => Reply::new(
+                451,
+                EnhancedCode::new(4, 7, 1),
+                "greylisted, try again later",
+            ),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+pub trait RecipientPolicy: fmt::Debug + Send + Sync {
+    /// Decide whether to accept `recipient` for the transaction in
+    /// `envelope`, which holds the recipients accepted so far. `identity`
+    /// is set if the client has authenticated, except on the MX profile,
+    /// which never relays.
+    async fn check(
+        &self,
+        recipient: &EmailAddress,
+        envelope: &Envelope,
+        identity: Option<&Identity>,
+    ) -> Verdict;
+}
+
+/// Accepts every recipient.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AcceptAll;
+
+#[async_trait::async_trait]
+impl RecipientPolicy for AcceptAll {
+    async fn check(
+        &self,
+        _recipient: &EmailAddress,
+        _envelope: &Envelope,
+        _identity: Option<&Identity>,
+    ) -> Verdict {
+        Verdict::Accept
+    }
+}
+
+/// Decides which addresses an authenticated user may use as the
+/// reverse-path and in the `From:` header of submitted mail
+/// ([Section 6.1] of RFC 6409).
+///
+/// [Section 6.1]: https://datatracker.ietf.org/doc/html/rfc6409#section-6.1
+#[async_trait::async_trait]
+pub trait SenderPolicy: fmt::Debug + Send + Sync {
+    /// Whether `identity` owns `address`.
+    async fn owns(&self, identity: &Identity, address: &EmailAddress) -> bool;
+}
+
+/// Users own the address they log in with and nothing else.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OwnAddress;
+
+#[async_trait::async_trait]
+impl SenderPolicy for OwnAddress {
+    async fn owns(&self, identity: &Identity, address: &EmailAddress) -> bool {
+        identity.0.eq_ignore_ascii_case(address.as_str())
+    }
+}
+
+/// Defers mail from unknown senders until they retry, which most spam
+/// software doesn't ([RFC 6647]). Only consulted for unauthenticated
+/// clients, after the recipient has been accepted.
+///
+/// [RFC 6647]: https://datatracker.ietf.org/doc/html/rfc6647
+#[async_trait::async_trait]
+pub trait GreylistPolicy: fmt::Debug + Send + Sync {
+    /// Whether to accept mail from `sender` at `peer` to `recipient` now,
+    /// or return [`Verdict::Greylisted`].
+    async fn check(
+        &self,
+        peer: IpAddr,
+        sender: Option<&EmailAddress>,
+        recipient: &EmailAddress,
+    ) -> Verdict;
+}
@@ -1,42 +1,91 @@
-use auth::Identity;
+use std::{
+    fmt::{self, Write},
+    net::SocketAddr,
+    ops::ControlFlow,
+    time::SystemTime,
+};
+
+use auth::{
+    guard::Failures,
+    sasl::{Mechanism, MechanismError, Plain, WhichMechanism},
+    Identity,
+};
+use base64::Engine;
+use email_address::EmailAddress;
 use line::{
+    compress::MaybeCompressed,
+    read_line,
     stream::{MaybeTls, ServerTlsStream},
     Connection,
 };
-use tokio::io::{AsyncRead, AsyncWrite, BufReader};
-use tracing::{debug, instrument};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, info, instrument, warn};
 
 use crate::{
-    command::{read_cmd, Command},
+    command::{
+        params::{MailParams, RcptParams},
+        read_cmd, Command,
+    },
     ehlo::{self, Extensions},
     io::bye,
-    message::{Envelope, Incoming},
+    message::{Client, Envelope, Incoming},
+    reply::{EnhancedCode, Reply},
+    server::{
+        milter::{self, Event, Milter, Response},
+        policy::Verdict,
+        Profile,
+    },
+    trace::Received,
 };
 
-type BufTlsStream<IO> = BufReader<MaybeTls<ServerTlsStream<IO>, IO>>;
+type BufTlsStream<IO> = BufReader<MaybeCompressed<MaybeTls<ServerTlsStream<IO>, IO>>>;
+
+const BASE64: base64::engine::GeneralPurpose = base64::engine::general_purpose::STANDARD;
+
+/// The longest SASL response line accepted, including the CRLF
+/// ([Section 4] of RFC 4954).
+///
+/// [Section 4]: https://datatracker.ietf.org/doc/html/rfc4954#section-4
+const AUTH_LINE_LIMIT: u64 = 12288;
 
 /// SMTP session with a client.
 pub struct Session<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> {
     connection: Connection<ServerTlsStream<IO>, IO>,
+    peer: SocketAddr,
     envelope: Option<Envelope>,
     helo_domain: Option<String>,
+    /// Whether the client greeted with EHLO.
+    esmtp: bool,
     identity: Option<Identity>,
     greeted: bool,
+    /// Replies that have not been sent yet. With PIPELINING
+    /// ([RFC 2920](https://datatracker.ietf.org/doc/html/rfc2920)), replies
+    /// to MAIL, RCPT and RSET are sent in batches.
+    pending: String,
     config: crate::server::Context<A>,
+    milter: Option<Milter>,
+    /// Failed authentication attempts on this connection.
+    auth_failures: Failures,
 }
 
 impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
     pub fn new(
         stream: impl Into<MaybeTls<ServerTlsStream<IO>, IO>>,
+        peer: SocketAddr,
         config: crate::server::Context<A>,
     ) -> Self {
         Self {
             connection: Connection::new(stream),
+            peer,
             envelope: None,
             helo_domain: None,
+            esmtp: false,
             identity: None,
             greeted: false,
+            pending: String::new(),
             config,
+            milter: None,
+            auth_failures: Failures::default(),
         }
     }
 
@@ -44,25 +93,88 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
         self.envelope = None;
     }
 
+    /// Queue a reply to be sent with the next batch.
+    fn queue_reply(&mut self, reply: impl fmt::Display) {
+        write!(self.pending, "{reply}").expect("writing to a String should not fail");
+    }
+
+    /// Send a reply along with any queued replies.
+    async fn reply(&mut self, reply: impl fmt::Display) -> std::io::Result<()> {
+        self.queue_reply(reply);
+        self.flush_replies().await
+    }
+
+    /// Send the queued replies.
+    async fn flush_replies(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        self.connection.write_flush(pending).await
+    }
+
     /// Send the SMTP greeting.
     async fn greet(&mut self) -> std::io::Result<()> {
-        self.connection
-            .write_flush(format!("220 {}\r\n", self.config.hostname))
+        self.reply(Reply::new(220, None, self.config.hostname.clone()))
             .await
     }
 
+    /// Connect to the milter, if there is one, and tell it about the
+    /// client. Returns the reply to turn the client away with.
+    async fn connect_milter(&mut self) -> Option<Reply> {
+        let config = self.config.milter.as_ref()?;
+        match Milter::connect(config).await {
+            Ok(milter) => self.milter = Some(milter),
+            Err(e) => {
+                warn!(%e, "failed to connect to milter");
+                return Some(Reply {
+                    code: 421,
+                    ..milter::unavailable()
+                });
+            }
+        }
+
+        let hostname = format!("[{}]", self.peer.ip());
+        let event = Event::Connect {
+            hostname: &hostname,
+            peer: self.peer,
+        };
+        // the client can only be turned away before the greeting
+        self.milter_event(event).await.map(|reply| Reply {
+            code: if reply.code >= 500 { 554 } else { 421 },
+            ..reply
+        })
+    }
+
+    /// Tell the milter, if there is one, about `event`. Returns the reply
+    /// to reject the command with.
+    async fn milter_event(&mut self, event: Event<'_>) -> Option<Reply> {
+        let milter = self.milter.as_mut()?;
+        match milter.event(event).await {
+            Ok(Response::Reject(reply)) => Some(reply),
+            Ok(Response::Continue | Response::Accept | Response::Discard) => None,
+            Err(e) => {
+                warn!(%e, "milter failed");
+                Some(milter::unavailable())
+            }
+        }
+    }
+
     /// Try to the current envelope and complain to the client if
     /// it's not completed.
     async fn take_envelope(&mut self) -> std::io::Result<Option<Envelope>> {
         match self.envelope.take() {
             None => {
-                self.connection
-                    .write_flush("503 need MAIL command\r\n")
-                    .await?;
+                self.reply(Reply::bad_sequence("need MAIL command")).await?;
                 Ok(None)
             }
             Some(envelope) if envelope.recipients.is_empty() => {
-                self.connection.write_flush("554 no recipients\r\n").await?;
+                self.reply(Reply::new(
+                    554,
+                    EnhancedCode::new(5, 5, 1),
+                    "no valid recipients",
+                ))
+                .await?;
                 self.envelope = Some(envelope);
                 Ok(None)
             }
@@ -76,46 +188,99 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
     async fn ehlo(&mut self, domain: String) -> std::io::Result<()> {
         debug!(?domain, "received ehlo");
         self.reset_mail_txn();
+        if let Some(reply) = self.milter_event(Event::Helo(&domain)).await {
+            return self.reply(reply).await;
+        }
         self.helo_domain = Some(domain);
+        self.esmtp = true;
 
-        let mut extensions = Extensions::_8BITMIME | Extensions::SMTPUTF8 | Extensions::CHUNKING;
+        let mut extensions = Extensions::_8BITMIME
+            | Extensions::SMTPUTF8
+            | Extensions::CHUNKING
+            | Extensions::PIPELINING
+            | Extensions::ENHANCEDSTATUSCODES
+            | Extensions::DSN;
 
         if self.config.tls.is_some() && self.connection.is_plain() {
             extensions |= Extensions::STARTTLS;
         }
 
-        self.connection
-            .write_flush(
-                ehlo::Response {
-                    domain: self.config.hostname.clone(),
-                    extensions,
-                    size: None,
-                    auth: ehlo::Auth::all(),
-                }
-                .to_string(),
-            )
-            .await
+        // don't offer to send passwords in the clear when TLS is required,
+        // nor to authenticate on the MX, where users have no business
+        let auth = if self.mx() || self.tls_required() {
+            ehlo::Auth::empty()
+        } else {
+            ehlo::Auth::PLAIN
+        };
+
+        self.reply(ehlo::Response {
+            domain: self.config.hostname.clone(),
+            extensions,
+            size: self.config.max_message_size,
+            auth,
+        })
+        .await
+    }
+
+    fn lmtp(&self) -> bool {
+        matches!(self.config.profile, Profile::Lmtp)
+    }
+
+    fn mx(&self) -> bool {
+        matches!(self.config.profile, Profile::Mx)
+    }
+
+    /// Whether the client has to use STARTTLS before authenticating, which
+    /// is the case for submission when TLS is available.
+    fn tls_required(&self) -> bool {
+        matches!(self.config.profile, Profile::Submission { .. })
+            && self.config.tls.is_some()
+            && self.connection.is_plain()
+    }
+
+    /// `530 5.7.0`, see [Section 4] of RFC 3207.
+    ///
+    /// [Section 4]: https://datatracker.ietf.org/doc/html/rfc3207#section-4
+    fn starttls_first() -> Reply {
+        Reply::new(
+            530,
+            EnhancedCode::new(5, 7, 0),
+            "must issue a STARTTLS command first",
+        )
     }
 
     async fn starttls(&mut self) -> std::io::Result<()> {
         if self.connection.is_tls() {
-            self.connection
-                .write_flush("454 Already using TLS\r\n")
-                .await?;
+            self.reply(Reply::bad_sequence("already using TLS")).await?;
             return Ok(());
         }
 
-        let tls_config = match &self.config.tls {
-            Some(tls_config) => tls_config.clone(),
-            None => {
-                self.connection
-                    .write_flush("454 TLS not available\r\n")
-                    .await?;
-                return Ok(());
-            }
+        let Some(tls_config) = self.config.tls.clone() else {
+            self.reply(Reply::new(
+                454,
+                EnhancedCode::new(4, 7, 0),
+                "TLS not available",
+            ))
+            .await?;
+            return Ok(());
         };
 
-        self.connection.write_flush("220 Go ahead\r\n").await?;
+        // anything pipelined after STARTTLS was sent in plain text, and must
+        // not be taken as sent over TLS (Section 4.2 of RFC 3207)
+        if !self.connection.stream_mut().buffer().is_empty() {
+            self.reply(Reply::bad_sequence(
+                "data sent after STARTTLS before its response",
+            ))
+            .await?;
+            return Ok(());
+        }
+
+        self.reply(Reply::new(
+            220,
+            EnhancedCode::new(2, 0, 0),
+            "ready to start TLS",
+        ))
+        .await?;
         self.connection.upgrade(&tls_config.into()).await?;
 
         // reset state
@@ -126,103 +291,1097 @@ impl<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator> Session<IO, A> {
         Ok(())
     }
 
+    fn client(&self) -> Client {
+        Client {
+            peer: self.peer,
+            helo: self.helo_domain.clone().unwrap_or_default(),
+            identity: self.identity.clone(),
+        }
+    }
+
+    /// The `Received:` header for a message with `envelope`.
+    fn received(&self, envelope: &Envelope) -> String {
+        let tls = self.connection.tls().map(|tls| tls.get_ref().1);
+        let version = tls
+            .and_then(|tls| tls.protocol_version())
+            .and_then(|version| version.as_str())
+            .map(|version| version.replace('_', "."));
+        let cipher = tls
+            .and_then(|tls| tls.negotiated_cipher_suite())
+            .and_then(|suite| suite.suite().as_str());
+
+        Received {
+            helo: self.helo_domain.as_deref().unwrap_or_default(),
+            peer: self.peer.ip(),
+            by: &self.config.hostname,
+            esmtp: self.esmtp,
+            tls: version.as_deref().zip(cipher),
+            identity: self.identity.as_ref(),
+            recipient: match envelope.recipients.as_slice() {
+                [recipient] => Some(&recipient.address),
+                _ => None,
+            },
+            date: SystemTime::now(),
+        }
+        .to_string()
+    }
+
+    async fn mail(&mut self, from: Option<EmailAddress>, params: MailParams) {
+        if self.helo_domain.is_none() {
+            self.queue_reply(Reply::bad_sequence("say HELO first"));
+            return;
+        }
+        if self.envelope.is_some() {
+            self.queue_reply(Reply::bad_sequence("transaction already started"));
+            return;
+        }
+        if params
+            .size
+            .zip(self.config.max_message_size)
+            .is_some_and(|(size, max)| size > max)
+        {
+            self.queue_reply(Reply::too_large());
+            return;
+        }
+
+        if let Profile::Submission { senders } = &self.config.profile {
+            if self.tls_required() {
+                self.queue_reply(Self::starttls_first());
+                return;
+            }
+            // https://datatracker.ietf.org/doc/html/rfc4954#section-6
+            let Some(identity) = &self.identity else {
+                self.queue_reply(Reply::new(
+                    530,
+                    EnhancedCode::new(5, 7, 0),
+                    "authentication required",
+                ));
+                return;
+            };
+            // the null reverse-path is allowed for notifications
+            if let Some(from) = &from {
+                if !senders.owns(identity, from).await {
+                    debug!(%from, ?identity, "sender not owned");
+                    self.queue_reply(Reply::new(
+                        553,
+                        EnhancedCode::new(5, 7, 1),
+                        format!("not authorized to send as {from}"),
+                    ));
+                    return;
+                }
+            }
+        }
+
+        if let Some(limiter) = &self.config.limiter {
+            let identity = self.identity.as_ref().map(|identity| identity.0.as_str());
+            if let Err(exceeded) = limiter.message(self.peer.ip(), identity) {
+                debug!(?identity, ?exceeded, "message rate exceeded");
+                self.queue_reply(Reply::new(
+                    451,
+                    EnhancedCode::new(4, 7, 1),
+                    format!(
+                        "too many messages, try again in {} seconds",
+                        exceeded.retry_secs()
+                    ),
+                ));
+                return;
+            }
+        }
+
+        let identity = self.identity.clone();
+        let event = Event::Mail {
+            from: from.as_ref(),
+            identity: identity.as_ref(),
+        };
+        if let Some(reply) = self.milter_event(event).await {
+            self.queue_reply(reply);
+            return;
+        }
+
+        self.envelope = Some(Envelope::new(from, params));
+        self.queue_reply(Reply::new(250, EnhancedCode::new(2, 1, 0), "ok"));
+    }
+
+    /// Read a SASL response from the client. `None` means the client
+    /// cancelled the exchange with `*`.
+    async fn sasl_response(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let mut buf = Vec::new();
+        let stream = self.connection.stream_mut();
+        match read_line(&mut stream.take(AUTH_LINE_LIMIT), &mut buf).await {
+            Ok(()) => Ok((buf != b"*").then_some(buf)),
+            Err(line::ReadLineError::Eof) => Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Err(line::ReadLineError::Io(e)) => Err(e),
+        }
+    }
+
+    /// Authenticate the client with SASL ([RFC 4954]).
+    ///
+    /// [RFC 4954]: https://datatracker.ietf.org/doc/html/rfc4954
+    async fn auth(
+        &mut self,
+        mechanism: WhichMechanism,
+        mut initial_response: Option<String>,
+    ) -> std::io::Result<()> {
+        // users authenticate on submission; on the MX, an authenticated
+        // session would be exempt from the checks every other sender gets
+        if self.mx() {
+            return self
+                .reply(Reply::new(
+                    502,
+                    EnhancedCode::new(5, 5, 1),
+                    "authentication not available",
+                ))
+                .await;
+        }
+        // https://datatracker.ietf.org/doc/html/rfc4954#section-4:
+        // The AUTH command is not permitted during a mail transaction.
+        // An AUTH command issued during a mail transaction MUST be
+        // rejected with a 503 reply.
+        if self.envelope.is_some() {
+            return self
+                .reply(Reply::bad_sequence("transaction already started"))
+                .await;
+        }
+        if self.identity.is_some() {
+            return self
+                .reply(Reply::bad_sequence("already authenticated"))
+                .await;
+        }
+        if self.tls_required() {
+            return self.reply(Self::starttls_first()).await;
+        }
+
+        let (mut plain, mut challenge) = match mechanism {
+            WhichMechanism::Plain => Plain::init(),
+        };
+
+        let identity = loop {
+            let response = if let Some(response) = initial_response.take() {
+                Some(response.into_bytes())
+            } else {
+                self.reply(Reply::new(334, None, BASE64.encode(&challenge)))
+                    .await?;
+                self.sasl_response().await?
+            };
+            let Some(response) = response else {
+                return self
+                    .reply(Reply::new(
+                        501,
+                        EnhancedCode::new(5, 0, 0),
+                        "authentication cancelled",
+                    ))
+                    .await;
+            };
+
+            // `=` is an empty initial response
+            let decoded = if response == b"=" {
+                Ok(Vec::new())
+            } else {
+                BASE64.decode(&response)
+            };
+            let auth = self.config.auth.as_ref();
+            let result = match (decoded, &self.config.guard) {
+                (Ok(bytes), Some(guard)) => {
+                    let validator = guard.validator(auth, self.peer.ip(), &self.auth_failures);
+                    plain.eat(&validator, &bytes).await
+                }
+                (Ok(bytes), None) => plain.eat(auth, &bytes).await,
+                (Err(_), _) => Err(MechanismError::Decode),
+            };
+
+            match result {
+                Ok(ControlFlow::Break(identity)) => break identity,
+                Ok(ControlFlow::Continue(next)) => challenge = next,
+                Err(MechanismError::Decode) => {
+                    return self
+                        .reply(Reply::new(
+                            501,
+                            EnhancedCode::new(5, 5, 2),
+                            "cannot decode response",
+                        ))
+                        .await;
+                }
+                Err(MechanismError::Validation(auth::ValidationError::InvalidCredentials)) => {
+                    return self
+                        .reply(Reply::new(
+                            535,
+                            EnhancedCode::new(5, 7, 8),
+                            "authentication credentials invalid",
+                        ))
+                        .await;
+                }
+                Err(MechanismError::Validation(e)) => {
+                    debug!(%e, "authentication failed");
+                    return self
+                        .reply(Reply::new(
+                            454,
+                            EnhancedCode::new(4, 7, 0),
+                            "temporary authentication failure",
+                        ))
+                        .await;
+                }
+            }
+        };
+
+        info!(?identity, "authenticated");
+        self.identity = Some(identity);
+        self.reply(Reply::new(
+            235,
+            EnhancedCode::new(2, 7, 0),
+            "authentication successful",
+        ))
+        .await
+    }
+
+    async fn rcpt(&mut self, to: EmailAddress, params: RcptParams) {
+        let Some(envelope) = &self.envelope else {
+            self.queue_reply(Reply::bad_sequence("need MAIL command"));
+            return;
+        };
+
+        let max_recipients = self.config.limiter.as_ref().and_then(|limiter| {
+            let identity = self.identity.as_ref().map(|identity| identity.0.as_str());
+            limiter.recipients(self.peer.ip(), identity)
+        });
+        // the MX only takes mail for local recipients, even from our own
+        // users, who relay through submission where their mail is checked
+        // and signed
+        let identity = match self.config.profile {
+            Profile::Mx => None,
+            Profile::Submission { .. } | Profile::Lmtp => self.identity.as_ref(),
+        };
+        let mut verdict = if max_recipients.is_some_and(|max| envelope.recipients.len() >= max) {
+            Verdict::TooManyRecipients
+        } else {
+            self.config.recipients.check(&to, envelope, identity).await
+        };
+        debug!(%to, ?verdict, "checked recipient");
+
+        // only mail from the outside is greylisted
+        if let (Verdict::Accept, Some(greylist), Profile::Mx) =
+            (verdict, &self.config.greylist, &self.config.profile)
+        {
+            verdict = greylist
+                .check(self.peer.ip(), envelope.from.as_ref(), &to)
+                .await;
+            debug!(%to, ?verdict, "checked greylist");
+        }
+
+        if verdict == Verdict::Accept {
+            if let Some(reply) = self.milter_event(Event::Rcpt(&to)).await {
+                self.queue_reply(reply);
+                return;
+            }
+            if let Some(envelope) = &mut self.envelope {
+                envelope.add_recipient(to, params);
+            }
+        }
+        self.queue_reply(Reply::from(verdict));
+    }
+
     #[instrument(skip_all)]
     pub async fn next_message(
         &mut self,
     ) -> std::io::Result<Option<Incoming<'_, BufTlsStream<IO>>>> {
         if !self.greeted {
-            self.greet().await?;
             self.greeted = true;
+            if let Some(reply) = self.connect_milter().await {
+                self.reply(reply).await?;
+                return Ok(None);
+            }
+            self.greet().await?;
         }
 
         loop {
+            // send the batched replies once the client has to wait for them
+            if self.connection.stream_mut().buffer().is_empty() {
+                self.flush_replies().await?;
+            }
+
             let cmd = match read_cmd(self.connection.stream_mut()).await? {
+                Some(Ok(cmd)) => cmd,
+                Some(Err(e)) => {
+                    // in turn, a client may have pipelined it
+                    self.queue_reply(Reply::from(e));
+                    continue;
+                }
                 None => return Ok(None),
-                Some(cmd) => cmd,
             };
 
             match cmd {
+                Command::Helo { .. } | Command::Ehlo { .. } if self.lmtp() => {
+                    self.reply(Reply::new(500, EnhancedCode::new(5, 5, 1), "use LHLO"))
+                        .await?;
+                }
+                Command::Lhlo { domain } if self.lmtp() => self.ehlo(domain).await?,
+                Command::Lhlo { .. } => {
+                    self.reply(Reply::new(
+                        500,
+                        EnhancedCode::new(5, 5, 2),
+                        "Unrecognized command",
+                    ))
+                    .await?;
+                }
                 Command::Helo { domain } => {
                     debug!(?domain, "received helo");
                     self.reset_mail_txn();
+                    if let Some(reply) = self.milter_event(Event::Helo(&domain)).await {
+                        self.reply(reply).await?;
+                        continue;
+                    }
                     self.helo_domain = Some(domain);
-                    self.connection.write_flush("250 hello\r\n").await?;
+                    self.esmtp = false;
+                    self.reply(Reply::new(250, None, self.config.hostname.clone()))
+                        .await?;
                 }
                 Command::Ehlo { domain } => self.ehlo(domain).await?,
-                Command::Mail { from } => {
-                    if self.helo_domain.is_none() {
-                        self.connection
-                            .write_flush("503 say HELO first\r\n")
-                            .await?;
-                    } else if self.envelope.is_some() {
-                        self.connection
-                            .write_flush("501 transaction already started\r\n")
-                            .await?;
-                    } else {
-                        self.envelope = Some(Envelope::new(from));
-                        self.connection.write_flush("250 ok\r\n").await?;
-                    }
-                }
-                Command::Rcpt { to } => match &mut self.envelope {
-                    None => {
-                        self.connection
-                            .write_flush("503 need MAIL command\r\n")
-                            .await?;
-                    }
-                    Some(envelope) => {
-                        envelope.recipients.insert(to);
-                        self.connection.write_flush("250 ok\r\n").await?;
-                    }
-                },
+                Command::Mail { from, params } => self.mail(from, params).await,
+                Command::Rcpt { to, params } => self.rcpt(to, params).await,
                 Command::Data => {
                     if let Some(envelope) = self.take_envelope().await? {
-                        self.connection.write_flush("354 go ahead\r\n").await?;
-                        return Ok(Some(Incoming::data(envelope, self.connection.stream_mut())));
+                        self.reply(Reply::new(354, None, "go ahead")).await?;
+                        let trace = self.received(&envelope);
+                        let lmtp = self.lmtp();
+                        return Ok(Some(
+                            Incoming::data(
+                                envelope,
+                                self.client(),
+                                trace,
+                                self.connection.stream_mut(),
+                                self.config.max_message_size,
+                            )
+                            .with_lmtp(lmtp)
+                            .with_milter(self.milter.as_mut()),
+                        ));
                     }
                 }
                 Command::Rset => {
                     self.reset_mail_txn();
-                    self.connection.write_flush("250 ok\r\n").await?;
+                    self.queue_reply(Reply::ok());
                 }
                 Command::Bdat { size, last } => {
                     if let Some(envelope) = self.take_envelope().await? {
                         debug!(size, last, "starting bdat");
-                        return Ok(Some(Incoming::bdat(
-                            envelope,
-                            size,
-                            last,
-                            self.connection.stream_mut(),
-                        )));
+                        self.flush_replies().await?;
+                        let trace = self.received(&envelope);
+                        let lmtp = self.lmtp();
+                        return Ok(Some(
+                            Incoming::bdat(
+                                envelope,
+                                self.client(),
+                                trace,
+                                size,
+                                last,
+                                self.connection.stream_mut(),
+                                self.config.max_message_size,
+                            )
+                            .with_lmtp(lmtp)
+                            .with_milter(self.milter.as_mut()),
+                        ));
                     }
                 }
-                Command::Quit => bye(self.connection.stream_mut()).await?,
-                Command::Noop => self.connection.write_flush("250 ok\r\n").await?,
+                Command::Quit => {
+                    self.flush_replies().await?;
+                    bye(self.connection.stream_mut()).await?;
+                }
+                Command::Noop => self.reply(Reply::ok()).await?,
                 Command::Starttls => self.starttls().await?,
                 Command::Auth {
                     mechanism,
                     initial_response,
                 } => {
-                    // https://datatracker.ietf.org/doc/html/rfc4954#section-4:
-                    // The AUTH command is not permitted during a mail transaction.
-                    // An AUTH command issued during a mail transaction MUST be
-                    // rejected with a 503 reply.
-                    if self.envelope.is_some() {
-                        self.connection
-                            .write_flush("503 transaction already started\r\n")
-                            .await?;
-                        continue;
+                    self.auth(mechanism, initial_response).await?;
+                    let guard = self.config.guard.as_ref();
+                    if guard.is_some_and(|guard| guard.exhausted(&self.auth_failures)) {
+                        self.reply(Reply::new(
+                            421,
+                            EnhancedCode::new(4, 7, 0),
+                            "too many failed authentication attempts",
+                        ))
+                        .await?;
+                        self.connection.stream_mut().shutdown().await?;
+                        return Ok(None);
                     }
+                }
+            }
+        }
+    }
+}
 
-                    if self.identity.is_some() {
-                        self.connection
-                            .write_flush("503 already authenticated\r\n")
-                            .await?;
-                        continue;
-                    }
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        io::Cursor,
+        net::IpAddr,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+        time::Duration,
+    };
 
-                    self.connection.write_flush("235 welcome\r\n").await?;
-                }
+    use auth::{
+        guard::{self, Guard},
+        Identity,
+    };
+    use email_address::EmailAddress;
+    use secrecy::ExposeSecret;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+    use tokio_rustls::rustls;
+    use util::limit::{self, Limits, Rate, RateLimiter};
+
+    use super::Session;
+    use crate::{
+        message::Envelope,
+        reply::{EnhancedCode, Reply},
+        server::{
+            policy::{AcceptAll, GreylistPolicy, OwnAddress, RecipientPolicy, Verdict},
+            Profile,
+        },
+    };
+
+    /// Accepts any user with the password `hunter2`.
+    struct Validator;
+
+    #[async_trait::async_trait]
+    impl auth::Validator for Validator {
+        async fn validate(
+            &self,
+            credentials: &auth::Credentials,
+        ) -> Result<auth::Identity, auth::ValidationError> {
+            if credentials.password.expose_secret() == "hunter2" {
+                Ok(Identity(credentials.username.clone()))
+            } else {
+                Err(auth::ValidationError::InvalidCredentials)
             }
         }
     }
+
+    /// Reads the client's input and records each write separately.
+    struct Recorder {
+        input: Cursor<&'static [u8]>,
+        writes: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl AsyncRead for Recorder {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.input).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.writes
+                .lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(buf).into_owned());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Knows bob and carol at example.com, and allows two recipients.
+    #[derive(Debug)]
+    struct Policy;
+
+    #[async_trait::async_trait]
+    impl RecipientPolicy for Policy {
+        async fn check(
+            &self,
+            recipient: &EmailAddress,
+            envelope: &Envelope,
+            _identity: Option<&Identity>,
+        ) -> Verdict {
+            if envelope.recipients.len() >= 2 {
+                Verdict::TooManyRecipients
+            } else if recipient.domain() != "example.com" {
+                Verdict::RelayDenied
+            } else if ["bob", "carol"].contains(&recipient.local_part()) {
+                Verdict::Accept
+            } else {
+                Verdict::NoSuchUser
+            }
+        }
+    }
+
+    /// Relays for authenticated clients only.
+    #[derive(Debug)]
+    struct Relay;
+
+    #[async_trait::async_trait]
+    impl RecipientPolicy for Relay {
+        async fn check(
+            &self,
+            _recipient: &EmailAddress,
+            _envelope: &Envelope,
+            identity: Option<&Identity>,
+        ) -> Verdict {
+            if identity.is_some() {
+                Verdict::Accept
+            } else {
+                Verdict::RelayDenied
+            }
+        }
+    }
+
+    const PEER: &str = "192.0.2.1:50000";
+
+    async fn session_writes(input: &'static [u8]) -> Vec<String> {
+        session_writes_with(input, Arc::new(AcceptAll)).await
+    }
+
+    async fn session_writes_with(
+        input: &'static [u8],
+        recipients: Arc<dyn RecipientPolicy>,
+    ) -> Vec<String> {
+        session_writes_in(input, context(recipients)).await
+    }
+
+    fn context(recipients: Arc<dyn RecipientPolicy>) -> crate::server::Context<Validator> {
+        crate::server::Context {
+            hostname: "mx.example.com".to_owned(),
+            tls: None,
+            auth: Arc::new(Validator),
+            max_message_size: None,
+            recipients,
+            greylist: None,
+            profile: Profile::Mx,
+            milter: None,
+            limiter: None,
+            guard: None,
+        }
+    }
+
+    fn submission_context(
+        recipients: Arc<dyn RecipientPolicy>,
+    ) -> crate::server::Context<Validator> {
+        crate::server::Context {
+            profile: Profile::Submission {
+                senders: Arc::new(OwnAddress),
+            },
+            ..context(recipients)
+        }
+    }
+
+    async fn session_writes_in(
+        input: &'static [u8],
+        context: crate::server::Context<Validator>,
+    ) -> Vec<String> {
+        let writes = Arc::default();
+        let recorder = Recorder {
+            input: Cursor::new(input),
+            writes: Arc::clone(&writes),
+        };
+        let mut session = Session::new(recorder, PEER.parse().unwrap(), context);
+
+        while session.next_message().await.unwrap().is_some() {}
+
+        let writes = writes.lock().unwrap();
+        writes.clone()
+    }
+
+    #[tokio::test]
+    async fn pipelining() {
+        let writes = session_writes(
+            b"EHLO client.example.com\r\n\
+            MAIL FROM:<alice@example.com>\r\n\
+            RCPT TO:<bob@example.com>\r\n\
+            RCPT TO:<carol@example.com>\r\n\
+            DATA\r\n",
+        )
+        .await;
+        assert_eq!(
+            writes[2],
+            "250 2.1.0 ok\r\n250 2.1.5 ok\r\n250 2.1.5 ok\r\n354 go ahead\r\n"
+        );
+
+        // replies are sent once there is no more input
+        let writes = session_writes(
+            b"EHLO client.example.com\r\n\
+            MAIL FROM:<alice@example.com>\r\n",
+        )
+        .await;
+        assert_eq!(writes[2], "250 2.1.0 ok\r\n");
+
+        // malformed commands are answered in turn
+        let writes = session_writes(
+            b"EHLO client.example.com\r\n\
+            MAIL FROM:<alice@example.com>\r\n\
+            RCPT TO:bad\r\n\
+            RCPT TO:<bob@example.com>\r\n\
+            RCPT TO:<\xff@example.com>\r\n\
+            DATA\r\n",
+        )
+        .await;
+        assert_eq!(
+            writes[2],
+            "250 2.1.0 ok\r\n\
+            501 5.5.4 Syntax: RCPT TO:<address>\r\n\
+            250 2.1.5 ok\r\n\
+            500 5.5.2 Invalid UTF-8\r\n\
+            354 go ahead\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn recipient_policy() {
+        let writes = session_writes_with(
+            b"EHLO client.example.com\r\n\
+            MAIL FROM:<alice@example.com>\r\n\
+            RCPT TO:<bob@example.com>\r\n\
+            RCPT TO:<dave@example.com>\r\n\
+            RCPT TO:<erin@example.net>\r\n\
+            RCPT TO:<carol@example.com>\r\n\
+            RCPT TO:<frank@example.com>\r\n\
+            DATA\r\n",
+            Arc::new(Policy),
+        )
+        .await;
+        assert_eq!(
+            writes[2],
+            "250 2.1.0 ok\r\n\
+            250 2.1.5 ok\r\n\
+            550 5.1.1 no such user\r\n\
+            551 5.7.1 relaying denied\r\n\
+            250 2.1.5 ok\r\n\
+            452 4.5.3 too many recipients\r\n\
+            354 go ahead\r\n"
+        );
+
+        // no recipients
+        let writes = session_writes_with(
+            b"EHLO client.example.com\r\n\
+            MAIL FROM:<alice@example.com>\r\n\
+            RCPT TO:<dave@example.com>\r\n\
+            DATA\r\n",
+            Arc::new(Policy),
+        )
+        .await;
+        assert_eq!(
+            writes[2],
+            "250 2.1.0 ok\r\n550 5.1.1 no such user\r\n554 5.5.1 no valid recipients\r\n"
+        );
+    }
+
+    /// Defers the first attempt for each recipient.
+    #[derive(Debug, Default)]
+    struct Greylist(Mutex<HashSet<String>>);
+
+    #[async_trait::async_trait]
+    impl GreylistPolicy for Greylist {
+        async fn check(
+            &self,
+            _peer: IpAddr,
+            _sender: Option<&EmailAddress>,
+            recipient: &EmailAddress,
+        ) -> Verdict {
+            if self.0.lock().unwrap().insert(recipient.to_string()) {
+                Verdict::Greylisted
+            } else {
+                Verdict::Accept
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn greylist() {
+        let greylisted = || crate::server::Context {
+            greylist: Some(Arc::new(Greylist::default())),
+            ..context(Arc::new(Policy))
+        };
+
+        let writes = session_writes_in(
+            b"EHLO client.example.com\r\n\
+            MAIL FROM:<alice@example.com>\r\n\
+            RCPT TO:<bob@example.com>\r\n\
+            RCPT TO:<dave@example.com>\r\n\
+            RCPT TO:<bob@example.com>\r\n",
+            greylisted(),
+        )
+        .await;
+        assert_eq!(
+            writes[2],
+            "250 2.1.0 ok\r\n\
+            451 4.7.1 greylisted, try again later\r\n\
+            550 5.1.1 no such user\r\n\
+            250 2.1.5 ok\r\n"
+        );
+
+        // there's no authenticating past the greylist on the MX
+        let writes = session_writes_in(
+            b"EHLO client.example.com\r\n\
+            AUTH PLAIN AGFsaWNlQGV4YW1wbGUub3JnAGh1bnRlcjI=\r\n\
+            MAIL FROM:<alice@example.com>\r\n\
+            RCPT TO:<bob@example.com>\r\n",
+            greylisted(),
+        )
+        .await;
+        assert_eq!(writes[2], "502 5.5.1 authentication not available\r\n");
+        assert_eq!(
+            writes[3],
+            "250 2.1.0 ok\r\n451 4.7.1 greylisted, try again later\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limits() {
+        let limiter = RateLimiter::new(limit::Config {
+            default: Limits {
+                messages: Some(Rate::per_hour(1)),
+                recipients: Some(1),
+                ..Limits::default()
+            },
+            ..limit::Config::default()
+        });
+        let writes = session_writes_in(
+            b"EHLO client.example.com\r\n\
+            MAIL FROM:<alice@example.com>\r\n\
+            RCPT TO:<bob@example.com>\r\n\
+            RCPT TO:<carol@example.com>\r\n\
+            RSET\r\n\
+            MAIL FROM:<alice@example.com>\r\n",
+            crate::server::Context {
+                limiter: Some(Arc::new(limiter)),
+                ..context(Arc::new(AcceptAll))
+            },
+        )
+        .await;
+        assert_eq!(
+            writes[2],
+            "250 2.1.0 ok\r\n\
+            250 2.1.5 ok\r\n\
+            452 4.5.3 too many recipients\r\n\
+            250 2.0.0 ok\r\n\
+            451 4.7.1 too many messages, try again in 3600 seconds\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn trace() {
+        let recorder = Recorder {
+            input: Cursor::new(
+                b"HELO client.example.org\r\n\
+                MAIL FROM:<alice@example.org>\r\n\
+                RCPT TO:<bob@example.com>\r\n\
+                DATA\r\n\
+                Subject: hi\r\n\
+                \r\n\
+                hello\r\n\
+                .\r\n",
+            ),
+            writes: Arc::default(),
+        };
+        let mut session = Session::new(
+            recorder,
+            PEER.parse().unwrap(),
+            crate::server::Context {
+                hostname: "mx.example.com".to_owned(),
+                tls: None,
+                auth: Arc::new(Validator),
+                max_message_size: None,
+                recipients: Arc::new(AcceptAll),
+                greylist: None,
+                profile: Profile::Mx,
+                milter: None,
+                limiter: None,
+                guard: None,
+            },
+        );
+
+        let mut message = session.next_message().await.unwrap().unwrap();
+        assert_eq!(message.client().helo, "client.example.org");
+        assert_eq!(message.client().peer, PEER.parse().unwrap());
+        let mut out = String::new();
+        message.with_trace().read_to_string(&mut out).await.unwrap();
+
+        let (received, date) = out.split_once(";\r\n\t").unwrap();
+        assert_eq!(
+            received,
+            "Received: from client.example.org ([192.0.2.1])\r\n\
+            \tby mx.example.com with SMTP\r\n\
+            \tfor <bob@example.com>"
+        );
+        let (_, body) = date.split_once("\r\n").unwrap();
+        assert_eq!(body, "Subject: hi\r\n\r\nhello\r\n");
+    }
+
+    #[tokio::test]
+    async fn auth() {
+        // "\0alice@example.org\0hunter2"
+        let writes = session_writes_in(
+            b"EHLO client.example.org\r\n\
+            AUTH PLAIN AGFsaWNlQGV4YW1wbGUub3JnAHdyb25n\r\n\
+            AUTH PLAIN\r\n\
+            *\r\n\
+            AUTH PLAIN\r\n\
+            AGFsaWNlQGV4YW1wbGUub3JnAGh1bnRlcjI=\r\n\
+            AUTH PLAIN AGFsaWNlQGV4YW1wbGUub3JnAGh1bnRlcjI=\r\n",
+            submission_context(Arc::new(AcceptAll)),
+        )
+        .await;
+        assert!(writes[1].ends_with("250 AUTH PLAIN\r\n"));
+        assert_eq!(
+            writes[2..],
+            [
+                "535 5.7.8 authentication credentials invalid\r\n",
+                "334 \r\n",
+                "501 5.0.0 authentication cancelled\r\n",
+                "334 \r\n",
+                "235 2.7.0 authentication successful\r\n",
+                "503 5.5.1 already authenticated\r\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_guard() {
+        let guard = Guard::new(guard::Config {
+            delay: Duration::ZERO,
+            max_failures_per_connection: 2,
+            ..guard::Config::default()
+        });
+        let writes = session_writes_in(
+            b"EHLO client.example.org\r\n\
+            AUTH PLAIN AGFsaWNlQGV4YW1wbGUub3JnAHdyb25n\r\n\
+            AUTH PLAIN AGFsaWNlQGV4YW1wbGUub3JnAHdyb25n\r\n\
+            AUTH PLAIN AGFsaWNlQGV4YW1wbGUub3JnAGh1bnRlcjI=\r\n",
+            crate::server::Context {
+                guard: Some(Arc::new(guard)),
+                ..submission_context(Arc::new(AcceptAll))
+            },
+        )
+        .await;
+        assert_eq!(
+            writes[2..],
+            [
+                "535 5.7.8 authentication credentials invalid\r\n",
+                "535 5.7.8 authentication credentials invalid\r\n",
+                "421 4.7.0 too many failed authentication attempts\r\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_relay_on_mx() {
+        let input = b"EHLO client.example.org\r\n\
+            AUTH PLAIN AGFsaWNlQGV4YW1wbGUub3JnAGh1bnRlcjI=\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<carol@example.net>\r\n";
+
+        // the MX neither offers nor accepts AUTH
+        let writes = session_writes_with(input, Arc::new(Relay)).await;
+        assert!(!writes[1].contains("AUTH"));
+        assert_eq!(writes[2], "502 5.5.1 authentication not available\r\n");
+        assert_eq!(writes[3], "250 2.1.0 ok\r\n551 5.7.1 relaying denied\r\n");
+
+        let writes = session_writes_in(input, submission_context(Arc::new(Relay))).await;
+        assert_eq!(writes[2], "235 2.7.0 authentication successful\r\n");
+        assert_eq!(writes[3], "250 2.1.0 ok\r\n250 2.1.5 ok\r\n");
+    }
+
+    #[tokio::test]
+    async fn submission() {
+        let submission = submission_context(Arc::new(AcceptAll));
+
+        let writes = session_writes_in(
+            b"EHLO client.example.org\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            AUTH PLAIN AGFsaWNlQGV4YW1wbGUub3JnAGh1bnRlcjI=\r\n\
+            MAIL FROM:<bob@example.org>\r\n\
+            MAIL FROM:<Alice@example.org>\r\n\
+            RCPT TO:<carol@example.net>\r\n\
+            DATA\r\n",
+            submission.clone(),
+        )
+        .await;
+        assert_eq!(
+            writes[2..],
+            [
+                "530 5.7.0 authentication required\r\n\
+                235 2.7.0 authentication successful\r\n",
+                "553 5.7.1 not authorized to send as bob@example.org\r\n\
+                250 2.1.0 ok\r\n\
+                250 2.1.5 ok\r\n\
+                354 go ahead\r\n",
+            ]
+        );
+
+        // the MX profile lets anyone send as anyone
+        let writes = session_writes(
+            b"EHLO client.example.org\r\n\
+            MAIL FROM:<bob@example.org>\r\n",
+        )
+        .await;
+        assert_eq!(writes[2], "250 2.1.0 ok\r\n");
+
+        // STARTTLS is required before AUTH once TLS is available
+        let tls = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(rustls::server::ResolvesServerCertUsingSni::new()));
+        let writes = session_writes_in(
+            b"EHLO client.example.org\r\n\
+            AUTH PLAIN AGFsaWNlQGV4YW1wbGUub3JnAGh1bnRlcjI=\r\n\
+            MAIL FROM:<alice@example.org>\r\n",
+            crate::server::Context {
+                tls: Some(Arc::new(tls)),
+                ..submission
+            },
+        )
+        .await;
+        assert!(writes[1].contains("250-STARTTLS\r\n"));
+        assert!(!writes[1].contains("AUTH"));
+        assert_eq!(
+            writes[2..],
+            [
+                "530 5.7.0 must issue a STARTTLS command first\r\n",
+                "530 5.7.0 must issue a STARTTLS command first\r\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn starttls_with_pipelined_data() {
+        let tls = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(rustls::server::ResolvesServerCertUsingSni::new()));
+        let writes = session_writes_in(
+            b"EHLO client.example.org\r\n\
+            STARTTLS\r\n\
+            EHLO client.example.org\r\n",
+            crate::server::Context {
+                tls: Some(Arc::new(tls)),
+                ..context(Arc::new(AcceptAll))
+            },
+        )
+        .await;
+        assert_eq!(
+            writes[2],
+            "503 5.5.1 data sent after STARTTLS before its response\r\n"
+        );
+        // the session goes on in plain text
+        assert!(writes[3].starts_with("250-mx.example.com"));
+    }
+
+    #[tokio::test]
+    async fn lmtp() {
+        let writes = Arc::default();
+        let recorder = Recorder {
+            input: Cursor::new(
+                b"EHLO mx.example.com\r\n\
+                LHLO mx.example.com\r\n\
+                MAIL FROM:<alice@example.org>\r\n\
+                RCPT TO:<bob@example.com>\r\n\
+                RCPT TO:<carol@example.com>\r\n\
+                DATA\r\n\
+                hello\r\n\
+                .\r\n",
+            ),
+            writes: Arc::clone(&writes),
+        };
+        let context = crate::server::Context {
+            profile: Profile::Lmtp,
+            ..context(Arc::new(AcceptAll))
+        };
+        let mut session = Session::new(recorder, PEER.parse().unwrap(), context);
+
+        let mut message = session.next_message().await.unwrap().unwrap();
+        let mut out = String::new();
+        message.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out, "hello\r\n");
+        message
+            .reply_each(vec![
+                Reply::ok(),
+                Reply::new(452, EnhancedCode::new(4, 2, 2), "mailbox full"),
+            ])
+            .await
+            .unwrap();
+
+        let writes = writes.lock().unwrap().clone();
+        assert_eq!(writes[1], "500 5.5.1 use LHLO\r\n");
+        assert!(writes[2].starts_with("250-mx.example.com\r\n"));
+        assert_eq!(writes[4], "250 2.0.0 ok\r\n452 4.2.2 mailbox full\r\n");
+
+        // SMTP has no LHLO
+        let writes = session_writes(b"LHLO mx.example.com\r\n").await;
+        assert_eq!(writes[1], "500 5.5.2 Unrecognized command\r\n");
+    }
+
+    /// A milter that rejects mail for dave@.
+    async fn fake_milter(listener: tokio::net::TcpListener) {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        while let Ok(len) = stream.read_u32().await {
+            let mut packet = vec![0; len as usize];
+            stream.read_exact(&mut packet).await.unwrap();
+            let response: &[u8] = match packet[0] {
+                b'O' => b"O\0\0\0\x06\0\0\0\0\0\0\0\0",
+                b'R' if packet[1..].starts_with(b"<dave@") => b"y550 5.7.1 not dave\0",
+                b'A' | b'D' => continue,
+                _ => b"c",
+            };
+            let len = u32::try_from(response.len()).unwrap();
+            stream.write_all(&len.to_be_bytes()).await.unwrap();
+            stream.write_all(response).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn milter() {
+        let with_milter = |address: std::net::SocketAddr| crate::server::Context {
+            milter: Some(crate::server::milter::Config {
+                address: crate::server::milter::Address::Tcp(address.to_string()),
+                timeout: std::time::Duration::from_secs(5),
+            }),
+            ..context(Arc::new(AcceptAll))
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(fake_milter(listener));
+
+        let writes = session_writes_in(
+            b"EHLO client.example.org\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<dave@example.com>\r\n\
+            RCPT TO:<bob@example.com>\r\n\
+            DATA\r\n",
+            with_milter(address),
+        )
+        .await;
+        assert_eq!(
+            writes[2],
+            "250 2.1.0 ok\r\n\
+            550 5.7.1 not dave\r\n\
+            250 2.1.5 ok\r\n\
+            354 go ahead\r\n"
+        );
+
+        // the client is turned away if the milter can't be reached
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let writes = session_writes_in(b"EHLO client.example.org\r\n", with_milter(address)).await;
+        assert_eq!(
+            writes,
+            ["421 4.7.1 content filter unavailable, try again later\r\n"]
+        );
+    }
 }
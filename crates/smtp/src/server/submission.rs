@@ -0,0 +1,273 @@
+//! Checking and fixing messages submitted by our users ([RFC 6409]).
+//!
+//! [RFC 6409]: https://datatracker.ietf.org/doc/html/rfc6409
+
+use std::{
+    fmt::Write,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use auth::Identity;
+use email_address::EmailAddress;
+
+use crate::{
+    header,
+    reply::{EnhancedCode, Reply},
+    server::policy::SenderPolicy,
+};
+
+/// Remove comments ([Section 3.2.2] of RFC 5322) outside quoted strings.
+///
+/// [Section 3.2.2]: https://datatracker.ietf.org/doc/html/rfc5322#section-3.2.2
+fn strip_comments(value: &str) -> String {
+    let mut out = String::new();
+    let mut depth = 0_usize;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' && depth == 0 {
+            quoted = !quoted;
+        } else if c == '(' && !quoted {
+            depth += 1;
+            continue;
+        } else if c == ')' && !quoted && depth > 0 {
+            depth -= 1;
+            continue;
+        }
+        if depth == 0 {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Split a `mailbox-list` ([Section 3.4] of RFC 5322) into its addresses.
+/// Returns `None` if any of them is malformed.
+///
+/// [Section 3.4]: https://datatracker.ietf.org/doc/html/rfc5322#section-3.4
+fn mailboxes(value: &str) -> Option<Vec<EmailAddress>> {
+    let value = strip_comments(value);
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(&value[start..]);
+
+    parts
+        .into_iter()
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let address = match (part.rfind('<'), part.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &part[start + 1..end],
+                _ => part,
+            };
+            EmailAddress::from_str(address.trim()).ok()
+        })
+        .collect()
+}
+
+/// The addresses in the `From:` header of `message`, or `None` if it has
+/// no `From:` header or one that can't be parsed.
+///
+/// ```
+/// # use smtp::server::submission::from_addresses;
+/// let addresses = from_addresses(
+///     b"From: \"Alice, A.\" <alice@example.org>, bob@example.org (Bob)\r\n\r\nhi\r\n",
+/// )
+/// .unwrap();
+/// assert_eq!(addresses[0].as_str(), "alice@example.org");
+/// assert_eq!(addresses[1].as_str(), "bob@example.org");
+/// ```
+#[must_use]
+pub fn from_addresses(message: &[u8]) -> Option<Vec<EmailAddress>> {
+    let (fields, _) = header::fields(message);
+    let mut from = fields
+        .iter()
+        .filter(|field| field.name.eq_ignore_ascii_case("from"));
+    let field = from.next()?;
+    if from.next().is_some() {
+        // there must be exactly one
+        return None;
+    }
+    mailboxes(&field.value(message)).filter(|addresses| !addresses.is_empty())
+}
+
+/// Check that `identity` owns every address in the `From:` header of
+/// `message`, so that users can't submit mail as someone else.
+///
+/// # Errors
+///
+/// Returns the reply rejecting the message otherwise.
+pub async fn check_from(
+    senders: &dyn SenderPolicy,
+    identity: &Identity,
+    message: &[u8],
+) -> Result<(), Reply> {
+    let Some(addresses) = from_addresses(message) else {
+        return Err(Reply::new(
+            550,
+            EnhancedCode::new(5, 6, 0),
+            "message must have a single valid From: header",
+        ));
+    };
+    for address in &addresses {
+        if !senders.owns(identity, address).await {
+            return Err(Reply::new(
+                550,
+                EnhancedCode::new(5, 7, 1),
+                format!("not authorized to send as {address}"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A new `Message-ID:` value, unique to this host.
+fn message_id(hostname: &str, now: SystemTime) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let micros = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("<{micros}.{}.{count}@{hostname}>", std::process::id())
+}
+
+/// Add the `Date:` and `Message-ID:` headers if `message` lacks them
+/// ([Sections 8.2 and 8.3] of RFC 6409). They are added at the end of the
+/// header section.
+///
+/// ```
+/// # use std::time::UNIX_EPOCH;
+/// # use smtp::server::submission::fix;
+/// let mut message = b"Date: Tue, 14 Nov 2023 22:13:20 +0000\r\n\r\nhi\r\n".to_vec();
+/// fix(&mut message, "mail.example.com", UNIX_EPOCH);
+/// let message = String::from_utf8(message).unwrap();
+/// assert!(message.starts_with("Date: Tue, 14 Nov 2023 22:13:20 +0000\r\nMessage-ID: <"));
+/// assert!(message.ends_with("@mail.example.com>\r\n\r\nhi\r\n"));
+/// ```
+///
+/// [Sections 8.2 and 8.3]: https://datatracker.ietf.org/doc/html/rfc6409#section-8.2
+pub fn fix(message: &mut Vec<u8>, hostname: &str, now: SystemTime) {
+    let (fields, end) = header::fields(message);
+    let has = |name: &str| {
+        fields
+            .iter()
+            .any(|field| field.name.eq_ignore_ascii_case(name))
+    };
+
+    let mut missing = String::new();
+    if !has("date") {
+        write!(missing, "Date: {}\r\n", crate::date::format(now))
+            .expect("writing to a String should not fail");
+    }
+    if !has("message-id") {
+        write!(missing, "Message-ID: {}\r\n", message_id(hostname, now))
+            .expect("writing to a String should not fail");
+    }
+    message.splice(end..end, missing.into_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use auth::Identity;
+
+    use super::{check_from, fix, from_addresses};
+    use crate::server::policy::OwnAddress;
+
+    #[test]
+    fn from() {
+        let addresses = from_addresses(
+            b"Subject: hi\r\n\
+            From: Alice (work)\r\n  <alice@example.org>\r\n\
+            \r\n\
+            From: mallory@example.org\r\n",
+        )
+        .unwrap();
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(addresses[0].as_str(), "alice@example.org");
+
+        assert!(from_addresses(b"Subject: hi\r\n\r\n").is_none());
+        assert!(from_addresses(b"From: alice\r\n\r\n").is_none());
+        assert!(from_addresses(b"From: a@example.org\r\nFrom: b@example.org\r\n\r\n").is_none());
+    }
+
+    #[tokio::test]
+    async fn check() {
+        let identity = Identity("alice@example.org".to_owned());
+        let check = |message: &'static [u8]| {
+            let identity = identity.clone();
+            async move { check_from(&OwnAddress, &identity, message).await }
+        };
+
+        assert!(check(b"From: Alice <Alice@Example.org>\r\n\r\n")
+            .await
+            .is_ok());
+
+        let reply = check(b"From: alice@example.org, bob@example.org\r\n\r\n")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            reply.to_string(),
+            "550 5.7.1 not authorized to send as bob@example.org\r\n"
+        );
+
+        let reply = check(b"Subject: hi\r\n\r\n").await.unwrap_err();
+        assert_eq!(reply.code, 550);
+    }
+
+    #[test]
+    fn fixing() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let mut message = b"Subject: hi\r\n\r\nhello\r\n".to_vec();
+        fix(&mut message, "mail.example.com", now);
+        let message = String::from_utf8(message).unwrap();
+        let (head, body) = message.split_once("\r\n\r\n").unwrap();
+        assert_eq!(body, "hello\r\n");
+        let lines: Vec<_> = head.split("\r\n").collect();
+        assert_eq!(
+            lines[..2],
+            ["Subject: hi", "Date: Tue, 14 Nov 2023 22:13:20 +0000"]
+        );
+        assert!(lines[2].starts_with("Message-ID: <1700000000000000."));
+        assert!(lines[2].ends_with("@mail.example.com>"));
+
+        // message IDs are unique
+        let mut other = b"\r\n".to_vec();
+        fix(&mut other, "mail.example.com", now);
+        assert!(!String::from_utf8(other).unwrap().contains(lines[2]));
+
+        // complete messages are left alone
+        let complete = b"Date: Tue, 14 Nov 2023 22:13:20 +0000\r\n\
+            Message-Id: <1@example.org>\r\n\
+            \r\n\
+            hello\r\n";
+        let mut message = complete.to_vec();
+        fix(&mut message, "mail.example.com", now);
+        assert_eq!(message, complete);
+    }
+}
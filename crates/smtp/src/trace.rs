@@ -0,0 +1,182 @@
+//! Trace headers ([Section 4.4] of RFC 5321), prepended to messages as
+//! they pass through.
+//!
+//! [Section 4.4]: https://datatracker.ietf.org/doc/html/rfc5321#section-4.4
+
+use std::{fmt, net::IpAddr, time::SystemTime};
+
+use auth::Identity;
+use email_address::EmailAddress;
+
+/// A `Received:` header recording how a message reached us.
+///
+/// ```
+/// # use std::time::{Duration, UNIX_EPOCH};
+/// # use smtp::trace::Received;
+/// let received = Received {
+///     helo: "client.example.org",
+///     peer: [192, 0, 2, 1].into(),
+///     by: "mx.example.com",
+///     esmtp: true,
+///     tls: None,
+///     identity: None,
+///     recipient: None,
+///     date: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+/// };
+/// assert_eq!(
+///     received.to_string(),
+///     "Received: from client.example.org ([192.0.2.1])\r\n\
+///     \tby mx.example.com with ESMTP;\r\n\
+///     \tTue, 14 Nov 2023 22:13:20 +0000\r\n",
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct Received<'a> {
+    /// The domain the client introduced itself with.
+    pub helo: &'a str,
+    pub peer: IpAddr,
+    /// Our hostname.
+    pub by: &'a str,
+    /// Whether the client used EHLO rather than HELO.
+    pub esmtp: bool,
+    /// The TLS version and cipher suite.
+    pub tls: Option<(&'a str, &'a str)>,
+    pub identity: Option<&'a Identity>,
+    /// The recipient, only given for single-recipient messages so that
+    /// the other recipients are not disclosed.
+    pub recipient: Option<&'a EmailAddress>,
+    pub date: SystemTime,
+}
+
+impl Received<'_> {
+    /// The protocol type ([RFC 3848]).
+    ///
+    /// [RFC 3848]: https://datatracker.ietf.org/doc/html/rfc3848
+    #[must_use]
+    pub fn protocol(&self) -> &'static str {
+        match (self.esmtp, self.tls.is_some(), self.identity.is_some()) {
+            (false, _, _) => "SMTP",
+            (true, false, false) => "ESMTP",
+            (true, true, false) => "ESMTPS",
+            (true, false, true) => "ESMTPA",
+            (true, true, true) => "ESMTPSA",
+        }
+    }
+}
+
+/// Write client-supplied text so that it can't break out of a header or
+/// comment.
+fn write_sanitized(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    for c in s.chars() {
+        match c {
+            '(' | ')' | '\\' => write!(f, "\\{c}")?,
+            c if c.is_control() => f.write_str("?")?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Received<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Received: from ")?;
+        write_sanitized(f, self.helo.split_whitespace().next().unwrap_or("unknown"))?;
+        match self.peer {
+            IpAddr::V4(ip) => write!(f, " ([{ip}])")?,
+            IpAddr::V6(ip) => write!(f, " ([IPv6:{ip}])")?,
+        }
+        f.write_str("\r\n")?;
+
+        if let Some((version, cipher)) = self.tls {
+            write!(f, "\t(using {version} with cipher {cipher})\r\n")?;
+        }
+        if let Some(identity) = self.identity {
+            f.write_str("\t(authenticated as ")?;
+            write_sanitized(f, &identity.0)?;
+            f.write_str(")\r\n")?;
+        }
+
+        write!(f, "\tby {} with {}", self.by, self.protocol())?;
+        if let Some(recipient) = self.recipient {
+            write!(f, "\r\n\tfor <{recipient}>")?;
+        }
+        write!(f, ";\r\n\t{}\r\n", crate::date::format(self.date))
+    }
+}
+
+/// The `Return-Path:` header added on final delivery, recording the
+/// reverse-path ([Section 4.4] of RFC 5321).
+///
+/// ```
+/// # use std::str::FromStr;
+/// # use email_address::EmailAddress;
+/// let from = EmailAddress::from_str("alice@example.org").unwrap();
+/// assert_eq!(
+///     smtp::trace::return_path(Some(&from)),
+///     "Return-Path: <alice@example.org>\r\n",
+/// );
+/// assert_eq!(smtp::trace::return_path(None), "Return-Path: <>\r\n");
+/// ```
+///
+/// [Section 4.4]: https://datatracker.ietf.org/doc/html/rfc5321#section-4.4
+#[must_use]
+pub fn return_path(from: Option<&EmailAddress>) -> String {
+    match from {
+        Some(from) => format!("Return-Path: <{from}>\r\n"),
+        None => "Return-Path: <>\r\n".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use auth::Identity;
+    use email_address::EmailAddress;
+
+    use super::Received;
+
+    #[test]
+    fn received() {
+        let identity = Identity("alice (admin)".to_owned());
+        let recipient = EmailAddress::from_str("bob@example.com").unwrap();
+        let received = Received {
+            helo: "[IPv6:2001:db8::1]",
+            peer: "2001:db8::1".parse().unwrap(),
+            by: "mx.example.com",
+            esmtp: true,
+            tls: Some(("TLSv1.3", "TLS13_AES_256_GCM_SHA384")),
+            identity: Some(&identity),
+            recipient: Some(&recipient),
+            date: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+        assert_eq!(received.protocol(), "ESMTPSA");
+        assert_eq!(
+            received.to_string(),
+            "Received: from [IPv6:2001:db8::1] ([IPv6:2001:db8::1])\r\n\
+            \t(using TLSv1.3 with cipher TLS13_AES_256_GCM_SHA384)\r\n\
+            \t(authenticated as alice \\(admin\\))\r\n\
+            \tby mx.example.com with ESMTPSA\r\n\
+            \tfor <bob@example.com>;\r\n\
+            \tTue, 14 Nov 2023 22:13:20 +0000\r\n"
+        );
+
+        let received = Received {
+            helo: "evil\r\nX-Injected: yes",
+            esmtp: false,
+            tls: None,
+            identity: None,
+            recipient: None,
+            ..received
+        };
+        assert_eq!(
+            received.to_string(),
+            "Received: from evil ([IPv6:2001:db8::1])\r\n\
+            \tby mx.example.com with SMTP;\r\n\
+            \tTue, 14 Nov 2023 22:13:20 +0000\r\n"
+        );
+    }
+}
@@ -0,0 +1,474 @@
+//! The Sender Policy Framework ([RFC 7208]), which lets domains publish the
+//! hosts that may send mail for them.
+//!
+//! `ptr` mechanisms are counted against the lookup limit but never match,
+//! and `%{p}` expands to `unknown`, since the [`Resolver`] doesn't do
+//! reverse lookups. Explanations (`exp=`) aren't fetched.
+//!
+//! [RFC 7208]: https://datatracker.ietf.org/doc/html/rfc7208
+
+#![warn(clippy::pedantic)]
+
+use std::{fmt, future::Future, net::IpAddr, pin::Pin};
+
+use dns::Resolver;
+use tracing::debug;
+
+mod macros;
+pub mod record;
+
+use macros::Context;
+use record::{Mechanism, Qualifier, Record};
+
+/// How many mechanisms and modifiers that need DNS lookups may be evaluated
+/// ([Section 4.6.4] of RFC 7208).
+///
+/// [Section 4.6.4]: https://datatracker.ietf.org/doc/html/rfc7208#section-4.6.4
+const MAX_LOOKUPS: usize = 10;
+
+/// How many of those lookups may come back empty.
+const MAX_VOID_LOOKUPS: usize = 2;
+
+/// How many names an `mx` mechanism may look up addresses for.
+const MAX_MX: usize = 10;
+
+/// The result of an SPF check ([Section 2.6] of RFC 7208).
+///
+/// [Section 2.6]: https://datatracker.ietf.org/doc/html/rfc7208#section-2.6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The domain has no SPF record, or isn't a domain at all.
+    None,
+    /// The domain makes no assertion about the host.
+    Neutral,
+    Pass,
+    Fail,
+    /// The host is probably not authorized.
+    SoftFail,
+    TempError,
+    /// The record is invalid or exceeds the lookup limits.
+    PermError,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Neutral => "neutral",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::SoftFail => "softfail",
+            Self::TempError => "temperror",
+            Self::PermError => "permerror",
+        })
+    }
+}
+
+impl From<Qualifier> for Status {
+    fn from(qualifier: Qualifier) -> Self {
+        match qualifier {
+            Qualifier::Pass => Self::Pass,
+            Qualifier::Fail => Self::Fail,
+            Qualifier::SoftFail => Self::SoftFail,
+            Qualifier::Neutral => Self::Neutral,
+        }
+    }
+}
+
+/// The outcome of checking a host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub status: Status,
+    /// Why the check ended in an error or with `none`.
+    pub reason: Option<String>,
+}
+
+/// A `temperror` or `permerror` that ends the evaluation.
+type Failure = (Status, String);
+
+fn temperror(e: &dns::Error) -> Failure {
+    (Status::TempError, format!("DNS lookup failed: {e}"))
+}
+
+/// Whether `domain` is a fully qualified name that can be looked up
+/// ([Section 4.3] of RFC 7208).
+///
+/// [Section 4.3]: https://datatracker.ietf.org/doc/html/rfc7208#section-4.3
+fn is_valid_domain(domain: &str) -> bool {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    domain.len() <= 253
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+/// Drop labels from the left until the name is short enough to look up
+/// ([Section 7.3] of RFC 7208).
+///
+/// [Section 7.3]: https://datatracker.ietf.org/doc/html/rfc7208#section-7.3
+fn truncate(mut name: &str) -> &str {
+    while name.len() > 253 {
+        match name.split_once('.') {
+            Some((_, rest)) => name = rest,
+            None => break,
+        }
+    }
+    name
+}
+
+/// Whether `ip` is in the network `network/len`.
+fn in_network(ip: IpAddr, network: IpAddr, len: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+struct Evaluation<'a, R: ?Sized> {
+    resolver: &'a R,
+    ip: IpAddr,
+    sender: &'a str,
+    helo: &'a str,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl<R: Resolver + ?Sized> Evaluation<'_, R> {
+    fn count_lookup(&mut self) -> Result<(), Failure> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err((Status::PermError, "too many DNS lookups".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Turn the result of a lookup into records, counting void lookups.
+    fn records<T>(&mut self, result: Result<Vec<T>, dns::Error>) -> Result<Vec<T>, Failure> {
+        let records = match result {
+            Ok(records) => records,
+            Err(dns::Error::NxDomain) => Vec::new(),
+            Err(e) => return Err(temperror(&e)),
+        };
+        if records.is_empty() {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                return Err((Status::PermError, "too many void DNS lookups".to_owned()));
+            }
+        }
+        Ok(records)
+    }
+
+    /// Expand the domain-spec of a mechanism or modifier.
+    fn target(&self, domain: &str, spec: Option<&str>) -> Result<String, Failure> {
+        let Some(spec) = spec else {
+            return Ok(domain.to_owned());
+        };
+        let context = Context {
+            sender: self.sender,
+            domain,
+            ip: self.ip,
+            helo: self.helo,
+        };
+        match context.expand(spec) {
+            Some(expanded) => Ok(truncate(&expanded).to_owned()),
+            None => Err((Status::PermError, format!("invalid macro in {spec}"))),
+        }
+    }
+
+    fn matches_any(&self, ips: &[IpAddr], v4: u8, v6: u8) -> bool {
+        ips.iter().any(|&network| {
+            let len = if network.is_ipv4() { v4 } else { v6 };
+            in_network(self.ip, network, len)
+        })
+    }
+
+    async fn matches(&mut self, domain: &str, mechanism: &Mechanism) -> Result<bool, Failure> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            &Mechanism::Ip(network, len) => Ok(in_network(self.ip, network, len)),
+            Mechanism::Include(spec) => {
+                self.count_lookup()?;
+                let target = self.target(domain, Some(spec))?;
+                match self.check_host(target.clone()).await? {
+                    Status::Pass => Ok(true),
+                    Status::Fail | Status::SoftFail | Status::Neutral => Ok(false),
+                    Status::None => Err((
+                        Status::PermError,
+                        format!("included domain {target} has no SPF record"),
+                    )),
+                    status @ (Status::TempError | Status::PermError) => {
+                        Err((status, format!("include:{target} failed")))
+                    }
+                }
+            }
+            &Mechanism::A(ref spec, v4, v6) => {
+                self.count_lookup()?;
+                let target = self.target(domain, spec.as_deref())?;
+                let result = self.resolver.ip(&target).await;
+                let ips = self.records(result)?;
+                Ok(self.matches_any(&ips, v4, v6))
+            }
+            &Mechanism::Mx(ref spec, v4, v6) => {
+                self.count_lookup()?;
+                let target = self.target(domain, spec.as_deref())?;
+                let result = self.resolver.mx(&target).await;
+                let mx = self.records(result)?;
+                if mx.len() > MAX_MX {
+                    return Err((
+                        Status::PermError,
+                        format!("too many MX records for {target}"),
+                    ));
+                }
+                for mx in mx.iter().filter(|mx| !mx.is_null()) {
+                    let ips = match self.resolver.ip(&mx.exchange).await {
+                        Ok(ips) => ips,
+                        Err(dns::Error::NxDomain) => continue,
+                        Err(e) => return Err(temperror(&e)),
+                    };
+                    if self.matches_any(&ips, v4, v6) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(_) => {
+                self.count_lookup()?;
+                Ok(false)
+            }
+            Mechanism::Exists(spec) => {
+                self.count_lookup()?;
+                let target = self.target(domain, Some(spec))?;
+                let result = self.resolver.ip(&target).await;
+                let ips = self.records(result)?;
+                Ok(ips.iter().any(IpAddr::is_ipv4))
+            }
+        }
+    }
+
+    /// The `check_host()` function of [Section 4] of RFC 7208, boxed since
+    /// `include` and `redirect` recurse.
+    ///
+    /// [Section 4]: https://datatracker.ietf.org/doc/html/rfc7208#section-4
+    fn check_host(
+        &mut self,
+        domain: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Status, Failure>> + Send + '_>> {
+        Box::pin(async move {
+            if !is_valid_domain(&domain) {
+                return Ok(Status::None);
+            }
+
+            let txt = match self.resolver.txt(&domain).await {
+                Ok(txt) => txt,
+                Err(dns::Error::NxDomain) => return Ok(Status::None),
+                Err(e) => return Err(temperror(&e)),
+            };
+            let mut records = txt.iter().filter(|txt| record::is_spf(txt));
+            let record = match (records.next(), records.next()) {
+                (None, _) => return Ok(Status::None),
+                (Some(record), None) => record,
+                (Some(_), Some(_)) => {
+                    return Err((
+                        Status::PermError,
+                        format!("{domain} has several SPF records"),
+                    ))
+                }
+            };
+            let record = Record::parse(record).map_err(|e| (Status::PermError, e))?;
+
+            for directive in &record.directives {
+                if self.matches(&domain, &directive.mechanism).await? {
+                    return Ok(directive.qualifier.into());
+                }
+            }
+
+            match &record.redirect {
+                Some(spec) => {
+                    self.count_lookup()?;
+                    let target = self.target(&domain, Some(spec))?;
+                    match self.check_host(target.clone()).await? {
+                        Status::None => Err((
+                            Status::PermError,
+                            format!("redirect target {target} has no SPF record"),
+                        )),
+                        status => Ok(status),
+                    }
+                }
+                None => Ok(Status::Neutral),
+            }
+        })
+    }
+}
+
+/// Check whether `ip` may send mail for `domain`.
+///
+/// `sender` is the reverse-path, or `postmaster@<helo>` when checking the
+/// HELO identity or a null reverse-path.
+///
+/// ```
+/// # use dns::StaticResolver;
+/// # tokio_test::block_on(async {
+/// let resolver = StaticResolver::default()
+///     .with_txt("example.com", "v=spf1 ip4:192.0.2.0/24 -all");
+///
+/// let check = |ip: [u8; 4]| {
+///     spf::check_host(&resolver, ip.into(), "example.com", "joe@example.com", "mx.example.com")
+/// };
+/// assert_eq!(check([192, 0, 2, 1]).await.status, spf::Status::Pass);
+/// assert_eq!(check([198, 51, 100, 1]).await.status, spf::Status::Fail);
+/// # });
+/// ```
+pub async fn check_host<R: Resolver + ?Sized>(
+    resolver: &R,
+    ip: IpAddr,
+    domain: &str,
+    sender: &str,
+    helo: &str,
+) -> Verification {
+    let mut evaluation = Evaluation {
+        resolver,
+        ip: ip.to_canonical(),
+        sender,
+        helo,
+        lookups: 0,
+        void_lookups: 0,
+    };
+
+    let verification = match evaluation.check_host(domain.to_owned()).await {
+        Ok(Status::None) => Verification {
+            status: Status::None,
+            reason: Some(format!("{domain} has no SPF record")),
+        },
+        Ok(status) => Verification {
+            status,
+            reason: None,
+        },
+        Err((status, reason)) => Verification {
+            status,
+            reason: Some(reason),
+        },
+    };
+    debug!(%ip, domain, ?verification, "checked SPF");
+    verification
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use dns::StaticResolver;
+
+    use super::{check_host, Status};
+
+    async fn check(resolver: &StaticResolver, ip: &str, sender: &str) -> Status {
+        let ip: IpAddr = ip.parse().unwrap();
+        let domain = sender.rsplit_once('@').unwrap().1;
+        check_host(resolver, ip, domain, sender, "mail.example.org")
+            .await
+            .status
+    }
+
+    #[tokio::test]
+    async fn mechanisms() {
+        let resolver = StaticResolver::default()
+            .with_txt(
+                "example.com",
+                "v=spf1 a mx/24 ip6:2001:db8::/32 include:_spf.example.net \
+                exists:%{l}.allow.example.com ~all",
+            )
+            .with_ip("example.com", [192, 0, 2, 10].into())
+            .with_mx("example.com", 10, "mx.example.com")
+            .with_ip("mx.example.com", [198, 51, 100, 1].into())
+            .with_txt("_spf.example.net", "v=spf1 ip4:203.0.113.0/24 -all")
+            .with_ip("vip.allow.example.com", [127, 0, 0, 2].into());
+
+        for (ip, sender, status) in [
+            ("192.0.2.10", "joe@example.com", Status::Pass),
+            ("198.51.100.200", "joe@example.com", Status::Pass),
+            ("::ffff:198.51.100.200", "joe@example.com", Status::Pass),
+            ("2001:db8::1", "joe@example.com", Status::Pass),
+            ("203.0.113.7", "joe@example.com", Status::Pass),
+            ("192.0.2.11", "vip@example.com", Status::Pass),
+            ("192.0.2.11", "joe@example.com", Status::SoftFail),
+            ("192.0.2.11", "joe@example.org", Status::None),
+        ] {
+            assert_eq!(check(&resolver, ip, sender).await, status, "{ip} {sender}");
+        }
+    }
+
+    #[tokio::test]
+    async fn redirect() {
+        let resolver = StaticResolver::default()
+            .with_txt("example.com", "v=spf1 redirect=_spf.example.com")
+            .with_txt("_spf.example.com", "v=spf1 ip4:192.0.2.0/24 -all")
+            .with_txt("example.net", "v=spf1 redirect=nothing.example.net")
+            .with_txt("nothing.example.net", "not spf")
+            .with_txt(
+                "example.org",
+                "v=spf1 -ip4:192.0.2.1 redirect=_spf.example.com",
+            );
+
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "a@example.com").await,
+            Status::Pass
+        );
+        assert_eq!(
+            check(&resolver, "192.0.3.1", "a@example.com").await,
+            Status::Fail
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "a@example.net").await,
+            Status::PermError
+        );
+        // mechanisms are evaluated before the redirect
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "a@example.org").await,
+            Status::Fail
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.2", "a@example.org").await,
+            Status::Pass
+        );
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let mut resolver = StaticResolver::default()
+            .with_txt("several.example", "v=spf1 -all")
+            .with_txt("several.example", "v=spf1 +all")
+            .with_txt("syntax.example", "v=spf1 ip4:192.0.2.300")
+            .with_txt("loop.example", "v=spf1 include:loop.example")
+            .with_txt(
+                "void.example",
+                "v=spf1 a:a.void.example a:b.void.example a:c.void.example",
+            )
+            .with_txt("missing.example", "v=spf1 include:nothing.example -all");
+        for i in 0..11 {
+            resolver = resolver.with_txt(
+                &format!("{i}.chain.example"),
+                format!("v=spf1 include:{}.chain.example", i + 1),
+            );
+        }
+        resolver = resolver.with_txt("11.chain.example", "v=spf1 +all");
+
+        for domain in ["several", "syntax", "loop", "void", "missing", "0.chain"] {
+            assert_eq!(
+                check(&resolver, "192.0.2.1", &format!("a@{domain}.example")).await,
+                Status::PermError,
+                "{domain}"
+            );
+        }
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "a@1.chain.example").await,
+            Status::Pass
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Macro expansion ([Section 7] of RFC 7208).
+//!
+//! [Section 7]: https://datatracker.ietf.org/doc/html/rfc7208#section-7
+
+use std::{fmt::Write, net::IpAddr};
+
+/// The values macros expand to.
+#[derive(Debug, Clone)]
+pub struct Context<'a> {
+    /// The sender, `postmaster@<helo>` if the reverse-path is null.
+    pub sender: &'a str,
+    /// The domain whose record is being evaluated.
+    pub domain: &'a str,
+    pub ip: IpAddr,
+    pub helo: &'a str,
+}
+
+const DELIMITERS: &[char] = &['.', '-', '+', ',', '/', '_', '='];
+
+/// Characters that are escaped in uppercase macros.
+fn is_unreserved(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~')
+}
+
+impl Context<'_> {
+    fn local_part(&self) -> &str {
+        self.sender
+            .rsplit_once('@')
+            .map_or("postmaster", |(local, _)| local)
+    }
+
+    fn sender_domain(&self) -> &str {
+        self.sender
+            .rsplit_once('@')
+            .map_or(self.sender, |(_, domain)| domain)
+    }
+
+    fn letter(&self, letter: char) -> Option<String> {
+        Some(match letter.to_ascii_lowercase() {
+            's' => self.sender.to_owned(),
+            'l' => self.local_part().to_owned(),
+            'o' => self.sender_domain().to_owned(),
+            'd' => self.domain.to_owned(),
+            'i' => match self.ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                // dot-separated nibbles
+                IpAddr::V6(ip) => ip.octets().iter().fold(String::new(), |mut out, octet| {
+                    if !out.is_empty() {
+                        out.push('.');
+                    }
+                    let _ = write!(out, "{:x}.{:x}", octet >> 4, octet & 0xf);
+                    out
+                }),
+            },
+            // validated reverse DNS names aren't looked up
+            'p' => "unknown".to_owned(),
+            'v' => match self.ip {
+                IpAddr::V4(_) => "in-addr".to_owned(),
+                IpAddr::V6(_) => "ip6".to_owned(),
+            },
+            'h' => self.helo.to_owned(),
+            _ => return None,
+        })
+    }
+
+    /// Expand a macro-string. Returns `None` if it's malformed.
+    pub fn expand(&self, spec: &str) -> Option<String> {
+        let mut out = String::with_capacity(spec.len());
+        let mut chars = spec.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next()? {
+                '%' => out.push('%'),
+                '_' => out.push(' '),
+                '-' => out.push_str("%20"),
+                '{' => {
+                    let (body, rest) = chars.as_str().split_once('}')?;
+                    out.push_str(&self.expand_macro(body)?);
+                    chars = rest.chars();
+                }
+                _ => return None,
+            }
+        }
+
+        Some(out)
+    }
+
+    /// Expand the part of a macro between the braces, e.g. `d2r`.
+    fn expand_macro(&self, body: &str) -> Option<String> {
+        let mut chars = body.chars();
+        let letter = chars.next()?;
+        let value = self.letter(letter)?;
+
+        let rest = chars.as_str();
+        let digits_end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let keep: Option<usize> = match &rest[..digits_end] {
+            "" => None,
+            digits => Some(digits.parse().ok().filter(|&n| n > 0)?),
+        };
+        let rest = &rest[digits_end..];
+        let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+            Some(delimiters) => (true, delimiters),
+            None => (false, rest),
+        };
+        if !delimiters.chars().all(|c| DELIMITERS.contains(&c)) {
+            return None;
+        }
+        let delimiters: Vec<char> = if delimiters.is_empty() {
+            vec!['.']
+        } else {
+            delimiters.chars().collect()
+        };
+
+        let mut parts: Vec<&str> = value.split(delimiters.as_slice()).collect();
+        if reverse {
+            parts.reverse();
+        }
+        if let Some(keep) = keep {
+            parts.drain(..parts.len().saturating_sub(keep));
+        }
+        let expanded = parts.join(".");
+
+        if letter.is_ascii_uppercase() {
+            Some(expanded.chars().fold(String::new(), |mut out, c| {
+                if is_unreserved(c) {
+                    out.push(c);
+                } else {
+                    let mut buf = [0; 4];
+                    for byte in c.encode_utf8(&mut buf).bytes() {
+                        let _ = write!(out, "%{byte:02X}");
+                    }
+                }
+                out
+            }))
+        } else {
+            Some(expanded)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Context;
+
+    /// The examples in Section 7.4 of RFC 7208.
+    #[test]
+    fn examples() {
+        let mut context = Context {
+            sender: "strong-bad@email.example.com",
+            domain: "email.example.com",
+            ip: [192, 0, 2, 3].into(),
+            helo: "mx.example.org",
+        };
+
+        for (spec, expanded) in [
+            ("%{s}", "strong-bad@email.example.com"),
+            ("%{o}", "email.example.com"),
+            ("%{d}", "email.example.com"),
+            ("%{d4}", "email.example.com"),
+            ("%{d3}", "email.example.com"),
+            ("%{d2}", "example.com"),
+            ("%{d1}", "com"),
+            ("%{dr}", "com.example.email"),
+            ("%{d2r}", "example.email"),
+            ("%{l}", "strong-bad"),
+            ("%{l-}", "strong.bad"),
+            ("%{lr}", "strong-bad"),
+            ("%{lr-}", "bad.strong"),
+            ("%{l1r-}", "strong"),
+            (
+                "%{ir}.%{v}._spf.%{d2}",
+                "3.2.0.192.in-addr._spf.example.com",
+            ),
+            ("%{lr-}.lp._spf.%{d2}", "bad.strong.lp._spf.example.com"),
+            (
+                "%{lr-}.lp.%{ir}.%{v}._spf.%{d2}",
+                "bad.strong.lp.3.2.0.192.in-addr._spf.example.com",
+            ),
+            (
+                "%{ir}.%{v}.%{l1r-}.lp._spf.%{d2}",
+                "3.2.0.192.in-addr.strong.lp._spf.example.com",
+            ),
+            (
+                "%{d2}.trusted-domains.example.net",
+                "example.com.trusted-domains.example.net",
+            ),
+            ("%%%_%-", "% %20"),
+            ("%{S}", "strong-bad%40email.example.com"),
+        ] {
+            assert_eq!(context.expand(spec).as_deref(), Some(expanded), "{spec}");
+        }
+
+        context.ip = "2001:db8::cb01".parse().unwrap();
+        assert_eq!(
+            context.expand("%{ir}.%{v}._spf.%{d2}").as_deref(),
+            Some(
+                "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com"
+            )
+        );
+
+        for invalid in ["%", "%x", "%{x}", "%{d0}", "%{d2q}", "%{d"] {
+            assert_eq!(context.expand(invalid), None, "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! SPF records ([Section 4.6] of RFC 7208).
+//!
+//! [Section 4.6]: https://datatracker.ietf.org/doc/html/rfc7208#section-4.6
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Qualifier {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mechanism {
+    All,
+    Include(String),
+    /// A domain-spec, defaulting to the current domain, and the IPv4 and
+    /// IPv6 prefix lengths.
+    A(Option<String>, u8, u8),
+    Mx(Option<String>, u8, u8),
+    Ptr(Option<String>),
+    Ip(IpAddr, u8),
+    Exists(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directive {
+    pub qualifier: Qualifier,
+    pub mechanism: Mechanism,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Record {
+    pub directives: Vec<Directive>,
+    pub redirect: Option<String>,
+}
+
+/// Whether `txt` is an SPF record, as opposed to some other TXT record.
+#[must_use]
+pub fn is_spf(txt: &str) -> bool {
+    txt.get(..6)
+        .is_some_and(|v| v.eq_ignore_ascii_case("v=spf1"))
+        && txt[6..].chars().next().is_none_or(|c| c == ' ')
+}
+
+/// Split off a `/len` or `//len` suffix.
+fn cidr(spec: &str) -> Result<(&str, u8, u8), String> {
+    let invalid = || format!("invalid prefix length in {spec}");
+    let (spec, v6) = match spec.split_once("//") {
+        Some((spec, len)) => (
+            spec,
+            len.parse()
+                .ok()
+                .filter(|&len| len <= 128)
+                .ok_or_else(invalid)?,
+        ),
+        None => (spec, 128),
+    };
+    let (spec, v4) = match spec.rsplit_once('/') {
+        Some((spec, len)) => (
+            spec,
+            len.parse()
+                .ok()
+                .filter(|&len| len <= 32)
+                .ok_or_else(invalid)?,
+        ),
+        None => (spec, 32),
+    };
+    Ok((spec, v4, v6))
+}
+
+fn domain_spec(spec: Option<&str>) -> Result<Option<String>, String> {
+    match spec {
+        None => Ok(None),
+        Some("") => Err("empty domain-spec".to_owned()),
+        Some(spec) => Ok(Some(spec.to_owned())),
+    }
+}
+
+fn parse_mechanism(term: &str) -> Result<Mechanism, String> {
+    let (name, arg) = match term.find([':', '/']) {
+        Some(i) if term[i..].starts_with(':') => (&term[..i], Some(&term[i + 1..])),
+        Some(i) => (&term[..i], None),
+        None => (term, None),
+    };
+    let required = || {
+        arg.filter(|arg| !arg.is_empty())
+            .map(ToOwned::to_owned)
+            .ok_or(format!("{name} needs an argument"))
+    };
+
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "all" if term.len() == 3 => Mechanism::All,
+        "include" => Mechanism::Include(required()?),
+        "exists" => Mechanism::Exists(required()?),
+        "ptr" => Mechanism::Ptr(domain_spec(arg)?),
+        "a" | "mx" => {
+            // the prefix lengths follow the domain-spec, if any
+            let (spec, v4, v6) = cidr(&term[name.len()..])?;
+            let spec = domain_spec(spec.strip_prefix(':'))?;
+            if name.eq_ignore_ascii_case("a") {
+                Mechanism::A(spec, v4, v6)
+            } else {
+                Mechanism::Mx(spec, v4, v6)
+            }
+        }
+        "ip4" => {
+            let arg = required()?;
+            let (ip, len) = arg.split_once('/').unwrap_or((&arg, "32"));
+            let ip: Ipv4Addr = ip
+                .parse()
+                .map_err(|_| format!("invalid address in {term}"))?;
+            let len = len
+                .parse()
+                .ok()
+                .filter(|&len| len <= 32)
+                .ok_or(format!("invalid prefix length in {term}"))?;
+            Mechanism::Ip(ip.into(), len)
+        }
+        "ip6" => {
+            let arg = required()?;
+            let (ip, len) = arg.split_once('/').unwrap_or((&arg, "128"));
+            let ip: Ipv6Addr = ip
+                .parse()
+                .map_err(|_| format!("invalid address in {term}"))?;
+            let len = len
+                .parse()
+                .ok()
+                .filter(|&len| len <= 128)
+                .ok_or(format!("invalid prefix length in {term}"))?;
+            Mechanism::Ip(ip.into(), len)
+        }
+        _ => return Err(format!("unknown mechanism {term}")),
+    })
+}
+
+impl Record {
+    /// Parse an SPF record. Any syntax error makes the whole record
+    /// invalid.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the first error.
+    pub fn parse(txt: &str) -> Result<Self, String> {
+        if !is_spf(txt) {
+            return Err("not an SPF record".to_owned());
+        }
+
+        let mut record = Self::default();
+        let mut explanation = false;
+        for term in txt[6..].split(' ').filter(|term| !term.is_empty()) {
+            // modifiers are name=value, where the name can't contain ':' or '/'
+            if let Some((name, value)) = term
+                .split_once('=')
+                .filter(|(name, _)| !name.contains([':', '/']))
+            {
+                let duplicate = match name.to_ascii_lowercase().as_str() {
+                    "redirect" => record.redirect.replace(value.to_owned()).is_some(),
+                    "exp" => std::mem::replace(&mut explanation, true),
+                    _ => false,
+                };
+                if duplicate {
+                    return Err(format!("duplicate {name} modifier"));
+                }
+                if value.is_empty() && name.eq_ignore_ascii_case("redirect") {
+                    return Err("empty redirect".to_owned());
+                }
+                continue;
+            }
+
+            let (qualifier, mechanism) = match term.chars().next() {
+                Some('+') => (Qualifier::Pass, &term[1..]),
+                Some('-') => (Qualifier::Fail, &term[1..]),
+                Some('~') => (Qualifier::SoftFail, &term[1..]),
+                Some('?') => (Qualifier::Neutral, &term[1..]),
+                _ => (Qualifier::Pass, term),
+            };
+            record.directives.push(Directive {
+                qualifier,
+                mechanism: parse_mechanism(mechanism)?,
+            });
+        }
+
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_spf, Directive, Mechanism, Qualifier, Record};
+
+    #[test]
+    fn parse() {
+        assert!(is_spf("v=spf1"));
+        assert!(is_spf("V=SPF1 -all"));
+        assert!(!is_spf("v=spf10 -all"));
+        assert!(!is_spf("v=DKIM1; p="));
+
+        let record = Record::parse(
+            "v=spf1 +mx a:colo.example.com/28 ~a//64 ip4:192.0.2.0/24 \
+            ip6:2001:db8::/32 include:_spf.%{d} exists:%{i}.x.example ?ptr \
+            redirect=_spf.example.com exp=explain.example.com -all",
+        )
+        .unwrap();
+        let mechanisms: Vec<_> = record
+            .directives
+            .iter()
+            .map(|directive| &directive.mechanism)
+            .collect();
+        assert_eq!(
+            mechanisms,
+            [
+                &Mechanism::Mx(None, 32, 128),
+                &Mechanism::A(Some("colo.example.com".to_owned()), 28, 128),
+                &Mechanism::A(None, 32, 64),
+                &Mechanism::Ip([192, 0, 2, 0].into(), 24),
+                &Mechanism::Ip("2001:db8::".parse().unwrap(), 32),
+                &Mechanism::Include("_spf.%{d}".to_owned()),
+                &Mechanism::Exists("%{i}.x.example".to_owned()),
+                &Mechanism::Ptr(None),
+                &Mechanism::All,
+            ]
+        );
+        assert_eq!(
+            record.directives[2],
+            Directive {
+                qualifier: Qualifier::SoftFail,
+                mechanism: Mechanism::A(None, 32, 64)
+            }
+        );
+        assert_eq!(record.redirect.as_deref(), Some("_spf.example.com"));
+
+        for invalid in [
+            "v=spf1 foo",
+            "v=spf1 ip4:192.0.2.0/33",
+            "v=spf1 include:",
+            "v=spf1 a:",
+            "v=spf1 redirect=a.example redirect=b.example",
+            "v=spf1 allx",
+        ] {
+            assert!(Record::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -1,5 +1,8 @@
 pub use bitflags;
 
+pub mod limit;
+pub mod net;
+
 #[macro_export(local_inner_macros)]
 macro_rules! flags {
     (
@@ -0,0 +1,346 @@
+//! Token-bucket rate limiting of clients, by network or by identity.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::net::Network;
+
+/// Up to `count` events per `period`. Bursts of `count` events are allowed,
+/// after which events are spread evenly over the period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub count: u32,
+    pub period: Duration,
+}
+
+impl Rate {
+    #[must_use]
+    pub const fn per_minute(count: u32) -> Self {
+        Self {
+            count,
+            period: Duration::from_secs(60),
+        }
+    }
+
+    #[must_use]
+    pub const fn per_hour(count: u32) -> Self {
+        Self {
+            count,
+            period: Duration::from_secs(60 * 60),
+        }
+    }
+
+    /// Tokens added per second.
+    fn refill(self) -> f64 {
+        f64::from(self.count) / self.period.as_secs_f64()
+    }
+}
+
+/// The rate was exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exceeded {
+    /// How long until the next event would be allowed.
+    pub retry_after: Duration,
+}
+
+impl Exceeded {
+    /// [`Self::retry_after`] in whole seconds, rounded up.
+    #[must_use]
+    pub fn retry_secs(&self) -> u64 {
+        self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket for each key. Buckets that have filled up again are the
+/// same as new ones, and are forgotten once there are many.
+#[derive(Debug)]
+struct Buckets<K> {
+    buckets: Mutex<HashMap<K, (Bucket, Rate)>>,
+}
+
+/// Buckets aren't pruned before there are this many.
+const PRUNE_THRESHOLD: usize = 1024;
+
+impl<K> Default for Buckets<K> {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::default(),
+        }
+    }
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+    fn take(&self, key: K, rate: Rate, now: Instant) -> Result<(), Exceeded> {
+        let refill = rate.refill();
+        let full = f64::from(rate.count);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.len() >= PRUNE_THRESHOLD && !buckets.contains_key(&key) {
+            buckets.retain(|_, (bucket, rate)| {
+                let tokens = bucket.tokens
+                    + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate.refill();
+                tokens < f64::from(rate.count)
+            });
+        }
+
+        let (bucket, _) = buckets.entry(key).or_insert((
+            Bucket {
+                tokens: full,
+                updated: now,
+            },
+            rate,
+        ));
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill).min(full);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Exceeded {
+                retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / refill),
+            })
+        }
+    }
+}
+
+/// The limits for a client. `None` means unlimited.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Connections per client address.
+    pub connections: Option<Rate>,
+    /// Messages per authenticated user, or per client address for
+    /// unauthenticated clients.
+    pub messages: Option<Rate>,
+    /// Recipients per message.
+    pub recipients: Option<usize>,
+    /// Authentication attempts per client address.
+    pub auth_attempts: Option<Rate>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub default: Limits,
+    /// Limits for clients in these networks instead of the default. The
+    /// first matching network is used.
+    pub networks: Vec<(Network, Limits)>,
+    /// Limits for these authenticated users instead of those of their
+    /// network.
+    pub identities: HashMap<String, Limits>,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Sender {
+    Client(Network),
+    Identity(String),
+}
+
+/// Rate limits shared by all listeners and sessions.
+///
+/// ```
+/// # use util::limit::{Config, Limits, Rate, RateLimiter};
+/// let limiter = RateLimiter::new(Config {
+///     default: Limits {
+///         connections: Some(Rate::per_minute(2)),
+///         ..Limits::default()
+///     },
+///     ..Config::default()
+/// });
+/// let client = [192, 0, 2, 1].into();
+/// assert!(limiter.connection(client).is_ok());
+/// assert!(limiter.connection(client).is_ok());
+/// assert!(limiter.connection(client).is_err());
+/// assert!(limiter.connection([192, 0, 2, 2].into()).is_ok());
+/// ```
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: Config,
+    connections: Buckets<Network>,
+    messages: Buckets<Sender>,
+    auth_attempts: Buckets<Network>,
+}
+
+/// Clients are told apart by address, except that an IPv6 client usually
+/// has a whole /64.
+fn client(address: IpAddr) -> Network {
+    Network::containing(address, 32, 64)
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// The limits for the client at `address`, authenticated as
+    /// `identity` if set.
+    #[must_use]
+    pub fn limits(&self, address: IpAddr, identity: Option<&str>) -> &Limits {
+        identity
+            .and_then(|identity| self.config.identities.get(identity))
+            .or_else(|| {
+                self.config
+                    .networks
+                    .iter()
+                    .find(|(network, _)| network.contains(address))
+                    .map(|(_, limits)| limits)
+            })
+            .unwrap_or(&self.config.default)
+    }
+
+    /// Count a new connection from `address`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client has connected too often.
+    pub fn connection(&self, address: IpAddr) -> Result<(), Exceeded> {
+        self.connection_at(address, Instant::now())
+    }
+
+    fn connection_at(&self, address: IpAddr, now: Instant) -> Result<(), Exceeded> {
+        match self.limits(address, None).connections {
+            Some(rate) => self.connections.take(client(address), rate, now),
+            None => Ok(()),
+        }
+    }
+
+    /// Count a new message from `address`, authenticated as `identity` if
+    /// set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client has sent too many messages.
+    pub fn message(&self, address: IpAddr, identity: Option<&str>) -> Result<(), Exceeded> {
+        self.message_at(address, identity, Instant::now())
+    }
+
+    fn message_at(
+        &self,
+        address: IpAddr,
+        identity: Option<&str>,
+        now: Instant,
+    ) -> Result<(), Exceeded> {
+        let Some(rate) = self.limits(address, identity).messages else {
+            return Ok(());
+        };
+        let sender = match identity {
+            Some(identity) => Sender::Identity(identity.to_owned()),
+            None => Sender::Client(client(address)),
+        };
+        self.messages.take(sender, rate, now)
+    }
+
+    /// The maximum number of recipients in a message from `address`,
+    /// authenticated as `identity` if set.
+    #[must_use]
+    pub fn recipients(&self, address: IpAddr, identity: Option<&str>) -> Option<usize> {
+        self.limits(address, identity).recipients
+    }
+
+    /// Count an authentication attempt from `address`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client has tried too often.
+    pub fn auth_attempt(&self, address: IpAddr) -> Result<(), Exceeded> {
+        match self.limits(address, None).auth_attempts {
+            Some(rate) => self
+                .auth_attempts
+                .take(client(address), rate, Instant::now()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use super::{Buckets, Config, Limits, Rate, RateLimiter, PRUNE_THRESHOLD};
+
+    #[test]
+    fn bucket() {
+        let buckets = Buckets::default();
+        let rate = Rate::per_minute(3);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // a burst of three, then one every 20 seconds
+        for _ in 0..3 {
+            assert!(buckets.take("a", rate, start).is_ok());
+        }
+        let exceeded = buckets.take("a", rate, at(5)).unwrap_err();
+        assert_eq!(exceeded.retry_after, Duration::from_secs(15));
+        assert!(buckets.take("a", rate, at(20)).is_ok());
+        assert!(buckets.take("a", rate, at(21)).is_err());
+        // other keys have their own bucket
+        assert!(buckets.take("b", rate, at(21)).is_ok());
+
+        // full buckets are forgotten
+        for key in 0..PRUNE_THRESHOLD {
+            buckets.take(key.to_string().leak(), rate, start).unwrap();
+        }
+        buckets.take("c", rate, at(120)).unwrap();
+        assert_eq!(buckets.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn limits() {
+        let unlimited = Limits::default();
+        let strict = Limits {
+            connections: Some(Rate::per_minute(1)),
+            messages: Some(Rate::per_hour(1)),
+            recipients: Some(1),
+            auth_attempts: Some(Rate::per_minute(1)),
+        };
+        let limiter = RateLimiter::new(Config {
+            default: strict.clone(),
+            networks: vec![("198.51.100.0/24".parse().unwrap(), unlimited.clone())],
+            identities: HashMap::from([("alice".to_owned(), unlimited.clone())]),
+        });
+        let now = Instant::now();
+        let stranger = [192, 0, 2, 1].into();
+        let friend = [198, 51, 100, 1].into();
+
+        assert_eq!(limiter.limits(stranger, None), &strict);
+        assert_eq!(limiter.limits(friend, None), &unlimited);
+        assert_eq!(limiter.limits(stranger, Some("alice")), &unlimited);
+        assert_eq!(limiter.recipients(stranger, Some("bob")), Some(1));
+
+        assert!(limiter.connection_at(stranger, now).is_ok());
+        assert!(limiter.connection_at(stranger, now).is_err());
+        assert!(limiter.connection_at(friend, now).is_ok());
+        assert!(limiter.connection_at(friend, now).is_ok());
+
+        // users are limited separately from their network
+        assert!(limiter.message_at(stranger, None, now).is_ok());
+        assert!(limiter.message_at(stranger, Some("bob"), now).is_ok());
+        assert!(limiter.message_at(stranger, None, now).is_err());
+        assert!(limiter.message_at(stranger, Some("bob"), now).is_err());
+        assert!(limiter.message_at(stranger, Some("alice"), now).is_ok());
+
+        // IPv6 clients are limited by /64
+        let v6 = |s: &str| s.parse().unwrap();
+        assert!(limiter.connection_at(v6("2001:db8::1"), now).is_ok());
+        assert!(limiter.connection_at(v6("2001:db8::2"), now).is_err());
+        assert!(limiter.connection_at(v6("2001:db8:0:1::1"), now).is_ok());
+    }
+}
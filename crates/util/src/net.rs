@@ -0,0 +1,151 @@
+//! IP networks in CIDR notation, for allowlists and for grouping clients.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// An IP network, e.g. `192.0.2.0/24`.
+///
+/// ```
+/// # use util::net::Network;
+/// let network: Network = "192.0.2.0/24".parse().unwrap();
+/// assert!(network.contains([192, 0, 2, 1].into()));
+/// assert!(!network.contains([198, 51, 100, 1].into()));
+///
+/// // a bare address is a network of its own
+/// let host: Network = "2001:db8::1".parse().unwrap();
+/// assert_eq!(host.to_string(), "2001:db8::1/128");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+/// The network couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseNetworkError(String);
+
+impl fmt::Display for ParseNetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid network {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseNetworkError {}
+
+const fn max_prefix(address: IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl Network {
+    /// The network with the first `prefix` bits of `address`, or `None` if
+    /// the prefix is too long for the address family. IPv4-mapped IPv6
+    /// addresses are treated as IPv4.
+    #[must_use]
+    pub fn new(address: IpAddr, prefix: u8) -> Option<Self> {
+        let address = address.to_canonical();
+        if prefix > max_prefix(address) {
+            return None;
+        }
+        let address = match address {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+            }
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+        };
+        Some(Self { address, prefix })
+    }
+
+    /// The network containing `address`, with a prefix of `v4` or `v6`
+    /// bits depending on the address family. Prefixes that are too long
+    /// are shortened.
+    ///
+    /// ```
+    /// # use util::net::Network;
+    /// let network = Network::containing([192, 0, 2, 1].into(), 24, 64);
+    /// assert_eq!(network.to_string(), "192.0.2.0/24");
+    /// ```
+    #[must_use]
+    pub fn containing(address: IpAddr, v4: u8, v6: u8) -> Self {
+        let address = address.to_canonical();
+        let prefix = match address {
+            IpAddr::V4(_) => v4.min(32),
+            IpAddr::V6(_) => v6.min(128),
+        };
+        Self::new(address, prefix).expect("prefix is within range")
+    }
+
+    #[must_use]
+    pub const fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    #[must_use]
+    pub const fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    #[must_use]
+    pub fn contains(&self, address: IpAddr) -> bool {
+        Self::new(address, self.prefix).is_some_and(|network| network == *self)
+    }
+}
+
+impl FromStr for Network {
+    type Err = ParseNetworkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseNetworkError(s.to_owned());
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => {
+                let address: IpAddr = address.parse().map_err(|_| err())?;
+                (address, prefix.parse().map_err(|_| err())?)
+            }
+            None => {
+                let address: IpAddr = s.parse().map_err(|_| err())?;
+                (address, max_prefix(address.to_canonical()))
+            }
+        };
+        Self::new(address, prefix).ok_or_else(err)
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Network;
+
+    #[test]
+    fn parse() {
+        let network: Network = "192.0.2.77/24".parse().unwrap();
+        assert_eq!(network.to_string(), "192.0.2.0/24");
+        assert!(network.contains("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!network.contains("2001:db8::1".parse().unwrap()));
+
+        let network: Network = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!network.contains("2001:db9::1".parse().unwrap()));
+
+        let all: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains([203, 0, 113, 1].into()));
+
+        for invalid in ["192.0.2.0/33", "192.0.2.0/", "example.com", "::/129"] {
+            assert!(invalid.parse::<Network>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,24 @@
+CREATE TABLE queue (
+  id BIGSERIAL PRIMARY KEY,
+  -- NULL for bounces, which are sent with the null reverse-path
+  sender TEXT,
+  -- ESMTP parameters of MAIL
+  params TEXT NOT NULL DEFAULT '',
+  data BYTEA NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE queue_recipients (
+  message_id BIGINT NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  address TEXT NOT NULL,
+  domain TEXT NOT NULL,
+  -- ESMTP parameters of RCPT
+  params TEXT NOT NULL DEFAULT '',
+  status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'delivered', 'failed')),
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  last_reply TEXT,
+  PRIMARY KEY (message_id, address)
+);
+
+CREATE INDEX queue_recipients_due ON queue_recipients (next_attempt_at) WHERE status = 'pending';
@@ -0,0 +1,9 @@
+CREATE TABLE domains (
+  name TEXT PRIMARY KEY
+);
+
+CREATE TABLE users (
+  -- lowercase
+  address TEXT PRIMARY KEY,
+  domain TEXT NOT NULL REFERENCES domains(name) ON DELETE CASCADE
+);
@@ -0,0 +1,15 @@
+CREATE TABLE greylist (
+  -- the client's /24 or /64, e.g. '192.0.2.0/24'
+  network TEXT NOT NULL,
+  -- lowercase; '' for the null reverse-path
+  sender TEXT NOT NULL,
+  -- lowercase
+  recipient TEXT NOT NULL,
+  first_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+  last_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+  -- whether the client has retried after the delay
+  passed BOOLEAN NOT NULL DEFAULT false,
+  PRIMARY KEY (network, sender, recipient)
+);
+
+CREATE INDEX greylist_last_seen ON greylist (last_seen);
@@ -0,0 +1,7 @@
+CREATE TABLE aliases (
+  -- lowercase
+  address TEXT PRIMARY KEY,
+  domain TEXT NOT NULL REFERENCES domains(name) ON DELETE CASCADE,
+  -- where mail for the alias is forwarded to
+  target TEXT NOT NULL
+);
@@ -0,0 +1,4 @@
+DELETE FROM queue WHERE id IN (SELECT message_id FROM queue_recipients WHERE status = 'held');
+ALTER TABLE queue_recipients DROP CONSTRAINT queue_recipients_status_check;
+ALTER TABLE queue_recipients ADD CONSTRAINT queue_recipients_status_check
+  CHECK (status IN ('pending', 'delivered', 'failed'));
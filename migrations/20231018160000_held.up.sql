@@ -0,0 +1,5 @@
+-- held recipients are never attempted: submissions the milter quarantined,
+-- kept for an operator to release or delete
+ALTER TABLE queue_recipients DROP CONSTRAINT queue_recipients_status_check;
+ALTER TABLE queue_recipients ADD CONSTRAINT queue_recipients_status_check
+  CHECK (status IN ('pending', 'held', 'delivered', 'failed'));
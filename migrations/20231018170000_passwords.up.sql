@@ -0,0 +1,3 @@
+-- an Argon2 hash in the PHC string format, see `brev --hash-password`;
+-- users without one can't log in
+ALTER TABLE users ADD COLUMN password TEXT;
@@ -0,0 +1,376 @@
+//! Authentication of incoming mail with DKIM, SPF and DMARC. The results
+//! are recorded in an `Authentication-Results:` header ([RFC 8601]) and the
+//! DMARC policy decides whether the message is delivered.
+//!
+//! [RFC 8601]: https://datatracker.ietf.org/doc/html/rfc8601
+
+use std::fmt::{self, Write};
+
+use dns::Resolver;
+use smtp::{
+    message::{Client, Envelope},
+    reply::{EnhancedCode, Reply},
+};
+use tracing::{info, instrument};
+
+/// The identity SPF was checked for ([Section 2.4] of RFC 7208).
+///
+/// [Section 2.4]: https://datatracker.ietf.org/doc/html/rfc7208#section-2.4
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpfIdentity {
+    MailFrom(String),
+    /// The HELO name, used for the null reverse-path.
+    Helo(String),
+}
+
+/// What to do with a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Disposition {
+    Accept,
+    /// Deliver the message, but not to the inbox.
+    Quarantine,
+    Reject(Reply),
+}
+
+#[derive(Debug, Clone)]
+pub struct Results {
+    /// The host that checked the message.
+    pub hostname: String,
+    pub dkim: Vec<dkim::Verification>,
+    pub spf: spf::Verification,
+    pub spf_identity: SpfIdentity,
+    /// The chain of intermediaries that handled the message before.
+    pub arc: dkim::arc::Validation,
+    /// The `From:` domain and its DMARC result, if the message has one.
+    pub dmarc: Option<(String, dmarc::Verification)>,
+}
+
+impl Results {
+    /// The `Authentication-Results:` header to prepend to the message.
+    #[must_use]
+    pub fn header(&self) -> String {
+        format!("Authentication-Results: {self}")
+    }
+
+    #[must_use]
+    pub fn disposition(&self) -> Disposition {
+        match self.dmarc.as_ref().map(|(_, dmarc)| dmarc.disposition) {
+            Some(dmarc::Policy::Reject) => Disposition::Reject(Reply::new(
+                550,
+                EnhancedCode::new(5, 7, 1),
+                "message rejected by DMARC policy",
+            )),
+            Some(dmarc::Policy::Quarantine) => Disposition::Quarantine,
+            Some(dmarc::Policy::None) | None => Disposition::Accept,
+        }
+    }
+}
+
+/// Write a `reason=` property, which is a `value` ([Section 5.1] of RFC
+/// 2045) and so has to be quoted.
+///
+/// [Section 5.1]: https://datatracker.ietf.org/doc/html/rfc2045#section-5.1
+fn write_reason(f: &mut fmt::Formatter<'_>, reason: Option<&str>) -> fmt::Result {
+    let Some(reason) = reason else {
+        return Ok(());
+    };
+    f.write_str(" reason=\"")?;
+    for c in reason.chars() {
+        match c {
+            '"' | '\\' => write!(f, "\\{c}")?,
+            c if c.is_control() => f.write_char('?')?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// The value of the `Authentication-Results:` header, one method per line.
+/// ARC records the same value in `ARC-Authentication-Results:`.
+///
+/// ```
+/// # use brev::authentication::{Results, SpfIdentity};
+/// let results = Results {
+///     hostname: "mx.example.com".to_owned(),
+///     dkim: Vec::new(),
+///     spf: spf::Verification { status: spf::Status::Pass, reason: None },
+///     spf_identity: SpfIdentity::MailFrom("alice@example.org".to_owned()),
+///     arc: dkim::arc::Validation {
+///         status: dkim::arc::ChainStatus::None,
+///         instance: 0,
+///         reason: None,
+///     },
+///     dmarc: None,
+/// };
+/// assert_eq!(
+///     results.header(),
+///     "Authentication-Results: mx.example.com;\r\n\
+///     \tdkim=none;\r\n\
+///     \tspf=pass smtp.mailfrom=alice@example.org;\r\n\
+///     \tarc=none;\r\n\
+///     \tdmarc=none\r\n",
+/// );
+/// ```
+impl fmt::Display for Results {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{};\r\n", self.hostname)?;
+
+        if self.dkim.is_empty() {
+            f.write_str("\tdkim=none;\r\n")?;
+        }
+        for dkim in &self.dkim {
+            write!(f, "\tdkim={}", dkim.status)?;
+            write_reason(f, dkim.reason.as_deref())?;
+            if let Some(domain) = &dkim.domain {
+                write!(f, " header.d={domain}")?;
+            }
+            if let Some(selector) = &dkim.selector {
+                write!(f, " header.s={selector}")?;
+            }
+            if let Some(b) = &dkim.b {
+                write!(f, " header.b={b}")?;
+            }
+            f.write_str(";\r\n")?;
+        }
+
+        write!(f, "\tspf={}", self.spf.status)?;
+        write_reason(f, self.spf.reason.as_deref())?;
+        match &self.spf_identity {
+            SpfIdentity::MailFrom(from) => write!(f, " smtp.mailfrom={from}")?,
+            SpfIdentity::Helo(helo) => write!(f, " smtp.helo={helo}")?,
+        }
+        f.write_str(";\r\n")?;
+
+        write!(f, "\tarc={}", self.arc.status)?;
+        write_reason(f, self.arc.reason.as_deref())?;
+        f.write_str(";\r\n")?;
+
+        match &self.dmarc {
+            Some((from, dmarc)) => {
+                write!(f, "\tdmarc={}", dmarc.status)?;
+                if let Some(policy) = dmarc.policy {
+                    write!(f, " (p={policy} dis={})", dmarc.disposition)?;
+                }
+                write_reason(f, dmarc.reason.as_deref())?;
+                write!(f, " header.from={from}\r\n")
+            }
+            None => f.write_str("\tdmarc=none\r\n"),
+        }
+    }
+}
+
+/// The `authserv-id` of an `Authentication-Results:` field: the first
+/// token of its value, after any comments.
+fn authserv_id(raw: &[u8]) -> Option<&[u8]> {
+    let colon = raw.iter().position(|&c| c == b':')?;
+    let mut value = &raw[colon + 1..];
+    loop {
+        value = value.trim_ascii_start();
+        if value.first() != Some(&b'(') {
+            break;
+        }
+        // comments nest ([Section 3.2.2] of RFC 5322)
+        let mut depth = 0;
+        let end = value.iter().position(|&c| {
+            match c {
+                b'(' => depth += 1,
+                b')' => depth -= 1,
+                _ => (),
+            }
+            depth == 0
+        })?;
+        value = &value[end + 1..];
+    }
+    let end = value
+        .iter()
+        .position(|&c| c == b';' || c == b'(' || c.is_ascii_whitespace())
+        .unwrap_or(value.len());
+    Some(
+        value[..end]
+            .strip_prefix(b"\"")
+            .map_or(&value[..end], |id| id.strip_suffix(b"\"").unwrap_or(id)),
+    )
+}
+
+/// Remove the `Authentication-Results:` fields that claim to be from
+/// `hostname`, since only we may add those ([Section 5] of RFC 8601).
+/// Returns how many were removed.
+///
+/// [Section 5]: https://datatracker.ietf.org/doc/html/rfc8601#section-5
+pub fn remove_forged_results(message: &mut Vec<u8>, hostname: &str) -> usize {
+    let base = message.as_ptr() as usize;
+    let (headers, _) = dkim::message::split(message);
+    let forged: Vec<_> = headers
+        .iter()
+        .filter(|header| {
+            header.name.eq_ignore_ascii_case("Authentication-Results")
+                && authserv_id(header.raw)
+                    .is_some_and(|id| id.eq_ignore_ascii_case(hostname.as_bytes()))
+        })
+        .map(|header| {
+            let start = header.raw.as_ptr() as usize - base;
+            start..start + header.raw.len()
+        })
+        .collect();
+
+    for range in forged.iter().rev() {
+        message.drain(range.clone());
+    }
+    forged.len()
+}
+
+/// Checks incoming mail with the records published in DNS.
+pub struct Authenticator<R> {
+    resolver: R,
+    hostname: String,
+}
+
+impl<R: Resolver> Authenticator<R> {
+    /// `hostname` identifies this server in `Authentication-Results:`.
+    pub fn new(resolver: R, hostname: impl Into<String>) -> Self {
+        Self {
+            resolver,
+            hostname: hostname.into(),
+        }
+    }
+
+    /// The `authserv-id` of our `Authentication-Results:`.
+    #[must_use]
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Check `message`, received from `client` with `envelope`.
+    #[instrument(skip_all, fields(peer = %client.peer))]
+    pub async fn authenticate(
+        &self,
+        client: &Client,
+        envelope: &Envelope,
+        message: &[u8],
+    ) -> Results {
+        let dkim = dkim::verify(&self.resolver, message).await;
+        let arc = dkim::arc::validate(&self.resolver, message).await;
+
+        let (sender, spf_domain, spf_identity) = match &envelope.from {
+            Some(from) => (
+                from.to_string(),
+                from.domain().to_owned(),
+                SpfIdentity::MailFrom(from.to_string()),
+            ),
+            None => (
+                format!("postmaster@{}", client.helo),
+                client.helo.clone(),
+                SpfIdentity::Helo(client.helo.clone()),
+            ),
+        };
+        let spf = spf::check_host(
+            &self.resolver,
+            client.peer.ip(),
+            &spf_domain,
+            &sender,
+            &client.helo,
+        )
+        .await;
+
+        let (headers, _) = dkim::message::split(message);
+        let dmarc = match dkim::message::from_domain(&headers) {
+            Some(from) => {
+                let dmarc = dmarc::verify(&self.resolver, &from, &dkim, &spf, &spf_domain).await;
+                Some((from, dmarc))
+            }
+            None => None,
+        };
+
+        let results = Results {
+            hostname: self.hostname.clone(),
+            dkim,
+            spf,
+            spf_identity,
+            arc,
+            dmarc,
+        };
+        info!(disposition = ?results.disposition(), "authenticated message");
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dns::StaticResolver;
+    use email_address::EmailAddress;
+    use smtp::{command::params::MailParams, message::Envelope};
+
+    use super::{remove_forged_results, Authenticator, Disposition};
+
+    #[tokio::test]
+    async fn authenticate() {
+        let resolver = StaticResolver::default()
+            .with_txt("example.org", "v=spf1 ip4:192.0.2.0/24 -all")
+            .with_txt("_dmarc.example.org", "v=DMARC1; p=reject");
+        let authenticator = Authenticator::new(resolver, "mx.example.com");
+        let envelope = Envelope::new(
+            Some(EmailAddress::new_unchecked("alice@example.org")),
+            MailParams::default(),
+        );
+        let message = b"From: Alice <alice@example.org>\r\n\r\nhi\r\n";
+
+        let client = smtp::message::Client {
+            peer: "192.0.2.1:50000".parse().unwrap(),
+            helo: "mail.example.org".to_owned(),
+            identity: None,
+        };
+        let results = authenticator
+            .authenticate(&client, &envelope, message)
+            .await;
+        assert_eq!(
+            results.header(),
+            "Authentication-Results: mx.example.com;\r\n\
+            \tdkim=none;\r\n\
+            \tspf=pass smtp.mailfrom=alice@example.org;\r\n\
+            \tarc=none;\r\n\
+            \tdmarc=pass (p=reject dis=none) header.from=example.org\r\n"
+        );
+        assert_eq!(results.disposition(), Disposition::Accept);
+
+        let client = smtp::message::Client {
+            peer: "198.51.100.1:50000".parse().unwrap(),
+            ..client
+        };
+        let results = authenticator
+            .authenticate(&client, &envelope, message)
+            .await;
+        assert_eq!(
+            results.header(),
+            "Authentication-Results: mx.example.com;\r\n\
+            \tdkim=none;\r\n\
+            \tspf=fail smtp.mailfrom=alice@example.org;\r\n\
+            \tarc=none;\r\n\
+            \tdmarc=fail (p=reject dis=reject) \
+            reason=\"no aligned DKIM signature or SPF pass\" header.from=example.org\r\n"
+        );
+        assert!(matches!(results.disposition(), Disposition::Reject(_)));
+    }
+
+    #[test]
+    fn forged_results() {
+        let mut message = b"Authentication-Results: MX.example.com;\r\n\
+            \tdkim=pass header.d=example.org\r\n\
+            Authentication-Results: mx.example.net; spf=pass\r\n\
+            From: Mallory <mallory@example.org>\r\n\
+            Authentication-Results: (looks legit)\r\n \"mx.example.com\"; dmarc=pass\r\n\
+            Authentication-Results: mx.example.com.evil; spf=pass\r\n\
+            \r\n\
+            Authentication-Results: mx.example.com; in the body\r\n"
+            .to_vec();
+
+        assert_eq!(remove_forged_results(&mut message, "mx.example.com"), 2);
+        assert_eq!(
+            message,
+            b"Authentication-Results: mx.example.net; spf=pass\r\n\
+            From: Mallory <mallory@example.org>\r\n\
+            Authentication-Results: mx.example.com.evil; spf=pass\r\n\
+            \r\n\
+            Authentication-Results: mx.example.com; in the body\r\n"
+        );
+    }
+}
@@ -0,0 +1,626 @@
+//! The configuration file, in TOML. See `brev.example.toml` for an example
+//! with every setting.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::{de, Deserialize, Deserializer};
+use smtp::server::milter;
+use util::{
+    limit::{self, Rate},
+    net::Network,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("can't read {}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The name we greet clients with and use in trace headers.
+    pub hostname: String,
+    /// The Postgres database, `DATABASE_URL` from the environment if not
+    /// set.
+    pub database_url: Option<String>,
+    /// The certificates for TLS listeners and STARTTLS.
+    pub tls: Option<Tls>,
+    pub listeners: Vec<Listener>,
+    #[serde(default)]
+    pub limits: Limits,
+    pub auth: Auth,
+    /// Load balancers that send a PROXY header with the client's address.
+    #[serde(default, deserialize_with = "parse_list")]
+    pub proxies: Vec<Network>,
+    /// A milter to filter incoming and submitted mail with, e.g.
+    /// `inet:localhost:11332` for rspamd.
+    #[serde(default, deserialize_with = "parse_option")]
+    pub milter: Option<milter::Address>,
+    /// Greylisting of incoming mail, off if not set.
+    pub greylist: Option<Greylist>,
+    /// The DKIM keys that submitted mail is signed with, one per domain.
+    #[serde(default)]
+    pub dkim: Vec<Dkim>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    /// Certificates chosen by the name the client asks for with SNI. The
+    /// first is used for clients that ask for none, or for a name none of
+    /// the certificates is for.
+    pub certificates: Vec<Certificate>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Certificate {
+    /// PEM file with the certificate chain, leaf first.
+    pub certificate: PathBuf,
+    /// PEM file with the private key.
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    #[serde(deserialize_with = "parse")]
+    pub address: Address,
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub tls: TlsMode,
+}
+
+/// Where to listen: a TCP address, or the path of a Unix socket.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            return Ok(Self::Unix(s.into()));
+        }
+        s.parse().map(Self::Tcp).map_err(|_| {
+            format!("invalid address {s:?}, expected e.g. \"0.0.0.0:25\", \"[::]:25\" or a path")
+        })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix(path) => path.display().fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// Incoming mail from other servers.
+    Smtp,
+    /// Outgoing mail from our users ([RFC 6409]).
+    ///
+    /// [RFC 6409]: https://datatracker.ietf.org/doc/html/rfc6409
+    Submission,
+    Imap,
+    /// Incoming mail from an MTA in front of us ([RFC 2033]).
+    ///
+    /// [RFC 2033]: https://datatracker.ietf.org/doc/html/rfc2033
+    Lmtp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Smtp => "SMTP",
+            Self::Submission => "submission",
+            Self::Imap => "IMAP",
+            Self::Lmtp => "LMTP",
+        })
+    }
+}
+
+/// How a listener uses TLS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Plain connections without TLS.
+    #[default]
+    None,
+    /// Plain connections that can be upgraded with STARTTLS.
+    Starttls,
+    /// TLS from the start ([RFC 8314]).
+    ///
+    /// [RFC 8314]: https://datatracker.ietf.org/doc/html/rfc8314
+    Implicit,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Limits {
+    /// The largest message accepted, in bytes.
+    pub max_message_size: u64,
+    /// The rates for clients that aren't in `networks` or `users`.
+    pub default: Rates,
+    /// Rates by client network, e.g. `"127.0.0.0/8"`. The most specific
+    /// network containing the client is used.
+    pub networks: BTreeMap<String, Rates>,
+    /// Rates for authenticated users, by username.
+    pub users: HashMap<String, Rates>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message_size: 50 * 1024 * 1024,
+            default: Rates {
+                connections_per_minute: Some(30),
+                messages_per_hour: Some(200),
+                recipients_per_message: Some(100),
+                auth_attempts_per_minute: Some(10),
+            },
+            networks: BTreeMap::new(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+/// Rate limits for a group of clients. Limits that aren't set are
+/// unlimited.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rates {
+    pub connections_per_minute: Option<u32>,
+    pub messages_per_hour: Option<u32>,
+    pub recipients_per_message: Option<usize>,
+    pub auth_attempts_per_minute: Option<u32>,
+}
+
+impl From<&Rates> for limit::Limits {
+    fn from(rates: &Rates) -> Self {
+        Self {
+            connections: rates.connections_per_minute.map(Rate::per_minute),
+            messages: rates.messages_per_hour.map(Rate::per_hour),
+            recipients: rates.recipients_per_message,
+            auth_attempts: rates.auth_attempts_per_minute.map(Rate::per_minute),
+        }
+    }
+}
+
+impl Limits {
+    /// The configuration of the rate limiter.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a network can't be parsed.
+    pub fn rate_limits(&self) -> Result<limit::Config, Error> {
+        let mut networks = self
+            .networks
+            .iter()
+            .map(|(network, rates)| {
+                let network: Network = network
+                    .parse()
+                    .map_err(|e| Error::Invalid(format!("limits.networks: {e}")))?;
+                Ok((network, rates.into()))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        // the limiter takes the first match
+        networks.sort_by_key(|(network, _)| std::cmp::Reverse(network.prefix()));
+
+        Ok(limit::Config {
+            default: (&self.default).into(),
+            networks,
+            identities: self
+                .users
+                .iter()
+                .map(|(user, rates)| (user.clone(), rates.into()))
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Auth {
+    pub backend: Backend,
+    /// Allow [`Backend::AcceptAll`] on listeners that log users in.
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+/// Where users and their passwords come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// The `users` table, with a hash of each password.
+    Database,
+    /// Accepts any username with any password. Only for testing.
+    AcceptAll,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Greylist {
+    /// Clients that are never greylisted.
+    #[serde(default, deserialize_with = "parse_list")]
+    pub allowlist: Vec<Network>,
+    /// How long a client must wait before retrying, in seconds.
+    pub delay_secs: Option<u64>,
+}
+
+impl Greylist {
+    #[must_use]
+    pub fn config(&self) -> crate::greylist::Config {
+        let default = crate::greylist::Config::default();
+        crate::greylist::Config {
+            allowlist: self.allowlist.clone(),
+            delay: self.delay_secs.map_or(default.delay, Duration::from_secs),
+            ..default
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dkim {
+    pub domain: String,
+    /// The selector the public key is published under, at
+    /// `<selector>._domainkey.<domain>`.
+    pub selector: String,
+    #[serde(deserialize_with = "parse")]
+    pub algorithm: dkim::Algorithm,
+    /// PEM file with the private key.
+    pub key: PathBuf,
+}
+
+impl From<&Dkim> for dkim::KeyConfig {
+    fn from(config: &Dkim) -> Self {
+        Self {
+            domain: config.domain.clone(),
+            selector: config.selector.clone(),
+            algorithm: config.algorithm,
+            key: config.key.clone(),
+        }
+    }
+}
+
+fn parse<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn parse_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    parse(deserializer).map(Some)
+}
+
+fn parse_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: Self = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// Read and check the configuration file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or the configuration is
+    /// invalid.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        std::fs::read_to_string(path)
+            .map_err(|source| Error::Read {
+                path: path.to_owned(),
+                source,
+            })?
+            .parse()
+    }
+
+    /// The listeners for `protocol`.
+    pub fn listeners(&self, protocol: Protocol) -> impl Iterator<Item = &Listener> {
+        self.listeners
+            .iter()
+            .filter(move |listener| listener.protocol == protocol)
+    }
+
+    /// Whether plain connections for `protocol` are offered STARTTLS.
+    #[must_use]
+    pub fn starttls(&self, protocol: Protocol) -> bool {
+        self.listeners(protocol)
+            .any(|listener| listener.tls == TlsMode::Starttls)
+    }
+
+    /// Read the DKIM keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a key can't be read, or is for another algorithm
+    /// than configured.
+    pub fn dkim_keys(&self) -> Result<dkim::Keys, dkim::Error> {
+        let config: Vec<dkim::KeyConfig> = self.dkim.iter().map(Into::into).collect();
+        dkim::Keys::load(&config)
+    }
+
+    /// Check what the types don't.
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::Invalid(message));
+
+        if self.hostname.is_empty() || self.hostname.contains(char::is_whitespace) {
+            return invalid(format!("invalid hostname {:?}", self.hostname));
+        }
+        if self
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.certificates.is_empty())
+        {
+            return invalid("no certificates in [tls]".to_owned());
+        }
+        if self.listeners.is_empty() {
+            return invalid("no listeners configured".to_owned());
+        }
+
+        let mut addresses = HashSet::new();
+        for Listener {
+            address,
+            protocol,
+            tls,
+        } in &self.listeners
+        {
+            if !addresses.insert(address) {
+                return invalid(format!("{address} is configured more than once"));
+            }
+            if matches!(address, Address::Unix(_)) && *protocol != Protocol::Lmtp {
+                return invalid(format!("{address}: only LMTP can listen on a Unix socket"));
+            }
+            if *tls != TlsMode::None {
+                if *protocol == Protocol::Lmtp {
+                    return invalid(format!("{address}: LMTP doesn't support TLS"));
+                }
+                if self.tls.is_none() {
+                    return invalid(format!(
+                        "{address}: TLS needs a certificate and key in [tls]"
+                    ));
+                }
+            }
+        }
+
+        // STARTTLS is set up per protocol, not per listener
+        for protocol in [Protocol::Smtp, Protocol::Submission, Protocol::Imap] {
+            if self.starttls(protocol)
+                && self
+                    .listeners(protocol)
+                    .any(|listener| listener.tls == TlsMode::None)
+            {
+                return invalid(format!(
+                    "{protocol} listeners must either all or none offer STARTTLS"
+                ));
+            }
+        }
+
+        // anyone could send as and read the mail of anyone
+        if self.auth.backend == Backend::AcceptAll && !self.auth.insecure {
+            for protocol in [Protocol::Smtp, Protocol::Submission, Protocol::Imap] {
+                if self.listeners(protocol).next().is_some() {
+                    return invalid(format!(
+                        "{protocol} listeners can't use the accept-all auth backend \
+                        without insecure = true"
+                    ));
+                }
+            }
+        }
+
+        let mut domains = HashSet::new();
+        for Dkim {
+            domain, selector, ..
+        } in &self.dkim
+        {
+            if domain.is_empty() || selector.is_empty() {
+                return invalid("[[dkim]] needs a domain and a selector".to_owned());
+            }
+            if !domains.insert(domain.to_ascii_lowercase()) {
+                return invalid(format!("more than one DKIM key for {domain}"));
+            }
+        }
+
+        self.limits.rate_limits()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use util::limit::{Rate, RateLimiter};
+
+    use super::{Address, Config, Protocol};
+
+    #[test]
+    fn example() {
+        let config: Config = include_str!("../brev.example.toml").parse().unwrap();
+        assert_eq!(config.hostname, "mail.example.com");
+        assert_eq!(config.listeners(Protocol::Imap).count(), 2);
+        assert!(config.starttls(Protocol::Smtp));
+        assert_eq!(config.dkim.len(), 2);
+        assert!(matches!(
+            config.listeners(Protocol::Lmtp).next().unwrap().address,
+            Address::Unix(_)
+        ));
+
+        let limiter = RateLimiter::new(config.limits.rate_limits().unwrap());
+        let local: IpAddr = [127, 0, 0, 1].into();
+        assert!(limiter.limits(local, None).connections.is_none());
+        let remote: IpAddr = [192, 0, 2, 1].into();
+        assert_eq!(
+            limiter.limits(remote, None).connections,
+            Some(Rate::per_minute(30))
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let minimal = r#"
+            hostname = "mail.example.com"
+            auth = { backend = "database" }
+        "#;
+        let check = |rest: &str| {
+            format!("{minimal}{rest}")
+                .parse::<Config>()
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        };
+
+        assert!(check(r#"listeners = [{ address = "0.0.0.0:25", protocol = "smtp" }]"#).is_ok());
+        let accept_all = |rest: &str| {
+            format!("hostname = \"mail.example.com\"\n{rest}")
+                .parse::<Config>()
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            accept_all(
+                r#"listeners = [{ address = "0.0.0.0:587", protocol = "submission" }]
+                auth = { backend = "accept-all" }"#
+            ),
+            Err(
+                "submission listeners can't use the accept-all auth backend \
+                without insecure = true"
+                    .to_owned()
+            )
+        );
+        assert!(accept_all(
+            r#"listeners = [{ address = "0.0.0.0:587", protocol = "submission" }]
+            auth = { backend = "accept-all", insecure = true }"#
+        )
+        .is_ok());
+        assert!(accept_all(
+            r#"listeners = [{ address = "/run/brev/lmtp.sock", protocol = "lmtp" }]
+            auth = { backend = "accept-all" }"#
+        )
+        .is_ok());
+
+        for (config, error) in [
+            ("listeners = []", "no listeners configured"),
+            (
+                r#"listeners = [{ address = "localhost:25", protocol = "smtp" }]"#,
+                "invalid address",
+            ),
+            (
+                r#"listeners = [{ address = "0.0.0.0:25", protocol = "pop3" }]"#,
+                "unknown variant `pop3`",
+            ),
+            (
+                r#"listeners = [
+                    { address = "0.0.0.0:25", protocol = "smtp" },
+                    { address = "0.0.0.0:25", protocol = "submission" },
+                ]"#,
+                "0.0.0.0:25 is configured more than once",
+            ),
+            (
+                r#"listeners = [{ address = "0.0.0.0:465", protocol = "submission", tls = "implicit" }]"#,
+                "0.0.0.0:465: TLS needs a certificate and key in [tls]",
+            ),
+            (
+                r#"listeners = [{ address = "0.0.0.0:25", protocol = "smtp" }]
+                tls = { certificates = [] }"#,
+                "no certificates in [tls]",
+            ),
+            (
+                r#"listeners = [{ address = "/run/brev/smtp.sock", protocol = "smtp" }]"#,
+                "only LMTP can listen on a Unix socket",
+            ),
+            (
+                r#"listeners = [
+                    { address = "0.0.0.0:143", protocol = "imap", tls = "starttls" },
+                    { address = "[::]:143", protocol = "imap" },
+                ]
+                tls = { certificates = [{ certificate = "cert.pem", key = "key.pem" }] }"#,
+                "IMAP listeners must either all or none offer STARTTLS",
+            ),
+            (
+                r#"listeners = [{ address = "0.0.0.0:25", protocol = "smtp" }]
+                [limits.networks."10.0.0.0/33"]"#,
+                "limits.networks: invalid network \"10.0.0.0/33\"",
+            ),
+            (
+                r#"listeners = [{ address = "0.0.0.0:25", protocol = "smtp" }]
+                hostnmae = "typo.example.com""#,
+                "unknown field `hostnmae`",
+            ),
+            (
+                r#"listeners = [{ address = "0.0.0.0:25", protocol = "smtp" }]
+                [[dkim]]
+                domain = "example.com"
+                selector = "mail"
+                algorithm = "rsa-sha1"
+                key = "dkim.pem""#,
+                "unsupported algorithm: rsa-sha1",
+            ),
+            (
+                r#"listeners = [{ address = "0.0.0.0:25", protocol = "smtp" }]
+                [[dkim]]
+                domain = "example.com"
+                selector = "mail"
+                algorithm = "rsa-sha256"
+                key = "rsa.pem"
+                [[dkim]]
+                domain = "Example.com"
+                selector = "2024"
+                algorithm = "ed25519-sha256"
+                key = "ed25519.pem""#,
+                "more than one DKIM key for Example.com",
+            ),
+        ] {
+            let e = check(config).unwrap_err();
+            assert!(e.contains(error), "{e:?} doesn't contain {error:?}");
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Aliases in our domains that forward mail to addresses elsewhere. The
+//! forwarded mail goes through [`Pipeline::forward`], which seals it with
+//! ARC.
+//!
+//! [`Pipeline::forward`]: crate::submission::Pipeline::forward
+
+use std::str::FromStr;
+
+use email_address::EmailAddress;
+use smtp::{
+    command::params::RcptParams,
+    message::{Envelope, Recipient},
+};
+use sqlx::PgPool;
+use tracing::warn;
+
+/// Where the recipients of a message go.
+#[derive(Debug, Default)]
+pub struct Routes<'a> {
+    /// Recipients delivered to the local mailboxes.
+    pub local: Vec<&'a Recipient>,
+    /// An envelope for each alias, to forward the message with.
+    pub forwards: Vec<Envelope>,
+}
+
+/// The `aliases` table.
+#[derive(Debug, Clone)]
+pub struct Aliases {
+    pool: PgPool,
+}
+
+impl Aliases {
+    #[must_use]
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// The address mail for `address` is forwarded to, if it's an alias.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database is unavailable.
+    pub async fn target(&self, address: &EmailAddress) -> sqlx::Result<Option<EmailAddress>> {
+        let target: Option<(String,)> =
+            sqlx::query_as("SELECT target FROM aliases WHERE address = $1")
+                .bind(address.as_str().to_lowercase())
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(
+            target.and_then(|(target,)| match EmailAddress::from_str(&target) {
+                Ok(target) => Some(target),
+                Err(e) => {
+                    warn!(%address, target, %e, "invalid alias target");
+                    None
+                }
+            }),
+        )
+    }
+
+    /// Split the recipients of `envelope` into local ones and aliases.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database is unavailable.
+    pub async fn route<'a>(&self, envelope: &'a Envelope) -> sqlx::Result<Routes<'a>> {
+        let mut routes = Routes::default();
+        for recipient in &envelope.recipients {
+            match self.target(&recipient.address).await? {
+                Some(target) => routes.forwards.push(forward(envelope, recipient, target)),
+                None => routes.local.push(recipient),
+            }
+        }
+        Ok(routes)
+    }
+}
+
+/// The envelope to forward a message received with `envelope` for the alias
+/// `recipient` to `target`.
+///
+/// The reverse-path is the alias itself, so that the message is sealed with
+/// the key of our domain and passes SPF, and bounces come back to us instead
+/// of to a sender that didn't write to `target`. Bounces keep the null
+/// reverse-path, so that they can't loop.
+#[must_use]
+pub fn forward(envelope: &Envelope, recipient: &Recipient, target: EmailAddress) -> Envelope {
+    let from = envelope.from.as_ref().map(|_| recipient.address.clone());
+    let mut forward = Envelope::new(from, envelope.params.clone());
+    forward.add_recipient(target, RcptParams::default());
+    forward
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use dkim::{Keys, PrivateKey, Signer};
+    use dns::StaticResolver;
+    use email_address::EmailAddress;
+    use smtp::server::{policy::AcceptAll, Context, Profile, Server};
+    use sqlx::PgPool;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::forward;
+    use crate::{authentication::Authenticator, queue::Queue, submission::Pipeline};
+
+    struct NoUsers;
+
+    #[async_trait::async_trait]
+    impl auth::Validator for NoUsers {
+        async fn validate(
+            &self,
+            _credentials: &auth::Credentials,
+        ) -> Result<auth::Identity, auth::ValidationError> {
+            Err(auth::ValidationError::InvalidCredentials)
+        }
+    }
+
+    /// A message for an alias, received over SMTP, leaves sealed with ARC.
+    #[tokio::test]
+    async fn sealed() {
+        let server = Server::new(Context {
+            hostname: "mx.example.org".to_owned(),
+            tls: None,
+            auth: Arc::new(NoUsers),
+            max_message_size: None,
+            recipients: Arc::new(AcceptAll),
+            greylist: None,
+            profile: Profile::Mx,
+            milter: None,
+            limiter: None,
+            guard: None,
+        });
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        client
+            .write_all(
+                b"EHLO mail.example.net\r\n\
+                MAIL FROM:<bob@example.net>\r\n\
+                RCPT TO:<list@example.org>\r\n\
+                DATA\r\n\
+                From: Bob <bob@example.net>\r\n\
+                Subject: hi\r\n\
+                \r\n\
+                hi\r\n\
+                .\r\n\
+                QUIT\r\n",
+            )
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        let mut session = server.accept::<DuplexStream>(stream, "192.0.2.1:50000".parse().unwrap());
+        let mut message = session.next_message().await.unwrap().unwrap();
+        let mut content = Vec::new();
+        message
+            .with_trace()
+            .read_to_end(&mut content)
+            .await
+            .unwrap();
+
+        let resolver =
+            StaticResolver::default().with_txt("example.net", "v=spf1 ip4:192.0.2.0/24 -all");
+        let results = Authenticator::new(resolver, "mx.example.org")
+            .authenticate(message.client(), message.envelope(), &content)
+            .await;
+        let envelope = forward(
+            message.envelope(),
+            &message.envelope().recipients[0],
+            EmailAddress::from_str("carol@example.com").unwrap(),
+        );
+        assert_eq!(envelope.from.as_ref().unwrap().as_str(), "list@example.org");
+        assert_eq!(envelope.recipients[0].address.as_str(), "carol@example.com");
+
+        let key = PrivateKey::from_ed25519_seed(&[7; 32]).unwrap();
+        let pipeline = Pipeline::new(
+            Queue::new(PgPool::connect_lazy("postgres://localhost/brev").unwrap()),
+            Keys::default().with_signer(Signer::new("example.org", "mail", key)),
+        );
+        let forwarded = [results.header().as_bytes(), &content].concat();
+        let sealed = String::from_utf8(pipeline.seal(&envelope, forwarded, &results)).unwrap();
+        message.accept().await.unwrap();
+        while session.next_message().await.unwrap().is_some() {}
+        drop(session);
+
+        let headers: Vec<_> = sealed
+            .lines()
+            .filter(|line| !line.starts_with(char::is_whitespace))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(
+            headers[..5],
+            [
+                "ARC-Seal",
+                "ARC-Message-Signature",
+                "ARC-Authentication-Results",
+                "Authentication-Results",
+                "Received",
+            ]
+        );
+        assert!(sealed.contains(
+            "ARC-Authentication-Results: i=1; mx.example.org;\r\n\
+            \tdkim=none;\r\n\
+            \tspf=pass smtp.mailfrom=bob@example.net;\r\n"
+        ));
+        assert!(sealed.ends_with("Subject: hi\r\n\r\nhi\r\n"));
+
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        assert!(replies.contains("250 2.0.0 "), "{replies}");
+    }
+}
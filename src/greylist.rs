@@ -0,0 +1,167 @@
+//! Greylisting ([RFC 6647]) against the `greylist` table. Mail is deferred
+//! the first time a client network sends from a sender to a recipient, and
+//! accepted when the client retries after a delay.
+//!
+//! [RFC 6647]: https://datatracker.ietf.org/doc/html/rfc6647
+
+use std::{net::IpAddr, time::Duration};
+
+use email_address::EmailAddress;
+use smtp::server::policy::{GreylistPolicy, Verdict};
+use sqlx::PgPool;
+use tracing::{debug, error, info};
+use util::net::Network;
+
+/// Delays and exemptions.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How long a client must wait before retrying.
+    pub delay: Duration,
+    /// How long after the first attempt a retry is still counted. Later
+    /// retries start over.
+    pub retry_window: Duration,
+    /// How long a passed triplet is remembered after it was last seen.
+    pub expiry: Duration,
+    /// Clients that are never greylisted.
+    pub allowlist: Vec<Network>,
+    /// How often to delete expired triplets.
+    pub expire_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(5 * 60),
+            retry_window: Duration::from_secs(2 * 24 * 60 * 60),
+            expiry: Duration::from_secs(35 * 24 * 60 * 60),
+            allowlist: Vec::new(),
+            expire_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Greylists by (client network, sender, recipient). Clients are grouped
+/// by /24 for IPv4 and /64 for IPv6, since large senders often retry from
+/// a different address in the same network.
+#[derive(Debug, Clone)]
+pub struct Greylist {
+    pool: PgPool,
+    config: Config,
+}
+
+impl Greylist {
+    #[must_use]
+    pub fn new(pool: PgPool, config: Config) -> Self {
+        Self { pool, config }
+    }
+
+    /// Delete expired triplets. Returns how many were deleted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database can't be reached.
+    pub async fn expire(&self) -> sqlx::Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM greylist \
+            WHERE (passed AND last_seen < now() - $1 * INTERVAL '1 second') \
+            OR (NOT passed AND first_seen < now() - $2 * INTERVAL '1 second')",
+        )
+        .bind(self.config.expiry.as_secs_f64())
+        .bind(self.config.retry_window.as_secs_f64())
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Expire triplets forever.
+    pub async fn run(&self) {
+        loop {
+            match self.expire().await {
+                Ok(0) => (),
+                Ok(expired) => info!(expired, "greylist triplets expired"),
+                Err(e) => error!(%e, "expiring the greylist failed"),
+            }
+            tokio::time::sleep(self.config.expire_interval).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl GreylistPolicy for Greylist {
+    async fn check(
+        &self,
+        peer: IpAddr,
+        sender: Option<&EmailAddress>,
+        recipient: &EmailAddress,
+    ) -> Verdict {
+        if self.config.allowlist.iter().any(|net| net.contains(peer)) {
+            return Verdict::Accept;
+        }
+
+        let network = Network::containing(peer, 24, 64);
+        // the first attempt inserts the triplet; retries within the window
+        // but after the delay pass it, and late retries start over
+        let passed = sqlx::query_as(
+            "INSERT INTO greylist (network, sender, recipient) VALUES ($1, $2, $3) \
+            ON CONFLICT (network, sender, recipient) DO UPDATE SET \
+                last_seen = now(), \
+                first_seen = CASE \
+                    WHEN NOT greylist.passed \
+                        AND greylist.first_seen < now() - $5 * INTERVAL '1 second' \
+                    THEN now() ELSE greylist.first_seen END, \
+                passed = greylist.passed OR greylist.first_seen \
+                    BETWEEN now() - $5 * INTERVAL '1 second' \
+                    AND now() - $4 * INTERVAL '1 second' \
+            RETURNING passed",
+        )
+        .bind(network.to_string())
+        .bind(sender.map_or_else(String::new, |sender| sender.as_str().to_lowercase()))
+        .bind(recipient.as_str().to_lowercase())
+        .bind(self.config.delay.as_secs_f64())
+        .bind(self.config.retry_window.as_secs_f64())
+        .fetch_one(&self.pool)
+        .await;
+
+        match passed {
+            Ok((true,)) => Verdict::Accept,
+            Ok((false,)) => {
+                debug!(%network, ?sender, %recipient, "greylisted");
+                Verdict::Greylisted
+            }
+            Err(e) => {
+                // better to let spam through than to lose mail
+                error!(%e, %recipient, "greylist lookup failed");
+                Verdict::Accept
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use email_address::EmailAddress;
+    use smtp::server::policy::{GreylistPolicy, Verdict};
+    use sqlx::PgPool;
+
+    use super::{Config, Greylist};
+
+    #[tokio::test]
+    async fn allowlist() {
+        let greylist = Greylist::new(
+            PgPool::connect_lazy("postgres://localhost/brev").unwrap(),
+            Config {
+                allowlist: vec!["192.0.2.0/24".parse().unwrap()],
+                ..Config::default()
+            },
+        );
+        let recipient = EmailAddress::from_str("bob@example.com").unwrap();
+
+        // allowlisted clients don't touch the database
+        let verdict = greylist
+            .check([192, 0, 2, 1].into(), None, &recipient)
+            .await;
+        assert_eq!(verdict, Verdict::Accept);
+    }
+}
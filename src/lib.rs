@@ -1,4 +1,14 @@
+pub mod authentication;
+pub mod config;
+pub mod forwarding;
+pub mod greylist;
 mod listener;
 pub mod operations;
+pub mod queue;
+pub mod recipients;
+pub mod store;
+pub mod submission;
+pub mod tls;
+pub mod users;
 
 pub use listener::MultiListener;
@@ -1,20 +1,47 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
+use futures_util::{
+    future::{select_all, BoxFuture},
+    stream::FuturesUnordered,
+    StreamExt,
+};
 use line::stream::{MaybeTls, ServerTlsStream};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use tokio_rustls::{rustls, TlsAcceptor};
-use tracing::info;
+use tracing::{debug, info, warn};
+use util::{limit::RateLimiter, net::Network};
 
+mod proxy;
+
+/// How long a load balancer has to send the PROXY header.
+const PROXY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client has to finish the TLS handshake.
+const TLS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection handed out by [`MultiListener::accept`].
+pub type Stream = MaybeTls<ServerTlsStream<TcpStream>, TcpStream>;
+
+/// Accepts connections on any number of plain and TLS sockets.
+#[derive(Default)]
 pub struct MultiListener {
-    plain: TcpListener,
-    tls: Option<(TcpListener, Arc<rustls::ServerConfig>)>,
+    plain: Vec<TcpListener>,
+    tls: Vec<(TcpListener, Arc<rustls::ServerConfig>)>,
+    admission: Arc<Admission>,
+    /// New connections waiting for their PROXY header or TLS handshake.
+    pending: FuturesUnordered<BoxFuture<'static, Option<(Stream, SocketAddr)>>>,
 }
 
 impl MultiListener {
     pub async fn new(plain: impl ToSocketAddrs) -> std::io::Result<Self> {
-        let plain: TcpListener = TcpListener::bind(plain).await?;
-        info!("Binding {}", plain.local_addr()?);
-        Ok(Self { plain, tls: None })
+        Self::default().with_plain(plain).await
+    }
+
+    pub async fn with_plain(mut self, addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Binding {}", listener.local_addr()?);
+        self.plain.push(listener);
+        Ok(self)
     }
 
     pub async fn with_tls(
@@ -24,37 +51,185 @@ impl MultiListener {
     ) -> std::io::Result<Self> {
         let listener = TcpListener::bind(addr).await?;
         info!("Binding {} (TLS)", listener.local_addr()?);
-        self.tls = Some((listener, config));
+        self.tls.push((listener, config));
         Ok(self)
     }
 
-    /// Accept a TLS connection if TLS is enabled. If not, a forever
-    /// pending future is returned.
-    async fn accept_tls(&self) -> std::io::Result<(ServerTlsStream<TcpStream>, SocketAddr)> {
-        match &self.tls {
-            Some((tls, config)) => {
-                let (stream, addr) = tls.accept().await?;
-                TlsAcceptor::from(config.clone())
-                    .accept(stream)
-                    .await
-                    .map(|stream| (stream, addr))
+    /// Close connections from clients that connect too often. Plain
+    /// connections are sent `rejection` first, e.g. a 421 reply for SMTP.
+    #[must_use]
+    pub fn with_limiter(mut self, limiter: Arc<RateLimiter>, rejection: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.admission).limiter = Some((limiter, rejection.into()));
+        self
+    }
+
+    /// Expect a PROXY header ([v1 or v2]) on connections from `proxies`
+    /// and use the client address in it. Connections from elsewhere are
+    /// taken as they are.
+    ///
+    /// [v1 or v2]: https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+    #[must_use]
+    pub fn with_proxies(mut self, proxies: Vec<Network>) -> Self {
+        Arc::make_mut(&mut self.admission).proxies = proxies;
+        self
+    }
+
+    /// Accept a connection on any of the sockets, once its PROXY header
+    /// and TLS handshake are done.
+    ///
+    /// Those happen concurrently for all new connections, and go on between
+    /// calls, so that a slow client doesn't hold up the others. Connections
+    /// that fail them are dropped. This is cancel safe.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if accepting on a socket fails.
+    pub async fn accept(&mut self) -> std::io::Result<(Stream, SocketAddr)> {
+        loop {
+            tokio::select! {
+                (plain, _) = accept_any(&self.plain) => {
+                    let (stream, addr) = plain?;
+                    self.pending.push(Box::pin(handshake(self.admission.clone(), stream, addr, None)));
+                }
+                (tls, i) = accept_any(self.tls.iter().map(|(tls, _)| tls)) => {
+                    let (stream, addr) = tls?;
+                    let config = self.tls[i].1.clone();
+                    self.pending.push(Box::pin(handshake(self.admission.clone(), stream, addr, Some(config))));
+                }
+                Some(accepted) = self.pending.next() => {
+                    if let Some(accepted) = accepted {
+                        return Ok(accepted);
+                    }
+                }
             }
-            None => std::future::pending().await,
         }
     }
+}
+
+/// What a new connection has to pass before it is handed out.
+#[derive(Clone, Default)]
+struct Admission {
+    /// Limits connections per client, and the line sent to plain
+    /// connections over the limit before closing them.
+    limiter: Option<(Arc<RateLimiter>, String)>,
+    /// Load balancers that send a PROXY header with the client's address.
+    proxies: Vec<Network>,
+}
 
-    pub async fn accept(
-        &self,
-    ) -> std::io::Result<(MaybeTls<ServerTlsStream<TcpStream>, TcpStream>, SocketAddr)> {
-        tokio::select! {
-            plain = self.plain.accept() => {
-                let (stream, addr) = plain?;
-                Ok((MaybeTls::from_plain(stream), addr))
+impl Admission {
+    /// The address of the client on a new connection from `addr`, read from
+    /// the PROXY header if `addr` is a trusted proxy. `None` if the header
+    /// is malformed or late, in which case the connection should be
+    /// dropped.
+    async fn client(&self, stream: &mut TcpStream, addr: SocketAddr) -> Option<SocketAddr> {
+        if !self.proxies.iter().any(|proxy| proxy.contains(addr.ip())) {
+            return Some(addr);
+        }
+        match tokio::time::timeout(PROXY_TIMEOUT, proxy::read_header(stream)).await {
+            Ok(Ok(client)) => Some(client.unwrap_or(addr)),
+            Ok(Err(e)) => {
+                warn!(%addr, %e, "bad PROXY header");
+                None
+            }
+            Err(_) => {
+                warn!(%addr, "timed out waiting for PROXY header");
+                None
             }
-            tls = self.accept_tls() => {
-                let (stream, addr) = tls?;
-                Ok((MaybeTls::from_tls(stream), addr))
+        }
+    }
+
+    /// Whether a connection from `addr` is within the limits.
+    fn allowed(&self, addr: SocketAddr) -> bool {
+        let Some((limiter, _)) = &self.limiter else {
+            return true;
+        };
+        match limiter.connection(addr.ip()) {
+            Ok(()) => true,
+            Err(exceeded) => {
+                debug!(%addr, ?exceeded, "connection rate exceeded");
+                false
             }
         }
     }
 }
+
+/// Read the PROXY header of a new connection, check the limits and start
+/// TLS if `tls` is set.
+async fn handshake(
+    admission: Arc<Admission>,
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    tls: Option<Arc<rustls::ServerConfig>>,
+) -> Option<(Stream, SocketAddr)> {
+    let addr = admission.client(&mut stream, addr).await?;
+    if !admission.allowed(addr) {
+        if let (None, Some((_, rejection))) = (&tls, &admission.limiter) {
+            // best effort, the client is over its limit anyway
+            let _ = stream.try_write(rejection.as_bytes());
+        }
+        // no point in a TLS handshake just to say no
+        return None;
+    }
+
+    let Some(config) = tls else {
+        return Some((MaybeTls::from_plain(stream), addr));
+    };
+    match tokio::time::timeout(TLS_TIMEOUT, TlsAcceptor::from(config).accept(stream)).await {
+        Ok(Ok(stream)) => Some((MaybeTls::from_tls(stream), addr)),
+        Ok(Err(e)) => {
+            debug!(%addr, %e, "TLS handshake failed");
+            None
+        }
+        Err(_) => {
+            debug!(%addr, "timed out waiting for TLS handshake");
+            None
+        }
+    }
+}
+
+/// Accept a connection on any of `listeners`, along with the index of the
+/// listener. If there are none, a forever pending future is returned.
+async fn accept_any<'a>(
+    listeners: impl IntoIterator<Item = &'a TcpListener>,
+) -> (std::io::Result<(TcpStream, SocketAddr)>, usize) {
+    let accepts: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| Box::pin(listener.accept()))
+        .collect();
+    if accepts.is_empty() {
+        return std::future::pending().await;
+    }
+    let (accepted, i, _) = select_all(accepts).await;
+    (accepted, i)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+    use super::MultiListener;
+
+    /// A proxy that is slow to send its header doesn't hold up the next.
+    #[tokio::test]
+    async fn slow_proxy() {
+        let mut listener = MultiListener::new("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_proxies(vec!["127.0.0.0/8".parse().unwrap()]);
+        let addr = listener.plain[0].local_addr().unwrap();
+
+        let _slow = TcpStream::connect(addr).await.unwrap();
+        let mut fast = TcpStream::connect(addr).await.unwrap();
+        fast.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 25\r\n")
+            .await
+            .unwrap();
+
+        let (_, client) = tokio::time::timeout(Duration::from_secs(1), listener.accept())
+            .await
+            .expect("held up by the slow proxy")
+            .unwrap();
+        assert_eq!(client, "192.0.2.1:56324".parse().unwrap());
+    }
+}
@@ -0,0 +1,190 @@
+//! The [PROXY protocol], with which a load balancer tells us the address of
+//! the client it is forwarding a connection for. Both the text (v1) and the
+//! binary (v2) headers are accepted.
+//!
+//! [PROXY protocol]: https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The v2 signature, which can't be mistaken for a v1 header or for the
+/// start of any other protocol.
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest v1 header, including the CRLF.
+const V1_MAX: usize = 107;
+
+/// The shortest header of either version, `PROXY UNKNOWN\r\n`.
+const MIN: usize = 15;
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed PROXY header")
+}
+
+/// Read a PROXY header from `stream`, leaving whatever follows it unread.
+/// Returns the original source address, or `None` if the balancer doesn't
+/// know it, e.g. for its own health checks.
+///
+/// # Errors
+///
+/// Returns an error if the stream doesn't start with a valid header.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // a client may send more right after the header, e.g. a TLS
+    // ClientHello, so we can't read ahead
+    let mut header = vec![0; MIN];
+    stream.read_exact(&mut header).await?;
+
+    if header.starts_with(b"PROXY ") {
+        while !header.ends_with(b"\r\n") {
+            if header.len() == V1_MAX {
+                return Err(malformed());
+            }
+            header.push(stream.read_u8().await?);
+        }
+        return parse_v1(&header);
+    }
+
+    if !header.starts_with(SIGNATURE) {
+        return Err(malformed());
+    }
+    let len = u16::from_be_bytes([header[14], stream.read_u8().await?]);
+    let mut addresses = vec![0; usize::from(len)];
+    stream.read_exact(&mut addresses).await?;
+    parse_v2(header[12], header[13], &addresses)
+}
+
+/// Parse a v1 header such as `PROXY TCP4 192.0.2.1 198.51.100.1 56324 25\r\n`.
+fn parse_v1(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let header = std::str::from_utf8(header).map_err(|_| malformed())?;
+    let mut fields = header.trim_end_matches("\r\n").split(' ').skip(1);
+
+    let v4 = match fields.next() {
+        Some("TCP4") => true,
+        Some("TCP6") => false,
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(malformed()),
+    };
+    let (Some(source), Some(_), Some(port), Some(_), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(malformed());
+    };
+
+    let source: IpAddr = source.parse().map_err(|_| malformed())?;
+    if source.is_ipv4() != v4 {
+        return Err(malformed());
+    }
+    let port = port.parse().map_err(|_| malformed())?;
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+/// Parse the rest of a v2 header after the signature.
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match version_command {
+        // LOCAL: the balancer's own connection
+        0x20 => return Ok(None),
+        // PROXY
+        0x21 => (),
+        _ => return Err(malformed()),
+    }
+
+    match family {
+        // TCP over IPv4
+        0x11 => {
+            let Some(addresses) = addresses.get(..12) else {
+                return Err(malformed());
+            };
+            let source: [u8; 4] = addresses[..4].try_into().expect("4 bytes");
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(source).into(), port)))
+        }
+        // TCP over IPv6
+        0x21 => {
+            let Some(addresses) = addresses.get(..36) else {
+                return Err(malformed());
+            };
+            let source: [u8; 16] = addresses[..16].try_into().expect("16 bytes");
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(source).into(), port)))
+        }
+        // unspecified, UDP or Unix sockets
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::read_header;
+
+    async fn read(mut input: &[u8]) -> (std::io::Result<Option<String>>, &[u8]) {
+        let result = read_header(&mut input).await;
+        let mut rest = Vec::new();
+        input.read_to_end(&mut rest).await.unwrap();
+        (
+            result.map(|addr| addr.map(|addr| addr.to_string())),
+            rest.leak(),
+        )
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 25\r\nEHLO").await;
+        assert_eq!(addr.unwrap().unwrap(), "192.0.2.1:56324");
+        assert_eq!(rest, b"EHLO");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 993\r\n").await;
+        assert_eq!(addr.unwrap().unwrap(), "[2001:db8::1]:56324");
+
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\n\x16\x03").await;
+        assert!(addr.unwrap().is_none());
+        assert_eq!(rest, b"\x16\x03");
+
+        let too_long = [&b"PROXY UNKNOWN "[..], &[b'x'; 200]].concat();
+        for malformed in [
+            &b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 25\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 25\r\n",
+            b"EHLO client.example.com\r\n",
+            &too_long,
+        ] {
+            assert!(read(malformed).await.0.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        header.extend([192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0, 25]);
+        header.extend(b"EHLO");
+        let (addr, rest) = read(&header).await;
+        assert_eq!(addr.unwrap().unwrap(), "192.0.2.1:56324");
+        assert_eq!(rest, b"EHLO");
+
+        // with a TLV after the addresses
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x27".to_vec();
+        header.extend([0x20, 0x01, 0x0d, 0xb8].iter().chain(&[0; 11]).chain(&[1]));
+        header.extend([0; 16]);
+        header.extend([0xdc, 0x04, 0x03, 0xe1]);
+        header.extend([0x04, 0x00, 0x00]);
+        let (addr, rest) = read(&header).await;
+        assert_eq!(addr.unwrap().unwrap(), "[2001:db8::1]:56324");
+        assert!(rest.is_empty());
+
+        // health checks from the balancer itself
+        let (addr, _) = read(b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00").await;
+        assert!(addr.unwrap().is_none());
+
+        let (addr, _) = read(b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x04\x01\x02\x03\x04").await;
+        assert!(addr.is_err());
+    }
+}
@@ -1,26 +1,56 @@
-use std::sync::Arc;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use brev::{operations, MultiListener};
-use futures_util::{stream::FuturesUnordered, FutureExt, StreamExt};
-use smtp::server::session::Session;
+use anyhow::Context as _;
+use auth::guard::{self, Guard};
+use brev::{
+    authentication::{self, Authenticator, Disposition},
+    config::{Address, Backend, Config, Protocol, TlsMode},
+    forwarding::{Aliases, Routes},
+    greylist::Greylist,
+    operations,
+    queue::{self, delivery::Transport, Queue, Worker},
+    recipients::Recipients,
+    store::{self, Folder},
+    submission::Pipeline,
+    tls::Resolver,
+    users::{self, Users},
+    MultiListener,
+};
+use futures_util::{future::select_all, stream::FuturesUnordered, StreamExt};
+use smtp::{
+    reply::{EnhancedCode, Reply},
+    server::{
+        milter::{self, Response},
+        policy::{GreylistPolicy, OwnAddress, SenderPolicy},
+        session::Session,
+        submission, Profile,
+    },
+};
 use sqlx::PgPool;
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite},
-    net::TcpStream,
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
 };
-use tokio_rustls::rustls::{self, Certificate, PrivateKey};
-use tracing::{error, info, instrument};
+use tokio_rustls::rustls;
+use tracing::{debug, error, info, instrument, warn};
+use util::limit::RateLimiter;
 
-pub struct Auth;
+/// The `accept-all` backend, which accepts any password.
+pub struct AcceptAll;
 
 #[async_trait::async_trait]
-impl auth::Validator for Auth {
+impl auth::Validator for AcceptAll {
     async fn validate(
         &self,
         credentials: &auth::Credentials,
     ) -> Result<auth::Identity, auth::ValidationError> {
         Ok(auth::Identity(credentials.username.clone()))
-        // todo!()
     }
 }
 
@@ -30,11 +60,21 @@ pub async fn handle_imap<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator>
     let mut futures = FuturesUnordered::new();
 
     loop {
-        tokio::select! {
-            Some(()) = futures.next() => {}
-            Some(res) = session.next_op().map(Result::transpose) => futures.push(operations::handle(res?)),
-            else => {
-                return Ok(());
+        // `next_op` is not cancel safe, so it's kept alive while the
+        // operations in progress are polled
+        let next_op = session.next_op();
+        tokio::pin!(next_op);
+
+        loop {
+            tokio::select! {
+                Some(()) = futures.next() => {}
+                res = &mut next_op => match res? {
+                    Some(op) => {
+                        futures.push(operations::handle(op));
+                        break;
+                    }
+                    None => return Ok(()),
+                },
             }
         }
     }
@@ -43,18 +83,14 @@ pub async fn handle_imap<IO: AsyncRead + AsyncWrite + Unpin, A: auth::Validator>
 #[instrument(skip_all)]
 async fn imap<A: auth::Validator + 'static>(
     context: imap::server::Context<A>,
+    mut listener: MultiListener,
 ) -> anyhow::Result<()> {
-    let mut listener = MultiListener::new("0.0.0.0:143").await?;
-    if let Some(tls) = context.tls.clone() {
-        listener = listener.with_tls("0.0.0.0:993", tls).await?;
-    }
-
     let server = imap::Server::new(context);
 
     loop {
         let (socket, addr) = listener.accept().await?;
         info!("Got connection from: {}", addr);
-        let session = server.accept::<TcpStream>(socket);
+        let session = server.accept::<TcpStream>(socket, addr);
 
         tokio::spawn(async move {
             if let Err(e) = handle_imap(session).await {
@@ -64,55 +100,300 @@ async fn imap<A: auth::Validator + 'static>(
     }
 }
 
+/// TLS configuration for relaying, trusting the Mozilla root certificates.
+#[allow(deprecated)] // webpki-roots 0.24 only exposes the roots this way
+fn client_tls_config() -> Arc<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    Arc::new(
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+const USAGE: &str = "usage: brev [--check-config] [CONFIG]\n       brev --hash-password";
+
+/// Read a password from stdin and print its hash for the `users` table.
+fn hash_password() -> anyhow::Result<()> {
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    anyhow::ensure!(!password.is_empty(), "no password on stdin");
+    let hash = users::hash_password(password).map_err(|e| anyhow::anyhow!("{e}"))?;
+    println!("{hash}");
+    Ok(())
+}
+
+/// Bind the listeners in `config` for `protocol`, or return `None` if
+/// there are none.
+async fn bind(
+    config: &Config,
+    protocol: Protocol,
+    tls: Option<&Arc<rustls::ServerConfig>>,
+    limiter: &Arc<RateLimiter>,
+    rejection: String,
+) -> anyhow::Result<Option<MultiListener>> {
+    let mut listeners = config.listeners(protocol).peekable();
+    if listeners.peek().is_none() {
+        return Ok(None);
+    }
+
+    let mut multi = MultiListener::default();
+    for listener in listeners {
+        let Address::Tcp(addr) = listener.address else {
+            anyhow::bail!("{protocol} can't listen on {}", listener.address);
+        };
+        multi = match listener.tls {
+            TlsMode::None | TlsMode::Starttls => multi.with_plain(addr).await?,
+            TlsMode::Implicit => {
+                let tls = tls.context("implicit TLS without a certificate")?;
+                multi.with_tls(addr, tls.clone()).await?
+            }
+        };
+    }
+    Ok(Some(
+        multi
+            .with_proxies(config.proxies.clone())
+            .with_limiter(limiter.clone(), rejection),
+    ))
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
     tracing_subscriber::fmt::init();
 
-    let pool = PgPool::connect(&std::env::var("DATABASE_URL")?).await?;
+    let mut check = false;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--check-config" => check = true,
+            "--hash-password" => return hash_password(),
+            _ if arg.starts_with('-') || path.is_some() => anyhow::bail!(USAGE),
+            _ => path = Some(PathBuf::from(arg)),
+        }
+    }
+    let path = path.unwrap_or_else(|| PathBuf::from("brev.toml"));
 
-    let cert = rcgen::generate_simple_self_signed(["localhost".to_owned()]).unwrap();
+    let config =
+        Config::load(&path).with_context(|| format!("invalid configuration {}", path.display()))?;
+    let resolver = config
+        .tls
+        .as_ref()
+        .map(|tls| Resolver::new(tls.certificates.clone()).map(Arc::new))
+        .transpose()
+        .context("invalid TLS certificate")?;
+    let database_url = config
+        .database_url
+        .clone()
+        .or_else(|| std::env::var("DATABASE_URL").ok())
+        .context("no database_url in the configuration, and DATABASE_URL isn't set")?;
+    let rate_limits = config.limits.rate_limits()?;
+    let dkim = config.dkim_keys().context("invalid DKIM key")?;
+    if check {
+        println!("{}: ok", path.display());
+        return Ok(());
+    }
 
-    let tls_config = Arc::new(
-        rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(
-                vec![Certificate(cert.serialize_der()?)],
-                PrivateKey(cert.serialize_private_key_der()),
-            )
-            .unwrap(),
+    let pool = PgPool::connect(&database_url).await?;
+
+    let worker = Worker::new(
+        Queue::new(pool.clone()),
+        Transport {
+            resolver: dns::SystemResolver::from_resolv_conf()?,
+            hostname: config.hostname.clone(),
+            port: 25,
+            tls: Some(client_tls_config().into()),
+            timeout: Duration::from_secs(10 * 60),
+        },
+        queue::Config::default(),
     );
+    let mut tasks = vec![tokio::spawn(async move {
+        worker.run().await;
+        anyhow::Ok(())
+    })];
+
+    // one configuration for all protocols, so that renewed certificates
+    // are picked up everywhere
+    let tls = resolver.map(|resolver| {
+        let tls = resolver.server_config();
+        tasks.push(tokio::spawn(async move {
+            resolver.watch().await?;
+            anyhow::Ok(())
+        }));
+        tls
+    });
+
+    let milter = config.milter.clone().map(|address| milter::Config {
+        address,
+        timeout: Duration::from_secs(30),
+    });
 
-    let auth = Arc::new(Auth);
-    let imap = tokio::spawn(imap(imap::server::Context {
-        tls: Some(tls_config.clone()),
+    let greylist = config.greylist.as_ref().map(|greylist| {
+        let greylist = Arc::new(Greylist::new(pool.clone(), greylist.config()));
+        let expiry = Arc::clone(&greylist);
+        tokio::spawn(async move { expiry.run().await });
+        greylist as Arc<dyn GreylistPolicy>
+    });
+
+    // submitted mail is signed and forwarded mail sealed with the same keys
+    let pipeline = Arc::new(Pipeline::new(Queue::new(pool.clone()), dkim));
+
+    let limiter = Arc::new(RateLimiter::new(rate_limits));
+    let guard = Arc::new(Guard::new(guard::Config::default()));
+
+    let auth: Arc<Box<dyn auth::Validator>> = match config.auth.backend {
+        Backend::Database => Arc::new(Box::new(Users::new(pool.clone()))),
+        Backend::AcceptAll => {
+            warn!("accepting any password, don't use the accept-all backend in production");
+            Arc::new(Box::new(AcceptAll))
+        }
+    };
+    let max_message_size = Some(config.limits.max_message_size);
+    // plain connections are offered STARTTLS if any listener says so
+    let starttls = |protocol| tls.clone().filter(|_| config.starttls(protocol));
+
+    if let Some(listener) = bind(
+        &config,
+        Protocol::Imap,
+        tls.as_ref(),
+        &limiter,
+        "* BYE [LIMIT] Too many connections\r\n".to_owned(),
+    )
+    .await?
+    {
+        tasks.push(tokio::spawn(imap(
+            imap::server::Context {
+                tls: starttls(Protocol::Imap),
+                auth: auth.clone(),
+                limiter: Some(limiter.clone()),
+                guard: Some(guard.clone()),
+            },
+            listener,
+        )));
+    }
+
+    if let Some(listener) = bind(
+        &config,
+        Protocol::Smtp,
+        tls.as_ref(),
+        &limiter,
+        too_many_connections().to_string(),
+    )
+    .await?
+    {
+        tasks.push(tokio::spawn(smtp(
+            smtp::server::Context {
+                hostname: config.hostname.clone(),
+                tls: starttls(Protocol::Smtp),
+                auth: auth.clone(),
+                max_message_size,
+                recipients: Arc::new(Recipients::new(pool.clone())),
+                greylist,
+                profile: Profile::Mx,
+                milter: milter.clone(),
+                limiter: Some(limiter.clone()),
+                guard: Some(guard.clone()),
+            },
+            pool.clone(),
+            pipeline.clone(),
+            listener,
+        )));
+    }
+
+    if let Some(listener) = bind(
+        &config,
+        Protocol::Submission,
+        tls.as_ref(),
+        &limiter,
+        too_many_connections().to_string(),
+    )
+    .await?
+    {
+        let senders: Arc<dyn SenderPolicy> = Arc::new(OwnAddress);
+        tasks.push(tokio::spawn(submission(
+            smtp::server::Context {
+                hostname: config.hostname.clone(),
+                tls: starttls(Protocol::Submission),
+                auth: auth.clone(),
+                max_message_size,
+                recipients: Arc::new(smtp::server::policy::AcceptAll),
+                greylist: None,
+                profile: Profile::Submission {
+                    senders: senders.clone(),
+                },
+                milter,
+                limiter: Some(limiter.clone()),
+                guard: Some(guard.clone()),
+            },
+            pipeline.clone(),
+            senders,
+            listener,
+        )));
+    }
+
+    let lmtp_context = smtp::server::Context {
+        hostname: config.hostname.clone(),
+        tls: None,
         auth: auth.clone(),
-    }));
-    let smtp = tokio::spawn(smtp(
-        smtp::server::Context {
-            hostname: "localhost".to_owned(),
-            tls: Some(tls_config.clone()),
-            auth: auth.clone(),
-        },
-        pool.clone(),
-    ));
+        max_message_size,
+        recipients: Arc::new(Recipients::new(pool.clone())),
+        greylist: None,
+        profile: Profile::Lmtp,
+        // the MTA in front of us runs the filters and limits
+        milter: None,
+        limiter: None,
+        guard: None,
+    };
+    for listener in config.listeners(Protocol::Lmtp) {
+        tasks.push(tokio::spawn(lmtp(
+            lmtp_context.clone(),
+            pool.clone(),
+            listener.address.clone(),
+        )));
+    }
 
-    tokio::select! {
-        res = imap => res,
-        res = smtp => res,
-    }?
+    let (res, _, _) = select_all(tasks).await;
+    res?
+}
+
+fn temporary_failure() -> Reply {
+    Reply::new(
+        451,
+        EnhancedCode::new(4, 3, 0),
+        "temporary failure, try again later",
+    )
+}
+
+fn too_many_connections() -> Reply {
+    Reply::new(
+        421,
+        EnhancedCode::new(4, 7, 0),
+        "too many connections, try again later",
+    )
 }
 
 #[instrument(skip_all)]
 async fn smtp<A: auth::Validator + 'static>(
     context: smtp::server::Context<A>,
     pool: PgPool,
+    pipeline: Arc<Pipeline>,
+    mut listener: MultiListener,
 ) -> anyhow::Result<()> {
-    let mut listener = MultiListener::new("0.0.0.0:25").await?;
-    if let Some(tls) = context.tls.clone() {
-        listener = listener.with_tls("0.0.0.0:465", tls).await?;
-    }
+    let authenticator = Arc::new(Authenticator::new(
+        dns::SystemResolver::from_resolv_conf()?,
+        context.hostname.clone(),
+    ));
+    let aliases = Aliases::new(pool.clone());
 
     let server = smtp::Server::new(context);
 
@@ -121,11 +402,16 @@ async fn smtp<A: auth::Validator + 'static>(
 
         info!("Got connection from: {}", addr);
 
-        let session = server.accept(socket);
+        let session = server.accept::<TcpStream>(socket, addr);
         let pool = pool.clone();
+        let aliases = aliases.clone();
+        let pipeline = pipeline.clone();
+        let authenticator = authenticator.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(session, pool).await {
+            if let Err(e) =
+                handle_connection(session, pool, &aliases, &pipeline, authenticator).await
+            {
                 error!("an error occurred: {e:?}");
             }
         });
@@ -134,22 +420,479 @@ async fn smtp<A: auth::Validator + 'static>(
 
 async fn handle_connection<IO: AsyncRead + AsyncWrite + Unpin + Send + Sync, A: auth::Validator>(
     mut session: Session<IO, A>,
-    _pool: PgPool,
+    pool: PgPool,
+    aliases: &Aliases,
+    pipeline: &Pipeline,
+    authenticator: Arc<Authenticator<dns::SystemResolver>>,
 ) -> anyhow::Result<()> {
-    println!("helo");
+    while let Some(mut message) = session.next_message().await? {
+        debug!(
+            from = ?message.envelope().from,
+            recipients = message.envelope().recipients.len(),
+            "receiving message"
+        );
+
+        let mut content = Vec::new();
+        let res = message.with_trace().read_to_end(&mut content).await;
+        if let Err(e) = res {
+            if message.is_too_large() {
+                message.reject(smtp::reply::Reply::too_large()).await?;
+                continue;
+            }
+            return Err(e.into());
+        }
+
+        // delivered locally, so this is the final hop
+        let mut out = smtp::trace::return_path(message.envelope().from.as_ref()).into_bytes();
+        let mut folder = Folder::Inbox;
+        let results = authenticator
+            .authenticate(message.client(), message.envelope(), &content)
+            .await;
+        out.extend_from_slice(results.header().as_bytes());
+        match results.disposition() {
+            Disposition::Accept => (),
+            Disposition::Quarantine => folder = Folder::Spam,
+            Disposition::Reject(reply) => {
+                message.reject(reply).await?;
+                continue;
+            }
+        }
+        let forged = authentication::remove_forged_results(&mut content, authenticator.hostname());
+        if forged > 0 {
+            info!(forged, "removed Authentication-Results claiming to be ours");
+        }
+        let outcome = message.filter(&mut content).await;
+        match outcome.response {
+            Response::Continue | Response::Accept => (),
+            Response::Discard => {
+                info!("discarding message");
+                message.accept().await?;
+                continue;
+            }
+            Response::Reject(reply) => {
+                message.reject(reply).await?;
+                continue;
+            }
+        }
+        if let Some(reason) = outcome.quarantine {
+            info!(%reason, "quarantining message");
+            folder = Folder::Spam;
+        }
+        out.extend_from_slice(&content);
+
+        let Routes { local, forwards } = match aliases.route(message.envelope()).await {
+            // quarantined mail stays here instead of being forwarded
+            Ok(_) if folder == Folder::Spam => Routes {
+                local: message.envelope().recipients.iter().collect(),
+                forwards: Vec::new(),
+            },
+            Ok(routes) => routes,
+            Err(e) => {
+                error!(%e, "alias lookup failed");
+                message.reject(temporary_failure()).await?;
+                continue;
+            }
+        };
+
+        let delivered: sqlx::Result<()> = async {
+            if !local.is_empty() {
+                store::deliver(&pool, folder, &out).await?;
+            }
+            if forwards.is_empty() {
+                return Ok(());
+            }
+            let forwarded = [results.header().as_bytes(), &content].concat();
+            for envelope in &forwards {
+                let id = pipeline
+                    .forward(envelope, forwarded.clone(), &results)
+                    .await?;
+                info!(id, to = %envelope.recipients[0].address, "forwarded message");
+            }
+            Ok(())
+        }
+        .await;
+
+        match delivered {
+            Ok(()) => {
+                for recipient in local {
+                    info!(to = %recipient.address, folder = folder.name(), "delivered message");
+                }
+                message.accept().await?;
+            }
+            Err(e) => {
+                error!(%e, "failed to deliver message");
+                message.reject(temporary_failure()).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
 
+/// Accept mail from our users, usually on port 587 with STARTTLS and on
+/// port 465 with implicit TLS ([RFC 8314]).
+///
+/// [RFC 8314]: https://datatracker.ietf.org/doc/html/rfc8314
+#[instrument(skip_all)]
+async fn submission<A: auth::Validator + 'static>(
+    context: smtp::server::Context<A>,
+    pipeline: Arc<Pipeline>,
+    senders: Arc<dyn SenderPolicy>,
+    mut listener: MultiListener,
+) -> anyhow::Result<()> {
+    let hostname = context.hostname.clone();
+
+    let server = smtp::Server::new(context);
+
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        info!("Got submission connection from: {}", addr);
+
+        let session = server.accept::<TcpStream>(socket, addr);
+        let pipeline = pipeline.clone();
+        let senders = senders.clone();
+        let hostname = hostname.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_submission(session, &pipeline, senders.as_ref(), &hostname).await
+            {
+                error!("an error occurred: {e:?}");
+            }
+        });
+    }
+}
+
+async fn handle_submission<IO: AsyncRead + AsyncWrite + Unpin + Send + Sync, A: auth::Validator>(
+    mut session: Session<IO, A>,
+    pipeline: &Pipeline,
+    senders: &dyn SenderPolicy,
+    hostname: &str,
+) -> anyhow::Result<()> {
     while let Some(mut message) = session.next_message().await? {
-        println!("Got message: {:?}", message.envelope());
+        let mut content = Vec::new();
+        let res = message.with_trace().read_to_end(&mut content).await;
+        if let Err(e) = res {
+            if message.is_too_large() {
+                message.reject(Reply::too_large()).await?;
+                continue;
+            }
+            return Err(e.into());
+        }
+
+        // the session doesn't start a transaction before AUTH
+        let Some(identity) = message.client().identity.clone() else {
+            message.reject(Reply::rejected()).await?;
+            continue;
+        };
+        if let Err(reply) = submission::check_from(senders, &identity, &content).await {
+            message.reject(reply).await?;
+            continue;
+        }
+        submission::fix(&mut content, hostname, SystemTime::now());
+        let outcome = message.filter(&mut content).await;
+        match outcome.response {
+            Response::Continue | Response::Accept => (),
+            Response::Discard => {
+                info!("discarding message");
+                message.accept().await?;
+                continue;
+            }
+            Response::Reject(reply) => {
+                message.reject(reply).await?;
+                continue;
+            }
+        }
+        let queued = match outcome.quarantine {
+            Some(reason) => {
+                info!(%reason, "quarantining message");
+                pipeline.hold(message.envelope(), content).await
+            }
+            None => pipeline.submit(message.envelope(), content).await,
+        };
+        match queued {
+            Ok(id) => {
+                info!(id, "queued submitted message");
+                message.accept().await?;
+            }
+            Err(e) => {
+                error!(%e, "failed to queue message");
+                message.reject(temporary_failure()).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Accept mail from an MTA in front of us over LMTP, on a Unix socket or
+/// on a TCP address that should be on the loopback interface.
+#[instrument(skip_all, fields(%address))]
+async fn lmtp<A: auth::Validator + 'static>(
+    context: smtp::server::Context<A>,
+    pool: PgPool,
+    address: Address,
+) -> anyhow::Result<()> {
+    let server = smtp::Server::new(context);
 
-        let mut out = String::new();
-        message.read_to_string(&mut out).await?;
-        message.accept().await?;
+    match address {
+        Address::Unix(path) => {
+            // a socket left behind by an earlier run
+            if std::fs::metadata(&path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                std::fs::remove_file(&path)?;
+            }
+            let listener = UnixListener::bind(&path)?;
+            info!("Binding {} (LMTP)", path.display());
 
-        println!("received {} bytes", out.len());
-        println!("{out}");
+            // the client is local, but Unix sockets have no address
+            let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+            loop {
+                let (socket, _) = listener.accept().await?;
+                let session = server.accept::<UnixStream>(socket, peer);
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_lmtp(session, pool).await {
+                        error!("an error occurred: {e:?}");
+                    }
+                });
+            }
+        }
+        Address::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("Binding {} (LMTP)", listener.local_addr()?);
+            loop {
+                let (socket, addr) = listener.accept().await?;
+                let session = server.accept::<TcpStream>(socket, addr);
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_lmtp(session, pool).await {
+                        error!("an error occurred: {e:?}");
+                    }
+                });
+            }
+        }
     }
+}
+
+async fn handle_lmtp<IO: AsyncRead + AsyncWrite + Unpin + Send + Sync, A: auth::Validator>(
+    mut session: Session<IO, A>,
+    pool: PgPool,
+) -> anyhow::Result<()> {
+    while let Some(mut message) = session.next_message().await? {
+        let mut content = Vec::new();
+        let res = message.with_trace().read_to_end(&mut content).await;
+        if let Err(e) = res {
+            if message.is_too_large() {
+                message.reject(Reply::too_large()).await?;
+                continue;
+            }
+            return Err(e.into());
+        }
+
+        // the MTA in front of us has already authenticated the message
+        let mut out = smtp::trace::return_path(message.envelope().from.as_ref()).into_bytes();
+        out.extend_from_slice(&content);
 
-    println!("Connection closed");
+        let recipients = &message.envelope().recipients;
+        let replies = match store::deliver(&pool, Folder::Inbox, &out).await {
+            Ok(_) => recipients
+                .iter()
+                .map(|recipient| {
+                    info!(to = %recipient.address, bytes = out.len(), "delivered message");
+                    Reply::ok()
+                })
+                .collect(),
+            Err(e) => {
+                error!(%e, "failed to deliver message");
+                vec![temporary_failure(); recipients.len()]
+            }
+        };
+        message.reply_each(replies).await?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use brev::{queue::Queue, submission::Pipeline};
+    use smtp::server::{milter, policy, Context, Profile, Server};
+    use sqlx::{postgres::PgPoolOptions, PgPool};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::TcpListener,
+    };
+
+    use super::{handle_lmtp, handle_submission, AcceptAll};
+
+    /// The replies to a message for two recipients, delivered over LMTP.
+    async fn lmtp_replies(pool: PgPool) -> String {
+        let server = Server::new(Context {
+            hostname: "mail.example.org".to_owned(),
+            tls: None,
+            auth: Arc::new(AcceptAll),
+            max_message_size: None,
+            recipients: Arc::new(policy::AcceptAll),
+            greylist: None,
+            profile: Profile::Lmtp,
+            milter: None,
+            limiter: None,
+            guard: None,
+        });
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        client
+            .write_all(
+                b"LHLO mx.example.org\r\n\
+                MAIL FROM:<bob@example.net>\r\n\
+                RCPT TO:<alice@example.org>\r\n\
+                RCPT TO:<carol@example.org>\r\n\
+                DATA\r\n\
+                Subject: hi\r\n\
+                \r\n\
+                hi\r\n\
+                .\r\n",
+            )
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+
+        let session = server.accept::<DuplexStream>(stream, "127.0.0.1:0".parse().unwrap());
+        handle_lmtp(session, pool).await.unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        replies
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn lmtp_stores(pool: PgPool) {
+        let replies = lmtp_replies(pool.clone()).await;
+        assert!(
+            replies.ends_with("354 go ahead\r\n250 2.0.0 ok\r\n250 2.0.0 ok\r\n"),
+            "{replies}"
+        );
+
+        // one copy for both recipients
+        let stored: Vec<(String, i32, Vec<u8>)> =
+            sqlx::query_as("SELECT mailbox, uid, data FROM messages")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        let [(mailbox, uid, data)] = &stored[..] else {
+            panic!("stored {} messages", stored.len());
+        };
+        assert_eq!((mailbox.as_str(), *uid), ("INBOX", 1));
+        let data = String::from_utf8_lossy(data);
+        assert!(
+            data.starts_with("Return-Path: <bob@example.net>\r\n"),
+            "{data}"
+        );
+        assert!(data.ends_with("Subject: hi\r\n\r\nhi\r\n"), "{data}");
+    }
+
+    #[tokio::test]
+    async fn lmtp_store_failure() {
+        // nothing listens on port 1
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_secs(1))
+            .connect_lazy("postgres://127.0.0.1:1/brev")
+            .unwrap();
+        let replies = lmtp_replies(pool).await;
+        assert!(
+            replies.ends_with(
+                "354 go ahead\r\n\
+                451 4.3.0 temporary failure, try again later\r\n\
+                451 4.3.0 temporary failure, try again later\r\n"
+            ),
+            "{replies}"
+        );
+    }
+
+    /// A milter that quarantines every message.
+    async fn quarantining_milter(listener: TcpListener) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        while let Ok(len) = stream.read_u32().await {
+            let mut packet = vec![0; len as usize];
+            stream.read_exact(&mut packet).await.unwrap();
+            let responses: &[&[u8]] = match packet[0] {
+                b'O' => &[b"O\0\0\0\x06\0\0\0\x20\0\0\0\0"],
+                b'E' => &[b"qlooks spammy\0", b"c"],
+                b'A' | b'D' => continue,
+                _ => &[b"c"],
+            };
+            for response in responses {
+                let len = u32::try_from(response.len()).unwrap();
+                stream.write_all(&len.to_be_bytes()).await.unwrap();
+                stream.write_all(response).await.unwrap();
+            }
+        }
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn quarantined_submission_is_held(pool: PgPool) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(quarantining_milter(listener));
+
+        let server = Server::new(Context {
+            hostname: "mail.example.org".to_owned(),
+            tls: None,
+            auth: Arc::new(AcceptAll),
+            max_message_size: None,
+            recipients: Arc::new(policy::AcceptAll),
+            greylist: None,
+            profile: Profile::Submission {
+                senders: Arc::new(policy::OwnAddress),
+            },
+            milter: Some(milter::Config {
+                address: milter::Address::Tcp(address.to_string()),
+                timeout: Duration::from_secs(5),
+            }),
+            limiter: None,
+            guard: None,
+        });
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        // "\0alice@example.org\0hunter2"
+        client
+            .write_all(
+                b"EHLO client.example.org\r\n\
+                AUTH PLAIN AGFsaWNlQGV4YW1wbGUub3JnAGh1bnRlcjI=\r\n\
+                MAIL FROM:<alice@example.org>\r\n\
+                RCPT TO:<bob@example.net>\r\n\
+                DATA\r\n\
+                From: alice@example.org\r\n\
+                Subject: hi\r\n\
+                \r\n\
+                hi\r\n\
+                .\r\n",
+            )
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+
+        let session = server.accept::<DuplexStream>(stream, "192.0.2.1:50000".parse().unwrap());
+        let pipeline = Pipeline::new(Queue::new(pool.clone()), dkim::Keys::default());
+        handle_submission(session, &pipeline, &policy::OwnAddress, "mail.example.org")
+            .await
+            .unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        assert!(
+            replies.ends_with("354 go ahead\r\n250 2.0.0 ok\r\n"),
+            "{replies}"
+        );
+
+        // queued, but never to be attempted
+        let recipients: Vec<(String, String)> =
+            sqlx::query_as("SELECT address, status FROM queue_recipients")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            recipients,
+            [("bob@example.net".to_owned(), "held".to_owned())]
+        );
+    }
+}
@@ -27,6 +27,7 @@ macro_rules! operations {
 operations! {
     fetch,
     list,
+    status,
     select,
     create,
 }
@@ -1,12 +1,31 @@
-use imap::server::ops::select::{Request, Response};
+use imap::server::{
+    mailbox::{Message, Snapshot},
+    ops::select::{Request, Response},
+};
 use imap_proto::{response::StatusResponse, Uid, command::list::{ListItem, Attributes}};
+use tokio::sync::watch;
 
 pub async fn select(req: Request) -> Result<Response, StatusResponse> {
     let Request { mailbox, read_only } = req;
 
+    let snapshot = Snapshot {
+        messages: (400..432)
+            .map(|uid| Message {
+                uid: Uid(uid.try_into().unwrap()),
+                flags: vec![],
+            })
+            .collect(),
+    };
+    // Mailboxes are static for now, so nothing ever publishes a change and
+    // the client is never sent EXISTS, EXPUNGE or FETCH updates. Once
+    // delivery and STORE write to a mailbox, they should publish its new
+    // snapshot on a sender kept per mailbox.
+    let (_, updates) = watch::channel(snapshot.clone());
+
     Ok(Response {
         flags: vec![],
-        exists: 32,
+        snapshot,
+        updates,
         uid_validity: 58943,
         next_uid: Uid(432.try_into().unwrap()),
         mailbox: ListItem {
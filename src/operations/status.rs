@@ -0,0 +1,14 @@
+use imap::server::ops::status::{Request, Response};
+use imap_proto::{command, response::StatusResponse, Uid};
+
+pub async fn status(req: Request) -> Result<Response, StatusResponse> {
+    let Request(command::Status { mailbox, items: _ }) = req;
+
+    Ok(Response(command::status::Response {
+        mailbox,
+        messages: Some(32),
+        uid_next: Some(Uid(432.try_into().unwrap())),
+        uid_validity: Some(Uid(58943.try_into().unwrap())),
+        ..Default::default()
+    }))
+}
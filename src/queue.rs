@@ -0,0 +1,415 @@
+//! Outbound mail queue. Messages for remote domains are spooled in
+//! Postgres and relayed by a [`Worker`], which retries temporary failures
+//! with exponential backoff and bounces permanent ones to the sender.
+
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use dns::Resolver;
+use email_address::EmailAddress;
+use smtp::{
+    command::params::{MailParams, RcptParams},
+    dsn::{self, Action, RecipientStatus},
+    message::{Envelope, Recipient},
+    reply::{EnhancedCode, Reply},
+};
+use sqlx::PgPool;
+use tracing::{debug, error, info, instrument, warn};
+
+use self::delivery::{Outcome, Status, Transport};
+
+pub mod delivery;
+
+/// How long recipients are locked by a worker while being delivered.
+const LEASE: Duration = Duration::from_secs(30 * 60);
+
+/// The spool of messages waiting to be relayed.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    pool: PgPool,
+}
+
+impl Queue {
+    #[must_use]
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Add a message to the queue, to be delivered to each recipient of
+    /// `envelope` as soon as possible.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message couldn't be stored.
+    #[instrument(skip_all, fields(from = ?envelope.from))]
+    pub async fn enqueue(&self, envelope: &Envelope, message: &[u8]) -> sqlx::Result<i64> {
+        self.insert(envelope, message, "pending").await
+    }
+
+    /// Add a message to the queue without ever delivering it, e.g. because
+    /// it was quarantined. It stays until an operator releases or deletes
+    /// it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message couldn't be stored.
+    #[instrument(skip_all, fields(from = ?envelope.from))]
+    pub async fn hold(&self, envelope: &Envelope, message: &[u8]) -> sqlx::Result<i64> {
+        self.insert(envelope, message, "held").await
+    }
+
+    async fn insert(&self, envelope: &Envelope, message: &[u8], status: &str) -> sqlx::Result<i64> {
+        let mut tx = self.pool.begin().await?;
+
+        let (id,): (i64,) = sqlx::query_as(
+            "INSERT INTO queue (sender, params, data) VALUES ($1, $2, $3) RETURNING id",
+        )
+        .bind(envelope.from.as_ref().map(EmailAddress::as_str))
+        .bind(envelope.params.to_string())
+        .bind(message)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for recipient in &envelope.recipients {
+            sqlx::query(
+                "INSERT INTO queue_recipients (message_id, address, domain, params, status) \
+                VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(id)
+            .bind(recipient.address.as_str())
+            .bind(recipient.address.domain().to_ascii_lowercase())
+            .bind(recipient.params.to_string())
+            .bind(status)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        info!(
+            id,
+            recipients = envelope.recipients.len(),
+            status,
+            "message queued"
+        );
+
+        Ok(id)
+    }
+}
+
+/// Retry and expiry policy.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The delay after the first failed attempt, doubled after each
+    /// further attempt.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long to keep trying before giving up and bouncing.
+    pub max_age: Duration,
+    /// How often to check for due recipients when the queue is idle.
+    pub poll_interval: Duration,
+    /// How many recipients to claim at once.
+    pub batch_size: i64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(5 * 60),
+            max_backoff: Duration::from_secs(4 * 60 * 60),
+            max_age: Duration::from_secs(5 * 24 * 60 * 60),
+            poll_interval: Duration::from_secs(10),
+            batch_size: 100,
+        }
+    }
+}
+
+impl Config {
+    /// The delay before the next attempt after `attempts` failed ones.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// A recipient claimed for delivery.
+#[derive(sqlx::FromRow)]
+struct Due {
+    message_id: i64,
+    address: String,
+    domain: String,
+    params: String,
+    attempts: i32,
+}
+
+#[derive(sqlx::FromRow)]
+struct Message {
+    sender: Option<String>,
+    params: String,
+    data: Vec<u8>,
+    /// Seconds since the Unix epoch.
+    arrival: i64,
+    expired: bool,
+}
+
+/// Delivers queued messages in the background.
+pub struct Worker<R: Resolver> {
+    queue: Queue,
+    transport: Transport<R>,
+    config: Config,
+}
+
+impl<R: Resolver> Worker<R> {
+    #[must_use]
+    pub fn new(queue: Queue, transport: Transport<R>, config: Config) -> Self {
+        Self {
+            queue,
+            transport,
+            config,
+        }
+    }
+
+    /// Process the queue forever.
+    pub async fn run(&self) {
+        loop {
+            match self.process().await {
+                Ok(0) => tokio::time::sleep(self.config.poll_interval).await,
+                Ok(_) => (),
+                Err(e) => {
+                    error!(%e, "processing the queue failed");
+                    tokio::time::sleep(self.config.poll_interval).await;
+                }
+            }
+        }
+    }
+
+    /// Attempt delivery to the recipients that are due, grouped by message
+    /// and domain. Returns how many recipients were attempted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database is unavailable.
+    pub async fn process(&self) -> sqlx::Result<usize> {
+        let due: Vec<Due> = sqlx::query_as(
+            "UPDATE queue_recipients \
+            SET next_attempt_at = now() + $1 * INTERVAL '1 second' \
+            WHERE (message_id, address) IN ( \
+                SELECT message_id, address FROM queue_recipients \
+                WHERE status = 'pending' AND next_attempt_at <= now() \
+                ORDER BY next_attempt_at \
+                LIMIT $2 \
+                FOR UPDATE SKIP LOCKED \
+            ) \
+            RETURNING message_id, address, domain, params, attempts",
+        )
+        .bind(LEASE.as_secs_f64())
+        .bind(self.config.batch_size)
+        .fetch_all(&self.queue.pool)
+        .await?;
+
+        let count = due.len();
+        let mut groups: BTreeMap<_, Vec<Due>> = BTreeMap::new();
+        for due in due {
+            groups
+                .entry((due.message_id, due.domain.clone()))
+                .or_default()
+                .push(due);
+        }
+
+        for ((message_id, domain), recipients) in groups {
+            self.deliver(message_id, &domain, recipients).await?;
+        }
+
+        Ok(count)
+    }
+
+    #[instrument(skip(self, due))]
+    async fn deliver(&self, message_id: i64, domain: &str, due: Vec<Due>) -> sqlx::Result<()> {
+        let pool = &self.queue.pool;
+
+        let message: Option<Message> = sqlx::query_as(
+            "SELECT sender, params, data, \
+                extract(epoch FROM created_at)::BIGINT AS arrival, \
+                created_at < now() - $2 * INTERVAL '1 second' AS expired \
+            FROM queue WHERE id = $1",
+        )
+        .bind(message_id)
+        .bind(self.config.max_age.as_secs_f64())
+        .fetch_optional(pool)
+        .await?;
+        let Some(message) = message else {
+            warn!("message vanished from the queue");
+            return Ok(());
+        };
+
+        let mut envelope = Envelope::new(
+            message
+                .sender
+                .as_deref()
+                .and_then(|sender| EmailAddress::from_str(sender).ok()),
+            MailParams::from_str(&message.params).unwrap_or_default(),
+        );
+        let mut claimed = Vec::with_capacity(due.len());
+        let mut invalid = Vec::new();
+        for due in due {
+            let params = RcptParams::from_str(&due.params).unwrap_or_default();
+            match EmailAddress::from_str(&due.address) {
+                Ok(address) => {
+                    envelope.add_recipient(address, params);
+                    claimed.push(due);
+                }
+                Err(e) => {
+                    warn!(address = due.address, %e, "invalid recipient in queue");
+                    // kept as stored, so that the bounce can name it
+                    let recipient = Recipient {
+                        address: EmailAddress::new_unchecked(due.address.clone()),
+                        params,
+                    };
+                    invalid.push((recipient, due));
+                }
+            }
+        }
+
+        let outcomes = if envelope.recipients.is_empty() {
+            Vec::new()
+        } else {
+            self.transport
+                .deliver(domain, &envelope, &message.data)
+                .await
+        };
+        let invalid_outcome = Outcome {
+            status: Status::Failed,
+            reply: Reply::new(553, EnhancedCode::new(5, 1, 3), "invalid recipient address"),
+            remote_mta: None,
+        };
+
+        let mut failed = Vec::new();
+        for ((recipient, due), mut outcome) in envelope
+            .recipients
+            .iter()
+            .zip(&claimed)
+            .zip(outcomes)
+            .chain(
+                invalid
+                    .iter()
+                    .map(|(recipient, due)| ((recipient, due), invalid_outcome.clone())),
+            )
+        {
+            let attempts = u32::try_from(due.attempts).unwrap_or_default() + 1;
+            if outcome.status == Status::Deferred && message.expired {
+                outcome.status = Status::Failed;
+            }
+            debug!(
+                address = due.address,
+                ?outcome,
+                attempts,
+                "delivery attempted"
+            );
+
+            let (status, next_attempt) = match outcome.status {
+                Status::Delivered => ("delivered", Duration::ZERO),
+                Status::Failed => ("failed", Duration::ZERO),
+                Status::Deferred => ("pending", self.config.backoff(attempts)),
+            };
+            sqlx::query(
+                "UPDATE queue_recipients \
+                SET status = $3, attempts = attempts + 1, last_reply = $4, \
+                    next_attempt_at = now() + $5 * INTERVAL '1 second' \
+                WHERE message_id = $1 AND address = $2",
+            )
+            .bind(message_id)
+            .bind(&due.address)
+            .bind(status)
+            .bind(outcome.reply.to_string())
+            .bind(next_attempt.as_secs_f64())
+            .execute(pool)
+            .await?;
+
+            if outcome.status == Status::Failed {
+                failed.push((recipient, outcome));
+            }
+        }
+
+        if !failed.is_empty() {
+            self.bounce(&envelope, &message, &failed).await?;
+        }
+
+        sqlx::query(
+            "DELETE FROM queue WHERE id = $1 AND NOT EXISTS ( \
+                SELECT 1 FROM queue_recipients \
+                WHERE message_id = $1 AND status = 'pending' \
+            )",
+        )
+        .bind(message_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Queue a delivery status notification for the failed recipients.
+    async fn bounce(
+        &self,
+        envelope: &Envelope,
+        message: &Message,
+        failed: &[(&Recipient, Outcome)],
+    ) -> sqlx::Result<()> {
+        let report = dsn::Report {
+            reporting_mta: &self.transport.hostname,
+            envelope,
+            arrival_date: UNIX_EPOCH
+                + Duration::from_secs(u64::try_from(message.arrival).unwrap_or_default()),
+            date: SystemTime::now(),
+            recipients: failed
+                .iter()
+                .map(|(recipient, outcome)| RecipientStatus {
+                    recipient,
+                    action: Action::Failed,
+                    status: match outcome.reply.enhanced {
+                        Some(code) if code.class == 5 => code,
+                        // gave up on a temporary failure
+                        _ => EnhancedCode::new(5, 4, 7),
+                    },
+                    remote_mta: outcome.remote_mta.as_deref(),
+                    diagnostic: Some(&outcome.reply),
+                })
+                .collect(),
+        };
+
+        let (Some(sender), Some(bounce)) = (&envelope.from, report.generate(&message.data)) else {
+            return Ok(());
+        };
+
+        let mut bounce_envelope = Envelope::new(None, MailParams::default());
+        bounce_envelope.add_recipient(sender.clone(), RcptParams::default());
+        self.queue.enqueue(&bounce_envelope, &bounce).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Config;
+
+    #[test]
+    fn backoff() {
+        let config = Config {
+            initial_backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(600),
+            ..Config::default()
+        };
+
+        let backoff: Vec<_> = (1..7)
+            .map(|attempts| config.backoff(attempts).as_secs())
+            .collect();
+        assert_eq!(backoff, [60, 120, 240, 480, 600, 600]);
+        assert_eq!(config.backoff(100), Duration::from_secs(600));
+    }
+}
@@ -0,0 +1,387 @@
+//! Delivery of a message to the mail exchangers of one domain.
+
+use std::{net::SocketAddr, time::Duration};
+
+use dns::Resolver;
+use smtp::{
+    client::{self, Client},
+    message::Envelope,
+    reply::{EnhancedCode, Reply},
+};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_rustls::{rustls::ServerName, TlsConnector};
+use tracing::{debug, info, instrument};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Delivered,
+    /// Delivery failed temporarily and should be retried.
+    Deferred,
+    /// Delivery failed permanently.
+    Failed,
+}
+
+/// The result of a delivery attempt for one recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub status: Status,
+    /// The reply of the remote MTA, or one describing why it couldn't be
+    /// reached.
+    pub reply: Reply,
+    /// The host that sent `reply`, if any.
+    pub remote_mta: Option<String>,
+}
+
+impl Outcome {
+    fn remote(reply: Reply, host: &str) -> Self {
+        let status = match reply.code / 100 {
+            2 => Status::Delivered,
+            5 => Status::Failed,
+            _ => Status::Deferred,
+        };
+        Self {
+            status,
+            reply,
+            remote_mta: Some(host.to_owned()),
+        }
+    }
+
+    fn local(code: u16, enhanced: EnhancedCode, text: String) -> Self {
+        let status = if code >= 500 {
+            Status::Failed
+        } else {
+            Status::Deferred
+        };
+        Self {
+            status,
+            reply: Reply::new(code, enhanced, text),
+            remote_mta: None,
+        }
+    }
+}
+
+/// Relays messages over SMTP to the mail exchangers found with `resolver`.
+pub struct Transport<R: Resolver> {
+    pub resolver: R,
+    /// The name we introduce ourselves with.
+    pub hostname: String,
+    /// The port to connect to, normally 25.
+    pub port: u16,
+    /// Used for STARTTLS when the server supports it. Delivery falls back
+    /// to plain text if the handshake or certificate verification fails.
+    pub tls: Option<TlsConnector>,
+    /// How long a session with one host may take.
+    pub timeout: Duration,
+}
+
+impl<R: Resolver> Transport<R> {
+    /// The hosts to try in order, or the outcome for all recipients if
+    /// there are none.
+    async fn hosts(&self, domain: &str) -> Result<Vec<String>, Outcome> {
+        match self.resolver.mx(domain).await {
+            // implicit MX (Section 5.1 of RFC 5321)
+            Ok(mx) if mx.is_empty() => Ok(vec![domain.to_owned()]),
+            Ok(mx) if mx.iter().any(dns::Mx::is_null) => Err(Outcome::local(
+                556,
+                EnhancedCode::new(5, 1, 10),
+                format!("{domain} does not accept mail"),
+            )),
+            Ok(mx) => Ok(mx.into_iter().map(|mx| mx.exchange).collect()),
+            Err(e) if e.is_temporary() => Err(Outcome::local(
+                451,
+                EnhancedCode::new(4, 4, 3),
+                format!("MX lookup for {domain} failed: {e}"),
+            )),
+            Err(e) => Err(Outcome::local(
+                550,
+                EnhancedCode::new(5, 1, 2),
+                format!("MX lookup for {domain} failed: {e}"),
+            )),
+        }
+    }
+
+    /// Deliver `message` to the recipients of `envelope`, which must all be
+    /// in `domain`. Returns an outcome for each recipient.
+    #[instrument(skip(self, envelope, message))]
+    pub async fn deliver(&self, domain: &str, envelope: &Envelope, message: &[u8]) -> Vec<Outcome> {
+        let all = |outcome: Outcome| vec![outcome; envelope.recipients.len()];
+
+        let hosts = match self.hosts(domain).await {
+            Ok(hosts) => hosts,
+            Err(outcome) => return all(outcome),
+        };
+
+        let mut last = None;
+        for host in &hosts {
+            let addrs = match self.resolver.ip(host).await {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    debug!(host, %e, "address lookup failed");
+                    last = Some(format!("address lookup for {host} failed: {e}"));
+                    continue;
+                }
+            };
+
+            for ip in addrs {
+                let addr = SocketAddr::new(ip, self.port);
+                match timeout(self.timeout, self.session(host, addr, envelope, message)).await {
+                    Ok(Ok(outcomes)) => return outcomes,
+                    Ok(Err(client::Error::Reply(reply))) if reply.code >= 500 => {
+                        return all(Outcome::remote(reply, host));
+                    }
+                    Ok(Err(e)) => {
+                        debug!(host, %addr, %e, "delivery failed");
+                        last = Some(format!("delivery to {host} ({addr}) failed: {e}"));
+                    }
+                    Err(_) => {
+                        debug!(host, %addr, "delivery timed out");
+                        last = Some(format!("delivery to {host} ({addr}) timed out"));
+                    }
+                }
+            }
+        }
+
+        all(Outcome::local(
+            451,
+            EnhancedCode::new(4, 4, 1),
+            last.unwrap_or_else(|| format!("no addresses found for {domain}")),
+        ))
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> Result<Client<TcpStream>, client::Error> {
+        let stream = TcpStream::connect(addr).await?;
+        Client::connect(stream, self.hostname.clone()).await
+    }
+
+    async fn session(
+        &self,
+        host: &str,
+        addr: SocketAddr,
+        envelope: &Envelope,
+        message: &[u8],
+    ) -> Result<Vec<Outcome>, client::Error> {
+        let mut client = self.connect(addr).await?;
+
+        // TLS is opportunistic (RFC 7435): a certificate that doesn't
+        // verify is better than no encryption at all, but a server that
+        // can't do TLS with us still gets the message in plain text
+        let mut tls = false;
+        if let Some(connector) = &self.tls {
+            if client
+                .extensions()
+                .extensions
+                .contains(smtp::ehlo::Extensions::STARTTLS)
+            {
+                let name = ServerName::try_from(host).map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid host name")
+                })?;
+                match client.starttls((name, connector)).await {
+                    Ok(()) => tls = true,
+                    // the session is still usable
+                    Err(client::Error::Reply(reply)) => {
+                        debug!(host, %reply, "STARTTLS refused, continuing in plain text");
+                    }
+                    Err(e) => {
+                        debug!(host, %e, "STARTTLS failed, reconnecting in plain text");
+                        client = self.connect(addr).await?;
+                    }
+                }
+            }
+        }
+
+        let delivery = client.send(envelope, message).await?;
+        info!(host, %addr, tls, "message sent");
+        if let Err(e) = client.quit().await {
+            debug!(%e, "QUIT failed");
+        }
+
+        Ok(delivery
+            .replies()
+            .map(|reply| Outcome::remote(reply.clone(), host))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc, time::Duration};
+
+    use dns::StaticResolver;
+    use email_address::EmailAddress;
+    use smtp::{
+        command::params::{MailParams, RcptParams},
+        message::Envelope,
+        reply::{EnhancedCode, Reply},
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::rustls;
+
+    use super::{Outcome, Status, Transport};
+
+    fn envelope() -> Envelope {
+        let mut envelope = Envelope::new(
+            Some(EmailAddress::from_str("alice@example.org").unwrap()),
+            MailParams::default(),
+        );
+        for to in ["bob@example.com", "carol@example.com"] {
+            envelope.add_recipient(EmailAddress::from_str(to).unwrap(), RcptParams::default());
+        }
+        envelope
+    }
+
+    fn transport(resolver: StaticResolver, port: u16) -> Transport<StaticResolver> {
+        Transport {
+            resolver,
+            hostname: "mail.example.org".to_owned(),
+            port,
+            tls: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// A server that accepts bob and rejects everyone else.
+    async fn server(listener: TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        serve(stream, false).await;
+    }
+
+    /// Serve one session. With `starttls`, STARTTLS is advertised, but the
+    /// connection is closed instead of starting the handshake.
+    async fn serve(stream: TcpStream, starttls: bool) {
+        let mut stream = BufReader::new(stream);
+        stream.write_all(b"220 mx.example.com\r\n").await.unwrap();
+
+        let mut line = String::new();
+        let mut data = false;
+        loop {
+            line.clear();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                return;
+            }
+            let reply: &[u8] = match line.as_str() {
+                _ if data && line == ".\r\n" => {
+                    data = false;
+                    b"250 queued\r\n"
+                }
+                _ if data => continue,
+                "DATA\r\n" => {
+                    data = true;
+                    b"354 go ahead\r\n"
+                }
+                "RCPT TO:<carol@example.com>\r\n" => b"550 no such user\r\n",
+                _ if starttls && line.starts_with("EHLO ") => {
+                    b"250-mx.example.com\r\n250 STARTTLS\r\n"
+                }
+                "STARTTLS\r\n" => {
+                    stream.write_all(b"220 go ahead\r\n").await.unwrap();
+                    return;
+                }
+                "QUIT\r\n" => b"221 bye\r\n",
+                _ => b"250 mx.example.com\r\n",
+            };
+            stream.write_all(reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn deliver() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(server(listener));
+
+        let resolver = StaticResolver::default()
+            .with_mx("example.com", 20, "mx2.example.com")
+            .with_mx("example.com", 10, "mx1.example.com")
+            .with_ip("mx2.example.com", [127, 0, 0, 1].into());
+        let outcomes = transport(resolver, port)
+            .deliver("example.com", &envelope(), b"hello\r\n")
+            .await;
+        server.await.unwrap();
+
+        assert_eq!(
+            outcomes,
+            [
+                Outcome {
+                    status: Status::Delivered,
+                    reply: Reply::new(250, None, "queued"),
+                    remote_mta: Some("mx2.example.com".to_owned()),
+                },
+                Outcome {
+                    status: Status::Failed,
+                    reply: Reply::new(550, None, "no such user"),
+                    remote_mta: Some("mx2.example.com".to_owned()),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn starttls_fallback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, true).await;
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, true).await;
+        });
+
+        let resolver = StaticResolver::default().with_ip("example.com", [127, 0, 0, 1].into());
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let transport = Transport {
+            tls: Some(Arc::new(tls).into()),
+            ..transport(resolver, port)
+        };
+
+        let outcomes = transport
+            .deliver("example.com", &envelope(), b"hello\r\n")
+            .await;
+        server.await.unwrap();
+
+        assert_eq!(outcomes[0].status, Status::Delivered);
+        assert_eq!(outcomes[1].status, Status::Failed);
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        // nothing listens on the port after the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let resolver = StaticResolver::default()
+            .with_ip("example.com", [127, 0, 0, 1].into())
+            .with_mx("example.net", 0, ".");
+        let transport = transport(resolver, port);
+
+        let outcomes = transport
+            .deliver("example.com", &envelope(), b"hello\r\n")
+            .await;
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| outcome.status == Status::Deferred
+                && outcome.reply.enhanced == Some(EnhancedCode::new(4, 4, 1))));
+
+        let outcomes = transport
+            .deliver("example.net", &envelope(), b"hello\r\n")
+            .await;
+        assert!(outcomes
+            .iter()
+            .all(|outcome| outcome.status == Status::Failed && outcome.reply.code == 556));
+
+        let outcomes = transport
+            .deliver("example.org", &envelope(), b"hello\r\n")
+            .await;
+        assert!(outcomes
+            .iter()
+            .all(|outcome| outcome.status == Status::Failed
+                && outcome.reply.enhanced == Some(EnhancedCode::new(5, 1, 2))));
+    }
+}
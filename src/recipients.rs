@@ -0,0 +1,57 @@
+//! Recipient validation against the `domains`, `users` and `aliases`
+//! tables.
+
+use auth::Identity;
+use email_address::EmailAddress;
+use smtp::{
+    message::Envelope,
+    server::policy::{RecipientPolicy, Verdict},
+};
+use sqlx::PgPool;
+use tracing::error;
+
+/// Accepts recipients that are users or aliases in local domains. Our
+/// users relay mail to other domains through submission, which doesn't use
+/// this.
+#[derive(Debug, Clone)]
+pub struct Recipients {
+    pool: PgPool,
+}
+
+impl Recipients {
+    #[must_use]
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl RecipientPolicy for Recipients {
+    async fn check(
+        &self,
+        recipient: &EmailAddress,
+        _envelope: &Envelope,
+        _identity: Option<&Identity>,
+    ) -> Verdict {
+        let lookup = sqlx::query_as(
+            "SELECT \
+                EXISTS (SELECT 1 FROM domains WHERE name = $1), \
+                EXISTS (SELECT 1 FROM users WHERE address = $2) \
+                    OR EXISTS (SELECT 1 FROM aliases WHERE address = $2)",
+        )
+        .bind(recipient.domain().to_ascii_lowercase())
+        .bind(recipient.as_str().to_lowercase())
+        .fetch_one(&self.pool)
+        .await;
+
+        match lookup {
+            Ok((true, true)) => Verdict::Accept,
+            Ok((true, false)) => Verdict::NoSuchUser,
+            Ok((false, _)) => Verdict::RelayDenied,
+            Err(e) => {
+                error!(%e, %recipient, "recipient lookup failed");
+                Verdict::TemporaryFailure
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Local delivery into the mailboxes served over IMAP.
+
+use sqlx::PgPool;
+use tracing::{info, instrument};
+
+/// The mailbox a delivered message goes to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Folder {
+    #[default]
+    Inbox,
+    /// Quarantined mail: mail that fails DMARC with `p=quarantine`, or that
+    /// the milter quarantines.
+    Spam,
+}
+
+impl Folder {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Inbox => "INBOX",
+            Self::Spam => "Spam",
+        }
+    }
+}
+
+/// Store `message` in `folder`, returning its UID.
+///
+/// # Errors
+///
+/// Returns an error if the message couldn't be stored.
+#[instrument(skip(pool, message))]
+pub async fn deliver(pool: &PgPool, folder: Folder, message: &[u8]) -> sqlx::Result<i32> {
+    let mut tx = pool.begin().await?;
+
+    let (uid,): (i32,) = sqlx::query_as(
+        "UPDATE mailboxes SET next_uid = next_uid + 1 WHERE name = $1 RETURNING next_uid - 1",
+    )
+    .bind(folder.name())
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query("INSERT INTO messages (mailbox, uid, data) VALUES ($1, $2, $3)")
+        .bind(folder.name())
+        .bind(uid)
+        .bind(message)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    info!(uid, bytes = message.len(), "message stored");
+
+    Ok(uid)
+}
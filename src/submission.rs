@@ -0,0 +1,181 @@
+//! Mail submitted by our users. Each message passes through a series of
+//! stages before it enters the outbound [`Queue`].
+//!
+//! Mail we forward, e.g. for aliases and mailing lists, is sealed with ARC
+//! instead, so that receivers can trust our authentication results once the
+//! forwarding has broken the original DKIM signatures.
+
+use std::time::SystemTime;
+
+use smtp::message::Envelope;
+use tracing::{instrument, warn};
+
+use crate::{authentication::Results, queue::Queue};
+
+pub struct Pipeline {
+    queue: Queue,
+    dkim: dkim::Keys,
+}
+
+impl Pipeline {
+    #[must_use]
+    pub fn new(queue: Queue, dkim: dkim::Keys) -> Self {
+        Self { queue, dkim }
+    }
+
+    /// Run the stages on `message`:
+    ///
+    /// 1. Sign it with the DKIM key of the domain in its `From:` header.
+    #[must_use]
+    pub fn prepare(&self, mut message: Vec<u8>) -> Vec<u8> {
+        match self.dkim.sign(&message, SystemTime::now()) {
+            Ok(Some(signature)) => {
+                message.splice(0..0, signature.into_bytes());
+            }
+            Ok(None) => (),
+            // an unsigned message is still better than none
+            Err(e) => warn!(%e, "DKIM signing failed"),
+        }
+        message
+    }
+
+    /// Prepare `message` and add it to the outbound queue.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message couldn't be queued.
+    #[instrument(skip_all, fields(from = ?envelope.from))]
+    pub async fn submit(&self, envelope: &Envelope, message: Vec<u8>) -> sqlx::Result<i64> {
+        let message = self.prepare(message);
+        self.queue.enqueue(envelope, &message).await
+    }
+
+    /// Add `message` to the outbound queue as is, but hold it there instead
+    /// of delivering it. It isn't signed, as it may never be sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message couldn't be queued.
+    #[instrument(skip_all, fields(from = ?envelope.from))]
+    pub async fn hold(&self, envelope: &Envelope, message: Vec<u8>) -> sqlx::Result<i64> {
+        self.queue.hold(envelope, &message).await
+    }
+
+    /// Add an ARC set to `message`, which was checked with `results` when it
+    /// arrived, with the key of the domain of the new reverse-path.
+    #[must_use]
+    pub fn seal(&self, envelope: &Envelope, mut message: Vec<u8>, results: &Results) -> Vec<u8> {
+        let Some(signer) = envelope
+            .from
+            .as_ref()
+            .and_then(|from| self.dkim.get(from.domain()))
+        else {
+            return message;
+        };
+        match signer.seal(
+            &message,
+            &results.arc,
+            &results.to_string(),
+            SystemTime::now(),
+        ) {
+            Ok(Some(set)) => {
+                message.splice(0..0, set.into_bytes());
+            }
+            Ok(None) => warn!("ARC chain is too long to extend"),
+            Err(e) => warn!(%e, "ARC sealing failed"),
+        }
+        message
+    }
+
+    /// Seal `message` and add it to the outbound queue for `envelope`,
+    /// whose reverse-path is one of our domains.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message couldn't be queued.
+    #[instrument(skip_all, fields(from = ?envelope.from))]
+    pub async fn forward(
+        &self,
+        envelope: &Envelope,
+        message: Vec<u8>,
+        results: &Results,
+    ) -> sqlx::Result<i64> {
+        let message = self.seal(envelope, message, results);
+        self.queue.enqueue(envelope, &message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dkim::{Keys, PrivateKey, Signer};
+    use email_address::EmailAddress;
+    use smtp::{command::params::MailParams, message::Envelope};
+    use sqlx::PgPool;
+
+    use super::Pipeline;
+    use crate::{
+        authentication::{Results, SpfIdentity},
+        queue::Queue,
+    };
+
+    fn pipeline() -> Pipeline {
+        let key = PrivateKey::from_ed25519_seed(&[7; 32]).unwrap();
+        Pipeline::new(
+            Queue::new(PgPool::connect_lazy("postgres://localhost/brev").unwrap()),
+            Keys::default().with_signer(Signer::new("example.org", "mail", key)),
+        )
+    }
+
+    #[tokio::test]
+    async fn prepare() {
+        let pipeline = pipeline();
+
+        let message = b"From: alice@example.org\r\n\r\nhi\r\n".to_vec();
+        let signed = pipeline.prepare(message.clone());
+        assert!(signed.starts_with(b"DKIM-Signature: v=1; a=ed25519-sha256;"));
+        assert!(signed.ends_with(&message));
+
+        // not one of our domains
+        let message = b"From: bob@example.com\r\n\r\nhi\r\n".to_vec();
+        assert_eq!(pipeline.prepare(message.clone()), message);
+    }
+
+    #[tokio::test]
+    async fn seal() {
+        let pipeline = pipeline();
+        let results = Results {
+            hostname: "mx.example.org".to_owned(),
+            dkim: Vec::new(),
+            spf: spf::Verification {
+                status: spf::Status::Pass,
+                reason: None,
+            },
+            spf_identity: SpfIdentity::MailFrom("bob@example.com".to_owned()),
+            arc: dkim::arc::Validation {
+                status: dkim::arc::ChainStatus::None,
+                instance: 0,
+                reason: None,
+            },
+            dmarc: None,
+        };
+        let message = b"From: bob@example.com\r\n\r\nhi\r\n".to_vec();
+
+        let envelope = Envelope::new(
+            Some(EmailAddress::new_unchecked("list-bounces@example.org")),
+            MailParams::default(),
+        );
+        let sealed = pipeline.seal(&envelope, message.clone(), &results);
+        let sealed = String::from_utf8(sealed).unwrap();
+        assert!(sealed.starts_with("ARC-Seal: i=1; a=ed25519-sha256;"));
+        assert!(
+            sealed.contains("ARC-Authentication-Results: i=1; mx.example.org;\r\n\tdkim=none;\r\n")
+        );
+
+        // not one of our domains
+        let envelope = Envelope::new(
+            Some(EmailAddress::new_unchecked("bob@example.com")),
+            MailParams::default(),
+        );
+        assert_eq!(pipeline.seal(&envelope, message.clone(), &results), message);
+    }
+}
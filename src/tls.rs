@@ -0,0 +1,381 @@
+//! Server certificates from PEM files, chosen by the name the client asks
+//! for with SNI ([RFC 6066]) and reloaded when they are renewed.
+//!
+//! [RFC 6066]: https://datatracker.ietf.org/doc/html/rfc6066#section-3
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls_pemfile::Item;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::rustls::{
+    self,
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey, SigningKey},
+    Certificate, PrivateKey, SignatureScheme,
+};
+use tracing::{error, info};
+
+use crate::config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("can't read {}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("no certificates in {}", .0.display())]
+    NoCertificates(PathBuf),
+    #[error("no private key in {}", .0.display())]
+    NoKey(PathBuf),
+    #[error("unsupported private key in {}", .0.display())]
+    UnsupportedKey(PathBuf),
+    #[error("invalid certificate in {}: {1:?}", .0.display())]
+    InvalidCertificate(PathBuf, webpki::Error),
+    #[error("the key in {} isn't for the certificate in {}", key.display(), certificate.display())]
+    KeyMismatch { certificate: PathBuf, key: PathBuf },
+}
+
+/// How often the files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+fn open(path: &Path) -> Result<BufReader<File>, Error> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|source| Error::Read {
+            path: path.to_owned(),
+            source,
+        })
+}
+
+/// Read the certificate chain in `path`, leaf first.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read or has no certificates.
+pub fn certificates(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let certificates = rustls_pemfile::certs(&mut open(path)?).map_err(|source| Error::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+    if certificates.is_empty() {
+        return Err(Error::NoCertificates(path.to_owned()));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+/// Read the first private key in `path`, in PKCS #8, PKCS #1 (RSA) or SEC1
+/// (EC) format.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read or has no key.
+pub fn private_key(path: &Path) -> Result<PrivateKey, Error> {
+    let mut reader = open(path)?;
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader).map_err(|source| Error::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        match item {
+            Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key)) => {
+                return Ok(PrivateKey(key));
+            }
+            Some(_) => (),
+            None => return Err(Error::NoKey(path.to_owned())),
+        }
+    }
+}
+
+/// Signature schemes to check keys with, and how to verify them.
+static SCHEMES: [(SignatureScheme, &webpki::SignatureAlgorithm); 5] = [
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::RSA_PSS_SHA256,
+        &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Whether `key` belongs to `certificate`, found by signing with it.
+fn matches(certificate: &webpki::EndEntityCert, key: &dyn SigningKey) -> bool {
+    let schemes: Vec<_> = SCHEMES.iter().map(|(scheme, _)| *scheme).collect();
+    let Some(signer) = key.choose_scheme(&schemes) else {
+        return false;
+    };
+    let Some((_, algorithm)) = SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+    else {
+        return false;
+    };
+    let message = b"brev key check";
+    signer.sign(message).is_ok_and(|signature| {
+        certificate
+            .verify_signature(algorithm, message, &signature)
+            .is_ok()
+    })
+}
+
+/// Load a certificate chain and its key, along with the DNS names the
+/// certificate is for.
+fn load(files: &config::Certificate) -> Result<(Arc<CertifiedKey>, Vec<String>), Error> {
+    let chain = certificates(&files.certificate)?;
+    let key = sign::any_supported_type(&private_key(&files.key)?)
+        .map_err(|_| Error::UnsupportedKey(files.key.clone()))?;
+
+    let invalid = |e| Error::InvalidCertificate(files.certificate.clone(), e);
+    let leaf = webpki::EndEntityCert::try_from(chain[0].0.as_slice()).map_err(invalid)?;
+    if !matches(&leaf, key.as_ref()) {
+        return Err(Error::KeyMismatch {
+            certificate: files.certificate.clone(),
+            key: files.key.clone(),
+        });
+    }
+    let names = leaf
+        .dns_names()
+        .map_err(invalid)?
+        .map(|name| <&str>::from(name).to_ascii_lowercase())
+        .collect();
+
+    Ok((Arc::new(CertifiedKey::new(chain, key)), names))
+}
+
+/// A set of certificates by name.
+struct Certificates {
+    /// By name, with wildcard names like `*.example.com` as they are.
+    names: HashMap<String, Arc<CertifiedKey>>,
+    /// For clients that don't send a name, or one we have no certificate
+    /// for.
+    default: Arc<CertifiedKey>,
+}
+
+impl Certificates {
+    fn load(files: &[config::Certificate]) -> Result<Self, Error> {
+        let mut names = HashMap::new();
+        let mut default = None;
+        for files in files {
+            let (certified, certificate_names) = load(files)?;
+            for name in certificate_names {
+                // the first certificate for a name is used
+                names.entry(name).or_insert_with(|| certified.clone());
+            }
+            default.get_or_insert(certified);
+        }
+        Ok(Self {
+            names,
+            default: default.expect("at least one certificate"),
+        })
+    }
+
+    fn select(&self, name: Option<&str>) -> Arc<CertifiedKey> {
+        let Some(name) = name.map(str::to_ascii_lowercase) else {
+            return self.default.clone();
+        };
+        let wildcard = || {
+            let (_, domain) = name.split_once('.')?;
+            self.names.get(&format!("*.{domain}"))
+        };
+        self.names
+            .get(&name)
+            .or_else(wildcard)
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+/// Chooses a certificate by SNI. The certificates can be reloaded while
+/// the server runs: handshakes already done are unaffected, and new ones
+/// get the new certificates.
+pub struct Resolver {
+    files: Vec<config::Certificate>,
+    certificates: RwLock<Arc<Certificates>>,
+}
+
+impl Resolver {
+    /// Load the certificates in `files`. The first is the default.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the files can't be read, or a key doesn't
+    /// belong to its certificate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `files` is empty.
+    pub fn new(files: Vec<config::Certificate>) -> Result<Self, Error> {
+        let certificates = Certificates::load(&files)?;
+        Ok(Self {
+            files,
+            certificates: RwLock::new(Arc::new(certificates)),
+        })
+    }
+
+    /// A server configuration using this resolver.
+    #[must_use]
+    pub fn server_config(self: &Arc<Self>) -> Arc<rustls::ServerConfig> {
+        Arc::new(
+            rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(self.clone()),
+        )
+    }
+
+    fn current(&self) -> Arc<Certificates> {
+        self.certificates
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Load the certificates again. The old ones are kept if that fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the files can't be read, or a key doesn't
+    /// belong to its certificate.
+    pub fn reload(&self) -> Result<(), Error> {
+        let certificates = Arc::new(Certificates::load(&self.files)?);
+        *self.certificates.write().unwrap_or_else(|e| e.into_inner()) = certificates;
+        Ok(())
+    }
+
+    /// When the files were last modified.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files
+            .iter()
+            .flat_map(|files| [&files.certificate, &files.key])
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Reload the certificates on `SIGHUP` and when the files change,
+    /// forever.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the signal handler can't be installed.
+    pub async fn watch(&self) -> std::io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        let mut modified = self.modified();
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("SIGHUP, reloading certificates"),
+                _ = poll.tick() => {
+                    // a failed reload is tried again on the next change,
+                    // e.g. when the key is written after the certificate
+                    let now = self.modified();
+                    if now == modified {
+                        continue;
+                    }
+                    modified = now;
+                    info!("certificate files changed, reloading");
+                }
+            }
+            match self.reload() {
+                Ok(()) => info!("certificates reloaded"),
+                Err(e) => error!(%e, "reloading certificates failed, keeping the old ones"),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current().select(client_hello.server_name()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use tokio_rustls::rustls::sign::CertifiedKey;
+
+    use super::{Error, Resolver};
+    use crate::config;
+
+    /// Write a self-signed certificate for `names` and its key to `dir`.
+    fn generate(dir: &Path, file: &str, names: &[&str]) -> config::Certificate {
+        let names = names
+            .iter()
+            .map(|name| (*name).to_owned())
+            .collect::<Vec<_>>();
+        let cert = rcgen::generate_simple_self_signed(names).unwrap();
+        let files = config::Certificate {
+            certificate: dir.join(format!("{file}.crt")),
+            key: dir.join(format!("{file}.key")),
+        };
+        std::fs::write(&files.certificate, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&files.key, cert.serialize_private_key_pem()).unwrap();
+        files
+    }
+
+    fn select(resolver: &Resolver, name: Option<&str>) -> Arc<CertifiedKey> {
+        resolver.current().select(name)
+    }
+
+    #[test]
+    fn sni() {
+        let dir = std::env::temp_dir().join(format!("brev-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mail = generate(&dir, "mail", &["mail.example.com"]);
+        let org = generate(&dir, "org", &["example.org", "*.example.org"]);
+
+        let resolver = Resolver::new(vec![mail.clone(), org.clone()]).unwrap();
+        let default = select(&resolver, None);
+        let same = |name, expected: &Arc<CertifiedKey>| {
+            Arc::ptr_eq(&select(&resolver, Some(name)), expected)
+        };
+        assert!(same("mail.example.com", &default));
+        assert!(same("unknown.example.net", &default));
+        let wildcard = select(&resolver, Some("example.org"));
+        assert!(!Arc::ptr_eq(&wildcard, &default));
+        assert!(same("IMAP.example.org", &wildcard));
+        assert!(same("a.b.example.org", &default));
+
+        // renewals are picked up, and bad files leave the old ones in place
+        generate(&dir, "mail", &["mail.example.com"]);
+        resolver.reload().unwrap();
+        let renewed = select(&resolver, None);
+        assert!(!Arc::ptr_eq(&renewed, &default));
+        std::fs::copy(&org.key, &mail.key).unwrap();
+        assert!(matches!(resolver.reload(), Err(Error::KeyMismatch { .. })));
+        assert!(same("mail.example.com", &renewed));
+
+        for (files, error) in [
+            ((&mail.key, &mail.key), "no certificates in"),
+            ((&mail.certificate, &mail.certificate), "no private key in"),
+            ((&dir.join("missing.crt"), &mail.key), "can't read"),
+        ] {
+            let files = config::Certificate {
+                certificate: files.0.clone(),
+                key: files.1.clone(),
+            };
+            let e = Resolver::new(vec![files]).err().unwrap().to_string();
+            assert!(e.starts_with(error), "{e}");
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+//! Logins against the `users` table, whose passwords are Argon2 hashes in
+//! the PHC string format.
+
+use argon2::{
+    password_hash::{self, rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use auth::{Credentials, Identity, ValidationError};
+use secrecy::{ExposeSecret, SecretString};
+use sqlx::PgPool;
+use tracing::{error, warn};
+
+/// Users log in with their address and password. The identity is the
+/// address in lowercase.
+#[derive(Debug, Clone)]
+pub struct Users {
+    pool: PgPool,
+}
+
+impl Users {
+    #[must_use]
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl auth::Validator for Users {
+    async fn validate(&self, credentials: &Credentials) -> Result<Identity, ValidationError> {
+        let address = credentials.username.to_lowercase();
+        let hash: Option<(Option<String>,)> =
+            sqlx::query_as("SELECT password FROM users WHERE address = $1")
+                .bind(&address)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(|e| {
+                    error!(%e, "user lookup failed");
+                    ValidationError::Unknown
+                })?;
+        let Some((Some(hash),)) = hash else {
+            return Err(ValidationError::InvalidCredentials);
+        };
+
+        // hashing is slow on purpose, so keep it off the runtime
+        let password = credentials.password.clone();
+        let valid = tokio::task::spawn_blocking(move || verify(&hash, &password))
+            .await
+            .map_err(|_| ValidationError::Unknown)?;
+        if valid {
+            Ok(Identity(address))
+        } else {
+            Err(ValidationError::InvalidCredentials)
+        }
+    }
+}
+
+fn verify(hash: &str, password: &SecretString) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.expose_secret().as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            warn!(%e, "invalid password hash");
+            false
+        }
+    }
+}
+
+/// Hash `password` for the `password` column of `users`.
+///
+/// # Errors
+///
+/// Returns an error if the password is too long to hash.
+pub fn hash_password(password: &str) -> Result<String, password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use auth::{Credentials, Identity, ValidationError, Validator};
+    use sqlx::PgPool;
+
+    use super::{hash_password, verify, Users};
+
+    #[test]
+    fn hash() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify(&hash, &"hunter2".to_owned().into()));
+        assert!(!verify(&hash, &"hunter3".to_owned().into()));
+        assert!(!verify("hunter2", &"hunter2".to_owned().into()));
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn validate(pool: PgPool) {
+        sqlx::query("INSERT INTO domains (name) VALUES ('example.org')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO users (address, domain, password) VALUES \
+                ('alice@example.org', 'example.org', $1), \
+                ('bob@example.org', 'example.org', NULL)",
+        )
+        .bind(hash_password("hunter2").unwrap())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let users = Users::new(pool);
+        let login = |username: &str, password: &str| Credentials {
+            username: username.to_owned(),
+            password: password.to_owned().into(),
+        };
+        assert_eq!(
+            users
+                .validate(&login("Alice@example.org", "hunter2"))
+                .await
+                .unwrap(),
+            Identity("alice@example.org".to_owned())
+        );
+        for (username, password) in [
+            ("alice@example.org", "hunter3"),
+            ("bob@example.org", ""),
+            ("carol@example.org", "hunter2"),
+        ] {
+            assert!(matches!(
+                users.validate(&login(username, password)).await,
+                Err(ValidationError::InvalidCredentials)
+            ));
+        }
+    }
+}